@@ -1,12 +1,422 @@
 extern crate pkg_config;
 
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The gperftools release built by the `vendored` feature
+const GPERFTOOLS_VERSION: &str = "2.15";
+
+/// The SHA-256 of the release tarball of `GPERFTOOLS_VERSION`
+const GPERFTOOLS_SHA256: &str = "c69fef855628c81ef56f12e3c58f2b7ce1f326c0a1fe783e5cae0b88cbbe9a80";
+
+/// The oldest gperftools release the bindings support
+const MIN_VERSION: &str = "2.0";
+
+/// What `src/ffi/bindings.rs` declares, which an installed `profiler.h`
+/// must declare too
+const DECLARATIONS: &[&str] = &[
+    "struct ProfilerOptions",
+    "filter_in_thread_arg",
+    "ProfilerStartWithOptions",
+    "ProfilerStart",
+    "ProfilerStop",
+    "ProfilerFlush",
+    "ProfilerRegisterThread",
+    "struct ProfilerState",
+    "start_time",
+    "profile_name[1024]",
+    "samples_gathered",
+    "ProfilerGetCurrentState",
+];
+
+/// Where Homebrew, on Apple silicon and Intel, and MacPorts install libraries
+const MACOS_PREFIXES: &[&str] = &[
+    "/opt/homebrew/opt/gperftools",
+    "/opt/homebrew",
+    "/usr/local/opt/gperftools",
+    "/usr/local",
+    "/opt/local",
+];
+
 fn main () {
-    match pkg_config::Config::new().atleast_version("2.0").probe("libprofiler") {
-        Ok(_) => (),
-        Err(_) => {
-            // Old gperftools do not come with a pkg-config file so just rely
-            // on the linker's path.
-            println!("cargo:rustc-link-lib=profiler");
-        },
+    println!("cargo:rerun-if-env-changed=GPERFTOOLS_SRC");
+    println!("cargo:rerun-if-env-changed=GPERFTOOLS_INCLUDE_DIR");
+    set_rustc_version();
+
+    // Nothing is linked when profiling is compiled out.
+    if feature("DISABLED") {
+        return;
+    }
+
+    // libprofiler is not needed by the pure Rust backend, and is found at
+    // runtime with `dylib-load`.
+    let cpu = feature("GPERFTOOLS") && !feature("DYLIB_LOAD");
+    let heap = feature("HEAP");
+
+    if feature("STATIC") && feature("DYLIB_LOAD") {
+        panic!("the `static` and `dylib-load` features conflict, libprofiler is either linked \
+                statically or loaded at runtime");
+    }
+
+    if feature("VENDORED") {
+        build_vendored(cpu, heap);
+        return;
+    }
+
+    if feature("STATIC") {
+        link_static(cpu, heap);
+        return;
+    }
+
+    let macos = env::var("TARGET").unwrap().contains("apple");
+    if macos {
+        add_macos_pkg_config_paths();
+    }
+
+    if heap {
+        match pkg_config::Config::new().probe("libtcmalloc") {
+            Ok(lib) => check_version("libtcmalloc", "heap", &lib.version),
+            Err(_) => {
+                if macos {
+                    add_macos_link_paths("libtcmalloc.dylib");
+                }
+                println!("cargo:rustc-link-lib=tcmalloc");
+            }
+        }
+    }
+
+    if cpu {
+        match pkg_config::Config::new().probe("libprofiler") {
+            Ok(lib) => {
+                check_version("libprofiler", "gperftools", &lib.version);
+                check_header(&lib.include_paths);
+                set_version(&lib.version);
+            }
+            Err(_) => {
+                check_header(&[]);
+                // Old gperftools do not come with a pkg-config file so just rely
+                // on the linker's path, which on macOS misses the package
+                // managers' prefixes.
+                if macos {
+                    add_macos_link_paths("libprofiler.dylib");
+                }
+                println!("cargo:rustc-link-lib=profiler");
+            },
+        };
+    }
+}
+
+/// Record the compiler version for profile metadata
+fn set_rustc_version() {
+    let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=CPUPROFILER_RUSTC_VERSION={}", version.trim());
+}
+
+/// Let pkg-config find the `.pc` files of Homebrew and MacPorts
+///
+/// pkg-config from a package manager searches its own prefix, but not the
+/// others', and a pkg-config installed another way searches neither.
+fn add_macos_pkg_config_paths() {
+    println!("cargo:rerun-if-env-changed=PKG_CONFIG_PATH");
+    let mut paths: Vec<PathBuf> = env::var_os("PKG_CONFIG_PATH")
+        .map(|paths| env::split_paths(&paths).collect())
+        .unwrap_or_default();
+    for prefix in MACOS_PREFIXES {
+        let dir = Path::new(prefix).join("lib/pkgconfig");
+        if dir.is_dir() && !paths.contains(&dir) {
+            paths.push(dir);
+        }
+    }
+    if let Ok(joined) = env::join_paths(paths) {
+        env::set_var("PKG_CONFIG_PATH", joined);
+    }
+}
+
+/// Add the package managers' library directories which contain `library` to
+/// the link search path
+fn add_macos_link_paths(library: &str) {
+    for prefix in MACOS_PREFIXES {
+        let dir = Path::new(prefix).join("lib");
+        if dir.join(library).exists() {
+            println!("cargo:rustc-link-search=native={}", dir.display());
+            return;
+        }
+    }
+}
+
+fn feature(name: &str) -> bool {
+    env::var_os(format!("CARGO_FEATURE_{}", name)).is_some()
+}
+
+/// Build libprofiler, and libtcmalloc for the heap profiler, from source and
+/// link them statically
+///
+/// The source is taken from `GPERFTOOLS_SRC` if set, otherwise the release
+/// tarball is downloaded into `OUT_DIR` and checked against
+/// `GPERFTOOLS_SHA256`.
+fn build_vendored(cpu: bool, heap: bool) {
+    if !cpu && !heap {
+        return;
+    }
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let src = match env::var_os("GPERFTOOLS_SRC") {
+        Some(src) => PathBuf::from(src),
+        None => download(&out),
+    };
+
+    // The heap profiler is configured in, so it gets its own build directory.
+    let build = out.join(if heap { "gperftools-build-heap" } else { "gperftools-build" });
+    fs::create_dir_all(&build).unwrap();
+    if !build.join("Makefile").exists() {
+        let mut configure = Command::new(src.join("configure"));
+        configure
+            .current_dir(&build)
+            .args(["--enable-static", "--disable-shared", "--with-pic"])
+            .arg("--disable-debugalloc");
+        if !heap {
+            configure.args(["--disable-heap-profiler", "--disable-heap-checker"]);
+        }
+        run(&mut configure);
+    }
+    let jobs = env::var("NUM_JOBS").unwrap_or_else(|_| "1".to_owned());
+    let mut make = Command::new("make");
+    make.current_dir(&build).arg(format!("-j{}", jobs));
+    if cpu {
+        make.arg("libprofiler.la");
+    }
+    if heap {
+        make.arg("libtcmalloc.la");
+    }
+    run(&mut make);
+
+    println!("cargo:rustc-link-search=native={}", build.join(".libs").display());
+    if cpu {
+        set_version(GPERFTOOLS_VERSION);
+        println!("cargo:rustc-link-lib=static=profiler");
+    }
+    if heap {
+        println!("cargo:rustc-link-lib=static=tcmalloc");
+    }
+
+    // gperftools uses libunwind when configure found it.
+    let config = fs::read_to_string(build.join("src/config.h")).unwrap_or_default();
+    if config.contains("#define HAVE_LIBUNWIND_H 1") {
+        if feature("STATIC") {
+            let archive = find_archive(&static_search_dirs(), "libunwind.a");
+            link_archive(&archive, "unwind");
+        } else {
+            println!("cargo:rustc-link-lib=unwind");
+        }
+    }
+    link_cxx_runtime();
+}
+
+/// Link the static libprofiler, and libtcmalloc for the heap profiler, which
+/// are installed on the system
+///
+/// libunwind is linked statically too when libprofiler uses it.
+fn link_static(cpu: bool, heap: bool) {
+    let dirs = static_search_dirs();
+    let mut unwind = false;
+    if cpu {
+        let archive = find_archive(&dirs, "libprofiler.a");
+        unwind |= uses_libunwind(&archive);
+        link_archive(&archive, "profiler");
+        match static_probe("libprofiler") {
+            Ok(lib) => {
+                check_version("libprofiler", "gperftools", &lib.version);
+                check_header(&lib.include_paths);
+                set_version(&lib.version);
+            }
+            Err(_) => check_header(&[]),
+        }
+    }
+    if heap {
+        let archive = find_archive(&dirs, "libtcmalloc.a");
+        unwind |= uses_libunwind(&archive);
+        link_archive(&archive, "tcmalloc");
+    }
+    if unwind {
+        let archive = find_archive(&dirs, "libunwind.a");
+        link_archive(&archive, "unwind");
+    }
+    link_cxx_runtime();
+}
+
+/// Returns the directories static archives are looked for in, in order
+///
+/// `GPERFTOOLS_LIB_DIR` comes first. Archives built against glibc cannot be
+/// linked into musl binaries, so for musl targets only the usual musl
+/// prefixes follow it, and otherwise the directories pkg-config knows of
+/// and the usual library directories do.
+fn static_search_dirs() -> Vec<PathBuf> {
+    println!("cargo:rerun-if-env-changed=GPERFTOOLS_LIB_DIR");
+    let target = env::var("TARGET").unwrap();
+    let mut dirs: Vec<PathBuf> = env::var_os("GPERFTOOLS_LIB_DIR").map(PathBuf::from).into_iter().collect();
+    if target.contains("musl") {
+        dirs.push(PathBuf::from("/usr/local/musl/lib"));
+        dirs.push(PathBuf::from("/usr/lib/musl/lib"));
+        dirs.push(Path::new("/usr").join(&target).join("lib"));
+        return dirs;
+    }
+    for name in ["libprofiler", "libtcmalloc"] {
+        if let Ok(lib) = static_probe(name) {
+            dirs.extend(lib.link_paths);
+        }
+    }
+    let arch = target.split('-').next().unwrap_or_default();
+    dirs.push(PathBuf::from(format!("/usr/lib/{}-linux-gnu", arch)));
+    for dir in ["/usr/local/lib", "/usr/lib64", "/usr/lib", "/opt/homebrew/lib", "/opt/local/lib"] {
+        dirs.push(PathBuf::from(dir));
+    }
+    dirs
+}
+
+fn static_probe(name: &str) -> Result<pkg_config::Library, pkg_config::Error> {
+    pkg_config::Config::new().statik(true).cargo_metadata(false).probe(name)
+}
+
+/// Returns the path of `archive` in the first of `dirs` which has it
+fn find_archive(dirs: &[PathBuf], archive: &str) -> PathBuf {
+    if let Some(path) = dirs.iter().map(|dir| dir.join(archive)).find(|path| path.is_file()) {
+        return path;
+    }
+    let searched: Vec<String> = dirs.iter().map(|dir| dir.display().to_string()).collect();
+    let hint = if env::var("TARGET").unwrap().contains("musl") {
+        "archives built for glibc cannot be linked into musl binaries, so build gperftools with musl-gcc"
+    } else {
+        "install the static libraries of gperftools and libunwind, such as Debian's libgoogle-perftools-dev"
     };
+    panic!("the `static` feature needs {} but it is not in any of: {}\n\
+            {}, set GPERFTOOLS_LIB_DIR to the directory containing it, or enable the `vendored` \
+            feature to build libprofiler from source",
+           archive, searched.join(", "), hint);
+}
+
+/// Returns whether the static library at `archive` calls into libunwind
+fn uses_libunwind(archive: &Path) -> bool {
+    // libunwind's functions are named after the architecture, such as
+    // `_ULx86_64_init_local`, and all end the same.
+    let bytes = fs::read(archive).unwrap_or_default();
+    bytes.windows(b"_init_local".len()).any(|window| window == b"_init_local")
+}
+
+fn link_archive(archive: &Path, name: &str) {
+    println!("cargo:rerun-if-changed={}", archive.display());
+    println!("cargo:rustc-link-search=native={}", archive.parent().unwrap().display());
+    println!("cargo:rustc-link-lib=static={}", name);
+}
+
+/// Link the C++ standard library, which gperftools is written against
+fn link_cxx_runtime() {
+    if env::var("TARGET").unwrap().contains("apple") {
+        println!("cargo:rustc-link-lib=c++");
+    } else {
+        println!("cargo:rustc-link-lib=stdc++");
+    }
+}
+
+/// Fail the build when `library`, found at `version` for `feature`, is
+/// older than the bindings support
+fn check_version(library: &str, feature: &str, version: &str) {
+    let parse = |version: &str| -> Vec<u32> { version.split('.').map(|part| part.parse().unwrap_or(0)).collect() };
+    if parse(version) < parse(MIN_VERSION) {
+        panic!("the `{}` feature needs {} from gperftools {} or later, but pkg-config found {}\n\
+                upgrade gperftools, or enable the `vendored` feature to build gperftools {} from source",
+               feature, library, MIN_VERSION, version, GPERFTOOLS_VERSION);
+    }
+}
+
+/// Fail the build when the installed `gperftools/profiler.h` lacks any of
+/// `DECLARATIONS`
+///
+/// The header is looked for in `GPERFTOOLS_INCLUDE_DIR`, then in the
+/// directories pkg-config gave and the usual include directories. Only
+/// the library is needed to build, so nothing is checked without a header.
+fn check_header(include_paths: &[PathBuf]) {
+    let mut dirs: Vec<PathBuf> = env::var_os("GPERFTOOLS_INCLUDE_DIR").map(PathBuf::from).into_iter().collect();
+    dirs.extend(include_paths.iter().cloned());
+    dirs.push(PathBuf::from("/usr/local/include"));
+    dirs.push(PathBuf::from("/usr/include"));
+    dirs.extend(MACOS_PREFIXES.iter().map(|prefix| Path::new(prefix).join("include")));
+
+    let header = match dirs.iter().map(|dir| dir.join("gperftools/profiler.h")).find(|path| path.is_file()) {
+        Some(header) => header,
+        None => return,
+    };
+    println!("cargo:rerun-if-changed={}", header.display());
+    let text = fs::read_to_string(&header).unwrap_or_default();
+    let missing: Vec<&str> = DECLARATIONS.iter().cloned().filter(|decl| !text.contains(decl)).collect();
+    if !missing.is_empty() {
+        panic!("{} does not declare {}, which the bindings of cpuprofiler need\n\
+                install gperftools {} or later, set GPERFTOOLS_INCLUDE_DIR to the directory holding \
+                its gperftools/profiler.h, or enable the `vendored` feature to build gperftools {} from source",
+               header.display(), missing.join(", "), MIN_VERSION, GPERFTOOLS_VERSION);
+    }
+}
+
+/// Record the version of the linked library for `library_version`
+fn set_version(version: &str) {
+    println!("cargo:rustc-env=CPUPROFILER_LIBPROFILER_VERSION={}", version);
+}
+
+fn download(out: &Path) -> PathBuf {
+    let name = format!("gperftools-{}", GPERFTOOLS_VERSION);
+    let src = out.join(&name);
+    if src.join("configure").exists() {
+        return src;
+    }
+
+    let tarball = out.join(format!("{}.tar.gz", name));
+    let url = format!(
+        "https://github.com/gperftools/gperftools/releases/download/{0}/{0}.tar.gz",
+        name
+    );
+    run(Command::new("curl").args(["-sSfL", "-o"]).arg(&tarball).arg(&url));
+    let digest = sha256(&tarball);
+    if digest != GPERFTOOLS_SHA256 {
+        let _ = fs::remove_file(&tarball);
+        panic!("{} has the SHA-256 {}, not {}\nthe download may have been tampered with, \
+                set GPERFTOOLS_SRC to an unpacked gperftools release you trust",
+               url, digest, GPERFTOOLS_SHA256);
+    }
+    run(Command::new("tar").arg("-xzf").arg(&tarball).arg("-C").arg(out));
+    src
+}
+
+/// Returns the SHA-256 of `file` in hex, with `sha256sum` or else `shasum`
+fn sha256(file: &Path) -> String {
+    let tools: [&[&str]; 2] = [&["sha256sum"], &["shasum", "-a", "256"]];
+    for tool in tools.iter() {
+        let output = match Command::new(tool[0]).args(&tool[1..]).arg(file).output() {
+            Ok(output) if output.status.success() => output,
+            _ => continue,
+        };
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if let Some(digest) = stdout.split_whitespace().next() {
+            return digest.to_ascii_lowercase();
+        }
+    }
+    panic!("could not hash {}\nthe `vendored` feature needs sha256sum or shasum, \
+            or set GPERFTOOLS_SRC to an unpacked gperftools release", file.display());
+}
+
+fn run(cmd: &mut Command) {
+    let status = cmd.status().unwrap_or_else(|e| {
+        panic!("failed to run {:?}: {}\nthe `vendored` feature needs curl, tar, make and a C++ compiler, \
+                or set GPERFTOOLS_SRC to an unpacked gperftools release", cmd, e)
+    });
+    if !status.success() {
+        panic!("{:?} failed with {}\nset GPERFTOOLS_SRC to an unpacked gperftools release to build from local sources",
+               cmd, status);
+    }
 }
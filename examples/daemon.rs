@@ -0,0 +1,76 @@
+//! A long running service profiled continuously by `agent::run`
+//!
+//! The configuration is read from the TOML file given as the argument, or
+//! else from the environment:
+//!
+//! ```text
+//! $ CPUPROFILER_AGENT_DIR=/tmp/daemon-profiles CPUPROFILER_AGENT_INTERVAL_SECS=5 \
+//!     CPUPROFILER_AGENT_CONTROL_SOCKET=/tmp/daemon.sock \
+//!     cargo run --example daemon --features agent
+//! ```
+//!
+//! The service runs until its input is closed or a line is entered, and
+//! `echo status | socat - UNIX-CONNECT:/tmp/daemon.sock` asks it what it
+//! is profiling meanwhile.
+
+extern crate cpuprofiler;
+
+use std::env;
+use std::io::{self, BufRead};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use cpuprofiler::agent::{self, AgentConfig};
+
+fn main() {
+    let config = match env::args_os().nth(1) {
+        Some(path) => AgentConfig::from_file(path),
+        None => AgentConfig::from_env(),
+    };
+    let config = config.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let running = agent::run(config).unwrap_or_else(|e| {
+        eprintln!("profiling could not start: {}", e);
+        process::exit(1);
+    });
+
+    let stopping = Arc::new(AtomicBool::new(false));
+    let workers: Vec<_> = (0..4)
+        .map(|worker| {
+            let stopping = stopping.clone();
+            thread::spawn(move || {
+                let mut handled = 0u64;
+                while !stopping.load(Ordering::SeqCst) {
+                    handle_request(worker + handled);
+                    handled += 1;
+                }
+                handled
+            })
+        })
+        .collect();
+
+    println!("serving, enter a line to stop");
+    let _ = io::stdin().lock().lines().next();
+
+    stopping.store(true, Ordering::SeqCst);
+    let handled: u64 = workers.into_iter().map(|worker| worker.join().unwrap()).sum();
+    println!("handled {} requests", handled);
+
+    match running.stop() {
+        Ok(files) => {
+            for file in files {
+                println!("profiled into {}", file.display());
+            }
+        }
+        Err(e) => eprintln!("profiling stopped early: {}", e),
+    }
+}
+
+/// Stands in for the work of the service
+fn handle_request(seed: u64) -> u64 {
+    (0..100_000u64).fold(seed, |acc, x| acc.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(x))
+}
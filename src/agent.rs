@@ -0,0 +1,748 @@
+//! Continuous profiling agent
+//!
+//! Available with the `agent` feature.
+//!
+//! The agent repeatedly profiles the process for a fixed window, converts each
+//! window to pprof and uploads it to a [Pyroscope](https://pyroscope.io) or
+//! [Parca](https://parca.dev) server. Profiling is done through the shared
+//! `PROFILER`, which is only locked while a window is started or stopped.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use cpuprofiler::agent::Agent;
+//!
+//! let agent = Agent::pyroscope("http://localhost:4040", "my-service")
+//!     .interval(Duration::from_secs(10))
+//!     .label("env", "staging")
+//!     .start()
+//!     .unwrap();
+//!
+//! // The service runs and is profiled in the background...
+//!
+//! agent.stop().unwrap();
+//! ```
+//!
+//! # Running as a service
+//!
+//! `run` sets up the profiling a long running service needs in one call:
+//! a [rotation](../rotate/index.html) profiling continuously into a
+//! directory, with each profile uploaded to a [sink](../sink/index.html),
+//! a [control socket](../control/index.html) for operators and a
+//! [policy](../policy/index.html) limiting the profiles they start. Its
+//! `AgentConfig` is built in code, or read with `AgentConfig::from_file` or
+//! `AgentConfig::from_env` so that deployments configure profiling without
+//! rebuilding, from the `[agent]` table of a TOML file:
+//!
+//! ```toml
+//! [agent]
+//! dir = "/var/lib/my-service/profiles"
+//! prefix = "my-service"
+//! interval_secs = 60
+//! retain = 10
+//! upload = "s3://my-profiles/my-service/"
+//! control_socket = "/run/my-service/profiler.sock"
+//! max_sessions_per_hour = 4
+//! max_duration_secs = 120
+//! allow = ["socket", "signal"]
+//! dir_quota_bytes = 536870912
+//! ```
+//!
+//! | Key | | Default |
+//! |-----|-|---------|
+//! | `dir` | where profiles are written, created if missing | `cpuprofiler` in the temporary directory |
+//! | `prefix` | the start of each file name | `cpu` |
+//! | `interval_secs` | how long each profile covers | 60 |
+//! | `retain` | how many profiles are kept in `dir` | all of them |
+//! | `continuous` | whether to profile continuously, or only when triggered | `true` |
+//! | `upload` | where to upload each profile, see `AgentConfig::upload` | nowhere |
+//! | `control_socket` | the path of a control socket | none |
+//! | `max_sessions_per_hour` | see `Policy::max_sessions_per_hour` | no limit |
+//! | `max_duration_secs` | see `Policy::max_duration` | no limit |
+//! | `allow` | the triggers allowed, see `policy::Trigger` | all of them |
+//! | `dir_quota_bytes` | how much `dir` may hold before triggers are refused | no limit |
+//!
+//! With `from_env` the variable `CPUPROFILER_AGENT_CONFIG` names the file,
+//! and each key is overridden by its variable in upper case, such as
+//! `CPUPROFILER_AGENT_INTERVAL_SECS`, with `allow` separated by commas and
+//! replacing the triggers the file allows.
+//!
+//! ```no_run
+//! use cpuprofiler::agent::{self, AgentConfig};
+//!
+//! let running = agent::run(AgentConfig::from_env().unwrap()).unwrap();
+//!
+//! // The service runs and is profiled in the background...
+//!
+//! running.stop().unwrap();
+//! ```
+//!
+//! The policy only limits the profiles started from the control socket and
+//! other triggers. While the rotation runs a profile is always running, so
+//! a `start` on the socket is answered with an error. A `stop` ends the
+//! rotation for good, keeping the profile it stopped without uploading it,
+//! and from then on the socket starts and stops profiles of its own. Set
+//! `continuous = false` to only profile when triggered, in which case
+//! nothing is uploaded.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ureq;
+
+#[cfg(unix)]
+use control::{ControlHandle, Controller};
+use error::Error;
+use json;
+use lock;
+use policy::{Policy, Trigger};
+use pprof::Encoder;
+use profile::Profile;
+use rotate::{RotatingHandle, RotatingProfiler};
+use sink::Directory;
+#[cfg(feature = "gcs")]
+use sink::Gcs;
+#[cfg(feature = "upload")]
+use sink::HttpPut;
+#[cfg(feature = "s3")]
+use sink::S3;
+use toml::{self, Value};
+
+/// The server profiles are uploaded to
+#[derive(Clone, Debug)]
+enum Ingest {
+    Pyroscope { url: String, app_name: String },
+    Parca { url: String },
+}
+
+/// Configuration for a continuous profiling agent
+#[derive(Clone, Debug)]
+pub struct Agent {
+    ingest: Ingest,
+    interval: Duration,
+    labels: Vec<(String, String)>,
+    scratch: PathBuf,
+}
+
+impl Agent {
+    /// Upload to the Pyroscope server at `url` under `app_name`
+    ///
+    /// Profiles are sent to the server's `/ingest` endpoint.
+    pub fn pyroscope<U: Into<String>, N: Into<String>>(url: U, app_name: N) -> Agent {
+        Agent::new(Ingest::Pyroscope {
+            url: url.into(),
+            app_name: app_name.into(),
+        })
+    }
+
+    /// Upload to the Parca server at `url`
+    ///
+    /// Profiles are sent to the HTTP gateway of the server's `WriteRaw` endpoint.
+    pub fn parca<U: Into<String>>(url: U) -> Agent {
+        Agent::new(Ingest::Parca { url: url.into() })
+    }
+
+    fn new(ingest: Ingest) -> Agent {
+        let scratch = env::temp_dir().join(format!("cpuprofiler-agent-{}.profile", process::id()));
+        Agent {
+            ingest,
+            interval: Duration::from_secs(10),
+            labels: Vec::new(),
+            scratch,
+        }
+    }
+
+    /// Set the length of each profiling window, defaults to 10 seconds
+    pub fn interval(mut self, interval: Duration) -> Agent {
+        self.interval = interval;
+        self
+    }
+
+    /// Attach a label to every uploaded profile
+    pub fn label<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Agent {
+        self.labels.push((key.into(), value.into()));
+        self
+    }
+
+    /// Set the file each window is written to before upload
+    ///
+    /// Defaults to a file in the system temporary directory.
+    pub fn scratch_file<P: Into<PathBuf>>(mut self, path: P) -> Agent {
+        self.scratch = path.into();
+        self
+    }
+
+    /// Start profiling and uploading on a background thread
+    ///
+    /// The agent ends when its window is stopped elsewhere, and that window
+    /// is not uploaded.
+    ///
+    /// # Failures
+    ///
+    /// - The first profiling window could not be started, see `Profiler::start`.
+    pub fn start(self) -> Result<AgentHandle, Error> {
+        let mut session = self.start_window()?;
+
+        let (tx, rx) = mpsc::channel();
+        let failed = Arc::new(AtomicUsize::new(0));
+        let failed_uploads = failed.clone();
+
+        let thread = thread::spawn(move || loop {
+            let started = SystemTime::now();
+            let stopping = !matches!(rx.recv_timeout(self.interval), Err(RecvTimeoutError::Timeout));
+
+            // A window stopped elsewhere ends the agent, and a profile started
+            // elsewhere since is left running.
+            let ours = {
+                let mut profiler = lock::lock();
+                let ours = profiler.session == session && profiler.state.is_running();
+                if ours {
+                    profiler.stop()?;
+                }
+                ours
+            };
+            if ours && self.upload(started).is_err() {
+                failed_uploads.fetch_add(1, Ordering::SeqCst);
+            }
+
+            if stopping || !ours {
+                return Ok(());
+            }
+            session = self.start_window()?;
+        });
+
+        Ok(AgentHandle {
+            stop: tx,
+            thread,
+            failed,
+        })
+    }
+
+    /// Start the next window, returning its session
+    fn start_window(&self) -> Result<u64, Error> {
+        let mut profiler = lock::lock();
+        profiler.start_path(&self.scratch)?;
+        Ok(profiler.session)
+    }
+
+    fn upload(&self, started: SystemTime) -> Result<(), Error> {
+        let profile = Profile::from_file(&self.scratch)?;
+        let duration = started.elapsed().unwrap_or_default();
+        let mut encoder = Encoder::new(&profile).time(started).duration(duration);
+        for (k, v) in &self.labels {
+            encoder = encoder.label(k.clone(), v.clone());
+        }
+        let body = encoder.encode();
+
+        let result = match self.ingest {
+            Ingest::Pyroscope { ref url, ref app_name } => {
+                let from = unix_secs(started);
+                let labels: Vec<String> = self.labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+                ureq::post(&format!("{}/ingest", url.trim_end_matches('/')))
+                    .query("name", &format!("{}{{{}}}", app_name, labels.join(",")))
+                    .query("from", &from.to_string())
+                    .query("until", &(from + duration.as_secs()).to_string())
+                    .query("format", "pprof")
+                    .query("sampleRate", &profile.frequency().to_string())
+                    .set("Content-Type", "application/octet-stream")
+                    .send_bytes(&body)
+            }
+            Ingest::Parca { ref url } => {
+                let mut labels = vec![("__name__".to_owned(), "process_cpu".to_owned())];
+                labels.extend(self.labels.iter().cloned());
+                let labels: Vec<String> = labels
+                    .iter()
+                    .map(|(k, v)| format!("{{\"name\":{},\"value\":{}}}", json::string(k), json::string(v)))
+                    .collect();
+                let request = format!(
+                    "{{\"series\":[{{\"labels\":{{\"labels\":[{}]}},\"samples\":[{{\"rawProfile\":\"{}\"}}]}}]}}",
+                    labels.join(","),
+                    base64(&body)
+                );
+                ureq::post(&format!("{}/profiles/writeraw", url.trim_end_matches('/')))
+                    .set("Content-Type", "application/json")
+                    .send_string(&request)
+            }
+        };
+
+        result.map(|_| ()).map_err(|e| Error::Upload(e.to_string()))
+    }
+}
+
+/// A handle to a running agent
+#[derive(Debug)]
+pub struct AgentHandle {
+    stop: Sender<()>,
+    thread: JoinHandle<Result<(), Error>>,
+    failed: Arc<AtomicUsize>,
+}
+
+impl AgentHandle {
+    /// Returns the number of windows that failed to upload so far
+    ///
+    /// Upload failures do not stop the agent, the window is dropped and
+    /// profiling continues.
+    pub fn failed_uploads(&self) -> usize {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    /// Stop the agent, uploading the current partial window
+    ///
+    /// # Failures
+    ///
+    /// - The agent stopped early because the profiler could not be
+    ///   started or stopped, for example because another profile was
+    ///   started between two of the agent's windows.
+    pub fn stop(self) -> Result<(), Error> {
+        // The thread may have already exited with an error.
+        let _ = self.stop.send(());
+        match self.thread.join() {
+            Ok(res) => res,
+            Err(_) => Err(Error::Internal),
+        }
+    }
+}
+
+/// Configuration of the profiling started by `run`
+#[derive(Clone, Debug)]
+pub struct AgentConfig {
+    dir: PathBuf,
+    prefix: String,
+    interval: Duration,
+    retain: Option<usize>,
+    continuous: bool,
+    upload: Option<String>,
+    control_socket: Option<PathBuf>,
+    policy: Option<Policy>,
+    dir_quota: Option<u64>,
+}
+
+impl Default for AgentConfig {
+    fn default() -> AgentConfig {
+        AgentConfig {
+            dir: env::temp_dir().join("cpuprofiler"),
+            prefix: "cpu".to_owned(),
+            interval: Duration::from_secs(60),
+            retain: None,
+            continuous: true,
+            upload: None,
+            control_socket: None,
+            policy: None,
+            dir_quota: None,
+        }
+    }
+}
+
+impl AgentConfig {
+    /// Profile continuously into the temporary directory, without
+    /// uploading, a control socket or a policy
+    pub fn new() -> AgentConfig {
+        AgentConfig::default()
+    }
+
+    /// Read the `[agent]` table of the TOML file at `path`, see the
+    /// [module documentation](index.html#running-as-a-service) for its keys
+    ///
+    /// # Failures
+    ///
+    /// - The file could not be read, `Error::Io`.
+    /// - See `from_toml`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<AgentConfig, Error> {
+        AgentConfig::from_toml(&fs::read_to_string(path)?)
+    }
+
+    /// Read the `[agent]` table of the TOML document `text`
+    ///
+    /// Other tables are ignored, so the configuration may be part of the
+    /// service's own file. Keys which are missing keep their defaults.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cpuprofiler::agent::AgentConfig;
+    ///
+    /// let config = AgentConfig::from_toml(
+    ///     "[agent]\n\
+    ///      dir = \"/var/lib/my-service/profiles\"\n\
+    ///      interval_secs = 30\n\
+    ///      allow = [\"socket\"]\n",
+    /// );
+    /// assert!(config.is_ok());
+    /// assert!(AgentConfig::from_toml("[agent]\ninterval_secs = \"soon\"\n").is_err());
+    /// ```
+    ///
+    /// # Failures
+    ///
+    /// - `text` is not TOML the agent understands, or a key of the table is
+    ///   unknown or has a value of the wrong type, `Error::InvalidConfig`.
+    pub fn from_toml(text: &str) -> Result<AgentConfig, Error> {
+        let entries = toml::parse(text).map_err(Error::InvalidConfig)?;
+        let mut config = AgentConfig::new();
+        for (name, value) in entries {
+            if let Some(key) = name.strip_prefix("agent.") {
+                config.set(key, value)?;
+            }
+        }
+        Ok(config)
+    }
+
+    /// Read the configuration from the environment
+    ///
+    /// This reads the file named by `CPUPROFILER_AGENT_CONFIG`, if it is
+    /// set, and then the variables of the keys which are set.
+    ///
+    /// # Failures
+    ///
+    /// - The file could not be read, see `from_file`.
+    /// - A variable is not Utf8, or does not hold a value of its key,
+    ///   `Error::InvalidConfig`.
+    pub fn from_env() -> Result<AgentConfig, Error> {
+        let mut config = match env::var_os("CPUPROFILER_AGENT_CONFIG") {
+            Some(ref path) if !path.is_empty() => AgentConfig::from_file(path)?,
+            _ => AgentConfig::new(),
+        };
+        for key in KEYS {
+            let var = format!("CPUPROFILER_AGENT_{}", key.to_uppercase());
+            match env::var(&var) {
+                Ok(value) => config.set(key, Value::String(value))?,
+                Err(env::VarError::NotUnicode(_)) => {
+                    return Err(Error::InvalidConfig(format!("{} is not Utf8", var)));
+                }
+                Err(env::VarError::NotPresent) => {}
+            }
+        }
+        Ok(config)
+    }
+
+    /// Set the key `key`, accepting strings for every type as the
+    /// environment holds nothing else
+    fn set(&mut self, key: &str, value: Value) -> Result<(), Error> {
+        let invalid = |expected: &str| Error::InvalidConfig(format!("`{}` must be {}", key, expected));
+        let string = |value: Value| match value {
+            Value::String(s) => Ok(s),
+            _ => Err(invalid("a string")),
+        };
+        let integer = |value: Value| match value {
+            Value::Integer(n) if n >= 0 => Ok(n as u64),
+            Value::String(ref s) => s.trim().parse().map_err(|_| invalid("a positive integer")),
+            _ => Err(invalid("a positive integer")),
+        };
+
+        match key {
+            "dir" => self.dir = string(value)?.into(),
+            "prefix" => self.prefix = string(value)?,
+            "interval_secs" => match integer(value)? {
+                0 => return Err(invalid("above 0")),
+                secs => self.interval = Duration::from_secs(secs),
+            },
+            "retain" => self.retain = Some(integer(value)? as usize),
+            "continuous" => {
+                self.continuous = match value {
+                    Value::Boolean(b) => b,
+                    Value::String(ref s) if s == "true" || s == "1" => true,
+                    Value::String(ref s) if s == "false" || s == "0" => false,
+                    _ => return Err(invalid("`true` or `false`")),
+                }
+            }
+            "upload" => self.upload = Some(string(value)?),
+            "control_socket" => self.control_socket = Some(string(value)?.into()),
+            "max_sessions_per_hour" => {
+                let sessions = integer(value)?.min(u32::MAX.into()) as u32;
+                self.policy = Some(self.policy.take().unwrap_or_default().max_sessions_per_hour(sessions));
+            }
+            "max_duration_secs" => {
+                let duration = Duration::from_secs(integer(value)?);
+                self.policy = Some(self.policy.take().unwrap_or_default().max_duration(duration));
+            }
+            "allow" => {
+                let names = match value {
+                    Value::Array(values) => values.into_iter().map(string).collect::<Result<Vec<_>, _>>()?,
+                    Value::String(s) => s.split(',').map(|name| name.trim().to_owned()).collect(),
+                    _ => return Err(invalid("an array of triggers")),
+                };
+                // The triggers replace any set before, such as by the file.
+                let mut policy = self.policy.take().unwrap_or_default().allow_all();
+                for name in names.iter().filter(|name| !name.is_empty()) {
+                    policy = policy.allow(trigger(name)?);
+                }
+                self.policy = Some(policy);
+            }
+            "dir_quota_bytes" => self.dir_quota = Some(integer(value)?),
+            _ => return Err(Error::InvalidConfig(format!("the key `{}` is unknown", key))),
+        }
+        Ok(())
+    }
+
+    /// Write profiles into `dir`, which is created if it does not exist
+    pub fn dir<D: Into<PathBuf>>(mut self, dir: D) -> AgentConfig {
+        self.dir = dir.into();
+        self
+    }
+
+    /// Name profiles `<prefix>-<timestamp>.profile`, defaults to `cpu`
+    pub fn prefix<P: Into<String>>(mut self, prefix: P) -> AgentConfig {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Set how long each profile covers, defaults to one minute
+    pub fn interval(mut self, interval: Duration) -> AgentConfig {
+        self.interval = interval;
+        self
+    }
+
+    /// Keep at most `count` profiles in the directory, see
+    /// `RotatingProfiler::retain`
+    pub fn retain(mut self, count: usize) -> AgentConfig {
+        self.retain = Some(count);
+        self
+    }
+
+    /// Choose whether to profile continuously, the default, or only when
+    /// a trigger such as the control socket starts a profile
+    pub fn continuous(mut self, enabled: bool) -> AgentConfig {
+        self.continuous = enabled;
+        self
+    }
+
+    /// Upload every profile to `destination`
+    ///
+    /// - `s3://<bucket>/<prefix>`: to Amazon S3 with the `s3` feature, with
+    ///   the credentials of `S3::from_env`.
+    /// - `gs://<bucket>/<prefix>`: to Google Cloud Storage with the `gcs`
+    ///   feature, with the token of the metadata server.
+    /// - `http://` or `https://` URLs: with `PUT` requests with the `upload`
+    ///   feature, see `HttpPut`.
+    /// - Anything else, or `file://` paths: copied into a local directory,
+    ///   such as a mounted volume.
+    pub fn upload<U: Into<String>>(mut self, destination: U) -> AgentConfig {
+        self.upload = Some(destination.into());
+        self
+    }
+
+    /// Listen for commands on a control socket at `path`, see `Controller`
+    pub fn control_socket<P: Into<PathBuf>>(mut self, path: P) -> AgentConfig {
+        self.control_socket = Some(path.into());
+        self
+    }
+
+    /// Limit the profiles triggers start with `policy`
+    pub fn policy(mut self, policy: Policy) -> AgentConfig {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Refuse triggered profiles while the directory holds `bytes` or
+    /// more, see `Policy::dir_quota`
+    pub fn dir_quota(mut self, bytes: u64) -> AgentConfig {
+        self.dir_quota = Some(bytes);
+        self
+    }
+}
+
+/// The keys of an `AgentConfig`
+const KEYS: &[&str] = &[
+    "dir",
+    "prefix",
+    "interval_secs",
+    "retain",
+    "continuous",
+    "upload",
+    "control_socket",
+    "max_sessions_per_hour",
+    "max_duration_secs",
+    "allow",
+    "dir_quota_bytes",
+];
+
+fn trigger(name: &str) -> Result<Trigger, Error> {
+    match name {
+        "http" => Ok(Trigger::Http),
+        "signal" => Ok(Trigger::Signal),
+        "socket" => Ok(Trigger::Socket),
+        "capture" => Ok(Trigger::Capture),
+        _ => Err(Error::InvalidConfig(format!("`{}` is not a trigger", name))),
+    }
+}
+
+/// Start the profiling `config` describes, until `RunHandle::stop`
+///
+/// Call this once, at startup. The policy is set on `PROFILER`, replacing
+/// any other. Profiles are started through the shared `PROFILER`, so the
+/// rest of the program should not start profiles of its own while the
+/// rotation runs.
+///
+/// # Examples
+///
+/// ```
+/// use std::env;
+/// use std::time::Duration;
+/// use cpuprofiler::agent::{self, AgentConfig};
+///
+/// let dir = env::temp_dir().join("agent-run-example");
+/// let config = AgentConfig::new()
+///     .dir(&dir)
+///     .interval(Duration::from_millis(50))
+///     .retain(2)
+///     .upload(dir.join("archive").to_str().unwrap());
+/// let running = agent::run(config).unwrap();
+///
+/// // The service runs and is profiled in the background...
+///
+/// let files = running.stop().unwrap();
+/// assert!(files.len() <= 2);
+/// ```
+///
+/// # Failures
+///
+/// - The directory could not be created, `Error::OutputPath`.
+/// - The upload destination needs a feature which is not enabled, or a
+///   control socket is asked for on a platform without Unix domain sockets,
+///   `Error::Unsupported`.
+/// - The upload destination is not profiling continuously,
+///   `Error::InvalidConfig`.
+/// - The S3 credentials could not be read, see `S3::from_env`.
+/// - The first profile could not be started, see `RotatingProfiler::start`.
+/// - The control socket could not be started, see `Controller::start`.
+pub fn run(config: AgentConfig) -> Result<RunHandle, Error> {
+    if config.upload.is_some() && !config.continuous {
+        return Err(Error::InvalidConfig("uploads need continuous profiling".to_owned()));
+    }
+    if cfg!(not(unix)) && config.control_socket.is_some() {
+        return Err(Error::Unsupported("control sockets on this platform".to_owned()));
+    }
+    fs::create_dir_all(&config.dir).map_err(|source| Error::OutputPath {
+        path: config.dir.clone(),
+        source,
+    })?;
+
+    let mut rotation = RotatingProfiler::new(&config.dir, config.prefix.clone()).interval(config.interval);
+    if let Some(count) = config.retain {
+        rotation = rotation.retain(count);
+    }
+    if let Some(ref destination) = config.upload {
+        rotation = upload_to(rotation, destination)?;
+    }
+
+    let policy = match (config.policy.clone(), config.dir_quota) {
+        (policy, Some(bytes)) => Some(policy.unwrap_or_default().dir_quota(&config.dir, bytes)),
+        (policy, None) => policy,
+    };
+    lock::lock().set_policy(policy);
+
+    let mut running = RunHandle {
+        rotation: None,
+        #[cfg(unix)]
+        control: None,
+    };
+    if config.continuous {
+        running.rotation = Some(rotation.start().inspect_err(|_| lock::lock().set_policy(None))?);
+    }
+    #[cfg(unix)]
+    {
+        if let Some(ref path) = config.control_socket {
+            match Controller::new(path.clone()).start() {
+                Ok(control) => running.control = Some(control),
+                Err(e) => {
+                    let _ = running.stop();
+                    return Err(e);
+                }
+            }
+        }
+    }
+    Ok(running)
+}
+
+/// Returns `rotation` uploading to `destination`, see `AgentConfig::upload`
+fn upload_to(rotation: RotatingProfiler, destination: &str) -> Result<RotatingProfiler, Error> {
+    let (scheme, rest) = match destination.find("://") {
+        Some(i) => (&destination[..i], &destination[i + 3..]),
+        None => ("file", destination),
+    };
+    #[cfg(any(feature = "s3", feature = "gcs"))]
+    let (bucket, prefix) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i + 1..]),
+        None => (rest, ""),
+    };
+    let sink = match scheme {
+        "file" => rotation.sink(Directory::new(rest)),
+        #[cfg(feature = "s3")]
+        "s3" => rotation.sink(S3::from_env(bucket)?.prefix(prefix)),
+        #[cfg(feature = "gcs")]
+        "gs" => rotation.sink(Gcs::new(bucket).prefix(prefix)),
+        #[cfg(feature = "upload")]
+        "http" | "https" => rotation.sink(HttpPut::new(destination)),
+        #[cfg(not(feature = "s3"))]
+        "s3" => return Err(Error::Unsupported("uploading to S3 without the `s3` feature".to_owned())),
+        #[cfg(not(feature = "gcs"))]
+        "gs" => return Err(Error::Unsupported("uploading to GCS without the `gcs` feature".to_owned())),
+        #[cfg(not(feature = "upload"))]
+        "http" | "https" => return Err(Error::Unsupported("uploading over HTTP without the `upload` feature".to_owned())),
+        _ => return Err(Error::Unsupported(format!("uploading to {}", destination))),
+    };
+    Ok(sink)
+}
+
+/// A handle to the profiling started by `run`
+#[derive(Debug)]
+pub struct RunHandle {
+    rotation: Option<RotatingHandle>,
+    #[cfg(unix)]
+    control: Option<ControlHandle>,
+}
+
+impl RunHandle {
+    /// Returns the number of profiles which could not be uploaded so far
+    pub fn failed_uploads(&self) -> usize {
+        self.rotation.as_ref().map(|rotation| rotation.failed_puts()).unwrap_or(0)
+    }
+
+    /// Stop profiling and listening, and remove the policy
+    ///
+    /// The current profile of the rotation, unless a `stop` on the socket
+    /// ended it, is finished and uploaded. Returns the profiles kept in the
+    /// directory, oldest first.
+    ///
+    /// # Failures
+    ///
+    /// - The rotation stopped early, see `RotatingHandle::stop`.
+    /// - The control socket could not be removed, see `ControlHandle::stop`.
+    pub fn stop(self) -> Result<Vec<PathBuf>, Error> {
+        #[cfg(unix)]
+        let control = self.control.map(|control| control.stop()).unwrap_or(Ok(()));
+        #[cfg(not(unix))]
+        let control = Ok(());
+        let files = self.rotation.map(|rotation| rotation.stop()).unwrap_or_else(|| Ok(Vec::new()));
+        lock::lock().set_policy(None);
+        control.and(files)
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub(crate) fn base64(data: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(TABLE[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
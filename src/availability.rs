@@ -0,0 +1,272 @@
+//! Checks of whether profiles will actually be produced
+
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::fs;
+use std::hint;
+use std::process;
+use std::time::{Duration, Instant};
+
+use error::Error;
+use lock;
+use paths;
+use profile::Profile;
+use Profiler;
+
+/// How long `check_sampling` keeps the CPU busy waiting for a sample
+const SAMPLING_CHECK: Duration = Duration::from_millis(500);
+
+/// How long `self_test` keeps the CPU busy in `self_test_burn`
+const SELF_TEST: Duration = Duration::from_secs(1);
+
+/// More than the size of `self_test_burn`'s code, in bytes
+const BURN_SIZE: u64 = 1024;
+
+/// Returns the version of gperftools that libprofiler was built from
+///
+/// The version is known when the library was found with pkg-config or built
+/// with the `vendored` feature, and is `None` otherwise.
+///
+/// # Examples
+///
+/// ```
+/// match cpuprofiler::library_version() {
+///     Some(version) => println!("using gperftools {}", version),
+///     None => println!("gperftools version unknown"),
+/// }
+/// ```
+pub fn library_version() -> Option<&'static str> {
+    if cfg!(any(feature = "disabled", feature = "dylib-load", not(feature = "gperftools"))) {
+        None
+    } else {
+        option_env!("CPUPROFILER_LIBPROFILER_VERSION")
+    }
+}
+
+/// Returns whether the cpuprofiler library is present
+///
+/// This is the same as `Profiler::is_available`.
+#[cfg(feature = "gperftools")]
+pub fn is_linked() -> bool {
+    ::ffi::is_available()
+}
+
+/// Returns whether the cpuprofiler library is present
+///
+/// This is the same as `Profiler::is_available`.
+#[cfg(not(feature = "gperftools"))]
+pub fn is_linked() -> bool {
+    false
+}
+
+/// Returns whether the current backend is able to record a profile
+///
+/// This starts and immediately stops a profile written to `/dev/null`, so
+/// it catches a library which is loaded but cannot start, as well as one
+/// which is missing. If a profile is already running the backend is known
+/// to work and `true` is returned.
+///
+/// This is always `false` with the `disabled` feature.
+///
+/// This locks `PROFILER`, so must not be called while the lock is held.
+///
+/// # Examples
+///
+/// ```
+/// if !cpuprofiler::is_functional() {
+///     println!("profiling is unavailable, no profiles will be written");
+/// }
+/// ```
+pub fn is_functional() -> bool {
+    lock::lock().probe()
+}
+
+/// Check that the current backend actually takes samples
+///
+/// `is_functional` only checks that a profile starts. On some platforms,
+/// macOS most of all, a profile starts but the timer's signal never
+/// arrives, so the profile is empty. This starts a profile into the
+/// temporary directory, keeps the CPU busy for up to half a second until a
+/// sample is taken, then stops and removes the profile.
+///
+/// This locks `PROFILER`, so must not be called while the lock is held.
+///
+/// # Failures
+///
+/// - A profile is already running, `Error::InvalidState`.
+/// - Profiling is compiled out with the `disabled` feature,
+///   `Error::Unsupported`.
+/// - No samples were taken, `Error::PlatformUnsupported`.
+/// - Any error starting the backend.
+///
+/// # Examples
+///
+/// ```
+/// use cpuprofiler::error::Error;
+///
+/// match cpuprofiler::check_sampling() {
+///     Err(Error::PlatformUnsupported(reason)) => println!("profiles will be empty: {}", reason),
+///     Err(e) => println!("profiling is unavailable: {}", e),
+///     Ok(()) => println!("profiling works"),
+/// }
+/// ```
+pub fn check_sampling() -> Result<(), Error> {
+    let mut profiler = lock::lock();
+    if cfg!(feature = "disabled") {
+        return Err(Error::Unsupported("profiling is compiled out with the disabled feature".to_owned()));
+    }
+    if profiler.state.is_running() {
+        return Err(Error::InvalidState(profiler.state));
+    }
+
+    let path = env::temp_dir().join(format!("cpuprofiler-check-{}.profile", process::id()));
+    let c_path = CString::new(paths::to_bytes(&path))?;
+    profiler.backend.start(&c_path)?;
+    let started = Instant::now();
+    while profiler.backend.samples_gathered() == 0 && started.elapsed() < SAMPLING_CHECK {
+        for i in 0..10_000u64 {
+            hint::black_box(i);
+        }
+    }
+    let samples = profiler.backend.samples_gathered();
+    let stopped = profiler.backend.stop();
+    let _ = fs::remove_file(&path);
+    stopped?;
+
+    if samples == 0 {
+        let reason = format!(
+            "no samples were taken in {}ms of CPU time, the profiling timer's signal is not arriving",
+            SAMPLING_CHECK.as_millis()
+        );
+        return Err(Error::PlatformUnsupported(reason));
+    }
+    Ok(())
+}
+
+/// The outcome of a `self_test` which passed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SelfTest {
+    /// The samples in the profile
+    pub samples: u64,
+    /// The samples whose stack is in the function which kept the CPU busy
+    pub in_function: u64,
+}
+
+impl fmt::Display for SelfTest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} of {} samples were taken in the test function", self.in_function, self.samples)
+    }
+}
+
+/// Check that profiling works end to end
+///
+/// `check_sampling` only checks that samples are taken. This keeps the CPU
+/// busy in a function of its own for a second, profiles it into the
+/// temporary directory, parses the profile and checks that the function
+/// appears in the stacks of its samples, then removes the profile. This
+/// catches the installation problems which otherwise only show as empty or
+/// useless profiles, like a profiling signal which another library handles
+/// or a libunwind which cannot unwind the program's stacks.
+///
+/// The function is recognized by its address, so debug info is not needed.
+///
+/// This locks `PROFILER`, so must not be called while the lock is held. The
+/// `cpuprofiler-selftest` binary runs it and reports the outcome.
+///
+/// # Failures
+///
+/// - A profile is already running, `Error::InvalidState`.
+/// - Profiling is compiled out with the `disabled` feature,
+///   `Error::Unsupported`.
+/// - No samples were taken, or none in the function,
+///   `Error::PlatformUnsupported`.
+/// - The profile could not be parsed.
+/// - Any error starting the backend.
+///
+/// # Examples
+///
+/// ```no_run
+/// match cpuprofiler::self_test() {
+///     Ok(test) => println!("profiling works, {}", test),
+///     Err(e) => println!("profiles will not be usable: {}", e),
+/// }
+/// ```
+pub fn self_test() -> Result<SelfTest, Error> {
+    let mut profiler = lock::lock();
+    if cfg!(feature = "disabled") {
+        return Err(Error::Unsupported("profiling is compiled out with the disabled feature".to_owned()));
+    }
+    if profiler.state.is_running() {
+        return Err(Error::InvalidState(profiler.state));
+    }
+
+    let path = env::temp_dir().join(format!("cpuprofiler-selftest-{}.profile", process::id()));
+    let c_path = CString::new(paths::to_bytes(&path))?;
+    profiler.backend.start(&c_path)?;
+    hint::black_box(self_test_burn(Instant::now() + SELF_TEST));
+    let stopped = profiler.backend.stop();
+    drop(profiler);
+    let profile = stopped.and_then(|_| Profile::from_file(&path));
+    let _ = fs::remove_file(&path);
+    let profile = profile?;
+
+    let start = self_test_burn as *const () as usize as u64;
+    let in_function = |addr: &u64| *addr >= start && *addr - start < BURN_SIZE;
+    let test = SelfTest {
+        samples: profile.total_samples(),
+        in_function: profile
+            .samples()
+            .iter()
+            .filter(|sample| sample.stack.iter().any(&in_function))
+            .map(|sample| sample.count)
+            .sum(),
+    };
+
+    if test.samples == 0 {
+        let reason = format!(
+            "no samples were taken in {}ms of CPU time, the profiling timer's signal is not arriving",
+            SELF_TEST.as_millis()
+        );
+        return Err(Error::PlatformUnsupported(reason));
+    }
+    if test.in_function == 0 {
+        let reason = format!(
+            "none of {} samples were taken in the function which kept the CPU busy, stacks are not being unwound",
+            test.samples
+        );
+        return Err(Error::PlatformUnsupported(reason));
+    }
+    Ok(test)
+}
+
+/// Keep the CPU busy until `until`
+///
+/// Not inlined, so that its samples can be told apart by address.
+#[inline(never)]
+fn self_test_burn(until: Instant) -> u64 {
+    let mut sum = 0u64;
+    while Instant::now() < until {
+        for i in 0..10_000u64 {
+            sum = hint::black_box(sum.wrapping_add(i));
+        }
+    }
+    sum
+}
+
+impl Profiler {
+    fn probe(&mut self) -> bool {
+        if cfg!(feature = "disabled") {
+            return false;
+        }
+        if self.state.is_running() {
+            return true;
+        }
+
+        let null = CStr::from_bytes_with_nul(b"/dev/null\0").unwrap();
+        match self.backend.start(null) {
+            Ok(()) => self.backend.stop().is_ok(),
+            Err(_) => false,
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use std::env;
+use std::ffi::CStr;
+use std::mem;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use libc;
+
+use backend::ProfilerBackend;
+use builder::{self, TimerKind, DEFAULT_FREQUENCY, MAX_FREQUENCY};
+use command;
+use error::Error;
+use ffi;
+use options::{self, RawOptions};
+use threads;
+
+/// The gperftools cpuprofiler library
+///
+/// This is the default backend.
+///
+/// With the `dylib-load` feature the library is loaded when the backend is
+/// first started rather than linked, and starting fails if it is not found.
+///
+/// The library takes its timer settings from the environment, once, when it
+/// is loaded. Settings which differ from that environment can therefore only
+/// be applied with `dylib-load`, before the library is loaded.
+///
+/// Pausing keeps the library's timer running but discards samples, through
+/// the thread filter of `ProfilerStartWithOptions`, which also counts the
+/// samples of each thread. Raw options, see the
+/// [`options`](../options/index.html) module, are written after the filter.
+/// Like the timer settings they are kept for later profiles.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Gperftools;
+
+/// Whether a profile was started with `filter`
+static FILTERED: AtomicBool = AtomicBool::new(false);
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// The raw options of the profiles started from now on
+    static ref RAW_OPTIONS: Mutex<RawOptions> = Mutex::new(RawOptions::new());
+}
+
+/// The options struct passed to the library, zeroed past the fields set
+#[repr(C, align(8))]
+struct Options([u8; options::MAX_SIZE]);
+
+// The modeled fields are written over the start of `Options`.
+const _: () = assert!(mem::size_of::<ffi::ProfilerOptions>() == options::MODELED_SIZE);
+
+/// Decides whether to keep a sample, running in the signal handler
+unsafe extern "C" fn filter(_arg: *mut c_void) -> c_int {
+    if PAUSED.load(Ordering::Relaxed) {
+        return 0;
+    }
+    threads::record();
+    1
+}
+
+impl ProfilerBackend for Gperftools {
+    fn start(&mut self, fname: &CStr) -> Result<(), Error> {
+        if !ffi::is_available() {
+            return Err(Error::LibraryUnavailable);
+        }
+        PAUSED.store(false, Ordering::SeqCst);
+        let mut options = Options([0; options::MAX_SIZE]);
+        RAW_OPTIONS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .write_to(&mut options.0);
+        let modeled = ffi::ProfilerOptions {
+            filter_in_thread: Some(filter),
+            filter_in_thread_arg: ptr::null_mut(),
+        };
+        let options = &mut options as *mut Options as *mut ffi::ProfilerOptions;
+        let res = unsafe {
+            ptr::write(options, modeled);
+            ffi::ProfilerStartWithOptions(fname.as_ptr(), options)
+        };
+        if res == 0 {
+            return Err(Error::StartRejected);
+        }
+        FILTERED.store(true, Ordering::SeqCst);
+        if let Err(e) = check_handler(self.signal()) {
+            self.stop()?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        unsafe {
+            ffi::ProfilerStop();
+        }
+        FILTERED.store(false, Ordering::SeqCst);
+        PAUSED.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<(), Error> {
+        if !FILTERED.load(Ordering::SeqCst) {
+            let reason = "pausing a profile libprofiler started by itself".to_owned();
+            return Err(Error::Unsupported(reason));
+        }
+        PAUSED.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), Error> {
+        PAUSED.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        unsafe {
+            ffi::ProfilerFlush();
+        }
+        Ok(())
+    }
+
+    fn frequency(&self) -> Option<u32> {
+        Some(current_frequency())
+    }
+
+    fn signal(&self) -> Option<c_int> {
+        // The library samples the wall clock when it was loaded with this set.
+        let timer = match env::var_os("CPUPROFILE_REALTIME") {
+            Some(_) => TimerKind::WallClock,
+            None => TimerKind::CpuTime,
+        };
+        Some(timer.signal())
+    }
+
+    fn samples_gathered(&self) -> u64 {
+        let state = unsafe {
+            let mut state: ffi::ProfilerState = mem::zeroed();
+            ffi::ProfilerGetCurrentState(&mut state);
+            state
+        };
+        state.samples_gathered.max(0) as u64
+    }
+
+    fn set_timer(&mut self, timer: TimerKind) -> Result<(), Error> {
+        let (value, matches) = timer_env(timer);
+        set_env("CPUPROFILE_REALTIME", value, matches, || timer.check_supported())
+    }
+
+    fn set_frequency(&mut self, hz: u32) -> Result<(), Error> {
+        let (value, matches) = frequency_env(hz);
+        set_env("CPUPROFILE_FREQUENCY", Some(&value), matches, || Ok(()))
+    }
+
+    fn set_per_thread_timers(&mut self, enabled: bool) -> Result<(), Error> {
+        let (value, matches) = per_thread_timers_env(enabled);
+        set_env("CPUPROFILE_PER_THREAD_TIMERS", value, matches, builder::check_per_thread_timers)
+    }
+
+    fn set_raw_options(&mut self, options: &RawOptions) -> Result<(), Error> {
+        *RAW_OPTIONS.lock().unwrap_or_else(|e| e.into_inner()) = options.clone();
+        Ok(())
+    }
+
+    fn check_available(&self) -> Result<(), Error> {
+        // Loading the library would fix its settings, so a library which is
+        // not loaded yet is only looked for.
+        let available = if ffi::is_initialized() {
+            ffi::is_available()
+        } else {
+            command::find_library().is_some()
+        };
+        if available {
+            Ok(())
+        } else {
+            Err(Error::LibraryUnavailable)
+        }
+    }
+
+    fn check_timer(&self, timer: TimerKind) -> Result<(), Error> {
+        let (value, matches) = timer_env(timer);
+        check_env("CPUPROFILE_REALTIME", value, matches, || timer.check_supported())
+    }
+
+    fn check_frequency(&self, hz: u32) -> Result<(), Error> {
+        let (value, matches) = frequency_env(hz);
+        check_env("CPUPROFILE_FREQUENCY", Some(&value), matches, || Ok(()))
+    }
+
+    fn check_per_thread_timers(&self, enabled: bool) -> Result<(), Error> {
+        let (value, matches) = per_thread_timers_env(enabled);
+        check_env("CPUPROFILE_PER_THREAD_TIMERS", value, matches, builder::check_per_thread_timers)
+    }
+}
+
+/// Check that the library handles the signal of its timer
+fn check_handler(signal: Option<c_int>) -> Result<(), Error> {
+    let signal = match signal {
+        Some(signal) if cfg!(not(feature = "disabled")) => signal,
+        _ => return Ok(()),
+    };
+    let handler = unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        if libc::sigaction(signal, ptr::null(), &mut action) != 0 {
+            return Ok(());
+        }
+        action.sa_sigaction
+    };
+    // The library can start and then record nothing, most often on macOS,
+    // when the signal is left to its default or ignored.
+    if handler == libc::SIG_DFL || handler == libc::SIG_IGN {
+        let reason = format!("libprofiler is not handling signal {}, so no samples would be taken", signal);
+        return Err(Error::PlatformUnsupported(reason));
+    }
+    Ok(())
+}
+
+/// Returns the `CPUPROFILE_REALTIME` value for `timer`, and whether the
+/// environment already has it
+fn timer_env(timer: TimerKind) -> (Option<&'static str>, bool) {
+    let wall_clock = timer == TimerKind::WallClock;
+    let matches = env::var_os("CPUPROFILE_REALTIME").is_some() == wall_clock;
+    (if wall_clock { Some("1") } else { None }, matches)
+}
+
+/// Returns the `CPUPROFILE_FREQUENCY` value for `hz`, and whether the
+/// environment already has it
+fn frequency_env(hz: u32) -> (String, bool) {
+    (hz.to_string(), current_frequency() == hz)
+}
+
+/// Returns the frequency `CPUPROFILE_FREQUENCY` has the library sample at
+fn current_frequency() -> u32 {
+    // The library falls back to its default for values it cannot use.
+    env::var("CPUPROFILE_FREQUENCY")
+        .ok()
+        .and_then(|f| f.parse().ok())
+        .filter(|&f| f > 0 && f <= MAX_FREQUENCY)
+        .unwrap_or(DEFAULT_FREQUENCY)
+}
+
+/// Returns the `CPUPROFILE_PER_THREAD_TIMERS` value, and whether the
+/// environment already has it
+fn per_thread_timers_env(enabled: bool) -> (Option<&'static str>, bool) {
+    let matches = env::var_os("CPUPROFILE_PER_THREAD_TIMERS").is_some() == enabled;
+    (if enabled { Some("1") } else { None }, matches)
+}
+
+/// Set `var` to `value` for libprofiler, or unset it for `None`
+///
+/// Nothing is done when the current environment already `matches`, see
+/// `check_env` for when the environment cannot be changed.
+fn set_env<F>(var: &str, value: Option<&str>, matches: bool, check: F) -> Result<(), Error>
+where
+    F: FnOnce() -> Result<(), Error>,
+{
+    if matches {
+        return Ok(());
+    }
+    check_env(var, value, matches, check)?;
+    match value {
+        Some(value) => env::set_var(var, value),
+        None => env::remove_var(var),
+    }
+    Ok(())
+}
+
+/// Check that `var` can be set to `value` for libprofiler
+///
+/// `check` runs unless the current environment already `matches`. Once the
+/// library is loaded the environment can no longer change its settings, so
+/// it must already match.
+fn check_env<F>(var: &str, value: Option<&str>, matches: bool, check: F) -> Result<(), Error>
+where
+    F: FnOnce() -> Result<(), Error>,
+{
+    if matches {
+        return Ok(());
+    }
+    if ffi::is_initialized() {
+        let reason = match value {
+            Some(value) => format!(
+                "libprofiler was loaded with a different {}, set {}={} when launching the program",
+                var, var, value
+            ),
+            None => format!("libprofiler was loaded with {}, unset it when launching the program", var),
+        };
+        return Err(Error::Unsupported(reason));
+    }
+    check()
+}
@@ -0,0 +1,311 @@
+//! Profiler backends
+//!
+//! The `Profiler` drives a `ProfilerBackend` which does the actual sampling.
+//! By default this is the gperftools cpuprofiler library, through the
+//! `Gperftools` backend. With the `sampler` feature a pure Rust sampling
+//! profiler is also available as `Sampler`, which does not need libprofiler to
+//! be installed. Both backends write the same profile format, so profiles can
+//! be consumed in the same way regardless of how they were recorded.
+//!
+//! To build without libprofiler at all disable the default `gperftools`
+//! feature, in which case `Sampler` becomes the default backend. This is how
+//! the crate is built on Windows, where gperftools cannot sample and the
+//! `Sampler` samples threads from a thread of its own instead of signals.
+//!
+//! Code which profiles can be tested without sampling at all with the
+//! [`MockProfiler`](../mock/struct.MockProfiler.html) of the `test-util`
+//! feature.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # #[cfg(feature = "sampler")]
+//! # fn main() {
+//! use cpuprofiler::PROFILER;
+//! use cpuprofiler::backend::Sampler;
+//!
+//! PROFILER.lock().unwrap().set_backend(Sampler::new()).unwrap();
+//! PROFILER.lock().unwrap().start("./sampler.profile").unwrap();
+//! // Code you want to sample goes here!
+//! PROFILER.lock().unwrap().stop().unwrap();
+//! # }
+//! # #[cfg(not(feature = "sampler"))]
+//! # fn main() {}
+//! ```
+
+#[cfg(any(feature = "sampler", feature = "test-util"))]
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fmt;
+#[cfg(any(feature = "sampler", feature = "test-util"))]
+use std::fs::File;
+#[cfg(any(feature = "sampler", feature = "test-util"))]
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+
+use libc::c_int;
+
+use builder::TimerKind;
+use error::Error;
+use options::RawOptions;
+#[cfg(any(feature = "sampler", feature = "test-util"))]
+use profile;
+use Profiler;
+
+#[cfg(feature = "gperftools")]
+mod gperftools;
+#[cfg(all(feature = "sampler", unix))]
+mod sampler;
+#[cfg(all(feature = "sampler", windows))]
+mod windows;
+
+#[cfg(feature = "gperftools")]
+pub use self::gperftools::Gperftools;
+#[cfg(all(feature = "sampler", unix))]
+pub use self::sampler::Sampler;
+#[cfg(all(feature = "sampler", windows))]
+pub use self::windows::Sampler;
+
+#[cfg(all(windows, feature = "gperftools", not(feature = "disabled")))]
+compile_error!("gperftools cannot profile on Windows, disable the default features and enable `sampler`");
+
+#[cfg(not(any(feature = "gperftools", feature = "sampler", feature = "test-util", feature = "disabled")))]
+compile_error!("cpuprofiler needs a backend, enable the `gperftools`, `sampler` or `test-util` feature");
+
+/// A source of cpu profiles
+///
+/// The `Profiler` guarantees that `start` and `stop` are called alternately,
+/// beginning with `start`.
+pub trait ProfilerBackend: Send + fmt::Debug {
+    /// Begin sampling, writing the profile to `fname`
+    fn start(&mut self, fname: &CStr) -> Result<(), Error>;
+
+    /// Stop sampling and finish writing the profile
+    fn stop(&mut self) -> Result<(), Error>;
+
+    /// Returns the number of samples gathered by the current profile
+    fn samples_gathered(&self) -> u64;
+
+    /// Returns how often the backend samples, in Hz, if it knows
+    ///
+    /// The default is `None`.
+    fn frequency(&self) -> Option<u32> {
+        None
+    }
+
+    /// Returns the signal which delivers samples while profiling
+    ///
+    /// The default is `None`, for backends which do not sample with signals.
+    fn signal(&self) -> Option<c_int> {
+        None
+    }
+
+    /// Sample with `timer` from the next `start` on
+    ///
+    /// The default only accepts `TimerKind::CpuTime`, for backends which
+    /// cannot sample anything else.
+    fn set_timer(&mut self, timer: TimerKind) -> Result<(), Error> {
+        match timer {
+            TimerKind::CpuTime => Ok(()),
+            other => Err(Error::Unsupported(format!("{} sampling", other))),
+        }
+    }
+
+    /// Stop taking samples until `resume`
+    ///
+    /// The default cannot pause.
+    fn pause(&mut self) -> Result<(), Error> {
+        Err(Error::Unsupported("pausing".to_owned()))
+    }
+
+    /// Take samples again after `pause`
+    fn resume(&mut self) -> Result<(), Error> {
+        Err(Error::Unsupported("pausing".to_owned()))
+    }
+
+    /// Write the samples taken so far to the profile
+    ///
+    /// The default does nothing, for backends which write every sample as
+    /// it is taken.
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Sample `hz` times a second from the next `start` on
+    ///
+    /// `hz` has been checked to be between 1 and `builder::MAX_FREQUENCY`.
+    /// The default rejects every frequency.
+    fn set_frequency(&mut self, hz: u32) -> Result<(), Error> {
+        Err(Error::Unsupported(format!("sampling at {} Hz", hz)))
+    }
+
+    /// Give each thread its own timer from the next `start` on
+    ///
+    /// The default only accepts `false`.
+    fn set_per_thread_timers(&mut self, enabled: bool) -> Result<(), Error> {
+        if enabled {
+            Err(Error::Unsupported("per thread timers".to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Pass `options` to the library from the next `start` on, see the
+    /// [`options`](../options/index.html) module
+    ///
+    /// `options` have been checked. The default only accepts no options.
+    fn set_raw_options(&mut self, options: &RawOptions) -> Result<(), Error> {
+        if options.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Unsupported("raw gperftools options".to_owned()))
+        }
+    }
+
+    /// Check that whatever the backend samples with is present
+    ///
+    /// This and the other checks are made by `Profiler::validate`, and must
+    /// not change anything. The default always passes.
+    fn check_available(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Check that `set_timer(timer)` and then `start` would succeed
+    ///
+    /// The default accepts what the default `set_timer` accepts, backends
+    /// which override one should override both.
+    fn check_timer(&self, timer: TimerKind) -> Result<(), Error> {
+        match timer {
+            TimerKind::CpuTime => Ok(()),
+            other => Err(Error::Unsupported(format!("{} sampling", other))),
+        }
+    }
+
+    /// Check that `set_frequency(hz)` and then `start` would succeed
+    ///
+    /// The default rejects every frequency, like `set_frequency`.
+    fn check_frequency(&self, hz: u32) -> Result<(), Error> {
+        Err(Error::Unsupported(format!("sampling at {} Hz", hz)))
+    }
+
+    /// Check that `set_per_thread_timers(enabled)` and then `start` would
+    /// succeed
+    ///
+    /// The default only accepts `false`, like `set_per_thread_timers`.
+    fn check_per_thread_timers(&self, enabled: bool) -> Result<(), Error> {
+        if enabled {
+            Err(Error::Unsupported("per thread timers".to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The backend used when profiling is compiled out
+#[cfg(feature = "disabled")]
+#[derive(Debug)]
+struct Noop;
+
+#[cfg(feature = "disabled")]
+impl ProfilerBackend for Noop {
+    fn start(&mut self, _fname: &CStr) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn samples_gathered(&self) -> u64 {
+        0
+    }
+
+    fn pause(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn set_timer(&mut self, _timer: TimerKind) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn set_frequency(&mut self, _hz: u32) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn set_per_thread_timers(&mut self, _enabled: bool) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn set_raw_options(&mut self, _options: &RawOptions) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn check_timer(&self, _timer: TimerKind) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn check_frequency(&self, _hz: u32) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn check_per_thread_timers(&self, _enabled: bool) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "disabled")]
+pub(crate) fn default_backend() -> Box<dyn ProfilerBackend> {
+    Box::new(Noop)
+}
+
+#[cfg(all(not(feature = "disabled"), feature = "gperftools"))]
+pub(crate) fn default_backend() -> Box<dyn ProfilerBackend> {
+    Box::new(Gperftools)
+}
+
+#[cfg(all(not(feature = "disabled"), not(feature = "gperftools"), feature = "sampler"))]
+pub(crate) fn default_backend() -> Box<dyn ProfilerBackend> {
+    Box::new(Sampler::new())
+}
+
+#[cfg(all(not(feature = "disabled"), not(any(feature = "gperftools", feature = "sampler")), feature = "test-util"))]
+pub(crate) fn default_backend() -> Box<dyn ProfilerBackend> {
+    Box::new(::mock::MockProfiler::new())
+}
+
+/// Write the profile in the cpuprofiler format, with native machine words
+///
+/// Anything previously written to `file` is replaced. `maps` are the memory
+/// mappings of the process, in the format of `/proc/self/maps`.
+#[cfg(any(feature = "sampler", feature = "test-util"))]
+pub(crate) fn write_profile(mut file: &File, period: u64, stacks: &HashMap<Vec<u64>, u64>, maps: &[u8]) -> io::Result<()> {
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut out = BufWriter::new(file);
+    profile::write_samples(&mut out, period, stacks.iter().map(|(stack, &count)| (count, &stack[..])))?;
+    out.write_all(maps)?;
+    out.flush()
+}
+
+impl Profiler {
+    /// Replace the backend used for future profiles
+    ///
+    /// With the `disabled` feature the backend is not replaced, so that
+    /// profiling stays compiled out.
+    ///
+    /// # Failures
+    ///
+    /// - The profiler is currently `Active`.
+    pub fn set_backend<B: ProfilerBackend + 'static>(&mut self, backend: B) -> Result<(), Error> {
+        if self.state.is_running() {
+            return Err(Error::InvalidState(self.state));
+        }
+        if cfg!(not(feature = "disabled")) {
+            self.backend = Box::new(backend);
+        }
+        Ok(())
+    }
+}
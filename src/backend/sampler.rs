@@ -0,0 +1,421 @@
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::CStr;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use backtrace;
+use libc::{self, c_int, c_void};
+
+use backend::{write_profile, ProfilerBackend};
+use builder::{TimerKind, DEFAULT_FREQUENCY};
+use error::Error;
+use tasks;
+use threads;
+
+const MAX_DEPTH: usize = 64;
+// Room for the signal handler frames which are skipped.
+const MAX_FRAMES: usize = MAX_DEPTH + 16;
+
+const EMPTY: u8 = 0;
+const WRITING: u8 = 1;
+const FULL: u8 = 2;
+
+/// A stack written by the signal handler and drained by a background thread
+struct Slot {
+    state: AtomicU8,
+    depth: UnsafeCell<usize>,
+    /// The task the stack was sampled in, see `tasks::current_id`
+    task: UnsafeCell<u32>,
+    stack: UnsafeCell<[u64; MAX_DEPTH]>,
+}
+
+/// The stacks drained from the ring so far
+#[derive(Default)]
+struct Gathered {
+    stacks: HashMap<Vec<u64>, u64>,
+    /// The stacks which were sampled in a task, by task
+    tasks: HashMap<(u32, Vec<u64>), u64>,
+}
+
+/// Fixed size storage shared with the signal handler
+///
+/// The signal handler claims the next slot in the ring, skipping the sample
+/// if the slot has not been drained yet, so recording a sample never allocates
+/// or takes a lock.
+struct Ring {
+    slots: Vec<Slot>,
+    next: AtomicUsize,
+    gathered: AtomicU64,
+}
+
+// Slots are only written after winning the EMPTY -> WRITING transition and
+// only read in the FULL state.
+unsafe impl Sync for Ring {}
+
+static RING: AtomicPtr<Ring> = AtomicPtr::new(ptr::null_mut());
+static IN_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// A pure Rust sampling profiler
+///
+/// Available with the `sampler` feature.
+///
+/// The `Sampler` uses a `SIGPROF` interval timer, like the cpuprofiler
+/// library, and unwinds the interrupted thread from the signal handler. The
+/// profile is written in the cpuprofiler format when the profiler is stopped.
+/// Sampling the wall clock uses a `SIGALRM` timer instead.
+///
+/// The sampling frequency defaults to the `CPUPROFILE_FREQUENCY` environment
+/// variable, or 100Hz if it is not set. The wall clock is sampled by default
+/// when `CPUPROFILE_REALTIME` is set.
+pub struct Sampler {
+    frequency: Option<u32>,
+    timer: Option<TimerKind>,
+    buffer_size: usize,
+    active: Option<Active>,
+}
+
+struct Active {
+    file: File,
+    period: u64,
+    timer: TimerKind,
+    ring: Arc<Ring>,
+    running: Arc<AtomicBool>,
+    drain: JoinHandle<()>,
+    gathered: Arc<Mutex<Gathered>>,
+    old_action: libc::sigaction,
+}
+
+impl Sampler {
+    /// Create a sampler with the default settings
+    pub fn new() -> Sampler {
+        Sampler {
+            frequency: None,
+            timer: None,
+            buffer_size: 4096,
+            active: None,
+        }
+    }
+
+    /// Set the sampling frequency in Hz
+    pub fn frequency(mut self, hz: u32) -> Sampler {
+        self.frequency = Some(hz);
+        self
+    }
+
+    /// Set the clock which decides when samples are taken
+    pub fn timer(mut self, timer: TimerKind) -> Sampler {
+        self.timer = Some(timer);
+        self
+    }
+
+    /// Set how many stacks may be buffered before samples are dropped
+    ///
+    /// The buffer is drained every few milliseconds, the default of 4096
+    /// is plenty unless sampling many busy threads at a high frequency.
+    pub fn buffer_size(mut self, stacks: usize) -> Sampler {
+        self.buffer_size = stacks.max(1);
+        self
+    }
+
+    fn effective_frequency(&self) -> u32 {
+        let env = env::var("CPUPROFILE_FREQUENCY").ok().and_then(|f| f.parse().ok());
+        self.frequency.or(env).filter(|&hz| hz > 0).unwrap_or(DEFAULT_FREQUENCY)
+    }
+
+    fn effective_timer(&self) -> TimerKind {
+        self.timer.unwrap_or_else(|| match env::var_os("CPUPROFILE_REALTIME") {
+            Some(_) => TimerKind::WallClock,
+            None => TimerKind::CpuTime,
+        })
+    }
+}
+
+impl Default for Sampler {
+    fn default() -> Sampler {
+        Sampler::new()
+    }
+}
+
+impl fmt::Debug for Sampler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sampler")
+            .field("frequency", &self.frequency)
+            .field("timer", &self.timer)
+            .field("buffer_size", &self.buffer_size)
+            .field("active", &self.active.is_some())
+            .finish()
+    }
+}
+
+impl ProfilerBackend for Sampler {
+    fn start(&mut self, fname: &CStr) -> Result<(), Error> {
+        let timer = self.effective_timer();
+        timer.check_supported()?;
+        let path = Path::new(::std::ffi::OsStr::from_bytes(fname.to_bytes()));
+        let file = File::create(path).map_err(|source| Error::OutputPath {
+            path: path.to_owned(),
+            source,
+        })?;
+        let period = 1_000_000 / self.effective_frequency() as u64;
+
+        let ring = Arc::new(Ring {
+            slots: (0..self.buffer_size)
+                .map(|_| Slot {
+                    state: AtomicU8::new(EMPTY),
+                    depth: UnsafeCell::new(0),
+                    task: UnsafeCell::new(0),
+                    stack: UnsafeCell::new([0; MAX_DEPTH]),
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+            gathered: AtomicU64::new(0),
+        });
+
+        // Only one sampler may own the signal handler at a time.
+        let ring_ptr = Arc::as_ptr(&ring) as *mut Ring;
+        if RING
+            .compare_exchange(ptr::null_mut(), ring_ptr, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(Error::Busy);
+        }
+
+        let old_action = unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void)
+                as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART | libc::SA_SIGINFO;
+            libc::sigemptyset(&mut action.sa_mask);
+            let mut old_action: libc::sigaction = mem::zeroed();
+            if libc::sigaction(timer.signal(), &action, &mut old_action) != 0 {
+                RING.store(ptr::null_mut(), Ordering::SeqCst);
+                return Err(io::Error::last_os_error().into());
+            }
+            old_action
+        };
+
+        let running = Arc::new(AtomicBool::new(true));
+        let gathered = Arc::new(Mutex::new(Gathered::default()));
+        let drain = {
+            let (ring, running, gathered) = (ring.clone(), running.clone(), gathered.clone());
+            thread::spawn(move || {
+                while running.load(Ordering::SeqCst) {
+                    drain(&ring, &gathered);
+                    thread::sleep(Duration::from_millis(10));
+                }
+            })
+        };
+
+        set_timer(timer, period);
+        self.active = Some(Active {
+            file,
+            period,
+            timer,
+            ring,
+            running,
+            drain,
+            gathered,
+            old_action,
+        });
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        let active = match self.active.take() {
+            Some(active) => active,
+            None => return Ok(()),
+        };
+
+        set_timer(active.timer, 0);
+        // A tick may still be pending on another thread, and the default
+        // action of the signal terminates the process. Our handler does
+        // nothing once the ring is gone, so it is left installed instead.
+        if active.old_action.sa_sigaction != libc::SIG_DFL {
+            unsafe {
+                libc::sigaction(active.timer.signal(), &active.old_action, ptr::null_mut());
+            }
+        }
+        RING.store(ptr::null_mut(), Ordering::SeqCst);
+        // Signals delivered just before the handler was removed may still be running.
+        while IN_HANDLER.load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+
+        active.running.store(false, Ordering::SeqCst);
+        let _ = active.drain.join();
+        drain(&active.ring, &active.gathered);
+
+        let gathered = active.gathered.lock().unwrap();
+        write_profile(&active.file, active.period, &gathered.stacks, &footer(&gathered))?;
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<(), Error> {
+        if let Some(ref active) = self.active {
+            set_timer(active.timer, 0);
+        }
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), Error> {
+        if let Some(ref active) = self.active {
+            set_timer(active.timer, active.period);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if let Some(ref active) = self.active {
+            drain(&active.ring, &active.gathered);
+            let gathered = active.gathered.lock().unwrap();
+            write_profile(&active.file, active.period, &gathered.stacks, &footer(&gathered))?;
+        }
+        Ok(())
+    }
+
+    fn signal(&self) -> Option<c_int> {
+        Some(self.effective_timer().signal())
+    }
+
+    fn samples_gathered(&self) -> u64 {
+        self.active
+            .as_ref()
+            .map(|a| a.ring.gathered.load(Ordering::SeqCst))
+            .unwrap_or(0)
+    }
+
+    fn set_timer(&mut self, timer: TimerKind) -> Result<(), Error> {
+        self.timer = Some(timer);
+        Ok(())
+    }
+
+    fn set_frequency(&mut self, hz: u32) -> Result<(), Error> {
+        self.frequency = Some(hz);
+        Ok(())
+    }
+
+    fn frequency(&self) -> Option<u32> {
+        Some(self.effective_frequency())
+    }
+
+    fn check_timer(&self, _timer: TimerKind) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn check_frequency(&self, _hz: u32) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+fn set_timer(timer: TimerKind, period_us: u64) {
+    let which = match timer {
+        TimerKind::CpuTime => libc::ITIMER_PROF,
+        TimerKind::WallClock => libc::ITIMER_REAL,
+    };
+    let interval = libc::timeval {
+        tv_sec: (period_us / 1_000_000) as libc::time_t,
+        tv_usec: (period_us % 1_000_000) as libc::suseconds_t,
+    };
+    let value = libc::itimerval {
+        it_interval: interval,
+        it_value: interval,
+    };
+    unsafe {
+        libc::setitimer(which, &value, ptr::null_mut());
+    }
+}
+
+fn drain(ring: &Ring, gathered: &Mutex<Gathered>) {
+    let mut gathered = gathered.lock().unwrap();
+    for slot in &ring.slots {
+        if slot.state.load(Ordering::Acquire) == FULL {
+            let stack = unsafe { (&*slot.stack.get())[..*slot.depth.get()].to_vec() };
+            let task = unsafe { *slot.task.get() };
+            slot.state.store(EMPTY, Ordering::Release);
+            if task != 0 {
+                *gathered.tasks.entry((task, stack.clone())).or_insert(0) += 1;
+            }
+            *gathered.stacks.entry(stack).or_insert(0) += 1;
+        }
+    }
+}
+
+extern "C" fn on_signal(_signal: c_int, _info: *mut libc::siginfo_t, ucontext: *mut c_void) {
+    IN_HANDLER.fetch_add(1, Ordering::SeqCst);
+    let ring = RING.load(Ordering::SeqCst);
+    if !ring.is_null() {
+        unsafe { record(&*ring, ucontext) };
+    }
+    IN_HANDLER.fetch_sub(1, Ordering::SeqCst);
+}
+
+unsafe fn record(ring: &Ring, ucontext: *mut c_void) {
+    let mut frames = [0u64; MAX_FRAMES];
+    let mut n = 0;
+    backtrace::trace_unsynchronized(|frame| {
+        frames[n] = frame.ip() as u64;
+        n += 1;
+        n < MAX_FRAMES
+    });
+
+    // Skip the frames of the signal handler itself.
+    let first = interrupted_pc(ucontext)
+        .and_then(|pc| frames[..n].iter().position(|&ip| ip == pc))
+        .unwrap_or(0);
+    let depth = (n - first).min(MAX_DEPTH);
+
+    let slot = &ring.slots[ring.next.fetch_add(1, Ordering::Relaxed) % ring.slots.len()];
+    if slot
+        .state
+        .compare_exchange(EMPTY, WRITING, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        return;
+    }
+    (&mut *slot.stack.get())[..depth].copy_from_slice(&frames[first..first + depth]);
+    *slot.depth.get() = depth;
+    *slot.task.get() = tasks::current_id();
+    slot.state.store(FULL, Ordering::Release);
+    ring.gathered.fetch_add(1, Ordering::Relaxed);
+    threads::record();
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe fn interrupted_pc(ucontext: *mut c_void) -> Option<u64> {
+    let ucontext = ucontext as *const libc::ucontext_t;
+    Some((*ucontext).uc_mcontext.gregs[libc::REG_RIP as usize] as u64)
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+unsafe fn interrupted_pc(ucontext: *mut c_void) -> Option<u64> {
+    let ucontext = ucontext as *const libc::ucontext_t;
+    Some((*ucontext).uc_mcontext.pc as u64)
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+unsafe fn interrupted_pc(_ucontext: *mut c_void) -> Option<u64> {
+    None
+}
+
+/// Returns the memory mappings of the process, which follow the samples
+fn maps() -> Vec<u8> {
+    fs::read("/proc/self/maps").unwrap_or_default()
+}
+
+/// Returns what follows the samples: the mappings, then the tasks
+fn footer(gathered: &Gathered) -> Vec<u8> {
+    let mut footer = maps();
+    footer.extend(tasks::section(&gathered.tasks));
+    footer
+}
@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::env;
+use std::ffi::CStr;
+use std::fmt;
+use std::fs::File;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use windows_sys::Win32::Foundation::{CloseHandle, FILETIME, HANDLE, INVALID_HANDLE_VALUE};
+use windows_sys::Win32::System::Diagnostics::ToolHelp::{
+    CreateToolhelp32Snapshot, Module32FirstW, Module32NextW, Thread32First, Thread32Next, MODULEENTRY32W,
+    TH32CS_SNAPMODULE, TH32CS_SNAPMODULE32, TH32CS_SNAPTHREAD, THREADENTRY32,
+};
+use windows_sys::Win32::System::Threading::{
+    GetCurrentProcessId, GetCurrentThreadId, GetThreadTimes, OpenThread, ResumeThread, SuspendThread,
+    THREAD_GET_CONTEXT, THREAD_QUERY_INFORMATION, THREAD_SUSPEND_RESUME,
+};
+
+use backend::{write_profile, ProfilerBackend};
+use builder::{TimerKind, DEFAULT_FREQUENCY};
+use error::Error;
+use paths;
+use threads;
+
+const MAX_DEPTH: usize = 64;
+
+/// A pure Rust sampling profiler
+///
+/// Available with the `sampler` feature.
+///
+/// On Windows the `Sampler` samples from a thread of its own: every period
+/// it suspends each other thread of the process in turn, unwinds its stack
+/// and resumes it. Sampling CPU time weights each thread's stack by the CPU
+/// time the thread used since it was last sampled, so threads which are
+/// blocked are not sampled. Sampling the wall clock samples every thread,
+/// running or not. The profile is written in the cpuprofiler format when
+/// the profiler is stopped, with the loaded modules in place of the memory
+/// mappings.
+///
+/// Stacks are only unwound on x86_64, with the unwind tables of the loaded
+/// modules.
+///
+/// The sampling frequency defaults to the `CPUPROFILE_FREQUENCY` environment
+/// variable, or 100Hz if it is not set. The wall clock is sampled by default
+/// when `CPUPROFILE_REALTIME` is set.
+pub struct Sampler {
+    frequency: Option<u32>,
+    timer: Option<TimerKind>,
+    active: Option<Active>,
+}
+
+struct Active {
+    file: File,
+    period: u64,
+    shared: Arc<Shared>,
+    thread: JoinHandle<()>,
+}
+
+/// What the sampling thread shares with the profiler
+struct Shared {
+    running: AtomicBool,
+    paused: AtomicBool,
+    gathered: AtomicU64,
+    stacks: Mutex<HashMap<Vec<u64>, u64>>,
+}
+
+impl Sampler {
+    /// Create a sampler with the default settings
+    pub fn new() -> Sampler {
+        Sampler {
+            frequency: None,
+            timer: None,
+            active: None,
+        }
+    }
+
+    /// Set the sampling frequency in Hz
+    pub fn frequency(mut self, hz: u32) -> Sampler {
+        self.frequency = Some(hz);
+        self
+    }
+
+    /// Set the clock which decides when samples are taken
+    pub fn timer(mut self, timer: TimerKind) -> Sampler {
+        self.timer = Some(timer);
+        self
+    }
+
+    fn effective_frequency(&self) -> u32 {
+        let env = env::var("CPUPROFILE_FREQUENCY").ok().and_then(|f| f.parse().ok());
+        self.frequency.or(env).filter(|&hz| hz > 0).unwrap_or(DEFAULT_FREQUENCY)
+    }
+
+    fn effective_timer(&self) -> TimerKind {
+        self.timer.unwrap_or_else(|| match env::var_os("CPUPROFILE_REALTIME") {
+            Some(_) => TimerKind::WallClock,
+            None => TimerKind::CpuTime,
+        })
+    }
+}
+
+impl Default for Sampler {
+    fn default() -> Sampler {
+        Sampler::new()
+    }
+}
+
+impl fmt::Debug for Sampler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sampler")
+            .field("frequency", &self.frequency)
+            .field("timer", &self.timer)
+            .field("active", &self.active.is_some())
+            .finish()
+    }
+}
+
+impl ProfilerBackend for Sampler {
+    fn start(&mut self, fname: &CStr) -> Result<(), Error> {
+        self.check_available()?;
+        let path = paths::from_bytes(fname.to_bytes());
+        let file = File::create(&path).map_err(|source| Error::OutputPath {
+            path: path.clone(),
+            source,
+        })?;
+        let period = 1_000_000 / self.effective_frequency() as u64;
+        let timer = self.effective_timer();
+
+        let shared = Arc::new(Shared {
+            running: AtomicBool::new(true),
+            paused: AtomicBool::new(false),
+            gathered: AtomicU64::new(0),
+            stacks: Mutex::new(HashMap::new()),
+        });
+        let thread = {
+            let shared = shared.clone();
+            thread::spawn(move || sample(&shared, timer, Duration::from_micros(period)))
+        };
+
+        self.active = Some(Active {
+            file,
+            period,
+            shared,
+            thread,
+        });
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        let active = match self.active.take() {
+            Some(active) => active,
+            None => return Ok(()),
+        };
+
+        active.shared.running.store(false, Ordering::SeqCst);
+        let _ = active.thread.join();
+
+        let stacks = active.shared.stacks.lock().unwrap();
+        write_profile(&active.file, active.period, &stacks, &maps())?;
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<(), Error> {
+        if let Some(ref active) = self.active {
+            active.shared.paused.store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), Error> {
+        if let Some(ref active) = self.active {
+            active.shared.paused.store(false, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if let Some(ref active) = self.active {
+            let stacks = active.shared.stacks.lock().unwrap();
+            write_profile(&active.file, active.period, &stacks, &maps())?;
+        }
+        Ok(())
+    }
+
+    fn samples_gathered(&self) -> u64 {
+        self.active
+            .as_ref()
+            .map(|a| a.shared.gathered.load(Ordering::SeqCst))
+            .unwrap_or(0)
+    }
+
+    fn set_timer(&mut self, timer: TimerKind) -> Result<(), Error> {
+        self.timer = Some(timer);
+        Ok(())
+    }
+
+    fn set_frequency(&mut self, hz: u32) -> Result<(), Error> {
+        self.frequency = Some(hz);
+        Ok(())
+    }
+
+    fn frequency(&self) -> Option<u32> {
+        Some(self.effective_frequency())
+    }
+
+    fn check_available(&self) -> Result<(), Error> {
+        if cfg!(target_arch = "x86_64") {
+            Ok(())
+        } else {
+            Err(Error::Unsupported("stacks can only be unwound on x86_64 Windows".to_owned()))
+        }
+    }
+
+    fn check_timer(&self, _timer: TimerKind) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn check_frequency(&self, _hz: u32) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Take samples every `period` until the profile stops
+fn sample(shared: &Shared, timer: TimerKind, period: Duration) {
+    let own = unsafe { GetCurrentThreadId() };
+    // The CPU time of each thread which has been accounted for by samples,
+    // in the 100ns units of `GetThreadTimes`.
+    let mut accounted: HashMap<u32, u64> = HashMap::new();
+    let period_units = (period.as_nanos() / 100).max(1) as u64;
+    let mut stack = [0u64; MAX_DEPTH];
+    let mut next = Instant::now() + period;
+
+    while shared.running.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        if next > now {
+            thread::sleep(next - now);
+        }
+        next += period;
+        if shared.paused.load(Ordering::SeqCst) {
+            continue;
+        }
+
+        for id in process_threads().into_iter().filter(|&id| id != own) {
+            let handle = unsafe {
+                OpenThread(
+                    THREAD_SUSPEND_RESUME | THREAD_GET_CONTEXT | THREAD_QUERY_INFORMATION,
+                    0,
+                    id,
+                )
+            };
+            if handle == 0 {
+                continue;
+            }
+
+            let count = match timer {
+                TimerKind::WallClock => 1,
+                TimerKind::CpuTime => {
+                    let used = cpu_time(handle);
+                    let accounted = accounted.entry(id).or_insert(used);
+                    let periods = used.saturating_sub(*accounted) / period_units;
+                    *accounted += periods * period_units;
+                    periods
+                }
+            };
+            // Nothing may allocate while the thread is suspended, it could
+            // hold the heap lock.
+            let depth = if count > 0 {
+                unsafe { capture(handle, &mut stack) }
+            } else {
+                0
+            };
+            unsafe {
+                CloseHandle(handle);
+            }
+
+            if depth > 0 {
+                *shared.stacks.lock().unwrap().entry(stack[..depth].to_vec()).or_insert(0) += count;
+                shared.gathered.fetch_add(count, Ordering::SeqCst);
+                threads::record_samples(id as u64, count);
+            }
+        }
+    }
+}
+
+/// Returns the ids of the threads of this process
+fn process_threads() -> Vec<u32> {
+    let mut ids = Vec::new();
+    unsafe {
+        let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0);
+        if snapshot == INVALID_HANDLE_VALUE {
+            return ids;
+        }
+        let process = GetCurrentProcessId();
+        let mut entry: THREADENTRY32 = mem::zeroed();
+        entry.dwSize = mem::size_of::<THREADENTRY32>() as u32;
+        let mut more = Thread32First(snapshot, &mut entry) != 0;
+        while more {
+            if entry.th32OwnerProcessID == process {
+                ids.push(entry.th32ThreadID);
+            }
+            more = Thread32Next(snapshot, &mut entry) != 0;
+        }
+        CloseHandle(snapshot);
+    }
+    ids
+}
+
+/// Returns the CPU time `thread` has used, in 100ns units
+fn cpu_time(thread: HANDLE) -> u64 {
+    unsafe {
+        let mut created: FILETIME = mem::zeroed();
+        let mut exited: FILETIME = mem::zeroed();
+        let mut kernel: FILETIME = mem::zeroed();
+        let mut user: FILETIME = mem::zeroed();
+        if GetThreadTimes(thread, &mut created, &mut exited, &mut kernel, &mut user) == 0 {
+            return 0;
+        }
+        let units = |time: FILETIME| (time.dwHighDateTime as u64) << 32 | time.dwLowDateTime as u64;
+        units(kernel) + units(user)
+    }
+}
+
+/// Suspend `thread`, write its stack into `stack` and resume it
+///
+/// Returns the depth of the stack, 0 if the thread could not be sampled.
+#[cfg(target_arch = "x86_64")]
+unsafe fn capture(thread: HANDLE, stack: &mut [u64]) -> usize {
+    use windows_sys::Win32::System::Diagnostics::Debug::{
+        GetThreadContext, RtlLookupFunctionEntry, RtlVirtualUnwind, CONTEXT, CONTEXT_FULL_AMD64, UNW_FLAG_NHANDLER,
+    };
+
+    /// `GetThreadContext` needs the context aligned to 16 bytes
+    #[repr(C, align(16))]
+    struct Aligned(CONTEXT);
+
+    if SuspendThread(thread) == u32::MAX {
+        return 0;
+    }
+    let mut context: Aligned = mem::zeroed();
+    context.0.ContextFlags = CONTEXT_FULL_AMD64;
+    let mut depth = 0;
+    if GetThreadContext(thread, &mut context.0) != 0 {
+        let context = &mut context.0;
+        while depth < stack.len() && context.Rip != 0 {
+            let pc = context.Rip;
+            stack[depth] = pc;
+            depth += 1;
+
+            let mut image_base = 0;
+            let function = RtlLookupFunctionEntry(pc, &mut image_base, ptr::null_mut());
+            if function.is_null() {
+                // Leaf functions have no unwind information and leave the
+                // return address on top of the stack.
+                if context.Rsp == 0 {
+                    break;
+                }
+                context.Rip = *(context.Rsp as *const u64);
+                context.Rsp += 8;
+            } else {
+                let mut handler_data = ptr::null_mut();
+                let mut frame = 0;
+                RtlVirtualUnwind(
+                    UNW_FLAG_NHANDLER,
+                    image_base,
+                    pc,
+                    function,
+                    context,
+                    &mut handler_data,
+                    &mut frame,
+                    ptr::null_mut(),
+                );
+            }
+        }
+    }
+    ResumeThread(thread);
+    depth
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+unsafe fn capture(_thread: HANDLE, _stack: &mut [u64]) -> usize {
+    0
+}
+
+/// Returns the loaded modules in the format of `/proc/self/maps`
+fn maps() -> Vec<u8> {
+    let mut maps = String::new();
+    unsafe {
+        let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPMODULE | TH32CS_SNAPMODULE32, 0);
+        if snapshot == INVALID_HANDLE_VALUE {
+            return Vec::new();
+        }
+        let mut entry: MODULEENTRY32W = mem::zeroed();
+        entry.dwSize = mem::size_of::<MODULEENTRY32W>() as u32;
+        let mut more = Module32FirstW(snapshot, &mut entry) != 0;
+        while more {
+            let start = entry.modBaseAddr as usize;
+            let end = start + entry.modBaseSize as usize;
+            let len = entry.szExePath.iter().position(|&c| c == 0).unwrap_or(entry.szExePath.len());
+            let path = String::from_utf16_lossy(&entry.szExePath[..len]);
+            maps.push_str(&format!("{:x}-{:x} r-xp 00000000 00:00 0 {}\n", start, end, path));
+            more = Module32NextW(snapshot, &mut entry) != 0;
+        }
+        CloseHandle(snapshot);
+    }
+    maps.into_bytes()
+}
@@ -0,0 +1,158 @@
+//! Profiling many iterations of a benchmark
+//!
+//! A single run of a fast function gets few samples, if any. `profile_iterations`
+//! runs a function repeatedly, profiling each iteration in memory, and merges
+//! the iterations into one profile alongside the time each one took. Only the
+//! iterations themselves are timed and sampled, starting and stopping the
+//! profiler between them is left out.
+//!
+//! # Examples
+//!
+//! ```
+//! use cpuprofiler::bench;
+//!
+//! let bench = bench::profile_iterations("sort", 20, || {
+//!     let mut v: Vec<u32> = (0..10_000).rev().collect();
+//!     v.sort();
+//! })
+//! .unwrap();
+//! println!("{}", bench.timing);
+//! println!("{} samples", bench.profile.total_samples());
+//! ```
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use error::Error;
+use lock;
+use profile::Profile;
+use Profiler;
+
+/// The merged profile and timings of `profile_iterations`
+#[derive(Clone, Debug)]
+pub struct BenchProfile {
+    /// The name the benchmark was run as
+    pub name: String,
+    /// The samples of every iteration, merged with `Profile::merge`
+    pub profile: Profile,
+    /// How long each iteration took, in the order they ran
+    pub iterations: Vec<Duration>,
+    /// Statistics of the iteration times
+    pub timing: Timing,
+}
+
+/// Statistics of the times of a benchmark's iterations
+///
+/// Every time is zero when no iterations were run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timing {
+    /// The number of iterations
+    pub iterations: usize,
+    /// The time of all iterations together
+    pub total: Duration,
+    /// The fastest iteration
+    pub min: Duration,
+    /// The slowest iteration
+    pub max: Duration,
+    /// The mean time of an iteration
+    pub mean: Duration,
+    /// The median time of an iteration
+    pub median: Duration,
+    /// The standard deviation of the iteration times
+    pub std_dev: Duration,
+}
+
+impl Timing {
+    /// Compute the statistics of `times`
+    pub fn from_times(times: &[Duration]) -> Timing {
+        if times.is_empty() {
+            return Timing::default();
+        }
+        let mut sorted = times.to_vec();
+        sorted.sort();
+        let total: Duration = times.iter().sum();
+        let mean = total / times.len() as u32;
+        let median = if sorted.len().is_multiple_of(2) {
+            (sorted[sorted.len() / 2 - 1] + sorted[sorted.len() / 2]) / 2
+        } else {
+            sorted[sorted.len() / 2]
+        };
+        let variance = times
+            .iter()
+            .map(|t| {
+                let d = t.as_secs_f64() - mean.as_secs_f64();
+                d * d
+            })
+            .sum::<f64>()
+            / times.len() as f64;
+
+        Timing {
+            iterations: times.len(),
+            total,
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+            mean,
+            median,
+            std_dev: Duration::from_secs_f64(variance.sqrt()),
+        }
+    }
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} iterations in {:?}: mean {:?}, median {:?}, min {:?}, max {:?}, std dev {:?}",
+            self.iterations, self.total, self.mean, self.median, self.min, self.max, self.std_dev
+        )
+    }
+}
+
+/// Stops the profile of an iteration which panics
+struct Stop<'a>(&'a mut Profiler);
+
+impl<'a> Drop for Stop<'a> {
+    fn drop(&mut self) {
+        if self.0.state().is_running() {
+            let _ = self.0.stop();
+        }
+    }
+}
+
+/// Run `f` `iters` times, profiling every iteration, as the benchmark `name`
+///
+/// `PROFILER` is locked throughout, so `f` must not use it. Each iteration
+/// is profiled with the backend's current settings; benchmarks which run
+/// for less than a few sampling periods need a higher frequency, see
+/// `ProfilerBuilder::frequency`. With the `disabled` feature the iterations
+/// are timed but the profile stays empty.
+///
+/// # Failures
+///
+/// - The profiler is running, or could not be started, see
+///   `Profiler::start_in_memory`.
+/// - An iteration's profile could not be read back or parsed.
+pub fn profile_iterations<F: FnMut()>(name: &str, iters: usize, mut f: F) -> Result<BenchProfile, Error> {
+    let mut profiler = lock::lock();
+
+    let mut profile = Profile::default();
+    let mut iterations = Vec::with_capacity(iters);
+    for _ in 0..iters {
+        profiler.start_in_memory()?;
+        let running = Stop(&mut profiler);
+        let started = Instant::now();
+        f();
+        iterations.push(started.elapsed());
+        let bytes = running.0.stop_to_vec()?;
+        if cfg!(not(feature = "disabled")) {
+            profile.merge(Profile::from_bytes(&bytes)?)?;
+        }
+    }
+
+    Ok(BenchProfile {
+        name: name.to_owned(),
+        profile,
+        timing: Timing::from_times(&iterations),
+        iterations,
+    })
+}
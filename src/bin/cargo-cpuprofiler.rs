@@ -0,0 +1,276 @@
+//! Profile any program and report on the result
+//!
+//! Installed as a cargo subcommand with the `cli` feature:
+//!
+//! ```text
+//! cargo cpuprofiler run [OPTIONS] -- <PROGRAM> [ARGS...]
+//! cargo cpuprofiler report [OPTIONS] <PROFILE>
+//! cargo cpuprofiler verify <PROFILE>...
+//! cargo cpuprofiler compare [OPTIONS] <BASELINE> <PROFILE>
+//! ```
+//!
+//! `run` preloads libprofiler into the program and asks it to profile the
+//! whole run through `CPUPROFILE`, so the program needs no changes. Both
+//! commands then symbolize the profile and print the functions with the most
+//! samples, followed by the samples of each thread if the profile has them,
+//! and the source lines of the functions picked with `--list`.
+//! `verify` checks profiles for truncation and corruption, and exits with 1
+//! if any has a problem. `compare` writes an HTML report on both profiles
+//! and how the second differs from the first, to attach to pull requests.
+
+extern crate cpuprofiler;
+
+use std::env;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+
+#[cfg(unix)]
+use cpuprofiler::command::ProfiledCommand;
+#[cfg(unix)]
+use cpuprofiler::error::Error;
+use cpuprofiler::profile::{self, Filter, Profile};
+use cpuprofiler::report;
+use cpuprofiler::symbolize::Symbolizer;
+
+const USAGE: &str = "\
+Usage:
+    cargo cpuprofiler run [OPTIONS] -- <PROGRAM> [ARGS...]
+    cargo cpuprofiler report [OPTIONS] <PROFILE>
+    cargo cpuprofiler verify <PROFILE>...
+    cargo cpuprofiler compare [OPTIONS] <BASELINE> <PROFILE>
+
+Options:
+    -o, --output <FILE>       Where `run` writes the profile [default: cpuprofiler.profile]
+    -n, --top <N>             Number of functions to report [default: 20]
+        --flamegraph <FILE>   Also write an SVG flamegraph
+        --folded <FILE>       Also write the stacks in the folded format
+        --focus <REGEX>       Only report stacks with a function matching REGEX
+        --ignore <REGEX>      Do not report stacks with a function matching REGEX
+        --hide <REGEX>        Remove the functions matching REGEX from the stacks
+        --nodefraction <F>    Cut off calls with less than F of the samples
+        --list <REGEX>        Also list the source lines of functions matching REGEX
+        --frequency <HZ>      Sampling frequency for `run`
+        --preload <LIB>       The libprofiler to preload for `run`
+        --html <DIR>          Where `compare` writes its report [default: cpuprofiler-report]
+    -h, --help                Print this message
+";
+
+#[derive(Debug, Default)]
+struct Options {
+    output: Option<PathBuf>,
+    top: Option<usize>,
+    flamegraph: Option<PathBuf>,
+    folded: Option<PathBuf>,
+    filter: Filter,
+    list: Option<String>,
+    frequency: Option<u32>,
+    preload: Option<String>,
+    html: Option<PathBuf>,
+    args: Vec<OsString>,
+}
+
+fn main() {
+    let mut args: Vec<OsString> = env::args_os().skip(1).collect();
+    // Cargo passes the subcommand name as the first argument.
+    if args.first().is_some_and(|a| a == "cpuprofiler") {
+        args.remove(0);
+    }
+
+    let code = match run(args) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            2
+        }
+    };
+    process::exit(code);
+}
+
+fn run(mut args: Vec<OsString>) -> Result<i32, String> {
+    if args.is_empty() {
+        return Err(format!("missing command\n\n{}", USAGE));
+    }
+    let command = args.remove(0);
+    let options = parse(args)?;
+
+    if command == "run" {
+        let output = options.output.clone().unwrap_or_else(|| PathBuf::from("cpuprofiler.profile"));
+        let code = profile_program(&options, &output)?;
+        report_on(&options, &output)?;
+        Ok(code)
+    } else if command == "report" {
+        let profile = match options.args.len() {
+            1 => PathBuf::from(&options.args[0]),
+            _ => return Err(format!("`report` takes one profile\n\n{}", USAGE)),
+        };
+        report_on(&options, &profile)?;
+        Ok(0)
+    } else if command == "verify" {
+        if options.args.is_empty() {
+            return Err(format!("`verify` needs a profile\n\n{}", USAGE));
+        }
+        let mut code = 0;
+        for path in options.args.iter().map(PathBuf::from) {
+            let verification =
+                profile::verify(&path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+            print!("{}: {}", path.display(), verification);
+            if !verification.is_valid() {
+                code = 1;
+            }
+        }
+        Ok(code)
+    } else if command == "compare" {
+        if options.args.len() != 2 {
+            return Err(format!("`compare` takes a baseline and a profile\n\n{}", USAGE));
+        }
+        let out_dir = options.html.clone().unwrap_or_else(|| PathBuf::from("cpuprofiler-report"));
+        let index = report::html(&options.args, &out_dir).map_err(|e| format!("failed to compare: {}", e))?;
+        println!("wrote {}", index.display());
+        Ok(0)
+    } else if command == "-h" || command == "--help" || command == "help" {
+        print!("{}", USAGE);
+        Ok(0)
+    } else {
+        Err(format!("unknown command {:?}\n\n{}", command, USAGE))
+    }
+}
+
+fn parse(args: Vec<OsString>) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let flag = match arg.to_str() {
+            Some(flag) if flag.starts_with('-') => flag.to_owned(),
+            _ => {
+                options.args.push(arg);
+                continue;
+            }
+        };
+        if flag == "--" {
+            options.args.extend(args);
+            break;
+        }
+        if flag == "-h" || flag == "--help" {
+            print!("{}", USAGE);
+            process::exit(0);
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", flag))?;
+        let number = |value: &OsString| {
+            value
+                .to_str()
+                .and_then(|v| v.parse::<u64>().ok())
+                .ok_or_else(|| format!("{} needs a number, got {:?}", flag, value))
+        };
+        match &*flag {
+            "-o" | "--output" => options.output = Some(PathBuf::from(value)),
+            "-n" | "--top" => options.top = Some(number(&value)? as usize),
+            "--flamegraph" => options.flamegraph = Some(PathBuf::from(value)),
+            "--folded" => options.folded = Some(PathBuf::from(value)),
+            "--focus" | "--ignore" | "--hide" => {
+                let pattern = value.to_str().ok_or_else(|| format!("{} needs a pattern, got {:?}", flag, value))?;
+                let filter = mem::take(&mut options.filter);
+                let filter = match &*flag {
+                    "--focus" => filter.focus(pattern),
+                    "--ignore" => filter.ignore(pattern),
+                    _ => filter.hide(pattern),
+                };
+                options.filter = filter.map_err(|e| e.to_string())?;
+            }
+            "--nodefraction" => {
+                let fraction = value
+                    .to_str()
+                    .and_then(|v| v.parse::<f64>().ok())
+                    .filter(|f| (0.0..=1.0).contains(f))
+                    .ok_or_else(|| format!("{} needs a fraction between 0 and 1, got {:?}", flag, value))?;
+                options.filter = mem::take(&mut options.filter).prune_below(fraction);
+            }
+            "--list" => {
+                let pattern = value.into_string().map_err(|value| format!("{} needs a pattern, got {:?}", flag, value))?;
+                options.list = Some(pattern);
+            }
+            "--frequency" => options.frequency = Some(number(&value)? as u32),
+            "--preload" => options.preload = value.into_string().ok(),
+            "--html" => options.html = Some(PathBuf::from(value)),
+            _ => return Err(format!("unknown option {}\n\n{}", flag, USAGE)),
+        }
+    }
+    Ok(options)
+}
+
+/// Run the program with libprofiler preloaded, returning its exit code
+#[cfg(unix)]
+fn profile_program(options: &Options, output: &Path) -> Result<i32, String> {
+    let (program, args) = match options.args.split_first() {
+        Some(split) => split,
+        None => return Err(format!("`run` needs a program to run\n\n{}", USAGE)),
+    };
+
+    let mut command = ProfiledCommand::new(program, output).args(args);
+    command = match options.preload {
+        Some(ref library) => command.preload_library(library),
+        None => command.preload(true),
+    };
+    if let Some(hz) = options.frequency {
+        command = command.frequency(hz);
+    }
+    let profiled = command.status().map_err(|e| match e {
+        Error::LibraryUnavailable => "libprofiler was not found, install gperftools or use --preload".to_owned(),
+        e => format!("failed to profile {:?}: {}", program, e),
+    })?;
+    Ok(profiled.status.code().unwrap_or(1))
+}
+
+/// Programs can only be profiled by preloading libprofiler, which needs Unix
+#[cfg(not(unix))]
+fn profile_program(_options: &Options, _output: &Path) -> Result<i32, String> {
+    Err("`run` needs libprofiler, which is not available on this platform, use `report` on existing profiles".to_owned())
+}
+
+fn report_on(options: &Options, path: &Path) -> Result<(), String> {
+    let profile = Profile::from_file(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let stacks = options.filter.apply(&Symbolizer::new().stacks(&profile));
+
+    let stdout = io::stdout();
+    report::write_top(&mut stdout.lock(), &stacks, options.top.unwrap_or(20))
+        .map_err(|e| e.to_string())?;
+    if !profile.threads().is_empty() {
+        let mut out = stdout.lock();
+        writeln!(out)
+            .and_then(|_| report::write_threads(&mut out, profile.threads()))
+            .map_err(|e| e.to_string())?;
+    }
+    if let Some(ref pattern) = options.list {
+        let mut out = stdout.lock();
+        writeln!(out)
+            .map_err(From::from)
+            .and_then(|_| report::annotate_source(&mut out, &profile, pattern))
+            .map_err(|e| e.to_string())?;
+    }
+
+    if let Some(ref folded) = options.folded {
+        write_file(folded, |out| report::write_folded(out, &stacks))?;
+    }
+    if let Some(ref flamegraph) = options.flamegraph {
+        let title = path.display().to_string();
+        write_file(flamegraph, |out| report::write_flamegraph(out, &stacks, &title))?;
+    }
+    Ok(())
+}
+
+fn write_file<F>(path: &Path, write: F) -> Result<(), String>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    let file = File::create(path).map_err(|e| format!("failed to create {}: {}", path.display(), e))?;
+    let mut out = BufWriter::new(file);
+    write(&mut out)
+        .and_then(|_| out.flush())
+        .map_err(|e| format!("failed to write {}: {}", path.display(), e))
+}
@@ -0,0 +1,23 @@
+//! Check that profiling works on this host
+//!
+//! ```text
+//! cpuprofiler-selftest
+//! ```
+//!
+//! Profiles a function which keeps the CPU busy for a second, and checks
+//! that the profile has samples in it, see `cpuprofiler::self_test`. Exits
+//! with 1 if profiling does not work.
+
+extern crate cpuprofiler;
+
+use std::process;
+
+fn main() {
+    match cpuprofiler::self_test() {
+        Ok(test) => println!("profiling works: {}", test),
+        Err(e) => {
+            eprintln!("profiling does not work: {}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! Starting the profiler from the environment
+
+use std::env;
+use std::mem;
+use std::path::Path;
+
+use error::Error;
+use exit;
+use lock;
+use paths;
+
+/// Start the profiler if the `CPUPROFILE` environment variable is set
+///
+/// This mirrors the cpuprofiler library when it is linked into C programs:
+/// the profile is written to the file named by `CPUPROFILE` and the profiler
+/// is stopped when the process exits. The filename may contain the tokens
+/// described in `Profiler::start`.
+///
+/// Returns whether profiling was started. Nothing is done if `CPUPROFILE` is
+/// unset or empty, or the profiler is already `Active`.
+///
+/// When libprofiler is linked it starts profiling by itself as the
+/// process loads if `CPUPROFILE` is set. That profile is adopted instead of
+/// starting a second, so it can be stopped through `PROFILER` as usual.
+///
+/// With the `ctor` feature this is called before `main`.
+///
+/// # Examples
+///
+/// ```
+/// if cpuprofiler::init_from_env().unwrap() {
+///     println!("profiling until the process exits");
+/// }
+/// ```
+///
+/// # Failures
+///
+/// - The profiler could not be started, see `Profiler::start`.
+pub fn init_from_env() -> Result<bool, Error> {
+    let fname = match env::var_os("CPUPROFILE") {
+        Some(ref fname) if !fname.is_empty() => fname.clone(),
+        _ => return Ok(false),
+    };
+
+    let mut profiler = lock::lock();
+    if profiler.state.is_running() {
+        // The profile the library started was adopted when `PROFILER` was
+        // first locked.
+        if !mem::replace(&mut profiler.adopted, false) {
+            return Ok(false);
+        }
+    } else if !profiler.adopt_library_profile() {
+        profiler.start(paths::to_bytes(Path::new(&fname)))?;
+    }
+    exit::register();
+    Ok(true)
+}
+
+#[cfg(feature = "ctor")]
+#[::ctor::ctor]
+fn init() {
+    let _ = init_from_env();
+}
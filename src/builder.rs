@@ -0,0 +1,580 @@
+//! Choosing how a profile is sampled
+//!
+//! `Profiler::start` samples with whatever settings the backend already has.
+//! A `ProfilerBuilder` picks the settings for the profile it starts, and fails
+//! to start if the backend cannot honour them.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use cpuprofiler::builder::{ProfilerBuilder, TimerKind};
+//!
+//! let path = env::temp_dir().join("wall-clock.profile");
+//! let started = ProfilerBuilder::new()
+//!     .timer(TimerKind::WallClock)
+//!     .start(path.to_str().unwrap());
+//! match started {
+//!     Ok(()) => {
+//!         // Code which waits on I/O or locks goes here!
+//!         cpuprofiler::stop().unwrap();
+//!     }
+//!     Err(e) => println!("wall clock sampling is unavailable: {}", e),
+//! }
+//! ```
+
+use std::fmt;
+#[cfg(all(feature = "gperftools", target_os = "linux"))]
+use std::io;
+#[cfg(all(unix, any(feature = "gperftools", feature = "sampler")))]
+use std::mem;
+use std::path::{Path, PathBuf};
+#[cfg(all(unix, any(feature = "gperftools", feature = "sampler")))]
+use std::ptr;
+use std::thread;
+
+#[cfg(all(unix, any(feature = "gperftools", feature = "sampler")))]
+use libc;
+
+use error::Error;
+#[cfg(feature = "gperftools")]
+use ffi;
+use lock;
+use options::RawOptions;
+use paths;
+use template;
+use threads;
+use validate::{self, Diagnosis, Settings};
+use {Profiler, ProfilerState};
+
+/// The clock which decides when samples are taken
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerKind {
+    /// Sample every period of CPU time used by the process, the default
+    ///
+    /// Threads which are blocked are never sampled.
+    CpuTime,
+    /// Sample every period of real time, whether the process is running or not
+    ///
+    /// This shows where time is spent waiting on I/O or locks, which makes
+    /// it the better choice for latency bound programs. It takes over the
+    /// `SIGALRM` signal and the `ITIMER_REAL` timer while profiling.
+    WallClock,
+}
+
+impl fmt::Display for TimerKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            TimerKind::CpuTime => write!(f, "CPU time"),
+            TimerKind::WallClock => write!(f, "wall clock"),
+        }
+    }
+}
+
+impl TimerKind {
+    /// Returns the signal which delivers samples taken with this timer
+    #[cfg(all(unix, any(feature = "gperftools", feature = "sampler")))]
+    pub(crate) fn signal(self) -> libc::c_int {
+        match self {
+            TimerKind::CpuTime => libc::SIGPROF,
+            TimerKind::WallClock => libc::SIGALRM,
+        }
+    }
+
+    /// Check that the process can be sampled with this timer
+    #[cfg(all(unix, any(feature = "gperftools", feature = "sampler")))]
+    pub(crate) fn check_supported(self) -> Result<(), Error> {
+        if self == TimerKind::CpuTime {
+            return Ok(());
+        }
+
+        unsafe {
+            let mut timer: libc::itimerval = mem::zeroed();
+            if libc::getitimer(libc::ITIMER_REAL, &mut timer) != 0 {
+                return Err(Error::Unsupported("real time interval timers are not available".to_owned()));
+            }
+            if timer.it_value.tv_sec != 0 || timer.it_value.tv_usec != 0 {
+                return Err(Error::Unsupported("the ITIMER_REAL timer is already in use".to_owned()));
+            }
+
+            let mut action: libc::sigaction = mem::zeroed();
+            libc::sigaction(libc::SIGALRM, ptr::null(), &mut action);
+            if action.sa_sigaction != libc::SIG_DFL && action.sa_sigaction != libc::SIG_IGN {
+                return Err(Error::Unsupported("SIGALRM already has a handler".to_owned()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The sampling frequency, in Hz, used unless another is chosen
+pub const DEFAULT_FREQUENCY: u32 = 100;
+
+/// The highest sampling frequency, in Hz, which the cpuprofiler library accepts
+pub const MAX_FREQUENCY: u32 = 4000;
+
+/// Check that threads can be given timers of their own
+///
+/// This needs Linux timers which signal a chosen thread, available since
+/// Linux 2.6.12, and per thread CPU clocks.
+#[cfg(all(feature = "gperftools", target_os = "linux"))]
+pub(crate) fn check_per_thread_timers() -> Result<(), Error> {
+    unsafe {
+        let mut event: libc::sigevent = mem::zeroed();
+        event.sigev_notify = libc::SIGEV_THREAD_ID;
+        event.sigev_signo = libc::SIGPROF;
+        event.sigev_notify_thread_id = libc::syscall(libc::SYS_gettid) as libc::c_int;
+
+        let mut timer: libc::timer_t = mem::zeroed();
+        if libc::timer_create(libc::CLOCK_THREAD_CPUTIME_ID, &mut event, &mut timer) != 0 {
+            let reason = format!("per thread timers could not be created: {}", io::Error::last_os_error());
+            return Err(Error::Unsupported(reason));
+        }
+        libc::timer_delete(timer);
+    }
+    Ok(())
+}
+
+/// Check that threads can be given timers of their own
+#[cfg(all(feature = "gperftools", not(target_os = "linux")))]
+pub(crate) fn check_per_thread_timers() -> Result<(), Error> {
+    Err(Error::Unsupported("per thread timers are only available on Linux".to_owned()))
+}
+
+/// Check that `hz` is a frequency the profiler can sample at
+pub(crate) fn check_frequency(hz: u32) -> Result<(), Error> {
+    if hz == 0 || hz > MAX_FREQUENCY {
+        let reason = format!("sampling at {} Hz, the frequency must be between 1 and {} Hz", hz, MAX_FREQUENCY);
+        return Err(Error::Unsupported(reason));
+    }
+    Ok(())
+}
+
+/// Settings for starting the profiler
+///
+/// Settings which are not chosen are left as the backend has them.
+#[derive(Clone, Debug, Default)]
+pub struct ProfilerBuilder {
+    timer: Option<TimerKind>,
+    frequency: Option<u32>,
+    per_thread_timers: Option<bool>,
+    metadata: Option<bool>,
+    metadata_env: Option<Vec<String>>,
+    create_dirs: Option<bool>,
+    fallback_dirs: Option<Vec<PathBuf>>,
+    skip_frames: Option<usize>,
+    max_depth: Option<usize>,
+    labels: Vec<(String, String)>,
+    raw_options: RawOptions,
+    dry_run: bool,
+}
+
+impl ProfilerBuilder {
+    /// Create a builder which changes no settings
+    pub fn new() -> ProfilerBuilder {
+        ProfilerBuilder::default()
+    }
+
+    /// Sample with `timer`
+    ///
+    /// The cpuprofiler library reads its timer from the `CPUPROFILE_REALTIME`
+    /// environment variable once, when it is loaded. When libprofiler is
+    /// linked that is before `main`, so to sample the wall clock set
+    /// `CPUPROFILE_REALTIME=1` when launching the program, and starting only
+    /// checks that it was set. With the `dylib-load` feature the variable is
+    /// set for you, as long as libprofiler is not loaded yet.
+    pub fn timer(mut self, timer: TimerKind) -> ProfilerBuilder {
+        self.timer = Some(timer);
+        self
+    }
+
+    /// Sample `hz` times a second
+    ///
+    /// The default of 100 Hz, `DEFAULT_FREQUENCY`, is too coarse for short
+    /// benchmarks. Frequencies must be between 1 Hz and `MAX_FREQUENCY`.
+    /// The period between samples is a whole number of microseconds, so the
+    /// frequency used can differ slightly from the one chosen. It is reported
+    /// by `Profiler::last_frequency` once the profile is stopped.
+    ///
+    /// The cpuprofiler library reads the `CPUPROFILE_FREQUENCY` environment
+    /// variable when it is loaded, see `timer` for what that means.
+    pub fn frequency(mut self, hz: u32) -> ProfilerBuilder {
+        self.frequency = Some(hz);
+        self
+    }
+
+    /// Give each thread a timer of its own
+    ///
+    /// With a single timer for the process the kernel delivers each sample
+    /// to whichever thread happens to be running, which skews profiles of
+    /// programs with many busy threads. Per thread timers measure the CPU
+    /// time of every thread separately.
+    ///
+    /// This needs Linux 2.6.12 or later, and a libprofiler built with
+    /// `SIGEV_THREAD_ID` support, which gperftools detects when it is
+    /// configured. Only threads which call `Profiler::register_thread` are
+    /// sampled, apart from the main thread. The cpuprofiler library reads
+    /// the `CPUPROFILE_PER_THREAD_TIMERS` environment variable when it is
+    /// loaded, see `timer` for what that means.
+    pub fn per_thread_timers(mut self, enabled: bool) -> ProfilerBuilder {
+        self.per_thread_timers = Some(enabled);
+        self
+    }
+
+    /// Write metadata next to the profile, see `Profiler::set_metadata`
+    ///
+    /// Like the other settings this is kept for later profiles.
+    pub fn metadata(mut self, enabled: bool) -> ProfilerBuilder {
+        self.metadata = Some(enabled);
+        self
+    }
+
+    /// Record the environment variable `var` in the metadata, see
+    /// `Profiler::set_metadata_env`
+    ///
+    /// The variables chosen replace those of earlier profiles, and are kept
+    /// for later ones.
+    pub fn metadata_env<S: Into<String>>(mut self, var: S) -> ProfilerBuilder {
+        self.metadata_env.get_or_insert_with(Vec::new).push(var.into());
+        self
+    }
+
+    /// Create missing parent directories, see `Profiler::set_create_dirs`
+    ///
+    /// Like the other settings this is kept for later profiles.
+    pub fn create_dirs(mut self, enabled: bool) -> ProfilerBuilder {
+        self.create_dirs = Some(enabled);
+        self
+    }
+
+    /// Write profiles which cannot be written to their read only path to
+    /// the first of `dirs` which can take them, see
+    /// `Profiler::set_fallback_dirs`
+    ///
+    /// Like the other settings this is kept for later profiles.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use cpuprofiler::builder::ProfilerBuilder;
+    /// use cpuprofiler::{Profiler, PROFILER};
+    ///
+    /// let path = env::temp_dir().join("fallback-builder-example.profile");
+    /// ProfilerBuilder::new()
+    ///     .fallback_dirs(Profiler::default_fallback_dirs())
+    ///     .start(path.to_str().unwrap())
+    ///     .unwrap();
+    /// let summary = PROFILER.lock().unwrap().stop_with_summary().unwrap();
+    /// if let Some(requested) = summary.requested {
+    ///     println!("{} was read only, profiled into {}", requested.display(), summary.path.display());
+    /// }
+    /// ```
+    pub fn fallback_dirs<I, P>(mut self, dirs: I) -> ProfilerBuilder
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.fallback_dirs = Some(dirs.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Drop the `frames` innermost frames of every stack, see
+    /// `Profiler::set_stack_trim`
+    ///
+    /// This hides the frames of the signal handler which took the sample,
+    /// which sit on top of every stack with some backends. Like the other
+    /// settings this is kept for later profiles.
+    pub fn skip_frames(mut self, frames: usize) -> ProfilerBuilder {
+        self.skip_frames = Some(frames);
+        self
+    }
+
+    /// Keep at most `depth` frames of every stack, the innermost ones, see
+    /// `Profiler::set_stack_trim`
+    ///
+    /// Deep recursive or async stacks make reports large and hard to read.
+    /// Like the other settings this is kept for later profiles.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use cpuprofiler::PROFILER;
+    /// use cpuprofiler::builder::ProfilerBuilder;
+    /// use cpuprofiler::profile::Profile;
+    ///
+    /// let path = env::temp_dir().join("trim-example.profile");
+    /// let mut profiler = PROFILER.lock().unwrap();
+    /// ProfilerBuilder::new()
+    ///     .skip_frames(1)
+    ///     .max_depth(64)
+    ///     .start_on(&mut profiler, path.to_str().unwrap())
+    ///     .unwrap();
+    /// // Code you want to sample goes here!
+    /// profiler.stop().unwrap();
+    /// # profiler.set_stack_trim(Default::default());
+    ///
+    /// let profile = Profile::from_file(&path).unwrap();
+    /// assert!(profile.samples().iter().all(|sample| sample.stack.len() <= 64));
+    /// ```
+    pub fn max_depth(mut self, depth: usize) -> ProfilerBuilder {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Attach the label `key` = `value` to the profile
+    ///
+    /// Labels describe the workload being profiled, such as the endpoint or
+    /// the tenant, so that continuous profiling backends can slice profiles
+    /// by them. They are written to the metadata, see `metadata`, and are
+    /// returned by `Profiler::labels` to pass on to `pprof::Encoder::labels`.
+    /// Unlike the other settings they only apply to the profile started.
+    pub fn label<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> ProfilerBuilder {
+        self.labels.push((key.into(), value.into()));
+        self
+    }
+
+    /// Returns the options set with `options::OptionsExt`
+    pub(crate) fn raw_options(&self) -> &RawOptions {
+        &self.raw_options
+    }
+
+    pub(crate) fn raw_options_mut(&mut self) -> &mut RawOptions {
+        &mut self.raw_options
+    }
+
+    /// Only check that the profile could be started, see `validate_on`
+    ///
+    /// `start_on` and the other ways of starting return the first problem
+    /// found, or `Ok` without starting the profile if there is none.
+    pub fn dry_run(mut self, enabled: bool) -> ProfilerBuilder {
+        self.dry_run = enabled;
+        self
+    }
+
+    /// Check that `profiler` could be started into `fname` with these
+    /// settings, without starting it or changing any setting
+    ///
+    /// See the [`validate`](../validate/index.html) module.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use cpuprofiler::PROFILER;
+    /// use cpuprofiler::builder::{ProfilerBuilder, TimerKind};
+    ///
+    /// let path = env::temp_dir().join("dry-run-example.profile");
+    /// let profiler = PROFILER.lock().unwrap();
+    /// let diagnosis = ProfilerBuilder::new()
+    ///     .timer(TimerKind::WallClock)
+    ///     .frequency(1000)
+    ///     .validate_on(&profiler, path.to_str().unwrap());
+    /// for check in diagnosis.checks() {
+    ///     if let Err(ref e) = check.result {
+    ///         println!("{} is not ready: {}", check.name, e);
+    ///     }
+    /// }
+    /// ```
+    pub fn validate_on<T: Into<Vec<u8>>>(&self, profiler: &Profiler, fname: T) -> Diagnosis {
+        let settings = Settings {
+            timer: self.timer,
+            frequency: self.frequency,
+            per_thread_timers: self.per_thread_timers,
+            create_dirs: self.create_dirs,
+            fallback_dirs: self.fallback_dirs.clone(),
+        };
+        validate::diagnose(profiler, fname.into(), &settings)
+    }
+
+    /// Start `PROFILER` with these settings
+    ///
+    /// # Failures
+    ///
+    /// - See `start_on`.
+    pub fn start<T: Into<Vec<u8>>>(&self, fname: T) -> Result<(), Error> {
+        self.start_on(&mut lock::lock(), fname)
+    }
+
+    /// Start `PROFILER` with these settings, writing to `path`
+    ///
+    /// The path is used as it is, see `Profiler::start_path`.
+    ///
+    /// # Failures
+    ///
+    /// - See `start_on`.
+    pub fn start_path<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        self.start(template::escape(&paths::to_bytes(path.as_ref())))
+    }
+
+    /// Start `profiler` with these settings
+    ///
+    /// This is for callers which already hold the `PROFILER` lock, or a
+    /// `ProfilerHandle` through `ProfilerHandle::with`.
+    ///
+    /// # Failures
+    ///
+    /// - The frequency is 0 or above `MAX_FREQUENCY`.
+    /// - The backend does not support a chosen setting, or the platform
+    ///   does not, such as wall clock sampling while the program uses
+    ///   `SIGALRM` itself.
+    /// - See `Profiler::start`.
+    pub fn start_on<T: Into<Vec<u8>>>(&self, profiler: &mut Profiler, fname: T) -> Result<(), Error> {
+        if self.dry_run {
+            return self.validate_on(profiler, fname).into_result();
+        }
+        if profiler.taken {
+            return Err(Error::Busy);
+        }
+        if profiler.state != ProfilerState::NotActive {
+            return Err(Error::InvalidState(profiler.state));
+        }
+        if let Some(hz) = self.frequency {
+            check_frequency(hz)?;
+        }
+
+        if let Some(timer) = self.timer {
+            profiler.backend.set_timer(timer)?;
+        }
+        if let Some(hz) = self.frequency {
+            profiler.backend.set_frequency(hz)?;
+        }
+        if let Some(enabled) = self.per_thread_timers {
+            profiler.backend.set_per_thread_timers(enabled)?;
+        }
+        if !self.raw_options.is_empty() {
+            self.raw_options.check()?;
+            profiler.backend.set_raw_options(&self.raw_options)?;
+        }
+        if let Some(enabled) = self.metadata {
+            profiler.metadata = enabled;
+        }
+        if let Some(ref vars) = self.metadata_env {
+            profiler.metadata_env = vars.clone();
+        }
+        if let Some(enabled) = self.create_dirs {
+            profiler.create_dirs = enabled;
+        }
+        if let Some(ref dirs) = self.fallback_dirs {
+            profiler.fallback_dirs = dirs.clone();
+        }
+        if let Some(frames) = self.skip_frames {
+            profiler.stack_trim = profiler.stack_trim.skip_frames(frames);
+        }
+        if let Some(depth) = self.max_depth {
+            profiler.stack_trim = profiler.stack_trim.max_depth(depth);
+        }
+        profiler.start_labelled(fname, self.labels.clone())
+    }
+}
+
+impl Profiler {
+    /// Returns the sampling frequency of the last profile, in Hz
+    ///
+    /// This is read from the header of the profile when the profiler is
+    /// stopped, so it is the frequency which was actually used. It is `None`
+    /// before the first profile is stopped, or if the profile could not be
+    /// read.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use cpuprofiler::PROFILER;
+    ///
+    /// let path = env::temp_dir().join("frequency-example.profile");
+    /// let mut profiler = PROFILER.lock().unwrap();
+    /// profiler.start(path.to_str().unwrap()).unwrap();
+    /// profiler.stop().unwrap();
+    /// println!("sampled at {:?} Hz", profiler.last_frequency());
+    /// ```
+    pub fn last_frequency(&self) -> Option<u64> {
+        self.last_frequency
+    }
+
+    /// Returns the labels of the running profile, or else of the last one
+    ///
+    /// Labels are attached with `ProfilerBuilder::label`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use cpuprofiler::PROFILER;
+    /// use cpuprofiler::builder::ProfilerBuilder;
+    /// use cpuprofiler::pprof::Encoder;
+    /// use cpuprofiler::profile::Profile;
+    ///
+    /// let path = env::temp_dir().join("label-example.profile");
+    /// let mut profiler = PROFILER.lock().unwrap();
+    /// ProfilerBuilder::new()
+    ///     .label("endpoint", "/search")
+    ///     .start_on(&mut profiler, path.to_str().unwrap())
+    ///     .unwrap();
+    /// // Code you want to sample goes here!
+    /// profiler.stop().unwrap();
+    ///
+    /// let profile = Profile::from_file(&path).unwrap();
+    /// let bytes = Encoder::new(&profile).labels(profiler.labels()).encode();
+    /// assert!(!bytes.is_empty());
+    /// ```
+    pub fn labels(&self) -> &[(String, String)] {
+        &self.labels
+    }
+
+    /// Register the calling thread with the cpuprofiler library
+    ///
+    /// With per thread timers only registered threads are sampled, so call
+    /// this at the start of every thread which should be profiled. The
+    /// thread's name, if it has one, also names its samples in
+    /// `Profile::threads`. The cpuprofiler library is not told when it is
+    /// not linked.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::thread;
+    /// use cpuprofiler::Profiler;
+    ///
+    /// let worker = thread::spawn(|| {
+    ///     Profiler::register_thread();
+    ///     // Work you want to sample goes here!
+    /// });
+    /// worker.join().unwrap();
+    /// ```
+    pub fn register_thread() {
+        if let Some(name) = thread::current().name() {
+            threads::set_name(threads::current(), name.to_owned());
+        }
+        #[cfg(feature = "gperftools")]
+        unsafe {
+            if ffi::is_available() {
+                ffi::ProfilerRegisterThread();
+            }
+        }
+    }
+
+    /// Name the calling thread's samples in `Profile::threads`
+    ///
+    /// Threads which are neither named here nor by `register_thread` are
+    /// named by the kernel's name for them, which is truncated to 15 bytes,
+    /// as long as they are still running when the profile stops. The name
+    /// is kept for later profiles.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::thread;
+    /// use cpuprofiler::Profiler;
+    ///
+    /// let io = thread::spawn(|| {
+    ///     Profiler::name_thread("io");
+    ///     // Work you want to sample goes here!
+    /// });
+    /// io.join().unwrap();
+    /// ```
+    pub fn name_thread<S: Into<String>>(name: S) {
+        threads::set_name(threads::current(), name.into());
+    }
+}
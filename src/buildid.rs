@@ -0,0 +1,149 @@
+//! Recording the build ids of the profiled binaries
+//!
+//! A build id identifies the exact build of a binary, so a profile exported
+//! from a stripped production binary can be symbolized elsewhere with the
+//! debug info of the same build, such as from a debuginfod server. When the
+//! profile stops the build id of every mapped binary which has one is
+//! appended after the memory mappings, one line per binary:
+//!
+//! ```text
+//! cpuprofiler-buildid: <build id> <path>
+//! ```
+//!
+//! Build ids are read from the `NT_GNU_BUILD_ID` note of ELF binaries, so
+//! they are only recorded on platforms with `/proc/self/maps`. A binary
+//! which was replaced while the process ran is still read, and recorded
+//! with the path of its mapping, which ends with ` (deleted)`.
+
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use profile::Mapping;
+
+/// The start of every line of the build id section
+pub(crate) const PREFIX: &str = "cpuprofiler-buildid:";
+
+const PT_NOTE: u32 = 4;
+const NT_GNU_BUILD_ID: u32 = 3;
+
+/// The largest note segment read, anything larger is not a build id note
+const MAX_NOTES: u64 = 1 << 20;
+
+/// Returns the build id of the ELF binary at `path` as hex, if it has one
+pub(crate) fn read(path: &Path) -> Option<String> {
+    let mut file = File::open(path).ok()?;
+    let mut header = [0u8; 64];
+    file.read_exact(&mut header[..52]).ok()?;
+    if &header[..4] != b"\x7fELF" {
+        return None;
+    }
+    let wide = match header[4] {
+        1 => false,
+        2 => true,
+        _ => return None,
+    };
+    let big_endian = header[5] == 2;
+    if wide {
+        file.read_exact(&mut header[52..]).ok()?;
+    }
+    let int = |bytes: &[u8]| {
+        let mut value = 0u64;
+        for i in 0..bytes.len() {
+            let b = if big_endian { bytes[i] } else { bytes[bytes.len() - 1 - i] };
+            value = (value << 8) | b as u64;
+        }
+        value
+    };
+
+    // e_phoff, e_phentsize and e_phnum
+    let (phoff, phentsize, phnum) = if wide {
+        (int(&header[0x20..0x28]), int(&header[0x36..0x38]), int(&header[0x38..0x3a]))
+    } else {
+        (int(&header[0x1c..0x20]), int(&header[0x2a..0x2c]), int(&header[0x2c..0x2e]))
+    };
+    let mut phdr = vec![0u8; phentsize as usize];
+    for i in 0..phnum {
+        file.seek(SeekFrom::Start(phoff + i * phentsize)).ok()?;
+        file.read_exact(&mut phdr).ok()?;
+        // p_type, p_offset and p_filesz
+        let (kind, offset, size) = if wide && phdr.len() >= 0x28 {
+            (int(&phdr[..4]), int(&phdr[0x08..0x10]), int(&phdr[0x20..0x28]))
+        } else if !wide && phdr.len() >= 0x14 {
+            (int(&phdr[..4]), int(&phdr[0x04..0x08]), int(&phdr[0x10..0x14]))
+        } else {
+            return None;
+        };
+        if kind != PT_NOTE as u64 || size > MAX_NOTES {
+            continue;
+        }
+        let mut notes = vec![0u8; size as usize];
+        file.seek(SeekFrom::Start(offset)).ok()?;
+        file.read_exact(&mut notes).ok()?;
+        if let Some(id) = find_build_id(&notes, &int) {
+            return Some(id);
+        }
+    }
+    None
+}
+
+/// Returns the build id among `notes`, the contents of a note segment
+fn find_build_id(notes: &[u8], int: &dyn Fn(&[u8]) -> u64) -> Option<String> {
+    let align = |n: usize| (n + 3) & !3;
+    let mut pos = 0;
+    while pos + 12 <= notes.len() {
+        let name_size = int(&notes[pos..pos + 4]) as usize;
+        let desc_size = int(&notes[pos + 4..pos + 8]) as usize;
+        let kind = int(&notes[pos + 8..pos + 12]) as u32;
+        let name = pos + 12;
+        let desc = name.checked_add(align(name_size))?;
+        let end = desc.checked_add(align(desc_size))?;
+        if desc + desc_size > notes.len() {
+            return None;
+        }
+        if kind == NT_GNU_BUILD_ID && &notes[name..name + name_size] == b"GNU\0" {
+            let id = &notes[desc..desc + desc_size];
+            return Some(id.iter().map(|b| format!("{:02x}", b)).collect());
+        }
+        pos = end;
+    }
+    None
+}
+
+/// Returns the build id of the binary backing `mapping`, a mapping of this
+/// process
+///
+/// The binary is read through `/proc/self/map_files`, which still reaches
+/// it after it was deleted or replaced, such as by a rolling deploy, and
+/// else from its path.
+pub(crate) fn read_mapped(mapping: &Mapping) -> Option<String> {
+    let mapped = format!("/proc/self/map_files/{:x}-{:x}", mapping.start, mapping.end);
+    read(Path::new(&mapped)).or_else(|| read(Path::new(mapping.path.as_ref()?)))
+}
+
+/// Append the build id section to the profile at `path`
+///
+/// The binaries are those mapped into the process now. Nothing is written
+/// when none of them has a build id.
+pub(crate) fn append(path: &Path) -> io::Result<()> {
+    let maps = fs::read_to_string("/proc/self/maps")?;
+    let mut seen = HashSet::new();
+    let mut section = String::new();
+    for mapping in maps.lines().filter_map(Mapping::parse) {
+        let binary = match mapping.path {
+            Some(ref binary) if mapping.executable && !binary.starts_with('[') => binary.clone(),
+            _ => continue,
+        };
+        if !seen.insert(binary.clone()) {
+            continue;
+        }
+        if let Some(id) = read_mapped(&mapping) {
+            section.push_str(&format!("{} {} {}\n", PREFIX, id, binary));
+        }
+    }
+    if section.is_empty() {
+        return Ok(());
+    }
+    OpenOptions::new().append(true).open(path)?.write_all(section.as_bytes())
+}
@@ -0,0 +1,280 @@
+//! Profiling automatically when the process gets busy
+//!
+//! CPU spikes in production are usually over by the time anyone attaches a
+//! profiler. An `AutoCapture` watches the process's CPU use from a
+//! background thread, and once it has stayed above a threshold for long
+//! enough it takes a profile of bounded length, optionally handing it to a
+//! [`ProfileSink`](../sink/trait.ProfileSink.html). After each capture it
+//! waits out a cooldown before it can capture again, so a process which is
+//! busy for hours is not profiled for hours.
+//!
+//! CPU use is the CPU time of every thread of the process, the same as
+//! `/proc/<pid>/stat` reports on Linux, over the wall clock time, as a
+//! percentage of one core like `top` shows it: a process keeping two cores
+//! busy is at 200%.
+//!
+//! A capture is skipped while the profiler is running or taken, since
+//! something else is profiling already, and when the profiler's
+//! [policy](../policy/index.html) refuses it.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use std::time::Duration;
+//! use cpuprofiler::capture::AutoCapture;
+//!
+//! let capture = AutoCapture::when_cpu_above(80.0, Duration::from_secs(30))
+//!     .profile_for(Duration::from_secs(10))
+//!     .dir(env::temp_dir().join("captures"))
+//!     .start()
+//!     .unwrap();
+//!
+//! // The service runs here!
+//!
+//! for captured in capture.stop() {
+//!     println!("{}", captured);
+//! }
+//! ```
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use error::Error;
+use lifecycle;
+use lock;
+use policy::Trigger;
+use sink::{ProfileSink, SharedSink};
+use timestamp;
+use watchdog;
+
+/// A profile taken by an `AutoCapture`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capture {
+    /// Where the profile was written
+    pub path: PathBuf,
+    /// The CPU use which set off the capture, in percent of one core
+    pub cpu: f64,
+    /// The profile session, see `Transition::session`
+    pub session: u64,
+}
+
+impl fmt::Display for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "captured {} at {:.1}% CPU", self.path.display(), self.cpu)
+    }
+}
+
+/// Configuration for capturing profiles on high CPU use
+#[derive(Clone, Debug)]
+pub struct AutoCapture {
+    threshold: f64,
+    sustained: Duration,
+    interval: Duration,
+    duration: Duration,
+    cooldown: Duration,
+    dir: PathBuf,
+    prefix: String,
+    sink: Option<SharedSink>,
+}
+
+impl AutoCapture {
+    /// Capture once the process has used more than `percent` of one core
+    /// for `sustained`
+    pub fn when_cpu_above(percent: f64, sustained: Duration) -> AutoCapture {
+        AutoCapture {
+            threshold: percent,
+            sustained,
+            interval: Duration::from_secs(1),
+            duration: Duration::from_secs(30),
+            cooldown: Duration::from_secs(600),
+            dir: PathBuf::from("."),
+            prefix: "capture".to_owned(),
+            sink: None,
+        }
+    }
+
+    /// Set how often CPU use is measured
+    ///
+    /// Defaults to every second. Each measurement covers the interval
+    /// before it, so usage must stay above the threshold in every interval
+    /// of the sustained period.
+    pub fn interval(mut self, interval: Duration) -> AutoCapture {
+        self.interval = interval;
+        self
+    }
+
+    /// Set how long each capture profiles for, defaults to 30s
+    pub fn profile_for(mut self, duration: Duration) -> AutoCapture {
+        self.duration = duration;
+        self
+    }
+
+    /// Set how long to wait after a capture before measuring again
+    ///
+    /// Defaults to 10 minutes.
+    pub fn cooldown(mut self, cooldown: Duration) -> AutoCapture {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Write captures into `dir`, defaults to the current directory
+    ///
+    /// The directory is created if it is missing.
+    pub fn dir<D: Into<PathBuf>>(mut self, dir: D) -> AutoCapture {
+        self.dir = dir.into();
+        self
+    }
+
+    /// Name captures `<prefix>-<timestamp>.profile`, defaults to `capture`
+    pub fn prefix<P: Into<String>>(mut self, prefix: P) -> AutoCapture {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Put every capture to `sink`
+    ///
+    /// The local profile is kept. A capture which could not be put is not
+    /// retried, see `AutoCaptureHandle::failed_puts`.
+    pub fn sink<S: ProfileSink + 'static>(mut self, sink: S) -> AutoCapture {
+        self.sink = Some(SharedSink::new(sink));
+        self
+    }
+
+    /// Start watching CPU use on a background thread
+    ///
+    /// # Failures
+    ///
+    /// - The threshold is not a positive percentage, or the interval or the
+    ///   profile length is zero, `Error::Unsupported`.
+    pub fn start(self) -> Result<AutoCaptureHandle, Error> {
+        if self.threshold.is_nan() || self.threshold <= 0.0 {
+            return Err(Error::Unsupported(format!(
+                "a CPU threshold of {}% is not a positive percentage",
+                self.threshold
+            )));
+        }
+        if self.interval == Duration::from_secs(0) || self.duration == Duration::from_secs(0) {
+            return Err(Error::Unsupported("capturing with a zero interval or profile length".to_owned()));
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let captures = Arc::new(Mutex::new(Vec::new()));
+        let failed = Arc::new(AtomicUsize::new(0));
+        let (captured, failed_puts) = (captures.clone(), failed.clone());
+
+        let thread = thread::spawn(move || {
+            let mut last = (Instant::now(), watchdog::process_cpu_time());
+            let mut above_since: Option<Instant> = None;
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(self.interval) {
+                let (then, then_cpu) = last;
+                last = (Instant::now(), watchdog::process_cpu_time());
+                let wall = last.0.duration_since(then).as_secs_f64();
+                let cpu = last.1.saturating_sub(then_cpu).as_secs_f64() / wall.max(1e-9) * 100.0;
+
+                if cpu <= self.threshold {
+                    above_since = None;
+                    continue;
+                }
+                let since = *above_since.get_or_insert(then);
+                if last.0.duration_since(since) < self.sustained {
+                    continue;
+                }
+
+                above_since = None;
+                let (capture, stopping) = match self.capture(cpu, &rx) {
+                    Some(captured) => captured,
+                    // Something else is profiling, measure again.
+                    None => continue,
+                };
+                lifecycle::captured(&capture);
+                if let Some(ref sink) = self.sink {
+                    if sink.put(&capture.path).is_err() {
+                        failed_puts.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+                captured.lock().unwrap_or_else(|e| e.into_inner()).push(capture);
+
+                if stopping || !matches!(rx.recv_timeout(self.cooldown), Err(RecvTimeoutError::Timeout)) {
+                    return;
+                }
+                // The cooldown and the capture do not count towards the
+                // next measurement.
+                last = (Instant::now(), watchdog::process_cpu_time());
+            }
+        });
+
+        Ok(AutoCaptureHandle {
+            stop: tx,
+            thread,
+            captures,
+            failed,
+        })
+    }
+
+    /// Take one profile, stopping early if the handle is stopped
+    ///
+    /// Returns the capture and whether the handle was stopped, or `None`
+    /// when the profiler is in use.
+    fn capture(&self, cpu: f64, rx: &Receiver<()>) -> Option<(Capture, bool)> {
+        let stamp = timestamp::format_utc(SystemTime::now());
+        let path = self.dir.join(format!("{}-{}.profile", self.prefix, stamp));
+        let session = {
+            let mut profiler = lock::try_lock().ok()?;
+            if profiler.taken || profiler.state.is_running() {
+                return None;
+            }
+            let create_dirs = profiler.create_dirs;
+            profiler.create_dirs = true;
+            let started = profiler.start_triggered(Trigger::Capture, |p| p.start_path(&path));
+            profiler.create_dirs = create_dirs;
+            started.ok()?;
+            profiler.session
+        };
+
+        let stopping = !matches!(rx.recv_timeout(self.duration), Err(RecvTimeoutError::Timeout));
+        let mut profiler = lock::lock();
+        if profiler.session == session && profiler.state.is_running() {
+            // A profile which did not stop cleanly still has its samples.
+            let _ = profiler.stop();
+        }
+        Some((Capture { path, cpu, session }, stopping))
+    }
+}
+
+/// A handle to a running `AutoCapture`
+#[derive(Debug)]
+pub struct AutoCaptureHandle {
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+    captures: Arc<Mutex<Vec<Capture>>>,
+    failed: Arc<AtomicUsize>,
+}
+
+impl AutoCaptureHandle {
+    /// Returns the profiles captured so far, oldest first
+    pub fn captures(&self) -> Vec<Capture> {
+        self.captures.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Returns the number of captures which could not be put to the sink
+    /// so far
+    pub fn failed_puts(&self) -> usize {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    /// Stop watching CPU use, ending a capture which is running
+    ///
+    /// Returns the profiles captured, oldest first.
+    pub fn stop(self) -> Vec<Capture> {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+        let captures = self.captures.lock().unwrap_or_else(|e| e.into_inner());
+        captures.clone()
+    }
+}
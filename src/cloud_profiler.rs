@@ -0,0 +1,392 @@
+//! Google Cloud Profiler agent
+//!
+//! Available with the `cloud-profiler` feature.
+//!
+//! [Cloud Profiler](https://cloud.google.com/profiler) decides when each
+//! process is profiled. The agent asks the server for work with a long
+//! polling `CreateProfile` request, profiles the process for as long as the
+//! server asked, and uploads the profile as gzipped pprof with
+//! `UpdateProfile`, the same way the Go and Java agents do. Profiling is done
+//! through the shared `PROFILER`, which is only locked while a profile is
+//! started or stopped.
+//!
+//! Requests are authenticated with the service account of the host, from
+//! the metadata server of Compute Engine, GKE or Cloud Run, unless a token
+//! is given.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use cpuprofiler::cloud_profiler::CloudProfiler;
+//!
+//! let agent = CloudProfiler::new("my-service")
+//!     .version("1.2.0")
+//!     .start()
+//!     .unwrap();
+//!
+//! // The service runs and is profiled whenever Cloud Profiler asks...
+//!
+//! agent.stop().unwrap();
+//! ```
+
+use std::cmp;
+use std::env;
+use std::fmt;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use ureq;
+
+use agent::base64;
+use error::Error;
+use gce;
+use json;
+use lock;
+use pprof::Encoder;
+use profile::Profile;
+
+const API_URL: &str = "https://cloudprofiler.googleapis.com/v2/";
+
+/// How long the server may hold a `CreateProfile` request open
+const POLL_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// The first delay after a failed request, doubled on each further failure
+const INITIAL_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The longest delay between failed requests
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// Configuration for a Cloud Profiler agent
+#[derive(Clone)]
+pub struct CloudProfiler {
+    service: String,
+    version: Option<String>,
+    project: Option<String>,
+    zone: Option<String>,
+    token: Option<String>,
+    labels: Vec<(String, String)>,
+    api: String,
+    scratch: PathBuf,
+}
+
+impl CloudProfiler {
+    /// Profile the process as a deployment of `service`
+    pub fn new<S: Into<String>>(service: S) -> CloudProfiler {
+        let scratch = env::temp_dir().join(format!("cpuprofiler-cloud-{}.profile", process::id()));
+        CloudProfiler {
+            service: service.into(),
+            version: None,
+            project: None,
+            zone: None,
+            token: None,
+            labels: Vec::new(),
+            api: API_URL.to_owned(),
+            scratch,
+        }
+    }
+
+    /// Set the version of the service, so profiles of versions can be compared
+    pub fn version<V: Into<String>>(mut self, version: V) -> CloudProfiler {
+        self.version = Some(version.into());
+        self
+    }
+
+    /// Set the Google Cloud project profiles belong to
+    ///
+    /// Defaults to the `GOOGLE_CLOUD_PROJECT` environment variable, or else
+    /// the project of the host.
+    pub fn project<P: Into<String>>(mut self, project: P) -> CloudProfiler {
+        self.project = Some(project.into());
+        self
+    }
+
+    /// Set the zone the service runs in, defaults to the zone of the host
+    pub fn zone<Z: Into<String>>(mut self, zone: Z) -> CloudProfiler {
+        self.zone = Some(zone.into());
+        self
+    }
+
+    /// Authenticate with the OAuth 2 access token `token`
+    ///
+    /// Tokens expire, so this is meant for short lived processes and tests.
+    pub fn token<T: Into<String>>(mut self, token: T) -> CloudProfiler {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Attach a label to the deployment
+    ///
+    /// Label names must be lowercase letters, digits and dashes.
+    pub fn label<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> CloudProfiler {
+        self.labels.push((key.into(), value.into()));
+        self
+    }
+
+    /// Send requests to `url` instead of the Cloud Profiler API
+    pub fn api_url<U: Into<String>>(mut self, url: U) -> CloudProfiler {
+        self.api = url.into();
+        if !self.api.ends_with('/') {
+            self.api.push('/');
+        }
+        self
+    }
+
+    /// Set the file each profile is written to before upload
+    ///
+    /// Defaults to a file in the system temporary directory.
+    pub fn scratch_file<P: Into<PathBuf>>(mut self, path: P) -> CloudProfiler {
+        self.scratch = path.into();
+        self
+    }
+
+    /// Start waiting for the server on a background thread
+    ///
+    /// # Failures
+    ///
+    /// - No project was set and the host is not on Google Cloud,
+    ///   `Error::Unsupported`.
+    pub fn start(mut self) -> Result<CloudProfilerHandle, Error> {
+        if self.project.is_none() {
+            self.project = env::var("GOOGLE_CLOUD_PROJECT").ok().filter(|p| !p.is_empty());
+        }
+        if self.project.is_none() {
+            let project = gce::get("project/project-id").map_err(|_| {
+                Error::Unsupported("the Google Cloud project is not set and the host has no metadata server".to_owned())
+            })?;
+            self.project = Some(project);
+        }
+        if self.zone.is_none() {
+            // Zones are served as `projects/<number>/zones/<zone>`.
+            self.zone = gce::get("instance/zone")
+                .ok()
+                .and_then(|zone| zone.rsplit('/').next().map(str::to_owned));
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let stopping = Arc::new(AtomicBool::new(false));
+        let polling = Arc::new(AtomicBool::new(false));
+        let failed = Arc::new(AtomicUsize::new(0));
+
+        let thread = {
+            let (stopping, polling, failed) = (stopping.clone(), polling.clone(), failed.clone());
+            thread::spawn(move || self.run(&rx, &stopping, &polling, &failed))
+        };
+
+        Ok(CloudProfilerHandle {
+            stop: tx,
+            stopping,
+            polling,
+            thread,
+            failed,
+        })
+    }
+
+    fn run(
+        &self,
+        rx: &Receiver<()>,
+        stopping: &AtomicBool,
+        polling: &AtomicBool,
+        failed: &AtomicUsize,
+    ) -> Result<(), Error> {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            polling.store(true, Ordering::SeqCst);
+            if stopping.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            let created = self.create_profile();
+            polling.store(false, Ordering::SeqCst);
+            if stopping.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+
+            let (profile, duration) = match created {
+                Ok(created) => created,
+                Err(retry) => {
+                    failed.fetch_add(1, Ordering::SeqCst);
+                    let delay = retry.unwrap_or(backoff);
+                    backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+                    if !matches!(rx.recv_timeout(delay), Err(RecvTimeoutError::Timeout)) {
+                        return Ok(());
+                    }
+                    continue;
+                }
+            };
+            backoff = INITIAL_BACKOFF;
+
+            let started = SystemTime::now();
+            lock::lock().start_path(&self.scratch)?;
+            let stopped_early = !matches!(rx.recv_timeout(duration), Err(RecvTimeoutError::Timeout));
+            lock::lock().stop()?;
+
+            if self.update_profile(&profile, started).is_err() {
+                failed.fetch_add(1, Ordering::SeqCst);
+            }
+            if stopped_early {
+                return Ok(());
+            }
+        }
+    }
+
+    fn authorization(&self) -> Result<String, Error> {
+        let token = match self.token {
+            Some(ref token) => token.clone(),
+            None => gce::access_token()?,
+        };
+        Ok(format!("Bearer {}", token))
+    }
+
+    fn deployment(&self) -> String {
+        let mut labels = Vec::new();
+        if let Some(ref version) = self.version {
+            labels.push(("version", &**version));
+        }
+        if let Some(ref zone) = self.zone {
+            labels.push(("zone", &**zone));
+        }
+        labels.extend(self.labels.iter().map(|(k, v)| (&**k, &**v)));
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|&(k, v)| format!("{}:{}", json::string(k), json::string(v)))
+            .collect();
+
+        format!(
+            "{{\"projectId\":{},\"target\":{},\"labels\":{{{}}}}}",
+            json::string(self.project.as_deref().unwrap_or("")),
+            json::string(&self.service),
+            labels.join(",")
+        )
+    }
+
+    /// Wait for the server to ask for a profile
+    ///
+    /// Returns the profile to fill in and how long to profile for, or on
+    /// failure how long the server asked us to wait before trying again.
+    fn create_profile(&self) -> Result<(String, Duration), Option<Duration>> {
+        let url = format!(
+            "{}projects/{}/profiles",
+            self.api,
+            self.project.as_deref().unwrap_or("")
+        );
+        let body = format!("{{\"deployment\":{},\"profileType\":[\"CPU\"]}}", self.deployment());
+
+        let authorization = self.authorization().map_err(|_| None)?;
+        let response = ureq::post(&url)
+            .set("Authorization", &authorization)
+            .set("Content-Type", "application/json")
+            .timeout(POLL_TIMEOUT)
+            .send_string(&body);
+
+        match response {
+            Ok(response) => {
+                let profile = response.into_string().map_err(|_| None)?;
+                let duration = gce::json_field(&profile, "duration")
+                    .and_then(|d| parse_duration(&d))
+                    .unwrap_or_else(|| Duration::from_secs(10));
+                Ok((profile, duration))
+            }
+            // The server is busy and says when to come back.
+            Err(ureq::Error::Status(_, response)) => Err(response
+                .into_string()
+                .ok()
+                .and_then(|body| gce::json_field(&body, "retryDelay"))
+                .and_then(|d| parse_duration(&d))),
+            Err(_) => Err(None),
+        }
+    }
+
+    /// Upload the profile which the server asked for with `profile`
+    fn update_profile(&self, profile: &str, started: SystemTime) -> Result<(), Error> {
+        let name = gce::json_field(profile, "name")
+            .ok_or_else(|| Error::Upload("the server sent a profile without a name".to_owned()))?;
+
+        let samples = Profile::from_file(&self.scratch)?;
+        let duration = started.elapsed().unwrap_or_default();
+        let encoded = Encoder::new(&samples).time(started).duration(duration).encode();
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&encoded)?;
+        let bytes = gzip.finish()?;
+
+        // The profile is sent back as received, with the bytes filled in.
+        let body = match profile.trim_start().strip_prefix('{') {
+            Some(rest) => format!("{{\"profileBytes\":\"{}\",{}", base64(&bytes), rest),
+            None => return Err(Error::Upload("the server sent a profile which is not an object".to_owned())),
+        };
+        ureq::patch(&format!("{}{}", self.api, name))
+            .set("Authorization", &self.authorization()?)
+            .set("Content-Type", "application/json")
+            .send_string(&body)
+            .map(|_| ())
+            .map_err(|e| Error::Upload(e.to_string()))
+    }
+}
+
+impl fmt::Debug for CloudProfiler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CloudProfiler")
+            .field("service", &self.service)
+            .field("version", &self.version)
+            .field("project", &self.project)
+            .field("zone", &self.zone)
+            .field("labels", &self.labels)
+            .field("api", &self.api)
+            .field("scratch", &self.scratch)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Parse a protobuf JSON duration, such as `10s` or `1.5s`
+fn parse_duration(s: &str) -> Option<Duration> {
+    let secs: f64 = s.strip_suffix('s')?.parse().ok()?;
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+/// A handle to a running Cloud Profiler agent
+#[derive(Debug)]
+pub struct CloudProfilerHandle {
+    stop: Sender<()>,
+    stopping: Arc<AtomicBool>,
+    polling: Arc<AtomicBool>,
+    thread: JoinHandle<Result<(), Error>>,
+    failed: Arc<AtomicUsize>,
+}
+
+impl CloudProfilerHandle {
+    /// Returns the number of requests to the server which failed so far
+    ///
+    /// Failures do not stop the agent, it backs off and asks again.
+    pub fn failed_requests(&self) -> usize {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    /// Stop the agent, uploading the current profile if one is running
+    ///
+    /// While the agent is waiting for the server this returns straight away,
+    /// and the background thread exits without profiling once the server
+    /// answers.
+    ///
+    /// # Failures
+    ///
+    /// - The agent stopped early because the profiler could not be
+    ///   started or stopped, for example because it was used elsewhere.
+    pub fn stop(self) -> Result<(), Error> {
+        self.stopping.store(true, Ordering::SeqCst);
+        // The thread may have already exited with an error.
+        let _ = self.stop.send(());
+        if self.polling.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        match self.thread.join() {
+            Ok(res) => res,
+            Err(_) => Err(Error::Internal),
+        }
+    }
+}
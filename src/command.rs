@@ -0,0 +1,258 @@
+//! Profiling child processes
+//!
+//! A `ProfiledCommand` runs a program with the environment variables which
+//! make libprofiler profile it from start to exit, then checks that the
+//! profile was written and parses it. Programs which link libprofiler, such
+//! as Rust programs using this crate, are profiled as they are; any other
+//! program needs libprofiler preloaded, see `ProfiledCommand::preload`.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use cpuprofiler::command::ProfiledCommand;
+//!
+//! let profiled = ProfiledCommand::new("./target/release/my-benchmark", "./bench.profile")
+//!     .arg("--quick")
+//!     .frequency(1000)
+//!     .preload(true)
+//!     .status()
+//!     .unwrap();
+//! assert!(profiled.status.success());
+//! println!("{} samples", profiled.profile.total_samples());
+//! ```
+
+use std::env;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus};
+
+use libc;
+
+use builder::TimerKind;
+use error::Error;
+use profile::Profile;
+
+/// Names libprofiler may be preloaded by, in order
+const LIBRARIES: &[&str] = &[
+    "libprofiler.so.0",
+    "libprofiler.so",
+    "libprofiler.0.dylib",
+    "libprofiler.dylib",
+    // dlopen on macOS does not search the prefixes of Homebrew on Apple
+    // silicon or of MacPorts.
+    "/opt/homebrew/lib/libprofiler.dylib",
+    "/opt/local/lib/libprofiler.dylib",
+];
+
+/// The variable the dynamic linker preloads libraries from
+#[cfg(target_os = "macos")]
+const PRELOAD_VAR: &str = "DYLD_INSERT_LIBRARIES";
+#[cfg(not(target_os = "macos"))]
+const PRELOAD_VAR: &str = "LD_PRELOAD";
+
+/// Returns the name of a libprofiler the dynamic linker can load
+///
+/// # Examples
+///
+/// ```
+/// match cpuprofiler::command::find_library() {
+///     Some(name) => println!("children can preload {}", name),
+///     None => println!("libprofiler is not installed"),
+/// }
+/// ```
+pub fn find_library() -> Option<&'static str> {
+    LIBRARIES.iter().cloned().find(|name| {
+        let c_name = CString::new(*name).unwrap();
+        unsafe {
+            let handle = libc::dlopen(c_name.as_ptr(), libc::RTLD_LAZY | libc::RTLD_LOCAL);
+            if handle.is_null() {
+                false
+            } else {
+                libc::dlclose(handle);
+                true
+            }
+        }
+    })
+}
+
+/// Which libprofiler is preloaded into the child
+#[derive(Debug)]
+enum Preload {
+    Find,
+    Library(OsString),
+}
+
+/// A child process which is profiled by libprofiler
+#[derive(Debug)]
+pub struct ProfiledCommand {
+    command: Command,
+    output: PathBuf,
+    frequency: Option<u32>,
+    timer: Option<TimerKind>,
+    signal: Option<libc::c_int>,
+    preload: Option<Preload>,
+}
+
+/// A profiled child process which has exited
+#[derive(Debug)]
+pub struct Profiled {
+    /// How the child exited
+    pub status: ExitStatus,
+    /// Where the profile was written
+    pub path: PathBuf,
+    /// The profile
+    pub profile: Profile,
+}
+
+impl ProfiledCommand {
+    /// Profile `program` into `output`
+    pub fn new<S: AsRef<OsStr>, P: Into<PathBuf>>(program: S, output: P) -> ProfiledCommand {
+        ProfiledCommand::from_command(Command::new(program), output)
+    }
+
+    /// Profile an already configured `command` into `output`
+    pub fn from_command<P: Into<PathBuf>>(command: Command, output: P) -> ProfiledCommand {
+        ProfiledCommand {
+            command,
+            output: output.into(),
+            frequency: None,
+            timer: None,
+            signal: None,
+            preload: None,
+        }
+    }
+
+    /// Pass `arg` to the program
+    pub fn arg<S: AsRef<OsStr>>(mut self, arg: S) -> ProfiledCommand {
+        self.command.arg(arg);
+        self
+    }
+
+    /// Pass `args` to the program
+    pub fn args<I, S>(mut self, args: I) -> ProfiledCommand
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.command.args(args);
+        self
+    }
+
+    /// Returns the command, to set anything else about the child
+    pub fn command_mut(&mut self) -> &mut Command {
+        &mut self.command
+    }
+
+    /// Sample `hz` times a second, through `CPUPROFILE_FREQUENCY`
+    pub fn frequency(mut self, hz: u32) -> ProfiledCommand {
+        self.frequency = Some(hz);
+        self
+    }
+
+    /// Sample with `timer`, through `CPUPROFILE_REALTIME`
+    pub fn timer(mut self, timer: TimerKind) -> ProfiledCommand {
+        self.timer = Some(timer);
+        self
+    }
+
+    /// Only profile between deliveries of `signal`, through `CPUPROFILESIGNAL`
+    ///
+    /// The child starts unprofiled, the first `signal` starts the profile
+    /// and the next stops it, so `status` fails unless the child was sent
+    /// the signal.
+    pub fn toggle_signal(mut self, signal: libc::c_int) -> ProfiledCommand {
+        self.signal = Some(signal);
+        self
+    }
+
+    /// Preload libprofiler into the child, for programs which do not link it
+    ///
+    /// The library is found with `find_library` when the child is started.
+    /// Preloading does not work for statically linked programs.
+    pub fn preload(mut self, enabled: bool) -> ProfiledCommand {
+        self.preload = if enabled { Some(Preload::Find) } else { None };
+        self
+    }
+
+    /// Preload the libprofiler at `library` into the child
+    pub fn preload_library<S: Into<OsString>>(mut self, library: S) -> ProfiledCommand {
+        self.preload = Some(Preload::Library(library.into()));
+        self
+    }
+
+    /// Run the child to completion and read its profile
+    ///
+    /// Any old file at the output path is removed first, so the profile
+    /// returned is always the child's.
+    ///
+    /// # Failures
+    ///
+    /// - The old profile could not be removed, `Error::OutputPath`.
+    /// - No libprofiler was found to preload, `Error::LibraryUnavailable`.
+    /// - The child could not be started, `Error::Io`.
+    /// - The child did not write a profile, because it neither linked nor
+    ///   preloaded libprofiler or exited without running `atexit`
+    ///   handlers, `Error::InvalidProfile`.
+    /// - The profile could not be parsed, see `Profile::from_file`.
+    pub fn status(&mut self) -> Result<Profiled, Error> {
+        match fs::remove_file(&self.output) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(source) => {
+                return Err(Error::OutputPath {
+                    path: self.output.clone(),
+                    source,
+                })
+            }
+            Ok(()) => {}
+        }
+
+        self.command.env("CPUPROFILE", &self.output);
+        if let Some(hz) = self.frequency {
+            self.command.env("CPUPROFILE_FREQUENCY", hz.to_string());
+        }
+        match self.timer {
+            Some(TimerKind::WallClock) => {
+                self.command.env("CPUPROFILE_REALTIME", "1");
+            }
+            Some(TimerKind::CpuTime) => {
+                self.command.env_remove("CPUPROFILE_REALTIME");
+            }
+            None => {}
+        }
+        if let Some(signal) = self.signal {
+            self.command.env("CPUPROFILESIGNAL", signal.to_string());
+        }
+        if let Some(ref preload) = self.preload {
+            let library = match *preload {
+                Preload::Find => OsString::from(find_library().ok_or(Error::LibraryUnavailable)?),
+                Preload::Library(ref library) => library.clone(),
+            };
+            let preload = match env::var_os(PRELOAD_VAR) {
+                Some(ref existing) if !existing.is_empty() => {
+                    let mut preload = library;
+                    preload.push(":");
+                    preload.push(existing);
+                    preload
+                }
+                _ => library,
+            };
+            self.command.env(PRELOAD_VAR, preload);
+        }
+
+        let status = self.command.status()?;
+        if !self.output.exists() {
+            return Err(Error::InvalidProfile(format!(
+                "{:?} did not write a profile, it may not load libprofiler or exit without running atexit handlers",
+                self.command.get_program()
+            )));
+        }
+        let profile = Profile::from_file(&self.output)?;
+        Ok(Profiled {
+            status,
+            path: self.output.clone(),
+            profile,
+        })
+    }
+}
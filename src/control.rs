@@ -0,0 +1,263 @@
+//! Starting and stopping profiles from outside the process
+//!
+//! A `Controller` listens on a Unix domain socket for commands, one per
+//! line, so an operator can profile a live process with a tool such as
+//! `socat` and without rebuilding it or sending it signals:
+//!
+//! - `start <path>`: start profiling into `path`.
+//! - `start <path> <seconds>`: profile into `path` for `seconds`, see
+//!   `Profiler::start_for`.
+//! - `stop`: stop the profile.
+//! - `status`: describe the profile running, if any.
+//!
+//! Every command is answered with one line, which starts with `ok` or
+//! `error`. Paths are relative to the working directory of the process,
+//! may use the `%p`-style placeholders of `Profiler::start` and may not
+//! contain white space. A `start` the profiler's
+//! [policy](../policy/index.html) refuses is answered with an error.
+//!
+//! ```text
+//! $ echo "start /tmp/svc.profile 30" | socat - UNIX-CONNECT:/run/svc/profiler.sock
+//! ok started /tmp/svc.profile for 30s
+//! ```
+//!
+//! The socket is only accessible to the user running the process.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use std::io::{BufRead, BufReader, Write};
+//! use std::os::unix::net::UnixStream;
+//! use cpuprofiler::control::Controller;
+//!
+//! let socket = env::temp_dir().join("control-example.sock");
+//! let control = Controller::new(socket.clone()).start().unwrap();
+//!
+//! let mut client = UnixStream::connect(&socket).unwrap();
+//! let mut answers = BufReader::new(client.try_clone().unwrap());
+//! let mut ask = |command: &str| {
+//!     client.write_all(command.as_bytes()).unwrap();
+//!     let mut answer = String::new();
+//!     answers.read_line(&mut answer).unwrap();
+//!     answer
+//! };
+//!
+//! let profile = env::temp_dir().join("control-example.profile");
+//! assert!(ask(&format!("start {} 30\n", profile.display())).starts_with("ok"));
+//! // The timed profile runs on after its command is answered.
+//! assert!(ask("status\n").starts_with("ok Active"));
+//! assert!(ask("stop\n").starts_with("ok"));
+//!
+//! control.stop().unwrap();
+//! ```
+
+use std::fs::{self, DirBuilder, Permissions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use error::Error;
+use lock;
+use policy::Trigger;
+use ProfilerState;
+
+/// Configuration for a control socket
+#[derive(Clone, Debug)]
+pub struct Controller {
+    path: PathBuf,
+}
+
+impl Controller {
+    /// Listen for commands on a socket at `path`
+    pub fn new<P: Into<PathBuf>>(path: P) -> Controller {
+        Controller { path: path.into() }
+    }
+
+    /// Start listening on a background thread
+    ///
+    /// A socket left at the path by a process which exited is replaced.
+    /// Connections are served on threads of their own.
+    ///
+    /// # Failures
+    ///
+    /// - Another process is listening on the path, `Error::Busy`.
+    /// - The socket could not be created, `Error::Io`.
+    pub fn start(self) -> Result<ControlHandle, Error> {
+        if let Ok(metadata) = fs::symlink_metadata(&self.path) {
+            if metadata.file_type().is_socket() {
+                if UnixStream::connect(&self.path).is_ok() {
+                    return Err(Error::Busy);
+                }
+                fs::remove_file(&self.path)?;
+            }
+        }
+        let listener = bind_private(&self.path)?;
+
+        let stopping = Arc::new(AtomicBool::new(false));
+        let stopped = stopping.clone();
+        let thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    thread::spawn(move || serve(stream));
+                }
+            }
+        });
+
+        Ok(ControlHandle {
+            path: self.path,
+            stopping,
+            thread,
+        })
+    }
+}
+
+/// Bind a socket at `path` which only the user running the process can
+/// connect to
+///
+/// The socket is bound in a directory only the user can enter, and moved to
+/// `path` once other users are refused, so that none can connect before.
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    // Binding refuses to replace a file, which renaming would not.
+    if fs::symlink_metadata(path).is_ok() {
+        return Err(io::Error::from(io::ErrorKind::AddrInUse));
+    }
+    let parent = path.parent().unwrap_or_else(|| Path::new(""));
+    let private = parent.join(format!(".cpuprofiler-{}", process::id()));
+    let _ = fs::remove_dir_all(&private);
+    DirBuilder::new().mode(0o700).create(&private)?;
+
+    let bound = private.join("sock");
+    let res = UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, Permissions::from_mode(0o600))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&private);
+    res
+}
+
+/// A handle to a listening control socket
+#[derive(Debug)]
+pub struct ControlHandle {
+    path: PathBuf,
+    stopping: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl ControlHandle {
+    /// Returns the path of the socket
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Stop listening and remove the socket
+    ///
+    /// Connections which are open are served until their clients close
+    /// them. A running profile is left running.
+    ///
+    /// # Failures
+    ///
+    /// - The socket could not be removed, `Error::Io`.
+    pub fn stop(self) -> Result<(), Error> {
+        self.stopping.store(true, Ordering::SeqCst);
+        // Wake the listener up, so it sees that it is stopping.
+        let _ = UnixStream::connect(&self.path);
+        if self.thread.join().is_err() {
+            return Err(Error::Internal);
+        }
+        match fs::remove_file(&self.path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res.map_err(Error::from),
+        }
+    }
+}
+
+/// Answer the commands of one client until it disconnects
+fn serve(stream: UnixStream) {
+    let mut out = match stream.try_clone() {
+        Ok(out) => out,
+        Err(_) => return,
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let answer = match execute(&line) {
+            Ok(done) => format!("ok {}\n", done),
+            Err(reason) => format!("error {}\n", reason),
+        };
+        if out.write_all(answer.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+/// Run one command, returning what was done or why it failed
+fn execute(line: &str) -> Result<String, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words[..] {
+        ["start", path] => {
+            let mut profiler = lock::lock();
+            profiler
+                .start_triggered(Trigger::Socket, |p| p.start(path))
+                .map_err(|e| e.to_string())?;
+            Ok(format!("started {}", display_path(profiler.path.as_deref())))
+        }
+        ["start", path, seconds] => {
+            let duration = seconds
+                .parse()
+                .ok()
+                .and_then(|s: f64| Duration::try_from_secs_f64(s).ok())
+                .filter(|d| !d.is_zero())
+                .ok_or_else(|| format!("{} is not a number of seconds", seconds))?;
+            let mut profiler = lock::lock();
+            // The profile stops by itself, whether or not the handle is kept.
+            profiler
+                .start_triggered(Trigger::Socket, |p| p.start_for(path, duration))
+                .map_err(|e| e.to_string())?;
+            Ok(format!("started {} for {}s", display_path(profiler.path.as_deref()), seconds))
+        }
+        ["stop"] => {
+            let mut profiler = lock::lock();
+            let path = profiler.path.clone();
+            profiler.stop().map_err(|e| e.to_string())?;
+            Ok(format!("stopped {}", display_path(path.as_deref())))
+        }
+        ["status"] => {
+            let profiler = lock::lock();
+            if profiler.state == ProfilerState::NotActive {
+                return Ok(profiler.state.to_string());
+            }
+            let elapsed = profiler.started.map(|started| started.elapsed()).unwrap_or_default();
+            Ok(format!(
+                "{} {} {} samples {:.1}s",
+                profiler.state,
+                display_path(profiler.path.as_deref()),
+                profiler.backend.samples_gathered(),
+                elapsed.as_secs_f64()
+            ))
+        }
+        _ => Err(format!(
+            "unknown command {:?}, expected start <path> [<seconds>], stop or status",
+            line.trim()
+        )),
+    }
+}
+
+fn display_path(path: Option<&Path>) -> String {
+    path.map(|path| path.display().to_string()).unwrap_or_default()
+}
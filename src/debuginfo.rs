@@ -0,0 +1,149 @@
+//! Finding the debug info of stripped binaries
+//!
+//! Release binaries are usually stripped, with their debug info shipped
+//! separately, such as in `-debuginfo` or `-dbg` packages which install it
+//! under `/usr/lib/debug`. The debug info of a binary is looked for, in
+//! order:
+//!
+//! - in the binary itself, or the `.dwp` package next to it;
+//! - under each debug directory, by build id, as
+//!   `<dir>/.build-id/<xx>/<rest of the id>.debug`;
+//! - by the name in the binary's `.gnu_debuglink` section, next to the
+//!   binary, in a `.debug` directory next to it, or under each debug
+//!   directory at the binary's own path;
+//! - with the `debuginfod` feature, from the debuginfod servers, which are
+//!   cached like elfutils caches them.
+
+#[cfg(feature = "debuginfod")]
+use std::env;
+use std::ffi::OsStr;
+#[cfg(feature = "debuginfod")]
+use std::fs::{self, File};
+#[cfg(feature = "debuginfod")]
+use std::io;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "debuginfod")]
+use std::time::Duration;
+
+use object::Object;
+#[cfg(feature = "debuginfod")]
+use ureq;
+
+/// Where the debug info of binaries is looked for
+#[derive(Clone, Debug)]
+pub(crate) struct DebugInfo {
+    pub(crate) dirs: Vec<PathBuf>,
+    #[cfg(feature = "debuginfod")]
+    pub(crate) urls: Vec<String>,
+    #[cfg(feature = "debuginfod")]
+    pub(crate) cache: Option<PathBuf>,
+}
+
+impl DebugInfo {
+    /// Look in `/usr/lib/debug`, and on the servers of `DEBUGINFOD_URLS`
+    pub(crate) fn from_env() -> DebugInfo {
+        DebugInfo {
+            dirs: vec![PathBuf::from("/usr/lib/debug")],
+            #[cfg(feature = "debuginfod")]
+            urls: parse_urls(&env::var("DEBUGINFOD_URLS").unwrap_or_default()),
+            #[cfg(feature = "debuginfod")]
+            cache: default_cache(),
+        }
+    }
+
+    /// Returns the file with the debug info of `binary`, when it is not in
+    /// the binary itself
+    pub(crate) fn locate(&self, binary: &Path, object: &object::File) -> Option<PathBuf> {
+        if object.section_by_name(".debug_info").is_some() {
+            return None;
+        }
+        let build_id = object.build_id().ok().and_then(|id| id).map(hex);
+
+        if let Some(ref id) = build_id {
+            if id.len() > 2 {
+                let found = self
+                    .dirs
+                    .iter()
+                    .map(|dir| dir.join(".build-id").join(&id[..2]).join(format!("{}.debug", &id[2..])))
+                    .find(|path| path.is_file());
+                if found.is_some() {
+                    return found;
+                }
+            }
+        }
+
+        if let Ok(Some((name, _crc))) = object.gnu_debuglink() {
+            let name = Path::new(OsStr::new(&*String::from_utf8_lossy(name))).to_path_buf();
+            let parent = binary.parent().unwrap_or_else(|| Path::new("/"));
+            let mut candidates = vec![parent.join(&name), parent.join(".debug").join(&name)];
+            for dir in &self.dirs {
+                candidates.push(dir.join(parent.strip_prefix("/").unwrap_or(parent)).join(&name));
+            }
+            // The debug link may name the binary itself.
+            let found = candidates.into_iter().find(|path| path.is_file() && path != binary);
+            if found.is_some() {
+                return found;
+            }
+        }
+
+        self.fetch(build_id.as_deref()?)
+    }
+
+    /// Download the debug info of the build `id`, or find it in the cache
+    #[cfg(feature = "debuginfod")]
+    fn fetch(&self, id: &str) -> Option<PathBuf> {
+        let cache = self.cache.as_ref()?;
+        if self.urls.is_empty() {
+            return None;
+        }
+        let path = cache.join(id).join("debuginfo");
+        if path.is_file() {
+            return Some(path);
+        }
+        fs::create_dir_all(path.parent()?).ok()?;
+        let partial = path.with_extension("partial");
+        for url in &self.urls {
+            let response = match ureq::get(&format!("{}/buildid/{}/debuginfo", url, id))
+                .timeout(Duration::from_secs(60))
+                .call()
+            {
+                Ok(response) => response,
+                Err(_) => continue,
+            };
+            let downloaded = File::create(&partial).and_then(|mut file| io::copy(&mut response.into_reader(), &mut file));
+            if downloaded.is_ok() && fs::rename(&partial, &path).is_ok() {
+                return Some(path);
+            }
+            let _ = fs::remove_file(&partial);
+        }
+        None
+    }
+
+    #[cfg(not(feature = "debuginfod"))]
+    fn fetch(&self, _id: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Returns the servers of a space separated list of URLs
+#[cfg(feature = "debuginfod")]
+pub(crate) fn parse_urls(urls: &str) -> Vec<String> {
+    urls.split_whitespace().map(|url| url.trim_end_matches('/').to_owned()).collect()
+}
+
+/// Returns the cache of `DEBUGINFOD_CACHE_PATH`, or else of the elfutils
+/// client
+#[cfg(feature = "debuginfod")]
+fn default_cache() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("DEBUGINFOD_CACHE_PATH") {
+        return Some(PathBuf::from(path));
+    }
+    if let Some(cache) = env::var_os("XDG_CACHE_HOME") {
+        return Some(PathBuf::from(cache).join("debuginfod_client"));
+    }
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache").join("debuginfod_client"))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
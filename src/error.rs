@@ -1,25 +1,130 @@
-//! Error handling for the cpuprofiler thanks to error_chain!
+//! Error handling for the cpuprofiler
+//!
+//! Every fallible function returns the same `Error` enum, which is `Send`,
+//! `Sync` and `'static` so that it can be boxed into other error types or
+//! passed between tasks. Errors caused by another error, such as a failed
+//! system call, return it from `source`.
+//!
+//! # Examples
+//!
+//! ```
+//! use cpuprofiler::PROFILER;
+//! use cpuprofiler::error::Error;
+//!
+//! match PROFILER.lock().unwrap().start("./missing-dir/example.profile") {
+//!     Err(Error::OutputPath { path, source }) => {
+//!         println!("can't profile into {}: {}", path.display(), source)
+//!     }
+//!     Err(e) => panic!("{}", e),
+//!     Ok(()) => PROFILER.lock().unwrap().stop().unwrap(),
+//! }
+//! ```
 
-use ProfilerState;
+use std::error;
+use std::ffi::NulError;
+use std::fmt;
 use std::io;
-use std::ffi;
-use std::str;
-
-error_chain! {
-    foreign_links {
-        Io(io::Error);
-        Nul(ffi::NulError);
-        Utf8(str::Utf8Error);
-    }
+use std::path::PathBuf;
+use std::str::Utf8Error;
 
-    errors {
-        InternalError {
-            description("Internal library error!")
-            display("Internal library error!")
+use ProfilerState;
+
+/// An error from the profiler
+#[derive(Debug)]
+pub enum Error {
+    /// The cpuprofiler library refused to start profiling
+    StartRejected,
+    /// The cpuprofiler library could not be loaded
+    LibraryUnavailable,
+    /// The profile cannot be written to `path`
+    OutputPath {
+        /// The path of the profile
+        path: PathBuf,
+        /// Why it cannot be written
+        source: io::Error,
+    },
+    /// The operation is invalid for the profiler's state
+    InvalidState(ProfilerState),
+    /// The profiler is in use by another thread, or by a `ProfilerHandle`
+    Busy,
+    /// The profiler does not support a setting
+    Unsupported(String),
+    /// The platform does not deliver the samples of a profile, such as a
+    /// macOS on which the profiling timer's signal never arrives
+    PlatformUnsupported(String),
+    /// Profile data could not be parsed
+    InvalidProfile(String),
+    /// A pattern is not a regular expression the profiler understands
+    InvalidPattern(String),
+    /// A profile could not be uploaded
+    Upload(String),
+    /// The configuration of the [agent](../agent/index.html) could not be
+    /// read
+    InvalidConfig(String),
+    /// The profiler's policy refused to let a trigger start a profile, see
+    /// the [`policy`](../policy/index.html) module
+    Refused(String),
+    /// A failure inside the profiler, such as a background thread panicking
+    Internal,
+    /// An I/O error
+    Io(io::Error),
+    /// A string passed to the cpuprofiler library contains a nul byte
+    Nul(NulError),
+    /// Data which should be Utf8 is not
+    Utf8(Utf8Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::StartRejected => write!(f, "The cpuprofiler library refused to start profiling"),
+            Error::LibraryUnavailable => write!(f, "The cpuprofiler library could not be loaded"),
+            Error::OutputPath { ref path, ref source } => {
+                write!(f, "Cannot write the profile to {}: {}", path.display(), source)
+            }
+            Error::InvalidState(state) => write!(f, "Operation is invalid for profiler state: {}", state),
+            Error::Busy => write!(f, "The profiler is in use elsewhere"),
+            Error::Unsupported(ref reason) => write!(f, "The profiler does not support this setting: {}", reason),
+            Error::PlatformUnsupported(ref reason) => write!(f, "The platform cannot be profiled: {}", reason),
+            Error::InvalidProfile(ref reason) => write!(f, "Invalid profile data: {}", reason),
+            Error::InvalidPattern(ref reason) => write!(f, "Invalid pattern: {}", reason),
+            Error::Upload(ref reason) => write!(f, "Failed to upload profile: {}", reason),
+            Error::InvalidConfig(ref reason) => write!(f, "Invalid agent configuration: {}", reason),
+            Error::Refused(ref reason) => write!(f, "The profiling policy refused to start a profile: {}", reason),
+            Error::Internal => write!(f, "Internal profiler error"),
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::Nul(ref e) => write!(f, "{}", e),
+            Error::Utf8(ref e) => write!(f, "{}", e),
         }
-        InvalidState(state: ProfilerState) {
-            description("Operation is invalid for profiler state")
-            display("Operation is invalid for profiler state: {}", state)
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::OutputPath { ref source, .. } => Some(source),
+            Error::Io(ref e) => Some(e),
+            Error::Nul(ref e) => Some(e),
+            Error::Utf8(ref e) => Some(e),
+            _ => None,
         }
     }
 }
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<NulError> for Error {
+    fn from(e: NulError) -> Error {
+        Error::Nul(e)
+    }
+}
+
+impl From<Utf8Error> for Error {
+    fn from(e: Utf8Error) -> Error {
+        Error::Utf8(e)
+    }
+}
@@ -0,0 +1,174 @@
+//! Stopping the profiler when the process exits
+//!
+//! A profile is only complete once the profiler has been stopped, so a
+//! process which exits while profiling leaves a truncated file behind.
+//! `Profiler::stop_at_exit` and `Profiler::stop_on_signals` make sure the
+//! profiler is stopped first.
+//!
+//! Like the signal toggle, the signal handlers only write to a pipe and the
+//! profiler is stopped by a background thread.
+
+use std::sync::Once;
+
+use libc;
+
+use lock;
+use Profiler;
+
+static REGISTER: Once = Once::new();
+
+/// Stop the profiler from an `atexit` handler
+///
+/// Registering more than once has no further effect.
+pub(crate) fn register() {
+    REGISTER.call_once(|| unsafe {
+        libc::atexit(stop_profiler);
+    });
+}
+
+extern "C" fn stop_profiler() {
+    // Another thread may hold the lock while the process exits, in which
+    // case waiting for it could hang forever. A lock poisoned by a panic is
+    // still flushed.
+    if let Ok(mut profiler) = lock::try_lock() {
+        if profiler.state().is_running() {
+            let _ = profiler.stop();
+        }
+    }
+}
+
+impl Profiler {
+    /// Stop the profiler when the process exits
+    ///
+    /// The profiler is stopped from an `atexit` handler, which runs when `main`
+    /// returns or `std::process::exit` is called. Processes killed by a
+    /// signal do not run it, see `stop_on_signals`.
+    ///
+    /// Calling this more than once has no further effect.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use cpuprofiler::PROFILER;
+    ///
+    /// let path = env::temp_dir().join("exit-example.profile");
+    /// let mut profiler = PROFILER.lock().unwrap();
+    /// profiler.stop_at_exit();
+    /// profiler.start(path.to_str().unwrap()).unwrap();
+    /// ```
+    pub fn stop_at_exit(&self) {
+        register();
+    }
+}
+
+/// Stopping the profiler on signals, which Windows does not have
+#[cfg(unix)]
+mod signals {
+    use std::io;
+    use std::mem;
+    use std::ptr;
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::sync::Mutex;
+    use std::thread;
+
+    use libc::{self, c_int, c_void};
+
+    use error::Error;
+    use lock;
+    use selfpipe;
+    use Profiler;
+
+    static WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+    lazy_static! {
+        static ref PIPE: Mutex<()> = Mutex::new(());
+    }
+
+    extern "C" fn on_signal(signal: c_int) {
+        unsafe {
+            selfpipe::write(
+                WRITE_FD.load(Ordering::SeqCst),
+                &signal as *const c_int as *const c_void,
+                mem::size_of::<c_int>(),
+            );
+        }
+    }
+
+    /// Create the pipe and the thread which stops the profiler, once
+    fn spawn_stopper() -> Result<(), Error> {
+        let _guard = PIPE.lock().unwrap();
+        if WRITE_FD.load(Ordering::SeqCst) >= 0 {
+            return Ok(());
+        }
+
+        let (read_fd, write_fd) = selfpipe::create()?;
+        WRITE_FD.store(write_fd, Ordering::SeqCst);
+
+        thread::spawn(move || loop {
+            let mut signal: c_int = 0;
+            let n = unsafe {
+                libc::read(read_fd, &mut signal as *mut c_int as *mut c_void, mem::size_of::<c_int>())
+            };
+            if n < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            } else if n <= 0 {
+                return;
+            }
+
+            let mut profiler = lock::lock();
+            if profiler.state.is_running() {
+                let _ = profiler.stop();
+            }
+            drop(profiler);
+
+            // Terminate the process as the signal would have without us.
+            unsafe {
+                libc::signal(signal, libc::SIG_DFL);
+                libc::raise(signal);
+            }
+        });
+        Ok(())
+    }
+
+    impl Profiler {
+        /// Stop the profiler before the process is terminated by one of `signals`
+        ///
+        /// Once the profiler is stopped the default action of the signal is
+        /// restored and the signal raised again, so the process still
+        /// terminates. Typically used with `SIGTERM` and `SIGINT`.
+        ///
+        /// This replaces any handlers already installed for `signals`.
+        ///
+        /// # Examples
+        ///
+        /// ```no_run
+        /// extern crate cpuprofiler;
+        /// extern crate libc;
+        ///
+        /// use cpuprofiler::PROFILER;
+        ///
+        /// PROFILER.lock().unwrap().stop_on_signals(&[libc::SIGTERM, libc::SIGINT]).unwrap();
+        /// ```
+        ///
+        /// # Failures
+        ///
+        /// - A signal handler could not be installed.
+        pub fn stop_on_signals(&self, signals: &[c_int]) -> Result<(), Error> {
+            spawn_stopper()?;
+
+            for &signal in signals {
+                unsafe {
+                    let mut action: libc::sigaction = mem::zeroed();
+                    action.sa_sigaction = on_signal as extern "C" fn(c_int) as libc::sighandler_t;
+                    action.sa_flags = libc::SA_RESTART;
+                    libc::sigemptyset(&mut action.sa_mask);
+                    if libc::sigaction(signal, &action, ptr::null_mut()) != 0 {
+                        return Err(io::Error::last_os_error().into());
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! Writing profiles elsewhere when their directory is read only
+//!
+//! Containers often run with a read only root filesystem, so a profile path
+//! which works on a developer's machine fails to start in production with
+//! a `PermissionDenied` `Error::OutputPath`. With `Profiler::set_fallback_dirs`,
+//! or `ProfilerBuilder::fallback_dirs`, a profile which cannot be written to
+//! its path, because the file or its directory is read only, is written to
+//! the same file name in the first fallback directory which can take it
+//! instead. Missing fallback directories are created.
+//!
+//! `Profiler::current_path` returns the path the profile is written to, and
+//! `ProfileSummary::requested` the path it was started with. Falling back
+//! is logged at `warn` with the `log` feature.
+
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use error::Error;
+use lifecycle;
+use {check_output_path, resolve_path, Profiler};
+
+impl Profiler {
+    /// Write profiles which cannot be written to their path to the first
+    /// of `dirs` which can take them, from the next profile on
+    ///
+    /// Only read only files and directories are fallen back from, a
+    /// missing directory is still an error without `set_create_dirs`.
+    /// Pass no directories to stop falling back.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use cpuprofiler::{Profiler, PROFILER};
+    ///
+    /// let path = env::temp_dir().join("fallback-example.profile");
+    /// let mut profiler = PROFILER.lock().unwrap();
+    /// profiler.set_fallback_dirs(Profiler::default_fallback_dirs());
+    /// profiler.start(path.to_str().unwrap()).unwrap();
+    /// println!("profiling into {}", profiler.current_path().unwrap().display());
+    /// profiler.stop().unwrap();
+    /// ```
+    pub fn set_fallback_dirs<I, P>(&mut self, dirs: I)
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.fallback_dirs = dirs.into_iter().map(Into::into).collect();
+    }
+
+    /// Returns the directories profiles fall back to, in order
+    pub fn fallback_dirs(&self) -> &[PathBuf] {
+        &self.fallback_dirs
+    }
+
+    /// Returns the temporary directory and `cpuprofiler` in the user's
+    /// cache directory, `XDG_CACHE_HOME` or else `~/.cache`
+    pub fn default_fallback_dirs() -> Vec<PathBuf> {
+        let mut dirs = vec![env::temp_dir()];
+        let cache = env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")));
+        if let Some(cache) = cache {
+            dirs.push(cache.join("cpuprofiler"));
+        }
+        dirs
+    }
+
+    /// Returns the path a profile at `path` will be written to, and
+    /// whether it fell back from `path`
+    ///
+    /// # Failures
+    ///
+    /// - `path` could not be written to, and neither could any fallback
+    ///   directory, the error for `path`.
+    pub(crate) fn resolve_fallback(&self, path: &Path) -> Result<(PathBuf, bool), Error> {
+        let error = match resolve_path(path, self.create_dirs) {
+            Ok(resolved) => return Ok((resolved, false)),
+            Err(error) => error,
+        };
+        for candidate in candidates(path, &error, &self.fallback_dirs) {
+            if let Ok(resolved) = resolve_path(&candidate, true) {
+                lifecycle::fell_back(&resolved, &error);
+                return Ok((resolved, true));
+            }
+        }
+        Err(error)
+    }
+}
+
+/// Check `path` like `resolve_fallback` does, without creating anything
+pub(crate) fn check_fallback(path: &Path, create_dirs: bool, dirs: &[PathBuf]) -> Result<(PathBuf, bool), Error> {
+    let error = match check_output_path(path, create_dirs) {
+        Ok(checked) => return Ok((checked, false)),
+        Err(error) => error,
+    };
+    for candidate in candidates(path, &error, dirs) {
+        if let Ok(checked) = check_output_path(&candidate, true) {
+            return Ok((checked, true));
+        }
+    }
+    Err(error)
+}
+
+/// Returns where a profile at `path` may fall back to after `error`
+fn candidates(path: &Path, error: &Error, dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let read_only = match *error {
+        Error::OutputPath { ref source, .. } => {
+            source.kind() == io::ErrorKind::PermissionDenied || source.kind() == io::ErrorKind::ReadOnlyFilesystem
+        }
+        _ => false,
+    };
+    match path.file_name() {
+        Some(name) if read_only => dirs.iter().map(|dir| dir.join(name)).collect(),
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,225 @@
+//! The raw cpuprofiler library functions
+//!
+//! Available with the `gperftools` feature.
+//!
+//! These are the functions declared in gperftools' `profiler.h`, for needs the
+//! safe API does not cover. They are linked the same way as the rest of the
+//! crate: with `dylib-load` they call into the library loaded at runtime, and
+//! do nothing if it could not be loaded, while with `disabled` they never do
+//! anything.
+//!
+//! The declarations are kept in `src/ffi/bindings.rs` in the form bindgen
+//! generates from the header. The build fails with an explanation when the
+//! installed gperftools is older than 2.0, or when its
+//! `gperftools/profiler.h`, if the headers are installed, lacks any of them.
+//! Set `GPERFTOOLS_INCLUDE_DIR` to check the header of a gperftools
+//! installed outside the usual include directories.
+//!
+//! The `Profiler` does not know about profiles started or stopped through
+//! these functions, so don't mix them with `PROFILER` for the same profile.
+//!
+//! # Safety
+//!
+//! Pointers passed to the functions must be valid for the duration of the
+//! call, and file names nul terminated.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::mem;
+//! use cpuprofiler::ffi;
+//!
+//! let state = unsafe {
+//!     let mut state: ffi::ProfilerState = mem::zeroed();
+//!     ffi::ProfilerGetCurrentState(&mut state);
+//!     state
+//! };
+//! println!("libprofiler is enabled: {}", state.enabled != 0);
+//! ```
+
+#![allow(non_snake_case)]
+
+mod bindings;
+
+pub use self::bindings::{ProfilerOptions, ProfilerState};
+
+#[cfg(all(not(feature = "disabled"), not(feature = "dylib-load")))]
+pub use self::bindings::{
+    ProfilerFlush, ProfilerGetCurrentState, ProfilerRegisterThread, ProfilerStart, ProfilerStartWithOptions,
+    ProfilerStop,
+};
+
+#[cfg(feature = "disabled")]
+pub use self::disabled::*;
+
+#[cfg(all(not(feature = "disabled"), feature = "dylib-load"))]
+pub use self::dynamic::*;
+
+/// Returns whether the library functions do anything
+///
+/// With `dylib-load` this loads the library, if it is not loaded yet.
+#[cfg(any(feature = "disabled", not(feature = "dylib-load")))]
+pub fn is_available() -> bool {
+    cfg!(not(feature = "disabled"))
+}
+
+/// Returns whether the library has initialized, reading its environment variables
+///
+/// A linked library initializes before `main`.
+#[cfg(any(feature = "disabled", not(feature = "dylib-load")))]
+pub fn is_initialized() -> bool {
+    cfg!(not(feature = "disabled"))
+}
+
+/// No-op stand-ins used when profiling is compiled out
+// The safety requirements are those of the declarations above.
+#[cfg(feature = "disabled")]
+#[allow(clippy::missing_safety_doc)]
+mod disabled {
+    use std::os::raw::{c_char, c_int};
+
+    use super::{ProfilerOptions, ProfilerState};
+
+    pub unsafe fn ProfilerStart(_fname: *const c_char) -> c_int {
+        1
+    }
+
+    pub unsafe fn ProfilerStartWithOptions(_fname: *const c_char, _options: *const ProfilerOptions) -> c_int {
+        1
+    }
+
+    pub unsafe fn ProfilerStop() {}
+
+    pub unsafe fn ProfilerFlush() {}
+
+    pub unsafe fn ProfilerGetCurrentState(state: *mut ProfilerState) {
+        (*state).enabled = 0;
+        (*state).samples_gathered = 0;
+    }
+
+    pub unsafe fn ProfilerRegisterThread() {}
+}
+
+/// The library functions, resolved when first used
+// The safety requirements are those of the declarations above.
+#[cfg(all(not(feature = "disabled"), feature = "dylib-load"))]
+#[allow(clippy::missing_safety_doc)]
+mod dynamic {
+    use std::ffi::CString;
+    use std::os::raw::{c_char, c_int};
+
+    use libc;
+    use libloading::Library;
+
+    use super::{ProfilerOptions, ProfilerState};
+
+    /// Names the library is looked up by, in order
+    const NAMES: &[&str] = &[
+        "libprofiler.so.0",
+        "libprofiler.so",
+        "libprofiler.0.dylib",
+        "libprofiler.dylib",
+        // dlopen on macOS does not search the prefixes of Homebrew on Apple
+        // silicon or of MacPorts.
+        "/opt/homebrew/lib/libprofiler.dylib",
+        "/opt/local/lib/libprofiler.dylib",
+    ];
+
+    struct Functions {
+        start_with_options: unsafe extern "C" fn(*const c_char, *const ProfilerOptions) -> c_int,
+        start: unsafe extern "C" fn(*const c_char) -> c_int,
+        stop: unsafe extern "C" fn(),
+        flush: unsafe extern "C" fn(),
+        get_current_state: unsafe extern "C" fn(*mut ProfilerState),
+        register_thread: unsafe extern "C" fn(),
+        // Keeps the functions above loaded.
+        _library: Library,
+    }
+
+    lazy_static! {
+        static ref FUNCTIONS: Option<Functions> = NAMES.iter().filter_map(|name| load(name)).next();
+    }
+
+    fn load(name: &str) -> Option<Functions> {
+        unsafe {
+            let library = Library::new(name).ok()?;
+            let start = *library.get(b"ProfilerStart\0").ok()?;
+            let start_with_options = *library.get(b"ProfilerStartWithOptions\0").ok()?;
+            let stop = *library.get(b"ProfilerStop\0").ok()?;
+            let flush = *library.get(b"ProfilerFlush\0").ok()?;
+            let get_current_state = *library.get(b"ProfilerGetCurrentState\0").ok()?;
+            let register_thread = *library.get(b"ProfilerRegisterThread\0").ok()?;
+            Some(Functions {
+                start,
+                start_with_options,
+                stop,
+                flush,
+                get_current_state,
+                register_thread,
+                _library: library,
+            })
+        }
+    }
+
+    pub fn is_available() -> bool {
+        FUNCTIONS.is_some()
+    }
+
+    /// Returns whether the library is loaded in the process, by us or anyone else
+    pub fn is_initialized() -> bool {
+        NAMES.iter().any(|name| {
+            let c_name = CString::new(*name).unwrap();
+            unsafe {
+                let handle = libc::dlopen(c_name.as_ptr(), libc::RTLD_LAZY | libc::RTLD_NOLOAD);
+                if handle.is_null() {
+                    false
+                } else {
+                    libc::dlclose(handle);
+                    true
+                }
+            }
+        })
+    }
+
+    pub unsafe fn ProfilerStart(fname: *const c_char) -> c_int {
+        match *FUNCTIONS {
+            Some(ref f) => (f.start)(fname),
+            None => 0,
+        }
+    }
+
+    pub unsafe fn ProfilerStartWithOptions(fname: *const c_char, options: *const ProfilerOptions) -> c_int {
+        match *FUNCTIONS {
+            Some(ref f) => (f.start_with_options)(fname, options),
+            None => 0,
+        }
+    }
+
+    pub unsafe fn ProfilerStop() {
+        if let Some(ref f) = *FUNCTIONS {
+            (f.stop)()
+        }
+    }
+
+    pub unsafe fn ProfilerFlush() {
+        if let Some(ref f) = *FUNCTIONS {
+            (f.flush)()
+        }
+    }
+
+    pub unsafe fn ProfilerGetCurrentState(state: *mut ProfilerState) {
+        match *FUNCTIONS {
+            Some(ref f) => (f.get_current_state)(state),
+            None => {
+                (*state).enabled = 0;
+                (*state).samples_gathered = 0;
+            }
+        }
+    }
+
+    pub unsafe fn ProfilerRegisterThread() {
+        if let Some(ref f) = *FUNCTIONS {
+            (f.register_thread)()
+        }
+    }
+}
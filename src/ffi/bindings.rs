@@ -0,0 +1,92 @@
+//! The declarations of `gperftools/profiler.h`
+//!
+//! These are kept as bindgen generates them from the header of gperftools
+//! 2.15, with `time_t` taken from libc and the header's comments shortened
+//! to doc comments. To compare them with another release's header, run:
+//!
+//! ```text
+//! bindgen gperftools/profiler.h --allowlist-function 'Profiler.*' \
+//!     --allowlist-type 'Profiler.*' --blocklist-type time_t --raw-line 'use libc::time_t;'
+//! ```
+//!
+//! `ProfilerEnable`, `ProfilerDisable` and `ProfilingIsEnabledForAllThreads`
+//! are left out: the header deprecates them, and `ProfilerStart` and
+//! `ProfilerStop` do the same. The build script checks an installed header
+//! for every declaration here, and the layout of the structs is checked
+//! below, so the bindings cannot drift from the library unnoticed.
+
+use std::mem;
+use std::os::raw::{c_char, c_int, c_void};
+
+use libc::time_t;
+
+/// Options for `ProfilerStartWithOptions`
+///
+/// Zeroed fields keep their defaults. Later gperftools releases may append
+/// fields, see the [`options`](../options/index.html) module.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ProfilerOptions {
+    /// Called in the sampled thread, a sample is only kept if it returns non-zero
+    ///
+    /// This runs in a signal handler, so it must be async signal safe.
+    pub filter_in_thread: Option<unsafe extern "C" fn(arg: *mut c_void) -> c_int>,
+    /// Passed to `filter_in_thread`
+    pub filter_in_thread_arg: *mut c_void,
+}
+
+/// The state of the cpuprofiler library, as returned by `ProfilerGetCurrentState`
+#[repr(C)]
+#[derive(Debug)]
+pub struct ProfilerState {
+    /// Non-zero while a profile is running
+    pub enabled: c_int,
+    /// When the profile was started, if it is running
+    pub start_time: time_t,
+    /// The nul terminated profile file name, if it is running
+    pub profile_name: [c_char; 1024],
+    /// The number of samples taken so far
+    pub samples_gathered: c_int,
+}
+
+// The layouts the C compiler gives the structs, on every target gperftools
+// supports.
+const _: () = assert!(mem::size_of::<ProfilerOptions>() == 2 * mem::size_of::<usize>());
+const _: () = assert!(mem::align_of::<ProfilerOptions>() == mem::align_of::<usize>());
+const _: () = assert!(mem::size_of::<ProfilerState>() == state_size());
+const _: () = assert!(mem::align_of::<ProfilerState>() == mem::align_of::<time_t>());
+
+/// Returns the size of `struct ProfilerState`, padded as in C
+const fn state_size() -> usize {
+    let start_time = pad(mem::size_of::<c_int>());
+    pad(start_time + mem::size_of::<time_t>() + 1024 + mem::size_of::<c_int>())
+}
+
+/// Round `offset` up to the alignment of `time_t`
+const fn pad(offset: usize) -> usize {
+    let align = mem::align_of::<time_t>();
+    offset.div_ceil(align) * align
+}
+
+#[cfg(all(not(feature = "disabled"), not(feature = "dylib-load")))]
+extern "C" {
+    /// Start profiling into `fname`, returning 0 on failure
+    pub fn ProfilerStart(fname: *const c_char) -> c_int;
+
+    /// Start profiling into `fname` with `options`, which may be null
+    ///
+    /// Returns 0 on failure.
+    pub fn ProfilerStartWithOptions(fname: *const c_char, options: *const ProfilerOptions) -> c_int;
+
+    /// Stop profiling and finish writing the profile
+    pub fn ProfilerStop();
+
+    /// Write the samples taken so far to the profile
+    pub fn ProfilerFlush();
+
+    /// Fill `state` with the library's current state
+    pub fn ProfilerGetCurrentState(state: *mut ProfilerState);
+
+    /// Register the calling thread, so it is sampled with per thread timers
+    pub fn ProfilerRegisterThread();
+}
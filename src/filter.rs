@@ -0,0 +1,169 @@
+//! Focusing symbolized stacks on the functions of interest
+
+use std::collections::HashMap;
+
+use error::Error;
+use pattern::Pattern;
+use report::Stack;
+
+/// Selects the stacks of a profile to report, like the `-focus`, `-ignore`,
+/// `-hide` and `-nodefraction` flags of pprof
+///
+/// A filter is applied to symbolized stacks before they are passed to any
+/// report or export. Patterns are regular expressions matched anywhere in
+/// a function name, anchor them with `^` and `$` to match whole names.
+/// Filters keep the stacks which match every `focus` pattern and no
+/// `ignore` pattern, then remove the `hide` frames from them, then prune
+/// what is left.
+///
+/// # Examples
+///
+/// ```
+/// use cpuprofiler::profile::Filter;
+/// use cpuprofiler::report::Stack;
+///
+/// let stack = |count, frames: &[&str]| Stack {
+///     count,
+///     frames: frames.iter().map(|f| f.to_string()).collect(),
+/// };
+/// let stacks = vec![
+///     stack(6, &["main", "app::parse", "alloc::raw_vec::finish_grow"]),
+///     stack(3, &["main", "app::render"]),
+///     stack(1, &["main", "std::rt::cleanup"]),
+/// ];
+///
+/// let filter = Filter::new()
+///     .focus("^app::")
+///     .unwrap()
+///     .hide("^(alloc|core)::")
+///     .unwrap();
+/// let stacks = filter.apply(&stacks);
+/// assert_eq!(stacks.len(), 2);
+/// assert_eq!(stacks[0].frames, vec!["main", "app::parse"]);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    focus: Vec<Pattern>,
+    ignore: Vec<Pattern>,
+    hide: Vec<Pattern>,
+    prune_below: f64,
+}
+
+impl Filter {
+    /// Create a filter which keeps every stack
+    pub fn new() -> Filter {
+        Filter::default()
+    }
+
+    /// Only keep stacks with a function matching `pattern`
+    ///
+    /// # Failures
+    ///
+    /// - `pattern` is not a regular expression, `Error::InvalidPattern`.
+    pub fn focus(mut self, pattern: &str) -> Result<Filter, Error> {
+        self.focus.push(Pattern::new(pattern)?);
+        Ok(self)
+    }
+
+    /// Drop stacks with a function matching `pattern`
+    ///
+    /// # Failures
+    ///
+    /// - `pattern` is not a regular expression, `Error::InvalidPattern`.
+    pub fn ignore(mut self, pattern: &str) -> Result<Filter, Error> {
+        self.ignore.push(Pattern::new(pattern)?);
+        Ok(self)
+    }
+
+    /// Remove the functions matching `pattern` from the stacks, keeping
+    /// their samples
+    ///
+    /// The samples of a removed function count towards the function which
+    /// called it. Stacks left with no functions are dropped.
+    ///
+    /// # Failures
+    ///
+    /// - `pattern` is not a regular expression, `Error::InvalidPattern`.
+    pub fn hide(mut self, pattern: &str) -> Result<Filter, Error> {
+        self.hide.push(Pattern::new(pattern)?);
+        Ok(self)
+    }
+
+    /// Cut stacks off at the first call path with less than `fraction` of
+    /// the samples
+    ///
+    /// `fraction` is between 0 and 1, pprof's default is 0.005. The samples
+    /// of a cut off call count towards its caller, stacks cut off at their
+    /// outermost function are dropped. The fraction is of the samples kept
+    /// by the other settings.
+    pub fn prune_below(mut self, fraction: f64) -> Filter {
+        self.prune_below = fraction;
+        self
+    }
+
+    /// Returns whether the filter keeps `stack`, before hiding and pruning
+    pub fn matches(&self, stack: &Stack) -> bool {
+        let any = |pattern: &Pattern| stack.frames.iter().any(|frame| pattern.is_match(frame));
+        self.focus.iter().all(any) && !self.ignore.iter().any(any)
+    }
+
+    /// Returns the stacks the filter keeps
+    ///
+    /// Stacks which become the same are merged, in the order they first
+    /// appear.
+    pub fn apply(&self, stacks: &[Stack]) -> Vec<Stack> {
+        let kept: Vec<Stack> = stacks
+            .iter()
+            .filter(|stack| self.matches(stack))
+            .map(|stack| Stack {
+                count: stack.count,
+                frames: stack
+                    .frames
+                    .iter()
+                    .filter(|frame| !self.hide.iter().any(|pattern| pattern.is_match(frame)))
+                    .cloned()
+                    .collect(),
+            })
+            .filter(|stack| !stack.frames.is_empty())
+            .collect();
+
+        let total: u64 = kept.iter().map(|stack| stack.count).sum();
+        let threshold = total as f64 * self.prune_below;
+        let mut paths: HashMap<&[String], u64> = HashMap::new();
+        if threshold > 0.0 {
+            for stack in &kept {
+                for depth in 1..=stack.frames.len() {
+                    *paths.entry(&stack.frames[..depth]).or_insert(0) += stack.count;
+                }
+            }
+        }
+
+        let mut merged: Vec<Stack> = Vec::new();
+        let mut index: HashMap<Vec<String>, usize> = HashMap::new();
+        for stack in &kept {
+            let depth = if threshold > 0.0 {
+                (1..=stack.frames.len())
+                    .take_while(|&depth| paths[&stack.frames[..depth]] as f64 >= threshold)
+                    .last()
+                    .unwrap_or(0)
+            } else {
+                stack.frames.len()
+            };
+            if depth == 0 {
+                continue;
+            }
+            let frames = stack.frames[..depth].to_vec();
+            match index.get(&frames) {
+                Some(&i) => merged[i].count += stack.count,
+                None => {
+                    index.insert(frames.clone(), merged.len());
+                    merged.push(Stack {
+                        count: stack.count,
+                        frames,
+                    });
+                }
+            }
+        }
+        merged
+    }
+}
@@ -0,0 +1,342 @@
+//! Profiling processes which fork
+//!
+//! A child created by `fork` inherits the running profile, including the
+//! open profile file which it shares with its parent. When the child exits or
+//! stops the profile it writes its copy of the unflushed samples into the same
+//! file as the parent, which corrupts the profile. Interval timers are not
+//! inherited, so the child is not sampled either.
+//!
+//! `Profiler::at_fork` chooses what happens in the child instead:
+//!
+//! - `ForkPolicy::Inherit`, the default: nothing is done.
+//! - `ForkPolicy::Disable`: the child's writes to the profile are discarded.
+//!   The parent's profile is left as it is.
+//! - `ForkPolicy::PerProcess`: the child continues the profile into a file of
+//!   its own, the parent's path followed by `.<pid>`, and is sampled with
+//!   the parent's timer. The child's profile starts with the parent's
+//!   samples which had not been written out yet.
+//!
+//! The child is handled from a `pthread_atfork` handler, which only uses
+//! system calls which are safe after forking a threaded process. It finds
+//! the profile file through `/proc/self/fd`, so policies other than
+//! `Inherit` are only supported on Linux.
+//!
+//! # Examples
+//!
+//! ```
+//! use cpuprofiler::PROFILER;
+//! use cpuprofiler::fork::ForkPolicy;
+//!
+//! let mut profiler = PROFILER.lock().unwrap();
+//! match profiler.at_fork(ForkPolicy::PerProcess) {
+//!     Ok(()) => println!("children write profiles of their own"),
+//!     Err(e) => println!("children share the profile: {}", e),
+//! }
+//! ```
+
+use std::cell::UnsafeCell;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Once;
+
+use libc;
+
+use error::Error;
+use Profiler;
+
+/// What a forked child does with the profile it inherits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForkPolicy {
+    /// Leave the child writing to the parent's profile
+    Inherit,
+    /// Discard everything the child writes to the profile
+    Disable,
+    /// Continue the profile in the child, into `<path>.<pid>`
+    PerProcess,
+}
+
+impl ForkPolicy {
+    fn from_usize(policy: usize) -> ForkPolicy {
+        match policy {
+            1 => ForkPolicy::Disable,
+            2 => ForkPolicy::PerProcess,
+            _ => ForkPolicy::Inherit,
+        }
+    }
+}
+
+/// The longest profile path which is followed into children
+const PATH_LEN: usize = 4096;
+
+/// Room left at the end of the path for `.<pid>` and the nul byte
+const PID_LEN: usize = 24;
+
+const NO_TIMER: libc::itimerval = libc::itimerval {
+    it_interval: libc::timeval { tv_sec: 0, tv_usec: 0 },
+    it_value: libc::timeval { tv_sec: 0, tv_usec: 0 },
+};
+
+/// What the child handler needs to know about the running profile
+struct State {
+    path: [u8; PATH_LEN],
+    len: usize,
+    prof_timer: libc::itimerval,
+    real_timer: libc::itimerval,
+}
+
+/// The state, locked with a raw mutex which the fork handlers hold across
+/// the fork
+struct Shared {
+    mutex: UnsafeCell<libc::pthread_mutex_t>,
+    state: UnsafeCell<State>,
+}
+
+// The state is only used with the mutex held.
+unsafe impl Sync for Shared {}
+
+static SHARED: Shared = Shared {
+    mutex: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER),
+    state: UnsafeCell::new(State {
+        path: [0; PATH_LEN],
+        len: 0,
+        prof_timer: NO_TIMER,
+        real_timer: NO_TIMER,
+    }),
+};
+
+static REGISTER: Once = Once::new();
+static POLICY: AtomicUsize = AtomicUsize::new(ForkPolicy::Inherit as usize);
+static REDIRECTED: AtomicBool = AtomicBool::new(false);
+
+/// Record the file of the running profile, or that none is running
+pub(crate) fn set_path(path: Option<&Path>) {
+    // The descriptor is found by the path the kernel reports for it.
+    let path = path.and_then(|p| p.canonicalize().ok());
+    unsafe {
+        libc::pthread_mutex_lock(SHARED.mutex.get());
+        let state = &mut *SHARED.state.get();
+        state.len = 0;
+        if let Some(path) = path {
+            let bytes = path.as_os_str().as_bytes();
+            if bytes.len() + PID_LEN <= PATH_LEN {
+                state.path[..bytes.len()].copy_from_slice(bytes);
+                state.len = bytes.len();
+            }
+        }
+        libc::pthread_mutex_unlock(SHARED.mutex.get());
+    }
+}
+
+/// Returns whether this process is a child whose profile was moved away
+/// from its parent's file
+pub(crate) fn redirected() -> bool {
+    REDIRECTED.load(Ordering::SeqCst)
+}
+
+extern "C" fn prepare() {
+    unsafe {
+        libc::pthread_mutex_lock(SHARED.mutex.get());
+        let state = &mut *SHARED.state.get();
+        libc::getitimer(libc::ITIMER_PROF, &mut state.prof_timer);
+        libc::getitimer(libc::ITIMER_REAL, &mut state.real_timer);
+    }
+}
+
+extern "C" fn parent() {
+    unsafe {
+        libc::pthread_mutex_unlock(SHARED.mutex.get());
+    }
+}
+
+extern "C" fn child() {
+    unsafe {
+        let state = &*SHARED.state.get();
+        let policy = ForkPolicy::from_usize(POLICY.load(Ordering::SeqCst));
+        if policy != ForkPolicy::Inherit && state.len > 0 && redirect(state, policy) {
+            REDIRECTED.store(true, Ordering::SeqCst);
+        }
+        libc::pthread_mutex_unlock(SHARED.mutex.get());
+    }
+}
+
+/// Point the child's profile descriptor away from the parent's file
+///
+/// Only async-signal-safe functions may be used here.
+#[cfg(target_os = "linux")]
+unsafe fn redirect(state: &State, policy: ForkPolicy) -> bool {
+    let fd = match find_fd(&state.path[..state.len]) {
+        Some(fd) => fd,
+        None => return false,
+    };
+
+    let target = if policy == ForkPolicy::PerProcess {
+        let mut path = [0u8; PATH_LEN];
+        path[..state.len].copy_from_slice(&state.path[..state.len]);
+        let mut len = state.len;
+        path[len] = b'.';
+        len += 1;
+        len += write_decimal(&mut path[len..], libc::getpid() as u64);
+        path[len] = 0;
+
+        let target = libc::open(
+            path.as_ptr() as *const libc::c_char,
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+            0o644,
+        );
+        if target >= 0 {
+            // The header was written when the profile started, and the
+            // child's samples follow it.
+            let mut header = [0u8; 5 * 8];
+            let len = 5 * std::mem::size_of::<usize>();
+            let read = libc::pread(fd, header.as_mut_ptr() as *mut libc::c_void, len, 0);
+            if read > 0 {
+                libc::write(target, header.as_ptr() as *const libc::c_void, read as usize);
+            }
+        }
+        target
+    } else {
+        libc::open(b"/dev/null\0".as_ptr() as *const libc::c_char, libc::O_WRONLY)
+    };
+    if target < 0 {
+        return false;
+    }
+    libc::dup2(target, fd);
+    libc::close(target);
+
+    if policy == ForkPolicy::PerProcess {
+        if state.prof_timer.it_interval.tv_usec != 0 || state.prof_timer.it_interval.tv_sec != 0 {
+            libc::setitimer(libc::ITIMER_PROF, &state.prof_timer, std::ptr::null_mut());
+        }
+        if state.real_timer.it_interval.tv_usec != 0 || state.real_timer.it_interval.tv_sec != 0 {
+            libc::setitimer(libc::ITIMER_REAL, &state.real_timer, std::ptr::null_mut());
+        }
+    }
+    true
+}
+
+#[cfg(not(target_os = "linux"))]
+unsafe fn redirect(_state: &State, _policy: ForkPolicy) -> bool {
+    false
+}
+
+/// Find the open descriptor of the file at `path`
+#[cfg(target_os = "linux")]
+unsafe fn find_fd(path: &[u8]) -> Option<libc::c_int> {
+    const PREFIX: &[u8] = b"/proc/self/fd/";
+
+    let dir = libc::open(
+        b"/proc/self/fd\0".as_ptr() as *const libc::c_char,
+        libc::O_RDONLY | libc::O_DIRECTORY,
+    );
+    if dir < 0 {
+        return None;
+    }
+
+    let mut found = None;
+    let mut entries = [0u8; 2048];
+    'read: loop {
+        let read = libc::syscall(libc::SYS_getdents64, dir, entries.as_mut_ptr(), entries.len());
+        if read <= 0 {
+            break;
+        }
+        let mut offset = 0;
+        while offset < read as usize {
+            // struct linux_dirent64: u64 inode, i64 offset, u16 length, u8 type, name
+            let entry = &entries[offset..];
+            let reclen = u16::from_ne_bytes([entry[16], entry[17]]) as usize;
+            let name = &entry[19..reclen];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            offset += reclen;
+
+            let fd = match parse_decimal(name) {
+                Some(fd) if fd != dir => fd,
+                _ => continue,
+            };
+            let mut link = [0u8; 64];
+            link[..PREFIX.len()].copy_from_slice(PREFIX);
+            let len = PREFIX.len() + write_decimal(&mut link[PREFIX.len()..], fd as u64);
+            link[len] = 0;
+
+            let mut target = [0u8; PATH_LEN];
+            let n = libc::readlink(
+                link.as_ptr() as *const libc::c_char,
+                target.as_mut_ptr() as *mut libc::c_char,
+                target.len(),
+            );
+            if n > 0 && &target[..n as usize] == path {
+                found = Some(fd);
+                break 'read;
+            }
+        }
+    }
+    libc::close(dir);
+    found
+}
+
+/// Write `n` in decimal at the start of `out`, returning the digits written
+fn write_decimal(out: &mut [u8], mut n: u64) -> usize {
+    let mut digits = [0u8; 20];
+    let mut len = 0;
+    loop {
+        digits[len] = b'0' + (n % 10) as u8;
+        len += 1;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    for (out, digit) in out.iter_mut().zip(digits[..len].iter().rev()) {
+        *out = *digit;
+    }
+    len
+}
+
+fn parse_decimal(s: &[u8]) -> Option<libc::c_int> {
+    if s.is_empty() {
+        return None;
+    }
+    let mut n: libc::c_int = 0;
+    for &b in s {
+        if !b.is_ascii_digit() {
+            return None;
+        }
+        n = n.checked_mul(10)?.checked_add((b - b'0') as libc::c_int)?;
+    }
+    Some(n)
+}
+
+impl Profiler {
+    /// Choose what forked children do with the running profile
+    ///
+    /// The policy applies to every fork from now on, including forks while
+    /// no profile is running, see the [`fork`](fork/index.html) module.
+    ///
+    /// # Failures
+    ///
+    /// - The policy is not `Inherit` and the platform is not Linux,
+    ///   `Error::Unsupported`.
+    /// - The fork handlers could not be registered, `Error::Io`.
+    pub fn at_fork(&mut self, policy: ForkPolicy) -> Result<(), Error> {
+        if policy != ForkPolicy::Inherit && cfg!(not(target_os = "linux")) {
+            return Err(Error::Unsupported("fork policies need /proc/self/fd".to_owned()));
+        }
+
+        let mut registered = Ok(());
+        REGISTER.call_once(|| unsafe {
+            let res = libc::pthread_atfork(Some(prepare), Some(parent), Some(child));
+            if res != 0 {
+                registered = Err(Error::Io(std::io::Error::from_raw_os_error(res)));
+            }
+        });
+        registered?;
+
+        POLICY.store(policy as usize, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Returns what forked children do with the running profile
+    pub fn fork_policy(&self) -> ForkPolicy {
+        ForkPolicy::from_usize(POLICY.load(Ordering::SeqCst))
+    }
+}
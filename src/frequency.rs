@@ -0,0 +1,254 @@
+//! Sampling faster for a while
+//!
+//! A capture of a whole service is best taken at a low frequency, but a hot
+//! inner loop needs many more samples to show where its time goes.
+//! `Profiler::with_frequency` samples a closure at a higher frequency
+//! without ending the running profile: the backend is stopped, the samples
+//! so far are read back and kept aside, the closure is profiled on its own
+//! at the new frequency, and then the backend is started again on the
+//! profile's path at the old frequency. The profile stays the same session,
+//! with the same path and labels, and when it stops the samples kept aside
+//! are merged back into it.
+
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use builder;
+use error::Error;
+use lifecycle;
+use lock;
+use paths;
+use profile::Profile;
+use {Profiler, ProfilerState};
+
+/// The running profile, while the closure is sampled on its own
+struct Swapped {
+    session: u64,
+    path: PathBuf,
+    before: Profile,
+    fast: PathBuf,
+}
+
+impl Profiler {
+    /// Sample at `hz` while `f` runs, then go back to the previous frequency
+    ///
+    /// If a profile is running, `f` is profiled on its own into
+    /// `<path>.<hz>hz`, and the profile carries on once `f` returns or
+    /// panics. The profile keeps its session, path and labels, and the
+    /// samples taken before `f` are merged back into it when it stops.
+    /// Profiles started by `f` fail with `Error::Busy`, and if `f` stops the
+    /// profile it is not carried on. Without a running profile `f` just
+    /// runs. Returns what `f` returned.
+    ///
+    /// The cpuprofiler library reads its frequency once, when it is first
+    /// used, so with the `Gperftools` backend this only works before any
+    /// profile has been taken. The `Sampler` backend changes frequency at
+    /// any time.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use cpuprofiler::{Profiler, PROFILER};
+    ///
+    /// PROFILER.lock().unwrap().start("./frequency-example.profile").unwrap();
+    /// // The rest of the capture is sampled at the usual frequency.
+    /// let sum = Profiler::with_frequency(1000, || (0..1_000_000u64).sum::<u64>()).unwrap();
+    /// assert_eq!(sum, 499_999_500_000);
+    /// PROFILER.lock().unwrap().stop().unwrap();
+    /// ```
+    ///
+    /// # Failures
+    ///
+    /// - The frequency is 0 or above `MAX_FREQUENCY`, or the backend cannot
+    ///   change its frequency, `Error::Unsupported`.
+    /// - The profiler has been taken, see `Profiler::take`, `Error::Busy`.
+    /// - The profile is paused, `Error::InvalidState`, or kept in memory,
+    ///   `Error::Unsupported`.
+    /// - The samples so far could not be read back, in which case the
+    ///   profile carries on without them.
+    /// - The backend could not be stopped or started again.
+    pub fn with_frequency<F, R>(hz: u32, f: F) -> Result<R, Error>
+    where
+        F: FnOnce() -> R,
+    {
+        Profiler::sample_faster(hz, false, f)
+    }
+
+    /// Sample at `hz` while `f` runs, merging its samples into the running
+    /// profile
+    ///
+    /// This behaves like `with_frequency`, except that no `<path>.<hz>hz`
+    /// is left behind: when the profile stops it holds the samples of `f`
+    /// too, weighed down to the profile's frequency so that each sample
+    /// still stands for the same CPU time.
+    ///
+    /// # Failures
+    ///
+    /// - See `with_frequency`.
+    /// - The samples of `f` could not be read back.
+    pub fn with_frequency_merged<F, R>(hz: u32, f: F) -> Result<R, Error>
+    where
+        F: FnOnce() -> R,
+    {
+        Profiler::sample_faster(hz, true, f)
+    }
+
+    fn sample_faster<F, R>(hz: u32, merge: bool, f: F) -> Result<R, Error>
+    where
+        F: FnOnce() -> R,
+    {
+        let (previous, swapped) = lock::lock().swap_out(hz)?;
+
+        let res = panic::catch_unwind(AssertUnwindSafe(f));
+
+        let restored = lock::lock().swap_in(previous, swapped, merge);
+        match res {
+            Ok(res) => restored.map(|_| res),
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+
+    /// Set the backend to `hz` and profile into `<path>.<hz>hz` instead
+    ///
+    /// Returns the previous frequency and the running profile, if any.
+    fn swap_out(&mut self, hz: u32) -> Result<(Option<u32>, Option<Swapped>), Error> {
+        builder::check_frequency(hz)?;
+        if self.taken {
+            return Err(Error::Busy);
+        }
+        if self.state == ProfilerState::Paused {
+            return Err(Error::InvalidState(self.state));
+        }
+        if self.in_memory.is_some() {
+            return Err(Error::Unsupported("changing the frequency of a profile kept in memory".to_owned()));
+        }
+
+        let previous = self.backend.frequency();
+        // This only affects the next start, so nothing has changed if it fails.
+        self.backend.set_frequency(hz)?;
+        let path = match self.path {
+            Some(ref path) if self.state.is_running() => path.clone(),
+            _ => {
+                self.taken = true;
+                return Ok((previous, None));
+            }
+        };
+
+        if let Err(e) = self.backend.stop() {
+            self.restore_frequency(previous);
+            return Err(e);
+        }
+        let mut fast = path.clone().into_os_string();
+        fast.push(format!(".{}hz", hz));
+        let fast = PathBuf::from(fast);
+        let before = match Profile::from_file(&path) {
+            Ok(before) => before,
+            Err(e) => {
+                self.carry_on(previous, &path)?;
+                return Err(e);
+            }
+        };
+        if let Err(e) = c_path(&fast).and_then(|fast| self.backend.start(&fast)) {
+            // The samples so far are kept even though the closure is not sampled.
+            self.parts.push(before);
+            self.carry_on(previous, &path)?;
+            return Err(e);
+        }
+
+        self.taken = true;
+        let swapped = Swapped {
+            session: self.session,
+            path,
+            before,
+            fast,
+        };
+        Ok((previous, Some(swapped)))
+    }
+
+    /// Stop sampling at the higher frequency and carry on with `swapped`
+    fn swap_in(&mut self, previous: Option<u32>, swapped: Option<Swapped>, merge: bool) -> Result<(), Error> {
+        self.taken = false;
+        let swapped = match swapped {
+            // The closure may have stopped the profile, or even started another.
+            Some(swapped) if self.session == swapped.session && self.state.is_running() => swapped,
+            _ => {
+                self.restore_frequency(previous);
+                return Ok(());
+            }
+        };
+
+        let stopped = self.backend.stop();
+        self.restore_frequency(previous);
+        let mut read = Ok(());
+        if stopped.is_ok() && merge {
+            read = Profile::from_file(&swapped.fast).map(|mut fast| {
+                fast.resample(swapped.before.sampling_period().as_micros() as u64);
+                self.parts.push(fast);
+                let _ = fs::remove_file(&swapped.fast);
+            });
+        }
+        self.parts.push(swapped.before);
+        self.backend.start(&c_path(&swapped.path)?)?;
+        stopped.and(read)
+    }
+
+    /// Go back to sampling into `path` at the `previous` frequency
+    fn carry_on(&mut self, previous: Option<u32>, path: &Path) -> Result<(), Error> {
+        self.restore_frequency(previous);
+        self.backend.start(&c_path(path)?)
+    }
+
+    fn restore_frequency(&mut self, previous: Option<u32>) {
+        if let Some(hz) = previous {
+            // The backend sampled at this frequency, so it takes it back.
+            let _ = self.backend.set_frequency(hz);
+        }
+    }
+
+    /// Merge the samples kept aside by `with_frequency` into the stopped
+    /// profile
+    pub(crate) fn merge_parts(&mut self) {
+        let parts = mem::take(&mut self.parts);
+        if parts.is_empty() {
+            return;
+        }
+        if let Some(path) = self.path.as_ref() {
+            if let Err(e) = merge_into(path, parts, self) {
+                lifecycle::failed("merge the samples taken before a change of frequency", &e);
+            }
+        }
+    }
+}
+
+/// Merge `parts` into the profile at `path`
+///
+/// The parts were read back before the profile was finished, so their
+/// stacks are trimmed here as the profile's are.
+fn merge_into(path: &Path, parts: Vec<Profile>, profiler: &Profiler) -> Result<(), Error> {
+    let mut profile = Profile::from_file(path)?;
+    for mut part in parts {
+        part.trim_stacks(&profiler.stack_trim);
+        profile.merge(part)?;
+    }
+
+    let mut partial = path.to_path_buf().into_os_string();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let written = File::create(&partial).and_then(|file| {
+        let mut out = BufWriter::new(file);
+        profile.write_to(&mut out)?;
+        out.flush()
+    });
+    written.and_then(|_| fs::rename(&partial, path)).map_err(|e| {
+        let _ = fs::remove_file(&partial);
+        Error::from(e)
+    })
+}
+
+fn c_path(path: &Path) -> Result<CString, Error> {
+    Ok(CString::new(paths::to_bytes(path))?)
+}
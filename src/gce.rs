@@ -0,0 +1,39 @@
+//! The metadata server of Google Cloud hosts
+//!
+//! Compute Engine, GKE and Cloud Run serve the project, the zone and access
+//! tokens for the host's service account from it.
+
+use std::time::Duration;
+
+use ureq;
+
+use error::Error;
+
+const METADATA_URL: &str = "http://metadata.google.internal/computeMetadata/v1/";
+
+/// Returns the metadata value at `path`, such as `project/project-id`
+pub fn get(path: &str) -> Result<String, Error> {
+    let response = ureq::get(&format!("{}{}", METADATA_URL, path))
+        .set("Metadata-Flavor", "Google")
+        .timeout(Duration::from_secs(5))
+        .call()
+        .map_err(|e| Error::Upload(format!("the metadata server did not answer: {}", e)))?;
+    Ok(response.into_string()?)
+}
+
+/// Returns an OAuth 2 access token for the host's service account
+pub fn access_token() -> Result<String, Error> {
+    let body = get("instance/service-accounts/default/token")?;
+    json_field(&body, "access_token").ok_or_else(|| Error::Upload("the metadata server sent no access token".to_owned()))
+}
+
+/// Returns the string value of the first `field` in a JSON object
+///
+/// This is enough for the small responses of Google APIs used here, whose
+/// values hold no escapes.
+pub fn json_field(body: &str, field: &str) -> Option<String> {
+    let key = format!("\"{}\"", field);
+    let rest = &body[body.find(&key)? + key.len()..];
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start().strip_prefix('"')?;
+    Some(rest[..rest.find('"')?].to_owned())
+}
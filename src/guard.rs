@@ -0,0 +1,77 @@
+//! Profiling which is safe across `.await` points
+//!
+//! Holding the `PROFILER` lock while a task awaits blocks every other task
+//! which touches the profiler, and deadlocks if one of them runs on the same
+//! thread. `Profiler::start_async` only locks `PROFILER` while the profiler
+//! is started and returns a `ProfileGuard`, which is `Send` and holds no lock.
+//! The profile is stopped when the guard is stopped or dropped, again taking
+//! the lock only briefly.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use cpuprofiler::Profiler;
+//!
+//! let path = env::temp_dir().join("guard-example.profile");
+//! let guard = Profiler::start_async(path.to_str().unwrap()).unwrap();
+//! // Code you want to sample goes here, this may await.
+//! guard.stop().unwrap();
+//! ```
+
+use error::Error;
+use lock;
+use {Profiler, ProfilerState};
+
+/// A running profile, stopped when the guard is dropped
+#[derive(Debug)]
+#[must_use = "the profile is stopped as soon as the guard is dropped"]
+pub struct ProfileGuard {
+    session: u64,
+    stopped: bool,
+}
+
+impl Profiler {
+    /// Start the profiler without holding the `PROFILER` lock afterwards
+    ///
+    /// # Failures
+    ///
+    /// - See `Profiler::start`.
+    pub fn start_async<T: Into<Vec<u8>>>(fname: T) -> Result<ProfileGuard, Error> {
+        let mut profiler = lock::lock();
+        profiler.start(fname)?;
+        Ok(ProfileGuard {
+            session: profiler.session,
+            stopped: false,
+        })
+    }
+}
+
+impl ProfileGuard {
+    /// Stop the profile
+    ///
+    /// # Failures
+    ///
+    /// - The profile was already stopped elsewhere.
+    pub fn stop(mut self) -> Result<(), Error> {
+        self.stopped = true;
+        let mut profiler = lock::lock();
+        if profiler.state.is_running() && profiler.session == self.session {
+            profiler.stop()
+        } else {
+            Err(Error::InvalidState(ProfilerState::NotActive))
+        }
+    }
+}
+
+impl Drop for ProfileGuard {
+    fn drop(&mut self) {
+        if self.stopped {
+            return;
+        }
+        let mut profiler = lock::lock();
+        if profiler.state.is_running() && profiler.session == self.session {
+            let _ = profiler.stop();
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Exclusive ownership of the profiler
+//!
+//! Locking `PROFILER` around every call makes it easy to hold the guard for
+//! too long, or to start a profile which another part of the program is
+//! about to start too. `Profiler::take` instead hands out a single
+//! `ProfilerHandle`, and while it exists only the handle can start the
+//! profiler. Each call on the handle locks `PROFILER` just for the duration
+//! of the call.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use cpuprofiler::Profiler;
+//!
+//! let mut handle = Profiler::take().expect("profiler already taken");
+//! assert!(Profiler::take().is_none());
+//!
+//! let path = env::temp_dir().join("handle-example.profile");
+//! handle.start(path.to_str().unwrap()).unwrap();
+//! // Code you want to sample goes here!
+//! handle.stop().unwrap();
+//!
+//! // Dropping the handle gives the profiler back.
+//! drop(handle);
+//! assert!(Profiler::take().is_some());
+//! ```
+
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use error::Error;
+use lock;
+use {Profiler, ProfilerState};
+
+/// The exclusive right to start the profiler
+///
+/// Dropping the handle stops a profile it started which is still running
+/// and releases the profiler, after which it may be taken again.
+#[derive(Debug)]
+pub struct ProfilerHandle {
+    session: Option<u64>,
+}
+
+impl Profiler {
+    /// Take exclusive ownership of the profiler
+    ///
+    /// Returns `None` if a `ProfilerHandle` already exists. While the handle
+    /// exists, `Profiler::start` through `PROFILER` fails.
+    pub fn take() -> Option<ProfilerHandle> {
+        let mut profiler = lock::lock();
+        if profiler.taken {
+            None
+        } else {
+            profiler.taken = true;
+            Some(ProfilerHandle { session: None })
+        }
+    }
+}
+
+impl ProfilerHandle {
+    /// Returns the profiler state
+    pub fn state(&self) -> ProfilerState {
+        lock::lock().state()
+    }
+
+    /// Start the profiler, see `Profiler::start`
+    ///
+    /// # Failures
+    ///
+    /// - See `Profiler::start`.
+    pub fn start<T: Into<Vec<u8>>>(&mut self, fname: T) -> Result<(), Error> {
+        let session = self.with(|profiler| profiler.start(fname).map(|_| profiler.session))?;
+        self.session = Some(session);
+        Ok(())
+    }
+
+    /// Start the profiler, see `Profiler::start_path`
+    ///
+    /// # Failures
+    ///
+    /// - See `Profiler::start`.
+    pub fn start_path<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let session = self.with(|profiler| profiler.start_path(path).map(|_| profiler.session))?;
+        self.session = Some(session);
+        Ok(())
+    }
+
+    /// Stop the profiler, see `Profiler::stop`
+    ///
+    /// # Failures
+    ///
+    /// - The profiler is `NotActive`.
+    pub fn stop(&mut self) -> Result<(), Error> {
+        self.session = None;
+        lock::lock().stop()
+    }
+
+    /// Run `f` with the profiler locked
+    ///
+    /// This gives access to the rest of the `Profiler` API. The lock is
+    /// released as soon as `f` returns.
+    pub fn with<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&mut Profiler) -> R,
+    {
+        let mut profiler = lock::lock();
+        profiler.taken = false;
+        let res = panic::catch_unwind(AssertUnwindSafe(|| f(&mut profiler)));
+        profiler.taken = true;
+        match res {
+            Ok(res) => res,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+}
+
+impl Drop for ProfilerHandle {
+    fn drop(&mut self) {
+        let mut profiler = lock::lock();
+        if profiler.state.is_running() && self.session == Some(profiler.session) {
+            let _ = profiler.stop();
+        }
+        profiler.taken = false;
+    }
+}
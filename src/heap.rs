@@ -0,0 +1,193 @@
+//! Bindings to the gperftools heap profiler
+//!
+//! Available with the `heap` feature, which links libtcmalloc.
+//!
+//! The heap profiler is driven like the cpu profiler, through the static
+//! `HEAP_PROFILER: Mutex<HeapProfiler>`. Once started, the heap profiler
+//! writes a profile named `<prefix>.<NNNN>.heap` each time it is dumped, and
+//! may also dump by itself as allocation grows (see the `HEAP_PROFILE_*`
+//! environment variables in the gperftools documentation).
+//!
+//! Note that the heap profiler only sees allocations made through tcmalloc,
+//! which is the case for Rust's default allocator once libtcmalloc is linked.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use cpuprofiler::heap::HEAP_PROFILER;
+//!
+//! let prefix = env::temp_dir().join("heap-example");
+//! HEAP_PROFILER.lock().unwrap().start(prefix.to_str().unwrap()).unwrap();
+//! // Code you want to profile goes here!
+//! HEAP_PROFILER.lock().unwrap().dump("after setup").unwrap();
+//! HEAP_PROFILER.lock().unwrap().stop().unwrap();
+//! ```
+
+use std::ffi::{CStr, CString};
+use std::sync::Mutex;
+
+use libc;
+
+use error::Error;
+use leak;
+use ProfilerState;
+
+lazy_static! {
+    /// Static reference to the heap profiler
+    ///
+    /// Like the cpuprofiler, the heap profiler library only supports one
+    /// active profile.
+    #[derive(Debug)]
+    pub static ref HEAP_PROFILER: Mutex<HeapProfiler> = Mutex::new(HeapProfiler {
+        state: ProfilerState::NotActive,
+    });
+}
+
+/// The `HeapProfiler`
+///
+/// Gives access to the heap profiler of the gperftools tcmalloc library.
+#[derive(Debug)]
+pub struct HeapProfiler {
+    state: ProfilerState,
+}
+
+impl HeapProfiler {
+    /// Returns the heap profiler state
+    pub fn state(&self) -> ProfilerState {
+        self.state
+    }
+
+    /// Start the heap profiler
+    ///
+    /// Profiles are written to files beginning with `prefix`, followed by
+    /// a sequence number and a `.heap` extension.
+    ///
+    /// # Failures
+    ///
+    /// - The heap profiler is currently `Active`.
+    /// - A `LeakChecker` exists, `Error::Busy`.
+    /// - `prefix` is not a valid `CString`.
+    pub fn start<T: Into<Vec<u8>>>(&mut self, prefix: T) -> Result<(), Error> {
+        if leak::checking() {
+            return Err(Error::Busy);
+        }
+        if self.state == ProfilerState::NotActive {
+            let c_prefix = CString::new(prefix)?;
+            unsafe {
+                ffi::HeapProfilerStart(c_prefix.as_ptr());
+            }
+            self.state = ProfilerState::Active;
+            Ok(())
+        } else {
+            Err(Error::InvalidState(self.state))
+        }
+    }
+
+    /// Fail unless the heap profiler is `NotActive`
+    pub(crate) fn check_inactive(&self) -> Result<(), Error> {
+        if self.state == ProfilerState::NotActive {
+            Ok(())
+        } else {
+            Err(Error::InvalidState(self.state))
+        }
+    }
+
+    /// Write a heap profile now
+    ///
+    /// The `reason` is recorded by the library to explain why the profile
+    /// was written.
+    ///
+    /// # Failures
+    ///
+    /// - The heap profiler is `NotActive`.
+    /// - `reason` is not a valid `CString`.
+    pub fn dump<T: Into<Vec<u8>>>(&mut self, reason: T) -> Result<(), Error> {
+        if self.state == ProfilerState::Active {
+            let c_reason = CString::new(reason)?;
+            unsafe {
+                ffi::HeapProfilerDump(c_reason.as_ptr());
+            }
+            Ok(())
+        } else {
+            Err(Error::InvalidState(self.state))
+        }
+    }
+
+    /// Returns the current heap profile without writing it to a file
+    ///
+    /// # Failures
+    ///
+    /// - The heap profiler is `NotActive`.
+    /// - The library did not return a profile.
+    /// - The profile is not valid Utf8.
+    pub fn profile(&self) -> Result<String, Error> {
+        if self.state != ProfilerState::Active {
+            return Err(Error::InvalidState(self.state));
+        }
+
+        unsafe {
+            let raw = ffi::GetHeapProfile();
+            if raw.is_null() {
+                return Err(Error::Internal);
+            }
+            // The profile is allocated with malloc and owned by the caller.
+            let profile = CStr::from_ptr(raw).to_str().map(|s| s.to_owned());
+            libc::free(raw as *mut libc::c_void);
+            Ok(profile?)
+        }
+    }
+
+    /// Stop the heap profiler
+    ///
+    /// # Failures
+    ///
+    /// - The heap profiler is `NotActive`.
+    pub fn stop(&mut self) -> Result<(), Error> {
+        if self.state == ProfilerState::Active {
+            unsafe {
+                ffi::HeapProfilerStop();
+            }
+            self.state = ProfilerState::NotActive;
+            Ok(())
+        } else {
+            Err(Error::InvalidState(self.state))
+        }
+    }
+}
+
+/// Declarations of the heap profiler functions
+#[allow(non_snake_case)]
+#[cfg(not(feature = "disabled"))]
+mod ffi {
+    use std::os::raw::c_char;
+
+    extern "C" {
+        pub fn HeapProfilerStart(prefix: *const c_char);
+
+        pub fn HeapProfilerDump(reason: *const c_char);
+
+        pub fn HeapProfilerStop();
+
+        pub fn GetHeapProfile() -> *mut c_char;
+    }
+}
+
+/// No-op stand-ins used when profiling is compiled out
+#[allow(non_snake_case)]
+#[cfg(feature = "disabled")]
+mod ffi {
+    use std::os::raw::c_char;
+
+    use libc;
+
+    pub unsafe fn HeapProfilerStart(_prefix: *const c_char) {}
+
+    pub unsafe fn HeapProfilerDump(_reason: *const c_char) {}
+
+    pub unsafe fn HeapProfilerStop() {}
+
+    pub unsafe fn GetHeapProfile() -> *mut c_char {
+        libc::strdup(b"\0".as_ptr() as *const c_char)
+    }
+}
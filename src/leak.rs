@@ -0,0 +1,269 @@
+//! Bindings to the gperftools heap leak checker
+//!
+//! Available with the `heap` feature, which links libtcmalloc.
+//!
+//! A `LeakChecker` snapshots the live heap objects when it is created, and
+//! `LeakChecker::no_leaks` checks that every object allocated since is
+//! either freed or still reachable. This is gperftools' `HeapLeakChecker`,
+//! which only checks when the program was started with `HEAPCHECK` set,
+//! such as `HEAPCHECK=local` or `HEAPCHECK=normal`, and only on Linux. In
+//! any other case every check passes, see `LeakChecker::is_active`. The
+//! leaks found are reported on stderr by the library, with the command to
+//! inspect them with pprof.
+//!
+//! Leak checking and heap profiling both hook tcmalloc's allocations, so
+//! they are not run together: a checker cannot be created while the
+//! `HEAP_PROFILER` is `Active`, and the heap profiler cannot be started
+//! while a checker exists. The cpu profiler may run meanwhile.
+//!
+//! # Examples
+//!
+//! ```
+//! use cpuprofiler::leak::LeakChecker;
+//!
+//! let mut checker = LeakChecker::new("parse").unwrap();
+//! // Code you want to check goes here!
+//! assert!(checker.no_leaks().unwrap());
+//! ```
+
+use std::ffi::CString;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use error::Error;
+use heap::HEAP_PROFILER;
+use ProfilerState;
+
+/// The number of checkers which exist
+static CHECKERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns whether a leak checker exists
+pub(crate) fn checking() -> bool {
+    CHECKERS.load(Ordering::SeqCst) > 0
+}
+
+/// What a `LeakChecker` found
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Leaks {
+    /// The bytes allocated since the checker was created which are neither
+    /// freed nor reachable
+    pub bytes: i64,
+    /// The objects allocated since the checker was created which are
+    /// neither freed nor reachable
+    pub objects: i64,
+}
+
+/// Checks a section of the program for heap leaks
+///
+/// The section starts when the checker is created and ends when it is
+/// checked. A checker is `Active` until it is checked, and can only be
+/// checked once.
+pub struct LeakChecker {
+    label: String,
+    // The C++ object is referred to by address, so it is kept in place.
+    raw: Box<ffi::RawChecker>,
+    leaks: Option<Leaks>,
+}
+
+impl fmt::Debug for LeakChecker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LeakChecker")
+            .field("label", &self.label)
+            .field("leaks", &self.leaks)
+            .finish()
+    }
+}
+
+impl LeakChecker {
+    /// Returns whether leaks are checked for, which needs the program to
+    /// have been started with `HEAPCHECK` set
+    pub fn is_active() -> bool {
+        unsafe { ffi::is_active() }
+    }
+
+    /// Start checking for leaks, naming the check `label`
+    ///
+    /// The label is used in the library's report and the names of the heap
+    /// profiles it writes to inspect the leaks.
+    ///
+    /// # Failures
+    ///
+    /// - The heap profiler is currently `Active`.
+    /// - `label` is not a valid `CString`.
+    pub fn new<T: Into<String>>(label: T) -> Result<LeakChecker, Error> {
+        let label = label.into();
+        let c_label = CString::new(label.clone())?;
+        // Held so that the heap profiler cannot start before the checker
+        // is counted.
+        let heap = HEAP_PROFILER.lock().unwrap();
+        heap.check_inactive()?;
+
+        let mut raw = Box::new(ffi::RawChecker::new());
+        // The library copies the name.
+        unsafe {
+            ffi::construct(&mut *raw, c_label.as_ptr());
+        }
+        CHECKERS.fetch_add(1, Ordering::SeqCst);
+        Ok(LeakChecker {
+            label,
+            raw,
+            leaks: None,
+        })
+    }
+
+    /// Returns the label of the check
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Returns `Active` until the checker has been checked
+    pub fn state(&self) -> ProfilerState {
+        if self.leaks.is_none() {
+            ProfilerState::Active
+        } else {
+            ProfilerState::NotActive
+        }
+    }
+
+    /// Check for leaks since the checker was created, returning `true` if
+    /// there are none
+    ///
+    /// The leaks found are reported on stderr and returned by `leaks`.
+    ///
+    /// # Failures
+    ///
+    /// - The checker has already been checked, it is `NotActive`.
+    pub fn no_leaks(&mut self) -> Result<bool, Error> {
+        if self.leaks.is_some() {
+            return Err(Error::InvalidState(self.state()));
+        }
+        let none = unsafe { ffi::no_leaks(&mut *self.raw) };
+        let leaks = unsafe {
+            Leaks {
+                bytes: ffi::bytes_leaked(&*self.raw) as i64,
+                objects: ffi::objects_leaked(&*self.raw) as i64,
+            }
+        };
+        self.leaks = Some(leaks);
+        Ok(none)
+    }
+
+    /// Returns the leaks found by `no_leaks`, `None` before checking
+    pub fn leaks(&self) -> Option<Leaks> {
+        self.leaks
+    }
+}
+
+impl Drop for LeakChecker {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::destruct(&mut *self.raw);
+        }
+        CHECKERS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Declarations of the `HeapLeakChecker` members
+///
+/// The class has no C interface, so its members are linked by their
+/// Itanium C++ ABI names, with the object as the first argument.
+#[cfg(not(feature = "disabled"))]
+mod ffi {
+    use std::os::raw::{c_char, c_int};
+
+    use libc::ssize_t;
+
+    /// Storage for a `HeapLeakChecker`, which is 56 bytes in gperftools 2
+    #[repr(C, align(8))]
+    pub struct RawChecker([u64; 16]);
+
+    impl RawChecker {
+        pub fn new() -> RawChecker {
+            RawChecker([0; 16])
+        }
+    }
+
+    /// `HeapLeakChecker::DO_NOT_SYMBOLIZE`, as `NoLeaks` passes it
+    const DO_NOT_SYMBOLIZE: c_int = 1;
+
+    extern "C" {
+        #[link_name = "_ZN15HeapLeakChecker8IsActiveEv"]
+        fn HeapLeakChecker_IsActive() -> bool;
+
+        #[link_name = "_ZN15HeapLeakCheckerC1EPKc"]
+        fn HeapLeakChecker_HeapLeakChecker(this: *mut RawChecker, name: *const c_char);
+
+        #[link_name = "_ZN15HeapLeakCheckerD1Ev"]
+        fn HeapLeakChecker_destructor(this: *mut RawChecker);
+
+        #[link_name = "_ZN15HeapLeakChecker9DoNoLeaksENS_15ShouldSymbolizeE"]
+        fn HeapLeakChecker_DoNoLeaks(this: *mut RawChecker, should_symbolize: c_int) -> bool;
+
+        #[link_name = "_ZNK15HeapLeakChecker11BytesLeakedEv"]
+        fn HeapLeakChecker_BytesLeaked(this: *const RawChecker) -> ssize_t;
+
+        #[link_name = "_ZNK15HeapLeakChecker13ObjectsLeakedEv"]
+        fn HeapLeakChecker_ObjectsLeaked(this: *const RawChecker) -> ssize_t;
+    }
+
+    pub unsafe fn is_active() -> bool {
+        HeapLeakChecker_IsActive()
+    }
+
+    pub unsafe fn construct(this: *mut RawChecker, name: *const c_char) {
+        HeapLeakChecker_HeapLeakChecker(this, name)
+    }
+
+    pub unsafe fn destruct(this: *mut RawChecker) {
+        HeapLeakChecker_destructor(this)
+    }
+
+    pub unsafe fn no_leaks(this: *mut RawChecker) -> bool {
+        HeapLeakChecker_DoNoLeaks(this, DO_NOT_SYMBOLIZE)
+    }
+
+    pub unsafe fn bytes_leaked(this: *const RawChecker) -> ssize_t {
+        HeapLeakChecker_BytesLeaked(this)
+    }
+
+    pub unsafe fn objects_leaked(this: *const RawChecker) -> ssize_t {
+        HeapLeakChecker_ObjectsLeaked(this)
+    }
+}
+
+/// No-op stand-ins used when profiling is compiled out, which never find
+/// leaks
+#[cfg(feature = "disabled")]
+mod ffi {
+    use std::os::raw::c_char;
+
+    use libc::ssize_t;
+
+    pub struct RawChecker;
+
+    impl RawChecker {
+        pub fn new() -> RawChecker {
+            RawChecker
+        }
+    }
+
+    pub unsafe fn is_active() -> bool {
+        false
+    }
+
+    pub unsafe fn construct(_this: *mut RawChecker, _name: *const c_char) {}
+
+    pub unsafe fn destruct(_this: *mut RawChecker) {}
+
+    pub unsafe fn no_leaks(_this: *mut RawChecker) -> bool {
+        true
+    }
+
+    pub unsafe fn bytes_leaked(_this: *const RawChecker) -> ssize_t {
+        0
+    }
+
+    pub unsafe fn objects_leaked(_this: *const RawChecker) -> ssize_t {
+        0
+    }
+}
@@ -16,6 +16,25 @@
 //! There may be some other dependencies for your system - these are explained well in their
 //! [INSTALL](https://github.com/gperftools/gperftools/blob/master/INSTALL) document.
 //! For example [libunwind](http://download.savannah.gnu.org/releases/libunwind/) (> 0.99.0) is required for 64 bit systems.
+//! To check an installation, run the `cpuprofiler-selftest` binary, which
+//! profiles a busy function and checks that it shows up in the profile, see
+//! [`self_test`](fn.self_test.html).
+//!
+//! On macOS gperftools installed with Homebrew (`brew install gperftools`)
+//! or MacPorts (`port install gperftools`) is found by the build. Profiling
+//! there can start and still record nothing, which
+//! [`check_sampling`](fn.check_sampling.html) detects.
+//!
+//! gperftools does not profile on Windows. There, disable the default features
+//! and enable `sampler`, which profiles without it:
+//!
+//! ```toml
+//! [dependencies]
+//! cpuprofiler = { version = "0.0.4", default-features = false, features = ["sampler"] }
+//! ```
+//!
+//! Signals, forks, control sockets and profiling child processes are only
+//! supported on Unix.
 //!
 //! # Usage
 //!
@@ -30,51 +49,312 @@
 //! The profiler is accessed via the static `PROFILER: Mutex<Profiler>`.
 //! We limit access this way to ensure that only one profiler is running at a time -
 //! this is a limitation of the cpuprofiler library.
+//!
+//! The free functions [`start`](fn.start.html) and [`stop`](fn.stop.html) lock
+//! `PROFILER` for you, and keep working if a thread panicked while holding it.
+//! [`Profiler::take`](struct.Profiler.html#method.take) hands out exclusive
+//! ownership of the profiler instead, see the [`handle`](handle/index.html) module,
+//! and [`Profiler::start_async`](struct.Profiler.html#method.start_async) returns a
+//! guard which can be held across `.await` points.
+//! [`Profiler::with_profile`](struct.Profiler.html#method.with_profile) profiles
+//! a closure, and rejects nested profiles while it runs, see the
+//! [`scope`](scope/index.html) module. To take turns with other parts of the
+//! program rather than fail while they profile, queue profiles with
+//! [`Profiler::enqueue`](struct.Profiler.html#method.enqueue).
+//!
+//! To choose how samples are taken, such as sampling the wall clock instead
+//! of CPU time, start the profiler with a
+//! [`ProfilerBuilder`](builder/struct.ProfilerBuilder.html). To sample a
+//! hot loop faster than the rest of a profile, run it in
+//! [`Profiler::with_frequency`](struct.Profiler.html#method.with_frequency).
+//! To keep samples out of `exec`, `dlopen` or code patching, run them in
+//! [`Profiler::suspend_around`](struct.Profiler.html#method.suspend_around).
+//! To record when, where and how each profile was taken, turn on
+//! [`Profiler::set_metadata`](struct.Profiler.html#method.set_metadata).
+//! To check that a host is ready to profile before the capture window, use
+//! [`Profiler::validate`](struct.Profiler.html#method.validate).
+//! To keep profiling from costing too much in production, run a
+//! [`Watchdog`](watchdog/struct.Watchdog.html). To start and stop profiles
+//! of a live process from the outside, listen on a
+//! [control socket](control/index.html). To see how CPU use changed over a
+//! long capture, split it into [time slices](slices/index.html). To profile
+//! many short sections of code into one profile, use a
+//! [`SectionProfiler`](sections/struct.SectionProfiler.html).
+//! The samples of each thread are counted too, and threads can be named with
+//! [`Profiler::name_thread`](struct.Profiler.html#method.name_thread), see
+//! [`Profile::threads`](profile/struct.Profile.html#method.threads).
+//!
+//! # Cargo features
+//!
+//! - `gperftools` (default): the [`Gperftools`](backend/struct.Gperftools.html) backend,
+//!   which links libprofiler, and its raw functions in [`ffi`](ffi/index.html).
+//!   For builds where binary size matters, [`minimal`](minimal/index.html)
+//!   only starts, flushes and stops the library.
+//! - `vendored`: build libprofiler from a gperftools release and link it
+//!   statically, instead of using the system library. The release is downloaded,
+//!   and its SHA-256 checked, unless `GPERFTOOLS_SRC` points at an unpacked
+//!   copy. Building needs `make` and a C++ compiler.
+//! - `static`: link the system's static libprofiler, and libunwind when it
+//!   uses it, so that programs can be built as single static executables, for
+//!   example for musl targets. The archives are looked for in
+//!   `GPERFTOOLS_LIB_DIR`, then where pkg-config and the usual library
+//!   directories have them. With `vendored` the libraries built from source
+//!   are linked instead; for musl targets build them with `CC=musl-gcc`.
+//! - `ctor`: call [`init_from_env`](fn.init_from_env.html) before `main`, so that
+//!   setting `CPUPROFILE` profiles the program without any code changes.
+//! - `dylib-load`: load libprofiler when the profiler is first started instead
+//!   of linking it, so binaries still run on hosts without gperftools. See
+//!   [`Profiler::is_available`](struct.Profiler.html#method.is_available).
+//! - `heap`: the [`heap`](heap/index.html) module, binding the gperftools heap
+//!   profiler, the [`leak`](leak/index.html) module binding its heap leak
+//!   checker, the [`session`](session/index.html) module profiling cpu and
+//!   heap together, and [`tcmalloc`](tcmalloc/index.html) allocator statistics.
+//!   This links libtcmalloc, which replaces the system allocator.
+//! - `sampler`: the pure Rust [`Sampler`](backend/struct.Sampler.html) backend,
+//!   the only backend on Windows.
+//! - `test-util`: the [`mock`](mock/index.html) backend, for unit testing
+//!   code which profiles without libprofiler. With only this feature the mock
+//!   is the default backend.
+//! - `serde`: `Serialize` and `Deserialize` for `ProfilerState`,
+//!   [`Transition`](transition/struct.Transition.html),
+//!   [`ProfileSummary`](summary/struct.ProfileSummary.html), the
+//!   [metrics snapshot](metrics/struct.Snapshot.html), the threads of a
+//!   [`Profile`](profile/struct.Profile.html) and the
+//!   [`report`](report/index.html) types, to emit them as JSON.
+//! - `agent`: the [`agent`](agent/index.html) module for continuous profiling.
+//! - `cloud-profiler`: the [`cloud_profiler`](cloud_profiler/index.html)
+//!   module, profiling the process whenever Google Cloud Profiler asks.
+//! - `upload`, `s3` and `gcs`: [sinks](sink/index.html) uploading finished
+//!   profiles with HTTP `PUT` requests, to Amazon S3 and to Google Cloud
+//!   Storage.
+//! - `symbolize`: the [`symbolize`](symbolize/index.html) module, resolving
+//!   profile addresses to function names for the [`report`](report/index.html)s.
+//! - `debuginfod`: download the debug info of stripped binaries from
+//!   debuginfod servers when symbolizing.
+//! - `cli`: the `cargo cpuprofiler` subcommand, which profiles any program and
+//!   prints a report of the most sampled functions.
+//! - `tower`: the [`middleware`](middleware/index.html) module, profiling HTTP
+//!   requests which ask for it.
+//! - `tracing`: the [`trace`](trace/index.html) module, profiling selected
+//!   `tracing` spans.
+//! - `log`: log when profiles start, pause, resume, flush and stop through
+//!   the `log` facade, with the target `cpuprofiler`.
+//! - `metrics`: the [`metrics`](metrics/index.html) module, counting profiler
+//!   activity for Prometheus.
+//! - `otel`: the [`otel`](otel/index.html) module, exporting profiling
+//!   sessions as OpenTelemetry spans.
+//! - `disabled`: compile the profiler out. The API is unchanged and still tracks
+//!   the profiler state but nothing is sampled, no files are written and
+//!   libprofiler is not linked. This lets profiling calls stay in the code base
+//!   and only be enabled for profiling builds.
 
 #![warn(missing_debug_implementations)]
 
-#[macro_use]
-extern crate error_chain;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
+#[cfg(feature = "sampler")]
+extern crate backtrace;
+#[cfg(any(feature = "agent", feature = "upload", feature = "debuginfod", feature = "otel"))]
+extern crate ureq;
+#[cfg(feature = "s3")]
+extern crate ring;
+#[cfg(feature = "cloud-profiler")]
+extern crate flate2;
+#[cfg(feature = "dylib-load")]
+extern crate libloading;
+#[cfg(feature = "ctor")]
+extern crate ctor;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "tracing")]
+extern crate tracing_subscriber;
+#[cfg(feature = "tower")]
+extern crate http;
+#[cfg(feature = "tower")]
+extern crate tower_layer;
+#[cfg(feature = "tower")]
+extern crate tower_service;
+#[cfg(feature = "symbolize")]
+extern crate addr2line;
+#[cfg(feature = "symbolize")]
+extern crate object;
+#[cfg(feature = "log")]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(windows)]
+extern crate windows_sys;
 
+pub mod backend;
+pub mod bench;
+pub mod builder;
+pub mod capture;
+#[cfg(unix)]
+pub mod command;
+#[cfg(unix)]
+pub mod control;
+#[cfg(feature = "cloud-profiler")]
+pub mod cloud_profiler;
 pub mod error;
+#[cfg(unix)]
+pub mod fork;
+#[cfg(feature = "gperftools")]
+pub mod ffi;
+pub mod guard;
+pub mod handle;
+#[cfg(feature = "heap")]
+pub mod heap;
+#[cfg(feature = "heap")]
+pub mod leak;
+pub mod memory;
+pub mod metadata;
+#[cfg(feature = "gperftools")]
+pub mod minimal;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "tower")]
+pub mod middleware;
+#[cfg(feature = "test-util")]
+pub mod mock;
+pub mod options;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod profile;
+pub mod queue;
+pub mod policy;
+pub mod pprof;
+pub mod report;
+pub mod rotate;
+pub mod scope;
+pub mod sections;
+#[cfg(feature = "heap")]
+pub mod session;
+#[cfg(unix)]
+pub mod signal;
+pub mod sink;
+pub mod slices;
+pub mod summary;
+#[cfg(feature = "symbolize")]
+pub mod symbolize;
+pub mod tasks;
+pub mod testing;
+#[cfg(feature = "heap")]
+pub mod tcmalloc;
+pub mod timed;
+#[cfg(feature = "tracing")]
+pub mod trace;
+pub mod transition;
+pub mod validate;
+pub mod watchdog;
+#[cfg(feature = "agent")]
+pub mod agent;
+
+mod availability;
+mod bootstrap;
+mod buildid;
+#[cfg(feature = "symbolize")]
+mod debuginfo;
+mod exit;
+mod fallback;
+mod filter;
+mod frequency;
+#[cfg(any(feature = "gcs", feature = "cloud-profiler"))]
+mod gce;
+mod json;
+mod lifecycle;
+mod lock;
+#[cfg(unix)]
+mod mask;
+mod panic;
+mod paths;
+mod pattern;
+mod resync;
+#[cfg(unix)]
+mod selfpipe;
+mod template;
+mod threads;
+mod timestamp;
+#[cfg(feature = "agent")]
+mod toml;
 
+use std::collections::HashMap;
+use std::env;
 use std::ffi::CString;
 use std::fmt;
-use std::fs::File;
-use std::os::raw::c_char;
-use std::path::Path;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use backend::ProfilerBackend;
+use error::Error;
 
-use error::{Error, ErrorKind};
+pub use availability::{check_sampling, is_functional, is_linked, library_version, self_test, SelfTest};
+pub use bootstrap::init_from_env;
+pub use lock::{start, stop, try_start, try_stop};
+pub use panic::install_panic_hook;
 
 use std::sync::Mutex;
+use std::time::Instant;
 
 lazy_static! {
     /// Static reference to the PROFILER
     ///
     /// The cpuprofiler library only supports one active profiler.
     /// Because of this we must use static access and wrap in a `Mutex`.
+    ///
+    /// When it is first locked the profiler takes over a profile the
+    /// cpuprofiler library is already writing, such as one started by
+    /// itself from `CPUPROFILE`, see `Profiler::current_path`.
     #[derive(Debug)]
-    pub static ref PROFILER: Mutex<Profiler> = Mutex::new(Profiler {
+    pub static ref PROFILER: Mutex<Profiler> = Mutex::new(Profiler::resynced(Profiler {
         state: ProfilerState::NotActive,
-    });
-}
-
-#[allow(non_snake_case)]
-extern "C" {
-    fn ProfilerStart(fname: *const c_char) -> i32;
-
-    fn ProfilerStop();
+        session: 0,
+        started: None,
+        path: None,
+        in_memory: None,
+        adopted: false,
+        taken: false,
+        last_frequency: None,
+        metadata: false,
+        metadata_env: Vec::new(),
+        create_dirs: false,
+        fallback_dirs: Vec::new(),
+        requested: None,
+        stack_trim: profile::StackTrim::new(),
+        labels: Vec::new(),
+        runs: HashMap::new(),
+        observers: Default::default(),
+        policy: None,
+        parts: Vec::new(),
+        backend: backend::default_backend(),
+    }));
 }
 
 /// The state of the profiler
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ProfilerState {
     /// When the profiler is active
     Active,
     /// When the profiler is inactive
     NotActive,
+    /// When a profile is running but no samples are taken, see `Profiler::pause`
+    Paused,
+    /// While samples are written out, when flushing or stopping the profile
+    ///
+    /// The profiler is locked while flushing, so this state is only seen by
+    /// observers registered with `Profiler::on_transition`.
+    Flushing,
+}
+
+impl ProfilerState {
+    /// Returns whether a profile has been started and not yet stopped
+    pub fn is_running(self) -> bool {
+        self != ProfilerState::NotActive
+    }
 }
 
 impl fmt::Display for ProfilerState {
@@ -82,21 +362,54 @@ impl fmt::Display for ProfilerState {
         match *self {
             ProfilerState::Active => write!(f, "Active"),
             ProfilerState::NotActive => write!(f, "NotActive"),
+            ProfilerState::Paused => write!(f, "Paused"),
+            ProfilerState::Flushing => write!(f, "Flushing"),
         }
     }
 }
 
 /// The `Profiler`
 ///
-/// The `Profiler` gives access to the _cpuprofiler_ library.
+/// The `Profiler` gives access to the _cpuprofiler_ library, or another
+/// [backend](backend/index.html).
 /// By storing the state of the profiler and limiting access
 /// we make the FFI safer.
 #[derive(Debug)]
 pub struct Profiler {
     state: ProfilerState,
+    session: u64,
+    started: Option<Instant>,
+    path: Option<PathBuf>,
+    in_memory: Option<memory::InMemory>,
+    /// Whether the running profile was adopted from the library when
+    /// `PROFILER` was first locked, until `init_from_env` claims it
+    adopted: bool,
+    taken: bool,
+    last_frequency: Option<u64>,
+    metadata: bool,
+    metadata_env: Vec<String>,
+    create_dirs: bool,
+    fallback_dirs: Vec<PathBuf>,
+    /// The path the running profile was started with, when it fell back
+    /// from it
+    requested: Option<PathBuf>,
+    stack_trim: profile::StackTrim,
+    labels: Vec<(String, String)>,
+    runs: HashMap<PathBuf, u64>,
+    observers: transition::Observers,
+    policy: Option<policy::Enforced>,
+    /// Samples of the running profile kept aside by `with_frequency`, to
+    /// be merged back into it when it stops
+    parts: Vec<profile::Profile>,
+    backend: Box<dyn ProfilerBackend>,
 }
 
 impl Profiler {
+    /// Take over the profile the library is writing, see `PROFILER`
+    fn resynced(mut profiler: Profiler) -> Profiler {
+        profiler.adopted = profiler.adopt_library_profile();
+        profiler
+    }
 
     /// Returns the profiler state
     ///
@@ -111,67 +424,496 @@ impl Profiler {
         self.state
     }
 
+    /// Returns the file the running profile is written to
+    ///
+    /// This is `None` when the profiler is `NotActive`, and for profiles
+    /// kept in memory. For a profile the cpuprofiler library started by
+    /// itself it is the name the library was given.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use cpuprofiler::PROFILER;
+    ///
+    /// let path = env::temp_dir().join("current-path-example.profile");
+    /// let mut profiler = PROFILER.lock().unwrap();
+    /// profiler.start(path.to_str().unwrap()).unwrap();
+    /// assert!(profiler.current_path().unwrap().ends_with("current-path-example.profile"));
+    /// profiler.stop().unwrap();
+    /// assert_eq!(profiler.current_path(), None);
+    /// ```
+    pub fn current_path(&self) -> Option<&Path> {
+        if self.in_memory.is_some() {
+            return None;
+        }
+        self.path.as_deref()
+    }
+
+    /// Returns whether the cpuprofiler library is available
+    ///
+    /// The library is always available when it is linked. With the
+    /// `dylib-load` feature this reports whether it could be loaded, and the
+    /// `Gperftools` backend fails to start when it is not. Other backends do
+    /// not need the library.
+    ///
+    /// This is `false` when the `gperftools` feature is not enabled or
+    /// profiling is `disabled`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cpuprofiler::Profiler;
+    ///
+    /// if !Profiler::is_available() {
+    ///     println!("libprofiler was not found, profiling is unavailable");
+    /// }
+    /// ```
+    pub fn is_available() -> bool {
+        availability::is_linked()
+    }
+
     /// Start the profiler
     ///
     /// Will begin sampling once this function has been called
     /// and will not stop until the `stop` function has been called.
     ///
     /// This function takes as an argument a filename. The filename must be
-    /// both valid Utf8 and a valid `CString`.
+    /// a valid `CString`, it does not need to be valid Utf8. To start the
+    /// profiler with a `Path` use `start_path`.
+    ///
+    /// The file is created if it does not exist and is truncated otherwise.
+    /// Relative paths are resolved against the current directory when the
+    /// profile starts. Missing parent directories are only created after
+    /// `set_create_dirs(true)`, and a profile which cannot be written to a
+    /// read only path is only written elsewhere after `set_fallback_dirs`.
+    ///
+    /// The filename may contain the following tokens which are expanded
+    /// before the profiler is started:
+    ///
+    /// - `%p`: the process id.
+    /// - `%t`: the current UTC time, as `YYYY-MM-DDTHH:MM:SS`.
+    /// - `%h`: the hostname.
+    /// - `%n`: the number of profiles previously started by this process, as four digits.
+    /// - `%%`: a literal `%`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use cpuprofiler::PROFILER;
+    ///
+    /// let template = env::temp_dir().join("template-%p-%n.profile");
+    /// PROFILER.lock().unwrap().start(template.to_str().unwrap()).unwrap();
+    /// PROFILER.lock().unwrap().stop().unwrap();
+    /// ```
     ///
     /// # Failures
     ///
-    /// - The profiler is currently `Active`.
-    /// - `fname` is not a valid `CString`.
-    /// - `fname` is not valid Utf8.
-    /// - `fname` is not a file.
-    /// - The user does not have write access to the file.
-    /// - An internal failure from the cpuprofiler library.
+    /// - The profiler is currently `Active`, `Error::InvalidState`.
+    /// - The profiler has been taken by a `ProfilerHandle`, see
+    ///   `Profiler::take`, `Error::Busy`.
+    /// - `fname` is not a valid `CString`, `Error::Nul`.
+    /// - `fname` is a directory, `Error::OutputPath`.
+    /// - The parent directory does not exist, or could not be created,
+    ///   `Error::OutputPath`.
+    /// - The user does not have write access to the file, or to its
+    ///   parent directory if it does not exist yet, `Error::OutputPath`.
+    /// - The cpuprofiler library could not be loaded, with `dylib-load`,
+    ///   `Error::LibraryUnavailable`.
+    /// - The cpuprofiler library refused to start, `Error::StartRejected`.
     pub fn start<T: Into<Vec<u8>>>(&mut self, fname: T) -> Result<(), Error> {
+        self.start_labelled(fname, Vec::new())
+    }
+
+    /// Start the profiler, attaching `labels` to the profile
+    pub(crate) fn start_labelled<T: Into<Vec<u8>>>(&mut self, fname: T, labels: Vec<(String, String)>) -> Result<(), Error> {
+        if self.taken {
+            return Err(Error::Busy);
+        }
         if self.state == ProfilerState::NotActive {
-            let c_fname = try!(CString::new(fname));
-            try!(check_file_path(c_fname.clone().into_string().unwrap()));
-
-            unsafe {
-                let res = ProfilerStart(c_fname.as_ptr());
-                if res == 0 {
-                    Err(ErrorKind::InternalError.into())
-                } else {
-                    self.state = ProfilerState::Active;
-                    Ok(())
-                }
+            let mut c_fname = CString::new(template::expand(&fname.into(), self.session))?;
+            let mut requested = None;
+            if cfg!(not(feature = "disabled")) {
+                let asked = paths::from_bytes(c_fname.as_bytes());
+                let (path, fell_back) = self.resolve_fallback(&asked)?;
+                c_fname = CString::new(paths::to_bytes(&path))?;
+                requested = if fell_back { Some(asked) } else { None };
+            }
+
+            threads::reset();
+            let path = paths::from_bytes(c_fname.as_bytes());
+            if let Err(e) = self.backend.start(&c_fname) {
+                lifecycle::start_failed(&path, &e);
+                return Err(e);
             }
+            lifecycle::started(&path, self.backend.frequency());
+            self.session += 1;
+            self.started = Some(Instant::now());
+            self.path = Some(path);
+            self.requested = requested;
+            self.labels = labels;
+            set_running(self.path.as_deref(), self.backend.signal());
+            self.transition(ProfilerState::Active);
+            Ok(())
         } else {
-            Err(ErrorKind::InvalidState(self.state).into())
+            Err(Error::InvalidState(self.state))
         }
     }
 
+    /// Start the profiler, writing to `path`
+    ///
+    /// This behaves like `start` but accepts any path the operating system
+    /// does, including paths which are not valid Utf8. The path is used as
+    /// it is, without expanding the `%` tokens of `start`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use cpuprofiler::PROFILER;
+    ///
+    /// let path = env::temp_dir().join("path-example.profile");
+    /// PROFILER.lock().unwrap().start_path(&path).unwrap();
+    /// PROFILER.lock().unwrap().stop().unwrap();
+    /// ```
+    ///
+    /// # Failures
+    ///
+    /// - See `start`.
+    pub fn start_path<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        self.start(template::escape(&paths::to_bytes(path.as_ref())))
+    }
+
+    /// Create missing parent directories of the profiles started from now on
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use cpuprofiler::PROFILER;
+    ///
+    /// let path = env::temp_dir().join("create-dirs-example").join("run1").join("cpu.profile");
+    /// let mut profiler = PROFILER.lock().unwrap();
+    /// profiler.set_create_dirs(true);
+    /// profiler.start(path.to_str().unwrap()).unwrap();
+    /// profiler.stop().unwrap();
+    /// ```
+    pub fn set_create_dirs(&mut self, enabled: bool) {
+        self.create_dirs = enabled;
+    }
+
+    /// Returns whether missing parent directories of profiles are created
+    pub fn creates_dirs(&self) -> bool {
+        self.create_dirs
+    }
+
+    /// Start the profiler, writing to the next numbered file for `prefix`
+    ///
+    /// Each call writes `<prefix>.0000.profile`, `<prefix>.0001.profile` and
+    /// so on, like the dumps of the gperftools heap profiler, so profiling
+    /// the same code repeatedly never overwrites an earlier run. The numbers
+    /// are counted separately for each prefix, from 0 in each process, and
+    /// only advance when the profiler starts. Use `%p` in the prefix to keep
+    /// the files of different processes apart.
+    ///
+    /// Returns the path of the profile.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use cpuprofiler::PROFILER;
+    ///
+    /// let prefix = env::temp_dir().join("next-example");
+    /// let mut profiler = PROFILER.lock().unwrap();
+    /// for _ in 0..3 {
+    ///     let path = profiler.start_next(&prefix).unwrap();
+    ///     // Code you want to sample goes here!
+    ///     profiler.stop().unwrap();
+    ///     println!("profiled into {}", path.display());
+    /// }
+    /// ```
+    ///
+    /// # Failures
+    ///
+    /// - See `start`.
+    pub fn start_next<P: AsRef<Path>>(&mut self, prefix: P) -> Result<PathBuf, Error> {
+        let prefix = prefix.as_ref();
+        let run = self.runs.get(prefix).cloned().unwrap_or(0);
+
+        let mut fname = paths::to_bytes(prefix);
+        fname.extend_from_slice(format!(".{:04}.profile", run).as_bytes());
+        self.start(fname)?;
+
+        self.runs.insert(prefix.to_owned(), run + 1);
+        Ok(self.path.clone().unwrap_or_default())
+    }
+
     /// Stop the profiler.
     ///
-    /// This will stop the profiler if it `Active` and return
+    /// This will stop the profiler if it `Active` or `Paused` and return
     /// an error otherwise.
     ///
     /// # Failures
     ///
     /// - The profiler is `NotActive`.
     pub fn stop(&mut self) -> Result<(), Error> {
-        if self.state == ProfilerState::Active {
-            unsafe {
-                ProfilerStop();
+        self.stop_profile().map(|_| ())
+    }
+
+    /// Stop the profiler, returning the memory of a profile kept in memory
+    ///
+    /// It is only taken once the backend stopped, so a profile which could
+    /// not be stopped keeps it.
+    pub(crate) fn stop_profile(&mut self) -> Result<Option<memory::InMemory>, Error> {
+        if self.state.is_running() {
+            let previous = self.state;
+            self.transition(ProfilerState::Flushing);
+            let samples = self.backend.samples_gathered();
+            if let Err(e) = self.backend.stop() {
+                lifecycle::failed("stop", &e);
+                self.transition(previous);
+                return Err(e);
             }
-            self.state = ProfilerState::NotActive;
-            Ok(())
+            lifecycle::stopped(self.path.as_deref(), self.started.map(|started| started.elapsed()), samples);
+            self.last_frequency = self.path.as_ref().and_then(|path| profile::header_frequency(path));
+            set_running(None, None);
+            // A forked child must not write into its parent's profile or
+            // overwrite its metadata.
+            if !redirected() && cfg!(not(feature = "disabled")) {
+                if let Some(path) = self.path.as_ref() {
+                    // The thread counts are a nicety on top of the stacks.
+                    let _ = threads::append(path);
+                    let _ = buildid::append(path);
+                    let _ = self.stack_trim.append(path);
+                }
+                self.merge_parts();
+            }
+            self.parts.clear();
+            if self.metadata && self.in_memory.is_none() && !redirected() && cfg!(not(feature = "disabled")) {
+                if let (Some(path), Some(started)) = (self.path.as_ref(), self.started) {
+                    // The profile was written, so a missing description is not worth failing for.
+                    let _ = metadata::write(path, started.elapsed(), self.last_frequency, &self.labels, &self.metadata_env);
+                }
+            }
+            self.started = None;
+            self.path = None;
+            self.requested = None;
+            let memory = self.in_memory.take();
+            self.adopted = false;
+            self.transition(ProfilerState::NotActive);
+            Ok(memory)
         } else {
-            Err(ErrorKind::InvalidState(self.state).into())
+            Err(Error::InvalidState(self.state))
+        }
+    }
+
+    /// Stop taking samples without ending the profile
+    ///
+    /// The profile continues with `resume`, or ends as usual with `stop`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use cpuprofiler::PROFILER;
+    ///
+    /// let path = env::temp_dir().join("pause-example.profile");
+    /// let mut profiler = PROFILER.lock().unwrap();
+    /// profiler.start(path.to_str().unwrap()).unwrap();
+    /// // Code you want to sample goes here!
+    /// profiler.pause().unwrap();
+    /// // Code you want to leave out goes here.
+    /// profiler.resume().unwrap();
+    /// profiler.stop().unwrap();
+    /// ```
+    ///
+    /// # Failures
+    ///
+    /// - The profiler is not `Active`.
+    /// - The backend cannot pause, such as the cpuprofiler library when it
+    ///   started the profile by itself from `CPUPROFILE`.
+    pub fn pause(&mut self) -> Result<(), Error> {
+        if self.state != ProfilerState::Active {
+            return Err(Error::InvalidState(self.state));
+        }
+        self.backend.pause()?;
+        lifecycle::paused(self.backend.samples_gathered());
+        self.transition(ProfilerState::Paused);
+        Ok(())
+    }
+
+    /// Take samples again after `pause`
+    ///
+    /// # Failures
+    ///
+    /// - The profiler is not `Paused`.
+    pub fn resume(&mut self) -> Result<(), Error> {
+        if self.state != ProfilerState::Paused {
+            return Err(Error::InvalidState(self.state));
         }
+        self.backend.resume()?;
+        lifecycle::resumed();
+        self.transition(ProfilerState::Active);
+        Ok(())
     }
+
+    /// Write the samples taken so far to the profile, without stopping it
+    ///
+    /// # Failures
+    ///
+    /// - The profiler is `NotActive`.
+    /// - The backend failed to write the samples.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if !self.state.is_running() {
+            return Err(Error::InvalidState(self.state));
+        }
+        let previous = self.state;
+        self.transition(ProfilerState::Flushing);
+        let res = self.backend.flush();
+        match res {
+            Ok(()) => lifecycle::flushed(self.backend.samples_gathered()),
+            Err(ref e) => lifecycle::failed("flush", e),
+        }
+        self.transition(previous);
+        res
+    }
+}
+
+/// Tell the fork handlers and the signal masks about the running profile
+#[cfg(unix)]
+fn set_running(path: Option<&Path>, signal: Option<libc::c_int>) {
+    fork::set_path(path);
+    mask::set_signal(signal);
+}
+
+#[cfg(not(unix))]
+fn set_running(_path: Option<&Path>, _signal: Option<libc::c_int>) {}
+
+/// Returns whether this process is a forked child whose profile was moved
+/// away from its parent's file
+#[cfg(unix)]
+fn redirected() -> bool {
+    fork::redirected()
+}
+
+#[cfg(not(unix))]
+fn redirected() -> bool {
+    false
+}
+
+/// Make `path` absolute and check that the profile can be written to it
+///
+/// The parent directory is canonicalized, so the profile keeps its place
+/// when the working directory changes, and is created first with
+/// `create_dirs`. Errors carry the absolute path.
+fn resolve_path(path: &Path, create_dirs: bool) -> Result<PathBuf, Error> {
+    let absolute = absolute_path(path)?;
+    let resolved = match (absolute.parent(), absolute.file_name()) {
+        (Some(parent), Some(name)) => {
+            if create_dirs {
+                fs::create_dir_all(parent).map_err(output_path(&absolute))?;
+            }
+            match parent.canonicalize() {
+                Ok(parent) => parent.join(name),
+                Err(_) => absolute,
+            }
+        }
+        _ => absolute,
+    };
+    check_path(&resolved).map_err(output_path(&resolved))?;
+    Ok(resolved)
 }
 
-fn check_file_path<P: AsRef<Path>>(path: P) -> Result<(), Error> {
-    let write_res = File::create(path);
+/// Check `path` like `resolve_path` does, without creating anything
+///
+/// With `create_dirs` a missing parent directory only has to be creatable
+/// in the closest directory which exists.
+fn check_output_path(path: &Path, create_dirs: bool) -> Result<PathBuf, Error> {
+    let absolute = absolute_path(path)?;
+    let missing = absolute.parent().filter(|parent| !parent.exists());
+    match missing {
+        Some(parent) if create_dirs => {
+            let existing = parent.ancestors().find(|dir| dir.exists()).unwrap_or_else(|| Path::new("/"));
+            let checked = if existing.is_dir() {
+                check_access(existing)
+            } else {
+                let reason = format!("{} is not a directory", existing.display());
+                Err(io::Error::new(io::ErrorKind::NotFound, reason))
+            };
+            checked.map_err(output_path(&absolute))?;
+            Ok(absolute)
+        }
+        _ => resolve_path(&absolute, false),
+    }
+}
+
+fn absolute_path(path: &Path) -> Result<PathBuf, Error> {
+    if path.is_absolute() {
+        Ok(path.to_owned())
+    } else {
+        Ok(env::current_dir().map_err(output_path(path))?.join(path))
+    }
+}
+
+fn output_path(path: &Path) -> impl FnOnce(io::Error) -> Error {
+    let path = path.to_owned();
+    move |source| Error::OutputPath { path, source }
+}
+
+/// Validate that the cpuprofiler library will be able to write to `path`
+///
+/// The library creates and truncates the file itself, so the file only
+/// needs to be writable if it already exists. Otherwise we require that
+/// its parent directory is writable.
+fn check_path(path: &Path) -> io::Result<()> {
+    match fs::metadata(path) {
+        Ok(ref meta) if meta.is_dir() => Err(io::Error::new(io::ErrorKind::InvalidInput, "profile path is a directory")),
+        Ok(_) => check_access(path),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            let parent = match path.parent() {
+                Some(p) if !p.as_os_str().is_empty() => p,
+                _ => Path::new("."),
+            };
+            match fs::metadata(parent) {
+                Ok(ref meta) if meta.is_dir() => {}
+                Ok(_) => {
+                    let reason = format!("{} is not a directory", parent.display());
+                    return Err(io::Error::new(io::ErrorKind::NotFound, reason));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    let reason = format!(
+                        "profile directory {} does not exist, create it or enable create_dirs",
+                        parent.display()
+                    );
+                    return Err(io::Error::new(io::ErrorKind::NotFound, reason));
+                }
+                Err(e) => return Err(e),
+            }
+            check_access(parent)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(unix)]
+fn check_access(path: &Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::access(c_path.as_ptr(), libc::W_OK) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
 
-    match write_res {
-        Ok(_) => Ok(()),
-        Err(e) => Err(e.into()),
+#[cfg(not(unix))]
+fn check_access(path: &Path) -> io::Result<()> {
+    if fs::metadata(path)?.permissions().readonly() {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "profile path is read only"))
+    } else {
+        Ok(())
     }
 }
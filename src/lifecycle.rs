@@ -0,0 +1,112 @@
+//! Logging the lifecycle of profiles
+//!
+//! The profiler reports every change of a profile's lifecycle here, which
+//! logs it and, with the `metrics` feature, counts it in the
+//! [`metrics`](../metrics/index.html) and, with the `otel` feature, exports
+//! it to [OpenTelemetry](../otel/index.html).
+//!
+//! With the `log` feature every profile logs, through the `log` facade and
+//! with the target `cpuprofiler`, when it starts, pauses, resumes, flushes
+//! and stops. Starting and stopping are logged at `info`, the rest at
+//! `debug` and failures of the backend at `warn`. Without the feature, or
+//! with `disabled`, nothing is logged.
+
+#![cfg_attr(not(feature = "log"), allow(unused_variables))]
+
+use std::path::Path;
+use std::time::Duration;
+
+use capture::Capture;
+use error::Error;
+use policy::Trigger;
+use watchdog::Trip;
+#[cfg(feature = "metrics")]
+use metrics;
+#[cfg(feature = "otel")]
+use otel;
+
+#[cfg(feature = "log")]
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {
+        if cfg!(not(feature = "disabled")) {
+            $level!(target: "cpuprofiler", $($arg)+);
+        }
+    };
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {{}};
+}
+
+/// A profile started writing to `path`
+pub(crate) fn started(path: &Path, frequency: Option<u32>) {
+    let with_hz = frequency.map(|hz| format!(" with frequency {} Hz", hz)).unwrap_or_default();
+    event!(info, "profiling started at {}{}", path.display(), with_hz);
+    #[cfg(feature = "metrics")]
+    metrics::started();
+    #[cfg(feature = "otel")]
+    otel::started(path, frequency);
+}
+
+/// The backend refused to start a profile at `path`
+pub(crate) fn start_failed(path: &Path, error: &Error) {
+    event!(warn, "profiling could not start at {}: {}", path.display(), error);
+    #[cfg(feature = "otel")]
+    otel::start_failed(path, error);
+}
+
+/// A profile which could not be written where asked is written to `path`
+pub(crate) fn fell_back(path: &Path, error: &Error) {
+    event!(warn, "profiling falls back to {}: {}", path.display(), error);
+}
+
+pub(crate) fn paused(samples: u64) {
+    event!(debug, "profiling paused after {} samples", samples);
+    #[cfg(feature = "otel")]
+    otel::event("paused", Some(samples));
+}
+
+pub(crate) fn resumed() {
+    event!(debug, "profiling resumed");
+    #[cfg(feature = "otel")]
+    otel::event("resumed", None);
+}
+
+/// The samples taken so far were written out
+pub(crate) fn flushed(samples: u64) {
+    event!(debug, "profile flushed {} samples", samples);
+    #[cfg(feature = "otel")]
+    otel::event("flushed", Some(samples));
+}
+
+/// The profile at `path` stopped after running for `elapsed`
+pub(crate) fn stopped(path: Option<&Path>, elapsed: Option<Duration>, samples: u64) {
+    let path = path.map(|path| path.display().to_string()).unwrap_or_default();
+    let secs = elapsed.map(|elapsed| elapsed.as_secs_f64()).unwrap_or(0.0);
+    event!(info, "profiling stopped after {:.3} seconds, {} samples written to {}", secs, samples, path);
+    #[cfg(feature = "metrics")]
+    metrics::stopped(elapsed, samples);
+    #[cfg(feature = "otel")]
+    otel::stopped(samples);
+}
+
+/// The watchdog stopped a profile which cost too much
+pub(crate) fn overhead(trip: &Trip) {
+    event!(warn, "profiling overhead too high, {}", trip);
+}
+
+/// An `AutoCapture` took a profile
+pub(crate) fn captured(capture: &Capture) {
+    event!(info, "high CPU use, {}", capture);
+}
+
+/// The policy refused to let `trigger` start a profile
+pub(crate) fn refused(trigger: Trigger, error: &Error) {
+    event!(warn, "{} trigger refused: {}", trigger, error);
+}
+
+/// The backend failed to stop or flush the profile
+pub(crate) fn failed(action: &str, error: &Error) {
+    event!(warn, "profile could not {}: {}", action, error);
+}
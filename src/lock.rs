@@ -0,0 +1,73 @@
+//! Access to `PROFILER` which survives panics
+
+use std::sync::{MutexGuard, TryLockError};
+
+use error::Error;
+use {Profiler, PROFILER};
+
+/// Lock `PROFILER`, recovering the lock if a thread panicked holding it
+///
+/// Every `Profiler` method leaves it in a consistent state before it can
+/// panic, so the profiler is safe to use after a poisoning panic.
+pub(crate) fn lock() -> MutexGuard<'static, Profiler> {
+    PROFILER.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Lock `PROFILER` without waiting for another thread
+pub(crate) fn try_lock() -> Result<MutexGuard<'static, Profiler>, Error> {
+    match PROFILER.try_lock() {
+        Ok(profiler) => Ok(profiler),
+        Err(TryLockError::Poisoned(e)) => Ok(e.into_inner()),
+        Err(TryLockError::WouldBlock) => Err(Error::Busy),
+    }
+}
+
+/// Start the profiler, see `Profiler::start`
+///
+/// Unlike `PROFILER.lock().unwrap()`, this keeps working if a thread
+/// panicked while holding the lock.
+///
+/// # Examples
+///
+/// ```
+/// let path = std::env::temp_dir().join("start-example.profile");
+/// cpuprofiler::start(path.to_str().unwrap()).unwrap();
+/// // Code you want to sample goes here!
+/// cpuprofiler::stop().unwrap();
+/// ```
+///
+/// # Failures
+///
+/// - See `Profiler::start`.
+pub fn start<T: Into<Vec<u8>>>(fname: T) -> Result<(), Error> {
+    lock().start(fname)
+}
+
+/// Stop the profiler, see `Profiler::stop`
+///
+/// # Failures
+///
+/// - See `Profiler::stop`.
+pub fn stop() -> Result<(), Error> {
+    lock().stop()
+}
+
+/// Start the profiler unless another thread holds the lock
+///
+/// # Failures
+///
+/// - Another thread holds the `PROFILER` lock.
+/// - See `Profiler::start`.
+pub fn try_start<T: Into<Vec<u8>>>(fname: T) -> Result<(), Error> {
+    try_lock()?.start(fname)
+}
+
+/// Stop the profiler unless another thread holds the lock
+///
+/// # Failures
+///
+/// - Another thread holds the `PROFILER` lock.
+/// - See `Profiler::stop`.
+pub fn try_stop() -> Result<(), Error> {
+    try_lock()?.stop()
+}
@@ -0,0 +1,194 @@
+//! Keeping samples out of sections with their own signal handling
+
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use libc::{self, c_int};
+
+use error::Error;
+use lock;
+use {Profiler, ProfilerState};
+
+/// The signal of the running profile, or 0
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// Record the signal of the running profile, or that none is running
+pub(crate) fn set_signal(signal: Option<c_int>) {
+    SIGNAL.store(signal.unwrap_or(0), Ordering::SeqCst);
+}
+
+/// Blocks a signal in the calling thread until dropped
+struct Blocked {
+    signal: c_int,
+    was_blocked: bool,
+}
+
+impl Blocked {
+    fn new(signal: c_int) -> Blocked {
+        unsafe {
+            let mut set: libc::sigset_t = mem::zeroed();
+            let mut old: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, signal);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &set, &mut old);
+            Blocked {
+                signal,
+                was_blocked: libc::sigismember(&old, signal) == 1,
+            }
+        }
+    }
+}
+
+impl Drop for Blocked {
+    fn drop(&mut self) {
+        if self.was_blocked {
+            return;
+        }
+        unsafe {
+            let mut set: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, self.signal);
+            libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, ptr::null_mut());
+        }
+    }
+}
+
+impl Profiler {
+    /// Returns the signal which delivers samples to the running profile
+    ///
+    /// This is `SIGPROF`, or `SIGALRM` when sampling the wall clock. The
+    /// profiler owns the handler of this signal while profiling, so the
+    /// application must not install a handler of its own for it, and should
+    /// pick another signal for its own timers. It is `None` while no profile
+    /// is running, or when the backend does not sample with signals.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use cpuprofiler::PROFILER;
+    ///
+    /// let path = env::temp_dir().join("sampling-signal-example.profile");
+    /// let mut profiler = PROFILER.lock().unwrap();
+    /// profiler.start(path.to_str().unwrap()).unwrap();
+    /// println!("sampling with signal {:?}", profiler.sampling_signal());
+    /// profiler.stop().unwrap();
+    /// ```
+    pub fn sampling_signal(&self) -> Option<c_int> {
+        if self.state.is_running() {
+            self.backend.signal()
+        } else {
+            None
+        }
+    }
+
+    /// Run `f` with the sampling signal blocked in the calling thread
+    ///
+    /// This protects critical sections which must not be interrupted, such
+    /// as code which changes signal handlers or calls functions which are
+    /// not safe against signals. A sample due while the signal is blocked is
+    /// delivered once `f` returns, or to another thread, so is attributed to
+    /// the code after `f` rather than lost. Other threads keep being
+    /// sampled.
+    ///
+    /// The signal is that of the running profile, see `sampling_signal`,
+    /// and nothing is blocked while no profile is running. This does not
+    /// lock `PROFILER`, so it may be called while it is locked.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cpuprofiler::Profiler;
+    ///
+    /// let sum = Profiler::with_signals_blocked(|| {
+    ///     // Code which must not be interrupted goes here!
+    ///     1 + 1
+    /// });
+    /// assert_eq!(sum, 2);
+    /// ```
+    pub fn with_signals_blocked<F, R>(f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let signal = SIGNAL.load(Ordering::SeqCst);
+        let _blocked = if signal != 0 { Some(Blocked::new(signal)) } else { None };
+        f()
+    }
+
+    /// Run `f` with sampling suspended, such as around `exec`, `dlopen` or
+    /// patching code
+    ///
+    /// A sample taken while the loader or the kernel rewrites the process
+    /// can unwind through half mapped code, which gives garbage stacks and
+    /// the occasional crash. A profile which is `Active` is paused, see
+    /// `pause`, the sampling signal is blocked in the calling thread as with
+    /// `with_signals_blocked`, and once `f` returns or panics the profile
+    /// is resumed. Observers see the profile pause and resume. A profile
+    /// which was already paused stays paused, and one which `f`, or another
+    /// thread, stopped or resumed is left as it is. Without a running
+    /// profile `f` just runs. Returns what `f` returned.
+    ///
+    /// `PROFILER` is not locked while `f` runs. A program started by `exec`
+    /// inherits the blocked signal in its signal mask.
+    ///
+    /// # Deadlocks
+    ///
+    /// `PROFILER` is locked before and after `f` runs, so calling this while
+    /// holding its guard, such as from `PROFILER.lock()`, deadlocks.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use cpuprofiler::{Profiler, PROFILER};
+    ///
+    /// let path = env::temp_dir().join("suspend-example.profile");
+    /// PROFILER.lock().unwrap().start(path.to_str().unwrap()).unwrap();
+    /// let loaded = Profiler::suspend_around(|| {
+    ///     // Load a plugin or patch code here!
+    ///     true
+    /// })
+    /// .unwrap();
+    /// assert!(loaded);
+    /// PROFILER.lock().unwrap().stop().unwrap();
+    /// ```
+    ///
+    /// # Failures
+    ///
+    /// - The profile could not be paused, see `pause`, in which case `f`
+    ///   is not run.
+    /// - The profile could not be resumed, see `resume`.
+    pub fn suspend_around<F, R>(f: F) -> Result<R, Error>
+    where
+        F: FnOnce() -> R,
+    {
+        let suspended = lock::lock().suspend()?;
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| Profiler::with_signals_blocked(f)));
+
+        let resumed = lock::lock().unsuspend(suspended);
+        match res {
+            Ok(res) => resumed.map(|_| res),
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+
+    /// Pause the profile if it is `Active`, returning its session
+    fn suspend(&mut self) -> Result<Option<u64>, Error> {
+        if self.state != ProfilerState::Active {
+            return Ok(None);
+        }
+        self.pause()?;
+        Ok(Some(self.session))
+    }
+
+    /// Resume the profile `suspend` paused, unless it changed meanwhile
+    fn unsuspend(&mut self, suspended: Option<u64>) -> Result<(), Error> {
+        match suspended {
+            Some(session) if self.session == session && self.state == ProfilerState::Paused => self.resume(),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Capturing profiles in memory
+//!
+//! The cpuprofiler library always writes to a file. For services which want
+//! to ship profiles elsewhere `Profiler::start_in_memory` manages the scratch
+//! file internally and `Profiler::stop_to_vec` returns the raw profile bytes.
+//! On Linux the profile is written to an anonymous memory file and never
+//! touches the file system.
+//!
+//! # Examples
+//!
+//! ```
+//! use cpuprofiler::PROFILER;
+//!
+//! PROFILER.lock().unwrap().start_in_memory().unwrap();
+//! // Code you want to sample goes here!
+//! let bytes = PROFILER.lock().unwrap().stop_to_vec().unwrap();
+//!
+//! assert!(!bytes.is_empty());
+//! ```
+
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use error::Error;
+use {Profiler, ProfilerState};
+
+/// The scratch file backing an in-memory profile
+#[derive(Debug)]
+pub(crate) struct InMemory {
+    file: Option<File>,
+    path: PathBuf,
+    temporary: bool,
+}
+
+impl InMemory {
+    #[cfg(target_os = "linux")]
+    fn create() -> Result<InMemory, Error> {
+        use libc;
+        use std::io;
+        use std::os::unix::io::FromRawFd;
+
+        let fd = unsafe { libc::memfd_create(b"cpuprofiler\0".as_ptr() as *const _, libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(InMemory {
+            file: Some(unsafe { File::from_raw_fd(fd) }),
+            path: PathBuf::from(format!("/proc/self/fd/{}", fd)),
+            temporary: false,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn create() -> Result<InMemory, Error> {
+        use std::env;
+        use std::process;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("cpuprofiler-{}-{}.profile", process::id(), COUNT.fetch_add(1, Ordering::SeqCst));
+        Ok(InMemory {
+            file: None,
+            path: env::temp_dir().join(name),
+            temporary: true,
+        })
+    }
+
+    fn read(&mut self) -> Result<Vec<u8>, Error> {
+        let mut file = match self.file.take() {
+            Some(file) => file,
+            None => File::open(&self.path)?,
+        };
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl Drop for InMemory {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl Profiler {
+    /// Start the profiler, keeping the profile in memory
+    ///
+    /// The profile should be retrieved with `stop_to_vec`, if `stop` is
+    /// called instead the profile is discarded.
+    ///
+    /// # Failures
+    ///
+    /// - The scratch file could not be created.
+    /// - The profiler could not be started, see `start`.
+    pub fn start_in_memory(&mut self) -> Result<(), Error> {
+        if self.state != ProfilerState::NotActive {
+            return Err(Error::InvalidState(self.state));
+        }
+
+        let memory = InMemory::create()?;
+        self.start_path(&memory.path)?;
+        self.in_memory = Some(memory);
+        Ok(())
+    }
+
+    /// Stop an in-memory profile and return the raw profile bytes
+    ///
+    /// The bytes can be parsed with `profile::Profile::from_bytes`.
+    ///
+    /// # Failures
+    ///
+    /// - The profiler is `NotActive`, or was not started with `start_in_memory`.
+    /// - The profile could not be read back.
+    pub fn stop_to_vec(&mut self) -> Result<Vec<u8>, Error> {
+        if self.in_memory.is_none() {
+            return Err(Error::InvalidState(self.state));
+        }
+        match self.stop_profile()? {
+            Some(mut memory) => memory.read(),
+            None => Err(Error::Internal),
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! Describing how a profile was taken
+//!
+//! A profile only records its samples and the memory map of the process.
+//! With `Profiler::set_metadata`, or `ProfilerBuilder::metadata`, stopping a
+//! profile also writes a JSON file next to it, named by `path_for`, which
+//! records:
+//!
+//! - `start` and `stop`: the wall clock times the profile started and
+//!   stopped, as UTC `YYYY-MM-DDTHH:MM:SSZ` timestamps.
+//! - `start_unix` and `stop_unix`: the same times in seconds since the Unix
+//!   epoch.
+//! - `frequency`: the sampling frequency in Hz, see
+//!   `Profiler::last_frequency`, or `null` if it is not known.
+//! - `cmdline`: the arguments of the process, starting with the program.
+//! - `git_sha`: the first of the `GIT_SHA`, `GIT_COMMIT`, `GITHUB_SHA` and
+//!   `CI_COMMIT_SHA` environment variables which is set, or `null`.
+//! - `hostname`: the name of the host.
+//! - `labels`: an object holding the labels attached with
+//!   `ProfilerBuilder::label`.
+//! - `env`: an object holding the environment variables chosen with
+//!   `Profiler::set_metadata_env` which are set.
+//! - `executable` and `executable_build_id`: the path of the program, which
+//!   ends with ` (deleted)` once it was replaced, and its build id, or
+//!   `null`.
+//! - `mappings`: the executable memory mappings of the process, each with
+//!   `start`, `end` and `offset` as hex strings, its `path` and its
+//!   `build_id`, or `null`. Only Linux has them, elsewhere this is empty.
+//! - `cpuprofiler_version` and `rustc_version`: the version of this crate
+//!   and of the compiler which built it.
+//!
+//! Everything is recorded as the profile stops, so the metadata still
+//! describes the process, and the binaries it ran, after the process exits
+//! or its binary is replaced, such as by a rolling deploy. That is what
+//! symbolizing the profile later needs.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use cpuprofiler::PROFILER;
+//! use cpuprofiler::metadata;
+//!
+//! let path = env::temp_dir().join("metadata-example.profile");
+//! let mut profiler = PROFILER.lock().unwrap();
+//! profiler.set_metadata(true);
+//! profiler.set_metadata_env(&["RUST_LOG"]);
+//! profiler.start(path.to_str().unwrap()).unwrap();
+//! // Code you want to sample goes here!
+//! profiler.stop().unwrap();
+//! profiler.set_metadata(false);
+//!
+//! println!("described in {}", metadata::path_for(&path).display());
+//! ```
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use buildid;
+use json;
+use profile::Mapping;
+use template;
+use timestamp;
+use Profiler;
+
+/// The environment variables holding the git revision, in order
+const GIT_SHA_VARS: &[&str] = &["GIT_SHA", "GIT_COMMIT", "GITHUB_SHA", "CI_COMMIT_SHA"];
+
+/// Returns the path of the metadata written for the profile at `profile`
+///
+/// `.meta.json` is appended to the whole file name, so `out.profile` is
+/// described by `out.profile.meta.json`, and profiles which only differ in
+/// their extension, such as `prof.1` and `prof.2`, are described apart.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::Path;
+/// use cpuprofiler::metadata;
+///
+/// assert_eq!(metadata::path_for("out.profile"), Path::new("out.profile.meta.json"));
+/// assert_eq!(metadata::path_for("prof.1"), Path::new("prof.1.meta.json"));
+/// ```
+pub fn path_for<P: AsRef<Path>>(profile: P) -> PathBuf {
+    let mut path = profile.as_ref().as_os_str().to_owned();
+    path.push(".meta.json");
+    PathBuf::from(path)
+}
+
+/// Write the metadata of a profile which ran for `elapsed` up until now
+pub(crate) fn write(
+    profile: &Path,
+    elapsed: Duration,
+    frequency: Option<u64>,
+    labels: &[(String, String)],
+    vars: &[String],
+) -> io::Result<()> {
+    let stop = SystemTime::now();
+    let start = stop.checked_sub(elapsed).unwrap_or(stop);
+
+    let cmdline: Vec<String> = env::args_os()
+        .map(|arg| json::string(&arg.to_string_lossy()))
+        .collect();
+    let git_sha = GIT_SHA_VARS
+        .iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|sha| !sha.is_empty())
+        .map(|sha| json::string(&sha));
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}: {}", json::string(k), json::string(v)))
+        .collect();
+    let vars: Vec<String> = vars
+        .iter()
+        .filter_map(|var| Some(format!("{}: {}", json::string(var), json::string(&env::var(var).ok()?))))
+        .collect();
+    let executable = env::current_exe().ok().map(|exe| json::string(&exe.to_string_lossy()));
+    let executable_build_id = executable_build_id().map(|id| json::string(&id));
+    let mappings: Vec<String> = fs::read_to_string("/proc/self/maps")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(Mapping::parse)
+        .filter(|mapping| mapping.executable)
+        .map(|mapping| {
+            let build_id = buildid::read_mapped(&mapping).map(|id| json::string(&id));
+            format!(
+                "    {{\"start\": \"{:#x}\", \"end\": \"{:#x}\", \"offset\": \"{:#x}\", \"path\": {}, \"build_id\": {}}}",
+                mapping.start,
+                mapping.end,
+                mapping.offset,
+                mapping.path.as_ref().map(|path| json::string(path)).as_deref().unwrap_or("null"),
+                build_id.as_deref().unwrap_or("null")
+            )
+        })
+        .collect();
+
+    let mut file = File::create(path_for(profile))?;
+    writeln!(file, "{{")?;
+    writeln!(file, "  \"start\": \"{}Z\",", timestamp::format_utc(start))?;
+    writeln!(file, "  \"stop\": \"{}Z\",", timestamp::format_utc(stop))?;
+    writeln!(file, "  \"start_unix\": {:.6},", unix_secs(start))?;
+    writeln!(file, "  \"stop_unix\": {:.6},", unix_secs(stop))?;
+    match frequency {
+        Some(hz) => writeln!(file, "  \"frequency\": {},", hz)?,
+        None => writeln!(file, "  \"frequency\": null,")?,
+    }
+    writeln!(file, "  \"cmdline\": [{}],", cmdline.join(", "))?;
+    writeln!(file, "  \"git_sha\": {},", git_sha.as_deref().unwrap_or("null"))?;
+    writeln!(file, "  \"hostname\": {},", json::string(&template::hostname()))?;
+    writeln!(file, "  \"labels\": {{{}}},", labels.join(", "))?;
+    writeln!(file, "  \"env\": {{{}}},", vars.join(", "))?;
+    writeln!(file, "  \"executable\": {},", executable.as_deref().unwrap_or("null"))?;
+    writeln!(file, "  \"executable_build_id\": {},", executable_build_id.as_deref().unwrap_or("null"))?;
+    if mappings.is_empty() {
+        writeln!(file, "  \"mappings\": [],")?;
+    } else {
+        writeln!(file, "  \"mappings\": [\n{}\n  ],", mappings.join(",\n"))?;
+    }
+    writeln!(file, "  \"cpuprofiler_version\": {},", json::string(env!("CARGO_PKG_VERSION")))?;
+    writeln!(file, "  \"rustc_version\": {}", json::string(env!("CPUPROFILER_RUSTC_VERSION")))?;
+    writeln!(file, "}}")?;
+    file.sync_all()
+}
+
+/// Returns the build id of the program, read through `/proc/self/exe` on
+/// Linux so that a replaced binary is still found
+fn executable_build_id() -> Option<String> {
+    if cfg!(target_os = "linux") {
+        buildid::read(Path::new("/proc/self/exe"))
+    } else {
+        buildid::read(&env::current_exe().ok()?)
+    }
+}
+
+fn unix_secs(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0)
+}
+
+impl Profiler {
+    /// Write metadata next to every profile written from now on
+    ///
+    /// The metadata is written when the profile is stopped, see the
+    /// [`metadata`](metadata/index.html) module for its contents. Profiles
+    /// kept in memory have no file to sit next to, so get no metadata.
+    /// Failing to write the metadata does not fail `stop`, since the
+    /// profile itself was written.
+    pub fn set_metadata(&mut self, enabled: bool) {
+        self.metadata = enabled;
+    }
+
+    /// Returns whether metadata is written next to profiles
+    pub fn writes_metadata(&self) -> bool {
+        self.metadata
+    }
+
+    /// Record the environment variables `vars` in the metadata
+    ///
+    /// They are read when the profile stops, and those which are not set
+    /// are left out. This replaces the variables chosen before.
+    pub fn set_metadata_env<I, S>(&mut self, vars: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.metadata_env = vars.into_iter().map(|var| var.as_ref().to_owned()).collect();
+    }
+
+    /// Returns the environment variables recorded in the metadata
+    pub fn metadata_env(&self) -> &[String] {
+        &self.metadata_env
+    }
+}
@@ -0,0 +1,106 @@
+//! Prometheus metrics of profiler activity
+//!
+//! The profiler counts its activity as it starts and stops profiles,
+//! including those of a [`RotatingProfiler`](../rotate/struct.RotatingProfiler.html),
+//! and `render` writes the counts in the Prometheus text format, for a
+//! service to serve from its metrics endpoint:
+//!
+//! - `profiler_active`: 1 while a profile is running, including while it
+//!   is paused, and 0 otherwise.
+//! - `samples_collected_total`: the samples of every profile which stopped,
+//!   as the backend counted them.
+//! - `profiles_written_total`: the profiles which stopped.
+//! - `last_profile_duration_seconds`: how long the last profile which
+//!   stopped ran for.
+//!
+//! The counts cover the whole process, they are not reset when profiles
+//! start. With the `disabled` feature no profiles are written, so only
+//! `profiler_active` changes.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use cpuprofiler::{metrics, PROFILER};
+//!
+//! let path = env::temp_dir().join("metrics-example.profile");
+//! PROFILER.lock().unwrap().start(path.to_str().unwrap()).unwrap();
+//! assert!(metrics::snapshot().active);
+//! PROFILER.lock().unwrap().stop().unwrap();
+//!
+//! print!("{}", metrics::render());
+//! ```
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static SAMPLES: AtomicU64 = AtomicU64::new(0);
+static PROFILES: AtomicU64 = AtomicU64::new(0);
+/// The duration of the last profile, in nanoseconds
+static LAST_DURATION: AtomicU64 = AtomicU64::new(0);
+
+/// The values of the metrics at one point in time
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Snapshot {
+    /// Whether a profile is running
+    pub active: bool,
+    /// The samples of every profile which stopped
+    pub samples_collected: u64,
+    /// The number of profiles which stopped
+    pub profiles_written: u64,
+    /// How long the last profile which stopped ran for, zero before any did
+    pub last_profile_duration: Duration,
+}
+
+/// Writes the metrics in the Prometheus text format
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "# HELP profiler_active Whether a CPU profile is running.")?;
+        writeln!(f, "# TYPE profiler_active gauge")?;
+        writeln!(f, "profiler_active {}", self.active as u8)?;
+        writeln!(f, "# HELP samples_collected_total Samples taken by CPU profiles which stopped.")?;
+        writeln!(f, "# TYPE samples_collected_total counter")?;
+        writeln!(f, "samples_collected_total {}", self.samples_collected)?;
+        writeln!(f, "# HELP profiles_written_total CPU profiles which stopped.")?;
+        writeln!(f, "# TYPE profiles_written_total counter")?;
+        writeln!(f, "profiles_written_total {}", self.profiles_written)?;
+        writeln!(f, "# HELP last_profile_duration_seconds How long the last CPU profile ran for.")?;
+        writeln!(f, "# TYPE last_profile_duration_seconds gauge")?;
+        writeln!(f, "last_profile_duration_seconds {}", self.last_profile_duration.as_secs_f64())
+    }
+}
+
+/// Returns the current values of the metrics
+pub fn snapshot() -> Snapshot {
+    Snapshot {
+        active: ACTIVE.load(Ordering::SeqCst),
+        samples_collected: SAMPLES.load(Ordering::SeqCst),
+        profiles_written: PROFILES.load(Ordering::SeqCst),
+        last_profile_duration: Duration::from_nanos(LAST_DURATION.load(Ordering::SeqCst)),
+    }
+}
+
+/// Returns the current values of the metrics in the Prometheus text format
+pub fn render() -> String {
+    snapshot().to_string()
+}
+
+/// A profile started
+pub(crate) fn started() {
+    ACTIVE.store(true, Ordering::SeqCst);
+}
+
+/// A profile which ran for `elapsed` stopped after taking `samples`
+pub(crate) fn stopped(elapsed: Option<Duration>, samples: u64) {
+    ACTIVE.store(false, Ordering::SeqCst);
+    if cfg!(feature = "disabled") {
+        return;
+    }
+    SAMPLES.fetch_add(samples, Ordering::SeqCst);
+    PROFILES.fetch_add(1, Ordering::SeqCst);
+    let nanos = elapsed.unwrap_or_default().as_nanos().min(u64::MAX as u128) as u64;
+    LAST_DURATION.store(nanos, Ordering::SeqCst);
+}
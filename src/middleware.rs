@@ -0,0 +1,278 @@
+//! Profiling individual HTTP requests
+//!
+//! Available with the `tower` feature.
+//!
+//! `ProfileLayer` is a tower layer, usable with axum, hyper or any other
+//! tower based server. Requests carrying the trigger header, `x-profile` by
+//! default, are profiled while they are handled. The pprof encoded profile
+//! is attached to the response as a `RequestProfile` extension, and written
+//! to a directory if one is configured.
+//!
+//! If the header value is a number of seconds the profile covers at least
+//! that long, continuing in the background after the response is sent. Such
+//! profiles are only written to the directory.
+//!
+//! Only one profile runs at a time. Profiling requests which arrive while
+//! the profiler is busy get an empty `409 Conflict` response, and those the
+//! profiler's [policy](../policy/index.html) refuses an empty
+//! `429 Too Many Requests` response.
+//!
+//! # Examples
+//!
+//! ```
+//! extern crate cpuprofiler;
+//! extern crate tower_layer;
+//!
+//! use cpuprofiler::middleware::{ProfileLayer, ProfileService};
+//! use tower_layer::Layer;
+//!
+//! fn profiled<S>(service: S) -> ProfileService<S> {
+//!     ProfileLayer::new().dir("/var/log/profiles").layer(service)
+//! }
+//! # fn main() {}
+//! ```
+
+use std::fs;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use http::header::HeaderName;
+use http::{Request, Response, StatusCode};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use error::Error;
+use lock;
+use policy::Trigger;
+use pprof::Encoder;
+use profile::Profile;
+use timestamp;
+
+/// The pprof encoded profile of a request, attached to its response
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestProfile(pub Vec<u8>);
+
+/// A layer which profiles requests carrying a trigger header
+#[derive(Clone, Debug)]
+pub struct ProfileLayer {
+    header: HeaderName,
+    dir: Option<PathBuf>,
+}
+
+impl ProfileLayer {
+    /// Profile requests with an `x-profile` header
+    pub fn new() -> ProfileLayer {
+        ProfileLayer {
+            header: HeaderName::from_static("x-profile"),
+            dir: None,
+        }
+    }
+
+    /// Use `header` as the trigger instead
+    ///
+    /// # Panics
+    ///
+    /// - `header` is not a valid lowercase header name.
+    pub fn header(mut self, header: &'static str) -> ProfileLayer {
+        self.header = HeaderName::from_static(header);
+        self
+    }
+
+    /// Also write each profile into `dir`, as `request-<timestamp>-<n>.pb`
+    pub fn dir<D: Into<PathBuf>>(mut self, dir: D) -> ProfileLayer {
+        self.dir = Some(dir.into());
+        self
+    }
+}
+
+impl Default for ProfileLayer {
+    fn default() -> ProfileLayer {
+        ProfileLayer::new()
+    }
+}
+
+impl<S> Layer<S> for ProfileLayer {
+    type Service = ProfileService<S>;
+
+    fn layer(&self, inner: S) -> ProfileService<S> {
+        ProfileService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// The service created by `ProfileLayer`
+#[derive(Clone, Debug)]
+pub struct ProfileService<S> {
+    inner: S,
+    layer: ProfileLayer,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for ProfileService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    ResBody: Default,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = ProfileFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> ProfileFuture<S::Future> {
+        let linger = match req.headers().get(&self.layer.header) {
+            Some(value) => value
+                .to_str()
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+                .map(Duration::from_secs),
+            None => {
+                return ProfileFuture {
+                    inner: Some(self.inner.call(req)),
+                    refusal: StatusCode::CONFLICT,
+                    capture: None,
+                }
+            }
+        };
+
+        let session = {
+            let mut profiler = lock::lock();
+            let started = if profiler.state().is_running() {
+                Err(Error::Busy)
+            } else {
+                profiler.start_triggered(Trigger::Http, |p| p.start_in_memory())
+            };
+            match started {
+                Ok(()) => profiler.session,
+                Err(e) => {
+                    let refusal = match e {
+                        Error::Refused(_) => StatusCode::TOO_MANY_REQUESTS,
+                        _ => StatusCode::CONFLICT,
+                    };
+                    return ProfileFuture {
+                        inner: None,
+                        refusal,
+                        capture: None,
+                    };
+                }
+            }
+        };
+
+        ProfileFuture {
+            inner: Some(self.inner.call(req)),
+            refusal: StatusCode::CONFLICT,
+            capture: Some(Capture {
+                session,
+                linger,
+                started: Instant::now(),
+                time: SystemTime::now(),
+                dir: self.layer.dir.clone(),
+            }),
+        }
+    }
+}
+
+/// A running request profile
+#[derive(Debug)]
+struct Capture {
+    session: u64,
+    linger: Option<Duration>,
+    started: Instant,
+    time: SystemTime,
+    dir: Option<PathBuf>,
+}
+
+impl Capture {
+    /// Stop the profile and return the pprof bytes, if it is still ours
+    fn finish(&self) -> Option<Vec<u8>> {
+        let raw = {
+            let mut profiler = lock::lock();
+            if !profiler.state().is_running() || profiler.session != self.session {
+                return None;
+            }
+            profiler.stop_to_vec().ok()?
+        };
+
+        let profile = Profile::from_bytes(&raw).ok()?;
+        let pprof = Encoder::new(&profile)
+            .time(self.time)
+            .duration(self.started.elapsed())
+            .encode();
+        if let Some(ref dir) = self.dir {
+            let name = format!("request-{}-{}.pb", timestamp::format_utc(self.time), self.session);
+            let _ = fs::write(dir.join(name), &pprof);
+        }
+        Some(pprof)
+    }
+}
+
+/// The response future of `ProfileService`
+#[derive(Debug)]
+pub struct ProfileFuture<F> {
+    // `None` when the request was refused.
+    inner: Option<F>,
+    // The status a refused request is answered with.
+    refusal: StatusCode,
+    capture: Option<Capture>,
+}
+
+impl<F, B, E> Future for ProfileFuture<F>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+    B: Default,
+{
+    type Output = Result<Response<B>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // The inner future is never moved out of its pinned location.
+        let this = unsafe { self.get_unchecked_mut() };
+        let inner = match this.inner {
+            Some(ref mut inner) => unsafe { Pin::new_unchecked(inner) },
+            None => {
+                let mut refused = Response::new(B::default());
+                *refused.status_mut() = this.refusal;
+                return Poll::Ready(Ok(refused));
+            }
+        };
+
+        let mut res = match inner.poll(cx) {
+            Poll::Ready(res) => res,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        if let Some(capture) = this.capture.take() {
+            let remaining = capture.linger.and_then(|l| l.checked_sub(capture.started.elapsed()));
+            match remaining {
+                Some(remaining) => {
+                    thread::spawn(move || {
+                        thread::sleep(remaining);
+                        capture.finish();
+                    });
+                }
+                None => {
+                    let pprof = capture.finish();
+                    if let (Some(pprof), &mut Ok(ref mut response)) = (pprof, &mut res) {
+                        response.extensions_mut().insert(RequestProfile(pprof));
+                    }
+                }
+            }
+        }
+        Poll::Ready(res)
+    }
+}
+
+impl<F> Drop for ProfileFuture<F> {
+    fn drop(&mut self) {
+        // The request was cancelled, don't leave the profiler running.
+        if let Some(capture) = self.capture.take() {
+            capture.finish();
+        }
+    }
+}
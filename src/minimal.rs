@@ -0,0 +1,118 @@
+//! A minimal profiler for size constrained builds
+//!
+//! Available with the `gperftools` feature.
+//!
+//! On stripped down embedded Linux images every kilobyte of the binary
+//! counts. This module only starts, flushes and stops the cpuprofiler
+//! library, with a small `Copy` error and no allocation, locking or lazy
+//! initialization: the running profile is tracked with an atomic flag.
+//! Nothing else of the crate is used, so when a program only calls these
+//! functions the linker drops the rest of it, `PROFILER` included. Build it
+//! with only the default feature, which depends on nothing but `libc` and
+//! the macro-only `lazy_static`:
+//!
+//! ```toml
+//! [dependencies]
+//! cpuprofiler = { version = "0.0.4", default-features = false, features = ["gperftools"] }
+//! ```
+//!
+//! Like the raw [`ffi`](../ffi/index.html) functions, profiles started here
+//! are not known to `PROFILER`, so don't use both in one program.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use std::ffi::CString;
+//! use cpuprofiler::minimal;
+//!
+//! let path = env::temp_dir().join("minimal-example.profile");
+//! let path = CString::new(path.to_str().unwrap()).unwrap();
+//! minimal::start(&path).unwrap();
+//! // Code you want to sample goes here!
+//! minimal::flush().unwrap();
+//! minimal::stop().unwrap();
+//! ```
+
+use std::error;
+use std::ffi::CStr;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use ffi;
+
+/// Whether a profile started here is running
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Why a minimal profile could not be started, flushed or stopped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// A profile is already running
+    Running,
+    /// No profile is running
+    NotRunning,
+    /// The library refused to start, such as when it cannot write the file
+    /// or is missing with `dylib-load`
+    StartRejected,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Running => write!(f, "a profile is already running"),
+            Error::NotRunning => write!(f, "no profile is running"),
+            Error::StartRejected => write!(f, "the cpuprofiler library refused to start"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// Start profiling into the file `path`
+///
+/// # Failures
+///
+/// - A profile started here is running, `Error::Running`.
+/// - The library refused to start, `Error::StartRejected`.
+pub fn start(path: &CStr) -> Result<(), Error> {
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        return Err(Error::Running);
+    }
+    if unsafe { ffi::ProfilerStart(path.as_ptr()) } == 0 {
+        RUNNING.store(false, Ordering::SeqCst);
+        return Err(Error::StartRejected);
+    }
+    Ok(())
+}
+
+/// Write the samples taken so far to the file
+///
+/// # Failures
+///
+/// - No profile started here is running, `Error::NotRunning`.
+pub fn flush() -> Result<(), Error> {
+    if !is_running() {
+        return Err(Error::NotRunning);
+    }
+    unsafe { ffi::ProfilerFlush() };
+    Ok(())
+}
+
+/// Stop profiling, writing out the profile
+///
+/// # Failures
+///
+/// - No profile started here is running, `Error::NotRunning`.
+pub fn stop() -> Result<(), Error> {
+    if !RUNNING.load(Ordering::SeqCst) {
+        return Err(Error::NotRunning);
+    }
+    unsafe { ffi::ProfilerStop() };
+    RUNNING.store(false, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Returns whether a profile started here is running
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
@@ -0,0 +1,271 @@
+//! A backend for testing code which profiles
+//!
+//! `MockProfiler` is a `ProfilerBackend` which takes no samples and needs no
+//! library, so code which starts and stops profiles can be unit tested on
+//! machines without libprofiler. It records what it was asked to do, can be
+//! told to fail, and writes an empty but valid profile to the path of each
+//! profile, so code which reads or uploads profiles works too.
+//!
+//! The mock is cloned before it is given to the profiler, and the clone
+//! which is kept sees everything the profiler does with it.
+//!
+//! Build with the default features off to not link libprofiler at all, the
+//! mock is then the default backend:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! cpuprofiler = { version = "0.0.4", default-features = false, features = ["test-util"] }
+//! ```
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use cpuprofiler::PROFILER;
+//! use cpuprofiler::mock::{Call, MockProfiler};
+//!
+//! let mock = MockProfiler::new().samples(7);
+//! PROFILER.lock().unwrap().set_backend(mock.clone()).unwrap();
+//!
+//! let path = env::temp_dir().join("mock-example.profile");
+//! PROFILER.lock().unwrap().start(path.to_str().unwrap()).unwrap();
+//! PROFILER.lock().unwrap().stop().unwrap();
+//!
+//! # if cfg!(not(feature = "disabled")) {
+//! assert!(mock.started()[0].ends_with("mock-example.profile"));
+//! assert_eq!(mock.calls().last(), Some(&Call::Stop));
+//! assert!(!mock.is_running());
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use backend::{write_profile, ProfilerBackend};
+use builder::{self, TimerKind, DEFAULT_FREQUENCY};
+use error::Error;
+use paths;
+
+/// A call the profiler made to a `MockProfiler`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Call {
+    /// `start`, with the path of the profile
+    Start(PathBuf),
+    /// `stop`
+    Stop,
+    /// `pause`
+    Pause,
+    /// `resume`
+    Resume,
+    /// `flush`
+    Flush,
+    /// `set_timer`
+    SetTimer(TimerKind),
+    /// `set_frequency`
+    SetFrequency(u32),
+    /// `set_per_thread_timers`
+    SetPerThreadTimers(bool),
+}
+
+/// What failures the mock was told to return
+#[derive(Clone, Copy, Debug, Default)]
+struct Failures {
+    start: bool,
+    stop: bool,
+    flush: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    calls: Vec<Call>,
+    path: Option<PathBuf>,
+    paused: bool,
+    frequency: Option<u32>,
+    samples: u64,
+    fail: Failures,
+}
+
+/// A backend which records calls instead of sampling
+///
+/// Every clone shares the same state.
+#[derive(Clone, Debug, Default)]
+pub struct MockProfiler {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockProfiler {
+    /// Create a mock which succeeds at everything
+    pub fn new() -> MockProfiler {
+        MockProfiler::default()
+    }
+
+    /// Report `samples` as gathered by every profile
+    ///
+    /// They are written to the profile as samples of a single address.
+    pub fn samples(self, samples: u64) -> MockProfiler {
+        self.lock().samples = samples;
+        self
+    }
+
+    /// Make `start` fail with `Error::StartRejected`
+    pub fn fail_start(self, fail: bool) -> MockProfiler {
+        self.lock().fail.start = fail;
+        self
+    }
+
+    /// Make `stop` fail with `Error::Internal`, after stopping
+    pub fn fail_stop(self, fail: bool) -> MockProfiler {
+        self.lock().fail.stop = fail;
+        self
+    }
+
+    /// Make `flush` fail with `Error::Internal`
+    pub fn fail_flush(self, fail: bool) -> MockProfiler {
+        self.lock().fail.flush = fail;
+        self
+    }
+
+    /// Returns the calls made so far, oldest first
+    pub fn calls(&self) -> Vec<Call> {
+        self.lock().calls.clone()
+    }
+
+    /// Returns the paths of the profiles started so far, oldest first
+    pub fn started(&self) -> Vec<PathBuf> {
+        self.lock()
+            .calls
+            .iter()
+            .filter_map(|call| match *call {
+                Call::Start(ref path) => Some(path.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns whether a profile is running, including while it is paused
+    pub fn is_running(&self) -> bool {
+        self.lock().path.is_some()
+    }
+
+    /// Returns whether a profile is running and paused
+    pub fn is_paused(&self) -> bool {
+        self.lock().paused
+    }
+
+    /// Forget the calls made so far
+    pub fn clear(&self) {
+        self.lock().calls.clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // A test which failed while holding the lock leaves usable state.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Write the profile which a mock with `samples` samples took
+fn write(path: &Path, samples: u64, frequency: u32) -> Result<(), Error> {
+    let mut stacks = HashMap::new();
+    if samples > 0 {
+        stacks.insert(vec![0x1000], samples);
+    }
+    let file = File::create(path)?;
+    write_profile(&file, 1_000_000 / u64::from(frequency), &stacks, b"")?;
+    Ok(())
+}
+
+impl ProfilerBackend for MockProfiler {
+    fn start(&mut self, fname: &CStr) -> Result<(), Error> {
+        let mut state = self.lock();
+        let path = paths::from_bytes(fname.to_bytes());
+        state.calls.push(Call::Start(path.clone()));
+        if state.fail.start {
+            return Err(Error::StartRejected);
+        }
+        write(&path, 0, state.frequency.unwrap_or(DEFAULT_FREQUENCY))?;
+        state.path = Some(path);
+        state.paused = false;
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        let mut state = self.lock();
+        state.calls.push(Call::Stop);
+        state.paused = false;
+        if let Some(path) = state.path.take() {
+            write(&path, state.samples, state.frequency.unwrap_or(DEFAULT_FREQUENCY))?;
+        }
+        if state.fail.stop {
+            return Err(Error::Internal);
+        }
+        Ok(())
+    }
+
+    fn samples_gathered(&self) -> u64 {
+        let state = self.lock();
+        if state.path.is_some() {
+            state.samples
+        } else {
+            0
+        }
+    }
+
+    fn frequency(&self) -> Option<u32> {
+        Some(self.lock().frequency.unwrap_or(DEFAULT_FREQUENCY))
+    }
+
+    fn set_timer(&mut self, timer: TimerKind) -> Result<(), Error> {
+        self.lock().calls.push(Call::SetTimer(timer));
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<(), Error> {
+        let mut state = self.lock();
+        state.calls.push(Call::Pause);
+        state.paused = true;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<(), Error> {
+        let mut state = self.lock();
+        state.calls.push(Call::Resume);
+        state.paused = false;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let mut state = self.lock();
+        state.calls.push(Call::Flush);
+        if state.fail.flush {
+            return Err(Error::Internal);
+        }
+        Ok(())
+    }
+
+    fn set_frequency(&mut self, hz: u32) -> Result<(), Error> {
+        let mut state = self.lock();
+        state.calls.push(Call::SetFrequency(hz));
+        state.frequency = Some(hz);
+        Ok(())
+    }
+
+    fn set_per_thread_timers(&mut self, enabled: bool) -> Result<(), Error> {
+        self.lock().calls.push(Call::SetPerThreadTimers(enabled));
+        Ok(())
+    }
+
+    fn check_timer(&self, _timer: TimerKind) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn check_frequency(&self, hz: u32) -> Result<(), Error> {
+        builder::check_frequency(hz)
+    }
+
+    fn check_per_thread_timers(&self, _enabled: bool) -> Result<(), Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+//! Setting `ProfilerOptions` fields this crate does not model
+//!
+//! gperftools extends the options of `ProfilerStartWithOptions` by
+//! appending fields to the struct, which callers zero so that the fields
+//! they do not know keep their defaults. The crate models the fields of the
+//! gperftools it was written against, `filter_in_thread` and its argument,
+//! and passes the library a larger zeroed struct. `OptionsExt::raw_option`
+//! writes bytes into the part past the modeled fields, so options of newer
+//! gperftools releases can be used before the crate models them. Libraries
+//! which do not have the fields do not read them.
+//!
+//! Offsets are in bytes from the start of the C struct, so they depend on
+//! the target's pointer size and on the field layout of `profiler.h`. Raw
+//! options are only used by the `Gperftools` backend, the other backends
+//! fail to start when any are set.
+//!
+//! # Examples
+//!
+//! ```
+//! use cpuprofiler::builder::ProfilerBuilder;
+//! use cpuprofiler::options::{OptionsExt, MODELED_SIZE};
+//!
+//! // An int field a newer gperftools appends to the struct.
+//! let builder = ProfilerBuilder::new().raw_option(MODELED_SIZE, &1i32.to_ne_bytes());
+//! assert_eq!(builder.options().size(), MODELED_SIZE + 4);
+//! ```
+
+use std::mem;
+
+use builder::ProfilerBuilder;
+use error::Error;
+
+/// The size of the fields the crate models, `filter_in_thread` and
+/// `filter_in_thread_arg`
+pub const MODELED_SIZE: usize = 2 * mem::size_of::<usize>();
+
+/// The largest options struct which can be passed to the library
+pub const MAX_SIZE: usize = 256;
+
+/// Raw bytes to write into the options struct past the modeled fields
+///
+/// Later writes to the same bytes replace earlier ones.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RawOptions {
+    fields: Vec<(usize, Vec<u8>)>,
+}
+
+impl RawOptions {
+    /// Create options which set no fields
+    pub fn new() -> RawOptions {
+        RawOptions::default()
+    }
+
+    /// Returns whether no field is set
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Returns the size of the struct the set fields need, 0 for none
+    pub fn size(&self) -> usize {
+        self.fields
+            .iter()
+            .map(|&(offset, ref value)| offset.saturating_add(value.len()))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns the fields set, as byte offsets and values, in the order
+    /// they were set
+    pub fn fields(&self) -> &[(usize, Vec<u8>)] {
+        &self.fields
+    }
+
+    /// Write `value` at `offset` bytes from the start of the struct
+    pub fn set(&mut self, offset: usize, value: &[u8]) {
+        self.fields.push((offset, value.to_vec()));
+    }
+
+    /// Check that every field is past the modeled fields and inside
+    /// `MAX_SIZE`
+    ///
+    /// # Failures
+    ///
+    /// - A field overlaps the modeled fields, or ends past `MAX_SIZE`,
+    ///   `Error::Unsupported`.
+    pub fn check(&self) -> Result<(), Error> {
+        for &(offset, ref value) in &self.fields {
+            if offset < MODELED_SIZE {
+                let reason = format!(
+                    "raw option at offset {}, the first {} bytes are the fields the crate sets",
+                    offset, MODELED_SIZE
+                );
+                return Err(Error::Unsupported(reason));
+            }
+            if offset.saturating_add(value.len()) > MAX_SIZE {
+                let reason = format!(
+                    "raw option of {} bytes at offset {}, options are at most {} bytes",
+                    value.len(),
+                    offset,
+                    MAX_SIZE
+                );
+                return Err(Error::Unsupported(reason));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the fields into `buf`, the whole options struct
+    ///
+    /// The fields have been checked, and `buf` is `MAX_SIZE` long.
+    #[cfg(feature = "gperftools")]
+    pub(crate) fn write_to(&self, buf: &mut [u8]) {
+        for &(offset, ref value) in &self.fields {
+            buf[offset..offset + value.len()].copy_from_slice(value);
+        }
+    }
+}
+
+/// Setting options the crate does not model
+pub trait OptionsExt {
+    /// Write `value` at `offset` bytes from the start of the options struct
+    ///
+    /// The offset must be at least `MODELED_SIZE`, and the field end within
+    /// `MAX_SIZE`, which is checked when the profile starts.
+    fn raw_option(self, offset: usize, value: &[u8]) -> Self;
+
+    /// Returns the raw options set so far
+    fn options(&self) -> &RawOptions;
+}
+
+impl OptionsExt for ProfilerBuilder {
+    fn raw_option(mut self, offset: usize, value: &[u8]) -> ProfilerBuilder {
+        self.raw_options_mut().set(offset, value);
+        self
+    }
+
+    fn options(&self) -> &RawOptions {
+        self.raw_options()
+    }
+}
@@ -0,0 +1,365 @@
+//! Exporting profiling sessions to OpenTelemetry
+//!
+//! Available with the `otel` feature.
+//!
+//! Once an `OtlpExporter` is installed, every profile the profiler runs is
+//! exported as a span named `cpuprofiler.session`, from when it starts to
+//! when it stops, to an OpenTelemetry collector over OTLP/HTTP with JSON
+//! encoding. The span has the attributes:
+//!
+//! - `cpuprofiler.path`: where the profile is written, empty for profiles
+//!   kept in memory.
+//! - `cpuprofiler.frequency`: the sampling frequency in Hz, when it was set.
+//! - `cpuprofiler.samples`: the samples the backend counted when the
+//!   profile stopped.
+//!
+//! Pausing, resuming and flushing the profile are recorded as the span
+//! events `paused`, `resumed` and `flushed`, the first and last with a
+//! `cpuprofiler.samples` attribute. A profile the backend refused to start
+//! is exported as a span with an error status.
+//!
+//! Spans are sent from a background thread, so starting and stopping the
+//! profiler never waits on the collector, and a span which could not be
+//! sent is dropped and counted, see `OtlpHandle::failed`. The running
+//! session's ids are returned by `current_session`, to link application
+//! spans to it.
+//!
+//! The profile data itself is not exported: the OTLP profiles signal is
+//! still in development and its encoding changes between releases. The
+//! `cpuprofiler.path` attribute tells a pipeline where to pick the profile
+//! up instead.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use cpuprofiler::otel::OtlpExporter;
+//! use cpuprofiler::PROFILER;
+//!
+//! let exporter = OtlpExporter::new("http://localhost:4318")
+//!     .service_name("my-service")
+//!     .install()
+//!     .unwrap();
+//!
+//! PROFILER.lock().unwrap().start("./otel-example.profile").unwrap();
+//! // Code you want to sample goes here!
+//! PROFILER.lock().unwrap().stop().unwrap();
+//!
+//! exporter.stop();
+//! ```
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ureq;
+
+use error::Error;
+use json;
+
+lazy_static! {
+    static ref EXPORTER: Mutex<Option<Installed>> = Mutex::new(None);
+}
+
+/// The ids of a profiling session's span
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionSpan {
+    /// The trace id, as 32 hex digits
+    pub trace_id: String,
+    /// The span id, as 16 hex digits
+    pub span_id: String,
+}
+
+/// Configuration for exporting profiling sessions to a collector
+#[derive(Clone, Debug)]
+pub struct OtlpExporter {
+    endpoint: String,
+    service_name: String,
+    headers: Vec<(String, String)>,
+    attributes: Vec<(String, String)>,
+    timeout: Duration,
+}
+
+impl OtlpExporter {
+    /// Export to the OTLP/HTTP collector at `endpoint`
+    ///
+    /// Spans are sent to the endpoint's `/v1/traces`, the default of a
+    /// collector listening on port 4318.
+    pub fn new<U: Into<String>>(endpoint: U) -> OtlpExporter {
+        OtlpExporter {
+            endpoint: endpoint.into(),
+            service_name: "unknown_service".to_owned(),
+            headers: Vec::new(),
+            attributes: Vec::new(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Set the `service.name` of the resource, defaults to `unknown_service`
+    pub fn service_name<N: Into<String>>(mut self, name: N) -> OtlpExporter {
+        self.service_name = name.into();
+        self
+    }
+
+    /// Send the header `name` with every request, such as credentials
+    pub fn header<K: Into<String>, V: Into<String>>(mut self, name: K, value: V) -> OtlpExporter {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Add the attribute `key` to the resource, such as
+    /// `deployment.environment`
+    pub fn attribute<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> OtlpExporter {
+        self.attributes.push((key.into(), value.into()));
+        self
+    }
+
+    /// Set how long to wait for the collector, defaults to 10s
+    pub fn timeout(mut self, timeout: Duration) -> OtlpExporter {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Start exporting sessions, from the next profile which starts
+    ///
+    /// # Failures
+    ///
+    /// - An exporter is already installed, `Error::Busy`.
+    pub fn install(self) -> Result<OtlpHandle, Error> {
+        let mut installed = EXPORTER.lock().unwrap_or_else(|e| e.into_inner());
+        if installed.is_some() {
+            return Err(Error::Busy);
+        }
+
+        let (tx, rx) = mpsc::channel::<Span>();
+        let exported = Arc::new(AtomicUsize::new(0));
+        let failed = Arc::new(AtomicUsize::new(0));
+        let (sent, not_sent) = (exported.clone(), failed.clone());
+
+        let thread = thread::spawn(move || {
+            let url = format!("{}/v1/traces", self.endpoint.trim_end_matches('/'));
+            for span in rx {
+                let mut request = ureq::post(&url)
+                    .timeout(self.timeout)
+                    .set("Content-Type", "application/json");
+                for (k, v) in &self.headers {
+                    request = request.set(k, v);
+                }
+                match request.send_string(&self.encode(&span)) {
+                    Ok(_) => sent.fetch_add(1, Ordering::SeqCst),
+                    Err(_) => not_sent.fetch_add(1, Ordering::SeqCst),
+                };
+            }
+        });
+
+        *installed = Some(Installed { spans: tx, open: None });
+        Ok(OtlpHandle {
+            thread,
+            exported,
+            failed,
+        })
+    }
+
+    /// Encode `span` as an `ExportTraceServiceRequest`
+    fn encode(&self, span: &Span) -> String {
+        let mut resource = vec![string_attribute("service.name", &self.service_name)];
+        resource.extend(self.attributes.iter().map(|(k, v)| string_attribute(k, v)));
+
+        let mut attributes = vec![string_attribute("cpuprofiler.path", &span.path)];
+        if let Some(hz) = span.frequency {
+            attributes.push(int_attribute("cpuprofiler.frequency", hz.into()));
+        }
+        if let Some(samples) = span.samples {
+            attributes.push(int_attribute("cpuprofiler.samples", samples));
+        }
+        let events: Vec<String> = span
+            .events
+            .iter()
+            .map(|event| {
+                let attributes = event
+                    .samples
+                    .map(|samples| int_attribute("cpuprofiler.samples", samples))
+                    .unwrap_or_default();
+                format!(
+                    "{{\"timeUnixNano\":\"{}\",\"name\":{},\"attributes\":[{}]}}",
+                    unix_nanos(event.time),
+                    json::string(event.name),
+                    attributes
+                )
+            })
+            .collect();
+        let status = match span.error {
+            Some(ref message) => format!("{{\"code\":2,\"message\":{}}}", json::string(message)),
+            None => "{\"code\":1}".to_owned(),
+        };
+
+        format!(
+            concat!(
+                "{{\"resourceSpans\":[{{\"resource\":{{\"attributes\":[{}]}},",
+                "\"scopeSpans\":[{{\"scope\":{{\"name\":\"cpuprofiler\",\"version\":{}}},",
+                "\"spans\":[{{\"traceId\":\"{}\",\"spanId\":\"{}\",\"name\":\"cpuprofiler.session\",\"kind\":1,",
+                "\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",",
+                "\"attributes\":[{}],\"events\":[{}],\"status\":{}}}]}}]}}]}}"
+            ),
+            resource.join(","),
+            json::string(env!("CARGO_PKG_VERSION")),
+            span.ids.trace_id,
+            span.ids.span_id,
+            unix_nanos(span.start),
+            unix_nanos(span.end),
+            attributes.join(","),
+            events.join(","),
+            status
+        )
+    }
+}
+
+/// A handle to an installed `OtlpExporter`
+#[derive(Debug)]
+pub struct OtlpHandle {
+    thread: JoinHandle<()>,
+    exported: Arc<AtomicUsize>,
+    failed: Arc<AtomicUsize>,
+}
+
+impl OtlpHandle {
+    /// Returns the number of sessions sent to the collector so far
+    pub fn exported(&self) -> usize {
+        self.exported.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of sessions which could not be sent so far
+    pub fn failed(&self) -> usize {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    /// Stop exporting, after sending the sessions which already stopped
+    ///
+    /// A session which is still running is not exported. Returns the
+    /// number of sessions sent.
+    pub fn stop(self) -> usize {
+        EXPORTER.lock().unwrap_or_else(|e| e.into_inner()).take();
+        let _ = self.thread.join();
+        self.exported.load(Ordering::SeqCst)
+    }
+}
+
+/// Returns the ids of the running session's span, `None` when no profile
+/// is running or no exporter is installed
+pub fn current_session() -> Option<SessionSpan> {
+    let installed = EXPORTER.lock().unwrap_or_else(|e| e.into_inner());
+    installed.as_ref()?.open.as_ref().map(|span| span.ids.clone())
+}
+
+/// The installed exporter and the span of the running session
+struct Installed {
+    spans: Sender<Span>,
+    open: Option<Span>,
+}
+
+/// A session, open until its profile stops
+struct Span {
+    ids: SessionSpan,
+    start: SystemTime,
+    end: SystemTime,
+    path: String,
+    frequency: Option<u32>,
+    samples: Option<u64>,
+    events: Vec<Event>,
+    error: Option<String>,
+}
+
+impl Span {
+    fn new(path: &Path, frequency: Option<u32>) -> Span {
+        let now = SystemTime::now();
+        Span {
+            ids: SessionSpan {
+                trace_id: format!("{:016x}{:016x}", random(), random()),
+                span_id: format!("{:016x}", random()),
+            },
+            start: now,
+            end: now,
+            path: path.display().to_string(),
+            frequency,
+            samples: None,
+            events: Vec::new(),
+            error: None,
+        }
+    }
+}
+
+struct Event {
+    name: &'static str,
+    time: SystemTime,
+    samples: Option<u64>,
+}
+
+/// Run `f` on the installed exporter, if there is one
+fn with_exporter<F: FnOnce(&mut Installed)>(f: F) {
+    let mut installed = EXPORTER.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(ref mut installed) = *installed {
+        f(installed);
+    }
+}
+
+/// A profile started writing to `path`
+pub(crate) fn started(path: &Path, frequency: Option<u32>) {
+    with_exporter(|installed| installed.open = Some(Span::new(path, frequency)));
+}
+
+/// The backend refused to start a profile at `path`
+pub(crate) fn start_failed(path: &Path, error: &Error) {
+    with_exporter(|installed| {
+        let mut span = Span::new(path, None);
+        span.error = Some(error.to_string());
+        let _ = installed.spans.send(span);
+    });
+}
+
+/// Record the event `name` on the running session's span
+pub(crate) fn event(name: &'static str, samples: Option<u64>) {
+    with_exporter(|installed| {
+        if let Some(ref mut span) = installed.open {
+            span.events.push(Event {
+                name,
+                time: SystemTime::now(),
+                samples,
+            });
+        }
+    });
+}
+
+/// The running session stopped after taking `samples`
+pub(crate) fn stopped(samples: u64) {
+    with_exporter(|installed| {
+        if let Some(mut span) = installed.open.take() {
+            span.end = SystemTime::now();
+            span.samples = Some(samples);
+            let _ = installed.spans.send(span);
+        }
+    });
+}
+
+/// Returns 64 bits which differ between calls and processes
+fn random() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(unix_nanos(SystemTime::now()));
+    hasher.finish()
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
+
+fn string_attribute(key: &str, value: &str) -> String {
+    format!("{{\"key\":{},\"value\":{{\"stringValue\":{}}}}}", json::string(key), json::string(value))
+}
+
+/// Integers are strings in the JSON encoding of OTLP
+fn int_attribute(key: &str, value: u64) -> String {
+    format!("{{\"key\":{},\"value\":{{\"intValue\":\"{}\"}}}}", json::string(key), value)
+}
@@ -0,0 +1,66 @@
+//! Keeping the profile of a panicking program
+
+use std::ffi::OsString;
+use std::fs;
+use std::panic;
+use std::path::PathBuf;
+
+use lock;
+use metadata;
+
+/// Stop the profiler when a thread panics
+///
+/// The active profile is stopped from a panic hook, before the thread
+/// unwinds or the process aborts, and renamed with a `.crash` suffix so
+/// profiles of failed runs are easy to tell apart. The previously installed
+/// hook, which usually prints the panic message, runs afterwards.
+///
+/// If the panicking thread holds the `PROFILER` lock the profile cannot be
+/// stopped and is left as is. Profiles kept in memory are stopped but not
+/// renamed. The metadata of the profile, see `Profiler::set_metadata`, is
+/// renamed along with it.
+///
+/// # Examples
+///
+/// ```
+/// use std::env;
+/// use cpuprofiler::PROFILER;
+///
+/// let path = env::temp_dir().join("panic-example.profile");
+/// cpuprofiler::install_panic_hook();
+/// PROFILER.lock().unwrap().start(path.to_str().unwrap()).unwrap();
+/// // A panic in here leaves panic-example.profile.crash behind.
+/// PROFILER.lock().unwrap().stop().unwrap();
+/// ```
+pub fn install_panic_hook() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        stop_as_crashed();
+        previous(info);
+    }));
+}
+
+fn stop_as_crashed() {
+    let mut profiler = match lock::try_lock() {
+        Ok(profiler) => profiler,
+        Err(_) => return,
+    };
+    if !profiler.state.is_running() {
+        return;
+    }
+
+    let path = if profiler.in_memory.is_some() { None } else { profiler.path.clone() };
+    let described = profiler.metadata;
+    if profiler.stop().is_err() {
+        return;
+    }
+    if let Some(path) = path {
+        let mut crashed = OsString::from(&path);
+        crashed.push(".crash");
+        let crashed = PathBuf::from(crashed);
+        if fs::rename(&path, &crashed).is_ok() && described {
+            // The description follows its profile.
+            let _ = fs::rename(metadata::path_for(&path), metadata::path_for(&crashed));
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Converting between profile paths and the bytes the profiler takes
+//!
+//! Profile names are taken as bytes, which on Unix are the path as it is.
+//! Windows paths are UTF-16, so there the bytes are UTF-8 and paths which
+//! are not valid Unicode are converted lossily.
+
+use std::path::{Path, PathBuf};
+
+/// Returns the path named by `bytes`
+#[cfg(unix)]
+pub(crate) fn from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Returns the bytes naming `path`
+#[cfg(unix)]
+pub(crate) fn to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn to_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
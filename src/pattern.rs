@@ -0,0 +1,297 @@
+//! Regular expressions for matching function names
+//!
+//! Only what is needed to pick out frames is supported: literals, `.`,
+//! classes such as `[a-z_]`, `[^:]`, `\d`, `\w` and `\s`, groups with `|`,
+//! the repetitions `*`, `+`, `?` and `{n,m}`, and the anchors `^` and `$`.
+//! Matching backtracks, which is plenty fast for symbol names.
+
+use std::fmt;
+
+use error::Error;
+
+#[derive(Clone, Debug)]
+enum Node {
+    Char(char),
+    Any,
+    /// Ranges of characters, and whether the class is negated
+    Class(Vec<(char, char)>, bool),
+    Start,
+    End,
+    /// Alternative sequences
+    Group(Vec<Vec<Node>>),
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+/// A compiled regular expression
+#[derive(Clone)]
+pub(crate) struct Pattern {
+    source: String,
+    alternatives: Vec<Vec<Node>>,
+}
+
+impl fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Pattern({:?})", self.source)
+    }
+}
+
+impl Pattern {
+    /// Compile `source`
+    ///
+    /// # Failures
+    ///
+    /// - `source` is not a regular expression, `Error::InvalidPattern`.
+    pub(crate) fn new(source: &str) -> Result<Pattern, Error> {
+        let mut parser = Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+        };
+        let alternatives = parser.alternatives().map_err(|reason| {
+            Error::InvalidPattern(format!("{:?} at offset {}: {}", source, parser.pos, reason))
+        })?;
+        if parser.pos < parser.chars.len() {
+            return Err(Error::InvalidPattern(format!("{:?} has an unmatched `)`", source)));
+        }
+        Ok(Pattern {
+            source: source.to_owned(),
+            alternatives,
+        })
+    }
+
+    /// Returns whether the pattern matches anywhere in `text`
+    pub(crate) fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        let matcher = Matcher { text: &text };
+        (0..=text.len()).any(|start| {
+            self.alternatives
+                .iter()
+                .any(|seq| matcher.sequence(seq, start, &|_| true))
+        })
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn alternatives(&mut self) -> Result<Vec<Vec<Node>>, &'static str> {
+        let mut alternatives = vec![self.sequence()?];
+        while self.eat('|') {
+            alternatives.push(self.sequence()?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, &'static str> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.repetition(atom)?);
+        }
+        Ok(nodes)
+    }
+
+    fn atom(&mut self) -> Result<Node, &'static str> {
+        match self.next() {
+            Some('(') => {
+                // Groups never capture, so `(?:` means the same as `(`.
+                if self.eat('?') && !self.eat(':') {
+                    return Err("only `(?:` groups are supported");
+                }
+                let alternatives = self.alternatives()?;
+                if !self.eat(')') {
+                    return Err("unclosed `(`");
+                }
+                Ok(Node::Group(alternatives))
+            }
+            Some('[') => self.class(),
+            Some('.') => Ok(Node::Any),
+            Some('^') => Ok(Node::Start),
+            Some('$') => Ok(Node::End),
+            Some('\\') => self.escape(),
+            Some('*') | Some('+') | Some('?') => Err("nothing to repeat"),
+            Some(c) => Ok(Node::Char(c)),
+            None => Err("unexpected end"),
+        }
+    }
+
+    fn escape(&mut self) -> Result<Node, &'static str> {
+        let class = |ranges: &[(char, char)], negated| Ok(Node::Class(ranges.to_vec(), negated));
+        match self.next() {
+            Some('d') => class(DIGITS, false),
+            Some('D') => class(DIGITS, true),
+            Some('w') => class(WORD, false),
+            Some('W') => class(WORD, true),
+            Some('s') => class(SPACE, false),
+            Some('S') => class(SPACE, true),
+            Some(c) => Ok(Node::Char(c)),
+            None => Err("trailing `\\`"),
+        }
+    }
+
+    fn class(&mut self) -> Result<Node, &'static str> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = match self.next() {
+                None => return Err("unclosed `[`"),
+                Some(']') if !first => break,
+                Some('\\') => match self.next() {
+                    Some('d') => {
+                        ranges.extend_from_slice(DIGITS);
+                        continue;
+                    }
+                    Some('w') => {
+                        ranges.extend_from_slice(WORD);
+                        continue;
+                    }
+                    Some('s') => {
+                        ranges.extend_from_slice(SPACE);
+                        continue;
+                    }
+                    Some(c) => c,
+                    None => return Err("trailing `\\`"),
+                },
+                Some(c) => c,
+            };
+            first = false;
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']') {
+                self.pos += 1;
+                let end = self.next().unwrap_or(c);
+                if end < c {
+                    return Err("range out of order");
+                }
+                ranges.push((c, end));
+            } else {
+                ranges.push((c, c));
+            }
+        }
+        Ok(Node::Class(ranges, negated))
+    }
+
+    fn repetition(&mut self, atom: Node) -> Result<Node, &'static str> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => return self.counted(atom),
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+        if let Node::Start | Node::End = atom {
+            return Err("nothing to repeat");
+        }
+        Ok(Node::Repeat(Box::new(atom), min, max))
+    }
+
+    /// Parse `{n}`, `{n,}` or `{n,m}` after `atom`
+    fn counted(&mut self, atom: Node) -> Result<Node, &'static str> {
+        self.pos += 1;
+        let min = self.number().ok_or("expected a number after `{`")?;
+        let max = if self.eat(',') {
+            if self.peek() == Some('}') {
+                None
+            } else {
+                Some(self.number().ok_or("expected a number after `,`")?)
+            }
+        } else {
+            Some(min)
+        };
+        if !self.eat('}') {
+            return Err("unclosed `{`");
+        }
+        if max.is_some_and(|max| max < min) {
+            return Err("repetition out of order");
+        }
+        Ok(Node::Repeat(Box::new(atom), min, max))
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect::<String>().parse().ok()
+    }
+}
+
+const DIGITS: &[(char, char)] = &[('0', '9')];
+const WORD: &[(char, char)] = &[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+const SPACE: &[(char, char)] = &[(' ', ' '), ('\t', '\r')];
+
+struct Matcher<'a> {
+    text: &'a [char],
+}
+
+impl<'a> Matcher<'a> {
+    /// Match `seq` at `pos`, then whatever `rest` matches after it
+    fn sequence(&self, seq: &[Node], pos: usize, rest: &dyn Fn(usize) -> bool) -> bool {
+        match seq.split_first() {
+            None => rest(pos),
+            Some((node, tail)) => self.node(node, pos, &|next| self.sequence(tail, next, rest)),
+        }
+    }
+
+    fn node(&self, node: &Node, pos: usize, rest: &dyn Fn(usize) -> bool) -> bool {
+        let here = self.text.get(pos).cloned();
+        match *node {
+            Node::Char(c) => here == Some(c) && rest(pos + 1),
+            Node::Any => here.is_some() && rest(pos + 1),
+            Node::Class(ref ranges, negated) => match here {
+                Some(c) => ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != negated && rest(pos + 1),
+                None => false,
+            },
+            Node::Start => pos == 0 && rest(pos),
+            Node::End => pos == self.text.len() && rest(pos),
+            Node::Group(ref alternatives) => alternatives.iter().any(|seq| self.sequence(seq, pos, rest)),
+            Node::Repeat(ref inner, min, max) => self.repeat(inner, min, max, pos, 0, rest),
+        }
+    }
+
+    /// Match `inner` as often as possible, having matched it `count` times
+    fn repeat(
+        &self,
+        inner: &Node,
+        min: usize,
+        max: Option<usize>,
+        pos: usize,
+        count: usize,
+        rest: &dyn Fn(usize) -> bool,
+    ) -> bool {
+        // Matches of nothing only count towards the minimum, or they would
+        // repeat forever.
+        if max.is_none_or(|max| count < max)
+            && self.node(inner, pos, &|next| {
+                (next != pos || count < min) && self.repeat(inner, min, max, next, count + 1, rest)
+            })
+        {
+            return true;
+        }
+        count >= min && rest(pos)
+    }
+}
@@ -0,0 +1,240 @@
+//! Guardrails for profiles started from outside the code
+//!
+//! The HTTP [`middleware`](../middleware/index.html), the
+//! [signal toggle](../struct.Profiler.html#method.install_signal_toggle),
+//! the [control socket](../control/index.html) and
+//! [`AutoCapture`](../capture/struct.AutoCapture.html) let profiles be
+//! started by operators, clients or load. Once a `Policy` is set with
+//! `Profiler::set_policy`, each of them asks it before starting a profile,
+//! and a profile it refuses is not started:
+//!
+//! - `max_sessions_per_hour`: triggered profiles which may start in any
+//!   hour.
+//! - `max_duration`: how long a triggered profile may run. It is stopped
+//!   from a background thread once it has run that long, so a request
+//!   profile which runs longer is dropped.
+//! - `allow`: the triggers which may start profiles at all.
+//! - `dir_quota`: how much a directory of profiles may hold. While its
+//!   files take up the quota no triggered profile starts, so clean it up
+//!   or use a [`RotatingProfiler`](../rotate/struct.RotatingProfiler.html)
+//!   with retention to make room.
+//!
+//! Profiles started by calling the `Profiler` directly are not limited,
+//! and do not count towards `max_sessions_per_hour`.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use cpuprofiler::PROFILER;
+//! use cpuprofiler::policy::{Policy, Trigger};
+//!
+//! let policy = Policy::new()
+//!     .max_sessions_per_hour(4)
+//!     .max_duration(Duration::from_secs(60))
+//!     .allow(Trigger::Signal)
+//!     .allow(Trigger::Socket)
+//!     .dir_quota("/var/log/profiles", 512 * 1024 * 1024);
+//!
+//! PROFILER.lock().unwrap().set_policy(Some(policy));
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use error::Error;
+use lifecycle;
+use lock;
+use Profiler;
+
+/// Something which starts profiles on its own
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Trigger {
+    /// A request to the HTTP middleware
+    Http,
+    /// The signal toggle
+    Signal,
+    /// A command to the control socket
+    Socket,
+    /// An `AutoCapture` seeing high CPU use
+    Capture,
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Trigger::Http => write!(f, "http"),
+            Trigger::Signal => write!(f, "signal"),
+            Trigger::Socket => write!(f, "socket"),
+            Trigger::Capture => write!(f, "capture"),
+        }
+    }
+}
+
+/// Limits on the profiles triggers may start
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Policy {
+    max_sessions_per_hour: Option<u32>,
+    max_duration: Option<Duration>,
+    allowed: Option<Vec<Trigger>>,
+    quota: Option<(PathBuf, u64)>,
+}
+
+impl Policy {
+    /// A policy which allows every trigger, without limits
+    pub fn new() -> Policy {
+        Policy::default()
+    }
+
+    /// Allow at most `sessions` triggered profiles to start in any hour
+    pub fn max_sessions_per_hour(mut self, sessions: u32) -> Policy {
+        self.max_sessions_per_hour = Some(sessions);
+        self
+    }
+
+    /// Stop triggered profiles once they have run for `duration`
+    pub fn max_duration(mut self, duration: Duration) -> Policy {
+        self.max_duration = Some(duration);
+        self
+    }
+
+    /// Allow `trigger` to start profiles
+    ///
+    /// Every trigger is allowed until this is first called, from then on
+    /// only the ones passed here.
+    pub fn allow(mut self, trigger: Trigger) -> Policy {
+        let allowed = self.allowed.get_or_insert_with(Vec::new);
+        if !allowed.contains(&trigger) {
+            allowed.push(trigger);
+        }
+        self
+    }
+
+    /// Allow every trigger again, forgetting those passed to `allow`
+    #[cfg(feature = "agent")]
+    pub(crate) fn allow_all(mut self) -> Policy {
+        self.allowed = None;
+        self
+    }
+
+    /// Refuse triggered profiles while the files in `dir` and its
+    /// subdirectories take up `bytes` or more
+    pub fn dir_quota<D: Into<PathBuf>>(mut self, dir: D, bytes: u64) -> Policy {
+        self.quota = Some((dir.into(), bytes));
+        self
+    }
+
+    /// Returns whether `trigger` may start profiles
+    pub fn allows(&self, trigger: Trigger) -> bool {
+        self.allowed.as_ref().is_none_or(|allowed| allowed.contains(&trigger))
+    }
+}
+
+/// A policy and the triggered profiles it let start in the last hour
+#[derive(Debug)]
+pub(crate) struct Enforced {
+    policy: Policy,
+    starts: VecDeque<Instant>,
+}
+
+impl Enforced {
+    /// Returns why `trigger` may not start a profile now, if it may not
+    fn refusal(&mut self, trigger: Trigger) -> Option<String> {
+        if !self.policy.allows(trigger) {
+            return Some(format!("the {} trigger is not allowed", trigger));
+        }
+        if let Some(max) = self.policy.max_sessions_per_hour {
+            let hour = Duration::from_secs(3600);
+            while self.starts.front().is_some_and(|start| start.elapsed() >= hour) {
+                self.starts.pop_front();
+            }
+            if self.starts.len() >= max as usize {
+                return Some(format!("the limit of {} profiles an hour was reached", max));
+            }
+        }
+        if let Some((ref dir, quota)) = self.policy.quota {
+            // A directory which cannot be measured is as good as full.
+            let used = dir_size(dir).unwrap_or(u64::MAX);
+            if used >= quota {
+                return Some(format!("{} holds {} of its {} byte quota", dir.display(), used, quota));
+            }
+        }
+        None
+    }
+}
+
+impl Profiler {
+    /// Set the policy triggers ask before starting profiles, or remove it
+    /// with `None`
+    ///
+    /// The count of profiles started in the last hour starts over with a
+    /// new policy. A profile which is running is not affected.
+    pub fn set_policy(&mut self, policy: Option<Policy>) {
+        self.policy = policy.map(|policy| Enforced {
+            policy,
+            starts: VecDeque::new(),
+        });
+    }
+
+    /// Returns the policy triggers ask before starting profiles, if one is
+    /// set
+    pub fn policy(&self) -> Option<&Policy> {
+        self.policy.as_ref().map(|enforced| &enforced.policy)
+    }
+
+    /// Start a profile for `trigger` with `start`, if the policy allows it
+    ///
+    /// A profile which starts counts towards the hourly limit and is
+    /// stopped once it has run for the policy's longest duration.
+    pub(crate) fn start_triggered<T, F>(&mut self, trigger: Trigger, start: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Profiler) -> Result<T, Error>,
+    {
+        if let Some(reason) = self.policy.as_mut().and_then(|enforced| enforced.refusal(trigger)) {
+            let error = Error::Refused(reason);
+            lifecycle::refused(trigger, &error);
+            return Err(error);
+        }
+        let started = start(self)?;
+
+        let session = self.session;
+        if let Some(ref mut enforced) = self.policy {
+            enforced.starts.push_back(Instant::now());
+            if let Some(limit) = enforced.policy.max_duration {
+                thread::spawn(move || {
+                    thread::sleep(limit);
+                    let mut profiler = lock::lock();
+                    if profiler.session == session && profiler.state.is_running() {
+                        // The profile is over whether or not it stopped cleanly.
+                        let _ = profiler.stop();
+                    }
+                });
+            }
+        }
+        Ok(started)
+    }
+}
+
+/// Returns the bytes the files in `dir` and its subdirectories take up
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let entries = match fs::read_dir(dir) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        entries => entries?,
+    };
+    let mut size = 0;
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
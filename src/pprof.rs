@@ -0,0 +1,513 @@
+//! Conversion of profiles to the pprof protobuf format
+//!
+//! [pprof](https://github.com/google/pprof) and most continuous profiling
+//! backends consume profiles encoded with
+//! [profile.proto](https://github.com/google/pprof/blob/master/proto/profile.proto).
+//! Addresses are exported unsymbolized, alongside the mappings they belong to
+//! and the build ids of their binaries, so that the consumer can symbolize
+//! them, even on another machine. With the `symbolize` feature
+//! `Encoder::symbols` embeds the functions and lines of every address
+//! instead, for binaries which are stripped wherever the profile is read.
+//! The samples of each thread are
+//! recorded as comments, which `pprof -comments` prints. Samples taken in an
+//! async task carry its name as their `task` label, see the
+//! [`tasks`](../tasks/index.html) module. Profiles too large to parse into
+//! memory can be encoded while they are read with a `StreamEncoder`, which
+//! leaves out the task labels since the tasks follow the samples.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use cpuprofiler::PROFILER;
+//! use cpuprofiler::profile::Profile;
+//! use cpuprofiler::pprof::Encoder;
+//!
+//! let path = env::temp_dir().join("pprof-example.profile");
+//! PROFILER.lock().unwrap().start(path.to_str().unwrap()).unwrap();
+//! // Code you want to sample goes here!
+//! PROFILER.lock().unwrap().stop().unwrap();
+//!
+//! let profile = Profile::from_file(&path).unwrap();
+//! let bytes = Encoder::new(&profile).label("service", "example").encode();
+//! assert!(!bytes.is_empty());
+//! ```
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use error::Error;
+use profile::{self, BuildId, Mapping, Profile, Reader, TaskSample, Thread};
+#[cfg(feature = "symbolize")]
+use symbolize::Symbolizer;
+use tasks;
+
+/// Encodes a `Profile` as an uncompressed pprof protobuf message
+#[derive(Debug)]
+pub struct Encoder<'a> {
+    profile: &'a Profile,
+    options: Options,
+}
+
+/// Encodes a profile as it is read, see `StreamEncoder::encode`
+#[derive(Debug, Default)]
+pub struct StreamEncoder {
+    options: Options,
+}
+
+/// What is recorded besides the samples
+#[derive(Debug, Default)]
+struct Options {
+    labels: Vec<(String, String)>,
+    time: Option<SystemTime>,
+    duration: Option<Duration>,
+    #[cfg(feature = "symbolize")]
+    symbols: bool,
+}
+
+impl<'a> Encoder<'a> {
+    /// Create an encoder for `profile`
+    pub fn new(profile: &'a Profile) -> Encoder<'a> {
+        Encoder {
+            profile,
+            options: Options::default(),
+        }
+    }
+
+    /// Attach a string label to every sample
+    pub fn label<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Encoder<'a> {
+        self.options.labels.push((key.into(), value.into()));
+        self
+    }
+
+    /// Attach every label in `labels` to every sample, such as those
+    /// returned by `Profiler::labels`
+    pub fn labels<'b, I>(mut self, labels: I) -> Encoder<'a>
+    where
+        I: IntoIterator<Item = &'b (String, String)>,
+    {
+        self.options.labels.extend(labels.into_iter().cloned());
+        self
+    }
+
+    /// Record the time at which the profile was started
+    pub fn time(mut self, time: SystemTime) -> Encoder<'a> {
+        self.options.time = Some(time);
+        self
+    }
+
+    /// Record how long the profile was collected for
+    pub fn duration(mut self, duration: Duration) -> Encoder<'a> {
+        self.options.duration = Some(duration);
+        self
+    }
+
+    /// Embed the functions, files and lines of every address
+    ///
+    /// Available with the `symbolize` feature. The binaries of the profile
+    /// are read while encoding, see `Symbolizer`, so encode where the
+    /// profile was taken. pprof then shows the functions without needing
+    /// the binaries, or their debug info, wherever the profile is read.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use cpuprofiler::PROFILER;
+    /// use cpuprofiler::profile::Profile;
+    /// use cpuprofiler::pprof::Encoder;
+    ///
+    /// let path = env::temp_dir().join("pprof-symbols-example.profile");
+    /// PROFILER.lock().unwrap().start(path.to_str().unwrap()).unwrap();
+    /// // Code you want to sample goes here!
+    /// PROFILER.lock().unwrap().stop().unwrap();
+    ///
+    /// let profile = Profile::from_file(&path).unwrap();
+    /// let bytes = Encoder::new(&profile).symbols(true).encode();
+    /// assert!(!bytes.is_empty());
+    /// ```
+    #[cfg(feature = "symbolize")]
+    pub fn symbols(mut self, enabled: bool) -> Encoder<'a> {
+        self.options.symbols = enabled;
+        self
+    }
+
+    /// Produce the encoded message
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = ProtoWriter::new(self.profile.sampling_period());
+        let mut out = writer.start(&self.options);
+        let mut tasks: HashMap<&[u64], Vec<&TaskSample>> = HashMap::new();
+        for task in self.profile.task_samples() {
+            tasks.entry(&task.stack).or_default().push(task);
+        }
+        for sample in self.profile.samples() {
+            // The samples of a stack taken in tasks are split off, labelled.
+            let mut rest = sample.count;
+            for task in tasks.get(&sample.stack[..]).into_iter().flatten() {
+                let count = task.count.min(rest);
+                rest -= count;
+                out.extend(writer.sample(&sample.stack, count, Some(&task.task)));
+            }
+            if rest > 0 {
+                out.extend(writer.sample(&sample.stack, rest, None));
+            }
+        }
+        out.extend(writer.finish(&self.options, self.profile.mappings(), self.profile.build_ids(), self.profile.threads()));
+        out
+    }
+}
+
+impl StreamEncoder {
+    /// Create an encoder with nothing recorded besides the samples
+    pub fn new() -> StreamEncoder {
+        StreamEncoder::default()
+    }
+
+    /// Attach a string label to every sample
+    pub fn label<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> StreamEncoder {
+        self.options.labels.push((key.into(), value.into()));
+        self
+    }
+
+    /// Attach every label in `labels` to every sample
+    pub fn labels<'b, I>(mut self, labels: I) -> StreamEncoder
+    where
+        I: IntoIterator<Item = &'b (String, String)>,
+    {
+        self.options.labels.extend(labels.into_iter().cloned());
+        self
+    }
+
+    /// Record the time at which the profile was started
+    pub fn time(mut self, time: SystemTime) -> StreamEncoder {
+        self.options.time = Some(time);
+        self
+    }
+
+    /// Record how long the profile was collected for
+    pub fn duration(mut self, duration: Duration) -> StreamEncoder {
+        self.options.duration = Some(duration);
+        self
+    }
+
+    /// Embed the functions, files and lines of every address, see
+    /// `Encoder::symbols`
+    #[cfg(feature = "symbolize")]
+    pub fn symbols(mut self, enabled: bool) -> StreamEncoder {
+        self.options.symbols = enabled;
+        self
+    }
+
+    /// Write the samples of `reader` to `out` as they are read
+    ///
+    /// The output is the same as `Encoder::encode` gives for the whole
+    /// profile, but only the distinct addresses are held in memory.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use std::fs::File;
+    /// use std::io::BufWriter;
+    /// use cpuprofiler::PROFILER;
+    /// use cpuprofiler::pprof::StreamEncoder;
+    /// use cpuprofiler::profile::Reader;
+    ///
+    /// let path = env::temp_dir().join("stream-pprof-example.profile");
+    /// PROFILER.lock().unwrap().start(path.to_str().unwrap()).unwrap();
+    /// // Code you want to sample goes here!
+    /// PROFILER.lock().unwrap().stop().unwrap();
+    ///
+    /// let reader = Reader::stream(File::open(&path).unwrap()).unwrap();
+    /// let mut out = BufWriter::new(File::create(env::temp_dir().join("stream-pprof-example.pb")).unwrap());
+    /// StreamEncoder::new().label("service", "example").encode(reader, &mut out).unwrap();
+    /// ```
+    ///
+    /// # Failures
+    ///
+    /// - The profile could not be read, see `Reader::next_sample`.
+    /// - `out` could not be written.
+    pub fn encode<R: Read, W: Write>(&self, mut reader: Reader<R>, out: &mut W) -> Result<(), Error> {
+        let mut writer = ProtoWriter::new(reader.sampling_period());
+        out.write_all(&writer.start(&self.options))?;
+        while let Some(sample) = reader.next_sample()? {
+            out.write_all(&writer.sample(&sample.stack, sample.count, None))?;
+        }
+        let footer = reader.finish()?;
+        out.write_all(&writer.finish(&self.options, &footer.mappings, &footer.build_ids, &footer.threads))?;
+        Ok(())
+    }
+}
+
+/// Encodes a profile in pieces: the start, each sample, then the rest
+///
+/// Protobuf fields may come in any order, so the samples are written
+/// before the mappings and locations they refer to.
+struct ProtoWriter {
+    strings: StringTable,
+    labels: Vec<(u64, u64)>,
+    period_nanos: u64,
+    // Locations are keyed by their (adjusted) address.
+    locations: HashMap<u64, u64>,
+    location_order: Vec<u64>,
+}
+
+impl ProtoWriter {
+    fn new(period: Duration) -> ProtoWriter {
+        ProtoWriter {
+            strings: StringTable::new(),
+            labels: Vec::new(),
+            period_nanos: period.as_secs() * 1_000_000_000 + period.subsec_nanos() as u64,
+            locations: HashMap::new(),
+            location_order: Vec::new(),
+        }
+    }
+
+    fn start(&mut self, options: &Options) -> Vec<u8> {
+        let mut out = Vec::new();
+        // sample_type: [samples/count, cpu/nanoseconds]
+        for &(ty, unit) in &[("samples", "count"), ("cpu", "nanoseconds")] {
+            let mut vt = Vec::new();
+            varint_field(&mut vt, 1, self.strings.index(ty));
+            varint_field(&mut vt, 2, self.strings.index(unit));
+            bytes_field(&mut out, 1, &vt);
+        }
+
+        let strings = &mut self.strings;
+        self.labels = options
+            .labels
+            .iter()
+            .map(|(k, v)| (strings.index(k), strings.index(v)))
+            .collect();
+        out
+    }
+
+    fn sample(&mut self, stack: &[u64], count: u64, task: Option<&str>) -> Vec<u8> {
+        let mut msg = Vec::new();
+        let mut ids = Vec::with_capacity(stack.len());
+        for (i, &pc) in stack.iter().enumerate() {
+            // Every frame but the innermost is a return address, point
+            // it back into the call instruction.
+            let addr = if i > 0 && pc > 0 { pc - 1 } else { pc };
+            let next_id = self.locations.len() as u64 + 1;
+            let location_order = &mut self.location_order;
+            let id = *self.locations.entry(addr).or_insert_with(|| {
+                location_order.push(addr);
+                next_id
+            });
+            ids.push(id);
+        }
+        packed_field(&mut msg, 1, &ids);
+        packed_field(&mut msg, 2, &[count, count * self.period_nanos]);
+        let task = task.map(|task| (self.strings.index(tasks::LABEL), self.strings.index(task)));
+        for &(k, v) in self.labels.iter().chain(&task) {
+            let mut label = Vec::new();
+            varint_field(&mut label, 1, k);
+            varint_field(&mut label, 2, v);
+            bytes_field(&mut msg, 3, &label);
+        }
+        let mut out = Vec::new();
+        bytes_field(&mut out, 2, &msg);
+        out
+    }
+
+    fn finish(mut self, options: &Options, mappings: &[Mapping], build_ids: &[BuildId], threads: &[Thread]) -> Vec<u8> {
+        let mut out = Vec::new();
+        #[cfg(feature = "symbolize")]
+        let mut symbols = if options.symbols {
+            Some(Symbols::new(mappings))
+        } else {
+            None
+        };
+        let strings = &mut self.strings;
+
+        let executable: Vec<_> = mappings.iter().filter(|m| m.executable).collect();
+        for (i, mapping) in executable.iter().enumerate() {
+            let mut msg = Vec::new();
+            varint_field(&mut msg, 1, i as u64 + 1);
+            varint_field(&mut msg, 2, mapping.start);
+            varint_field(&mut msg, 3, mapping.end);
+            varint_field(&mut msg, 4, mapping.offset);
+            if let Some(ref path) = mapping.path {
+                varint_field(&mut msg, 5, strings.index(path));
+            }
+            if let Some(id) = profile::find_build_id(build_ids, mapping) {
+                varint_field(&mut msg, 6, strings.index(id));
+            }
+            #[cfg(feature = "symbolize")]
+            {
+                if symbols.is_some() {
+                    // has_functions, has_filenames, has_line_numbers, has_inline_frames
+                    for field in 7..=10 {
+                        varint_field(&mut msg, field, 1);
+                    }
+                }
+            }
+            bytes_field(&mut out, 3, &msg);
+        }
+
+        for (i, &addr) in self.location_order.iter().enumerate() {
+            let mut msg = Vec::new();
+            varint_field(&mut msg, 1, i as u64 + 1);
+            if let Some(m) = executable.iter().position(|m| m.contains(addr)) {
+                varint_field(&mut msg, 2, m as u64 + 1);
+            }
+            varint_field(&mut msg, 3, addr);
+            #[cfg(feature = "symbolize")]
+            {
+                if let Some(ref mut symbols) = symbols {
+                    symbols.lines(&mut msg, strings, addr);
+                }
+            }
+            bytes_field(&mut out, 4, &msg);
+        }
+
+        #[cfg(feature = "symbolize")]
+        {
+            if let Some(symbols) = symbols {
+                out.extend(symbols.functions);
+            }
+        }
+
+        if let Some(time) = options.time {
+            if let Ok(since) = time.duration_since(UNIX_EPOCH) {
+                varint_field(&mut out, 9, since.as_secs() * 1_000_000_000 + since.subsec_nanos() as u64);
+            }
+        }
+        if let Some(duration) = options.duration {
+            varint_field(&mut out, 10, duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64);
+        }
+
+        let mut period_type = Vec::new();
+        varint_field(&mut period_type, 1, strings.index("cpu"));
+        varint_field(&mut period_type, 2, strings.index("nanoseconds"));
+        bytes_field(&mut out, 11, &period_type);
+        varint_field(&mut out, 12, self.period_nanos);
+
+        // pprof has no notion of threads, so their samples are comments.
+        for thread in threads {
+            let comment = format!(
+                "thread {} {}: {} samples",
+                thread.id,
+                thread.name.as_deref().unwrap_or("?"),
+                thread.samples
+            );
+            varint_field(&mut out, 13, strings.index(&comment));
+        }
+
+        // The string table must be written with the empty string first.
+        for s in &strings.strings {
+            bytes_field(&mut out, 6, s.as_bytes());
+        }
+
+        out
+    }
+}
+
+/// The functions and lines of the locations of a profile, see
+/// `Encoder::symbols`
+#[cfg(feature = "symbolize")]
+struct Symbols<'a> {
+    mappings: &'a [Mapping],
+    symbolizer: Symbolizer,
+    /// The id of each function, by name and file
+    ids: HashMap<(String, String), u64>,
+    /// The encoded `Function` messages
+    functions: Vec<u8>,
+}
+
+#[cfg(feature = "symbolize")]
+impl<'a> Symbols<'a> {
+    fn new(mappings: &'a [Mapping]) -> Symbols<'a> {
+        Symbols {
+            mappings,
+            symbolizer: Symbolizer::new(),
+            ids: HashMap::new(),
+            functions: Vec::new(),
+        }
+    }
+
+    /// Add the `Line` of every frame at `addr` to the location `msg`,
+    /// innermost first as pprof expects inlined frames
+    fn lines(&mut self, msg: &mut Vec<u8>, strings: &mut StringTable, addr: u64) {
+        for frame in self.symbolizer.resolve_mapped(self.mappings, addr) {
+            let name = match frame.function {
+                Some(name) => name,
+                None => continue,
+            };
+            let file = frame.file.unwrap_or_default();
+            let next_id = self.ids.len() as u64 + 1;
+            let functions = &mut self.functions;
+            let id = *self.ids.entry((name.clone(), file.clone())).or_insert_with(|| {
+                let mut function = Vec::new();
+                varint_field(&mut function, 1, next_id);
+                varint_field(&mut function, 2, strings.index(&name));
+                varint_field(&mut function, 3, strings.index(&name));
+                varint_field(&mut function, 4, strings.index(&file));
+                bytes_field(functions, 5, &function);
+                next_id
+            });
+
+            let mut line = Vec::new();
+            varint_field(&mut line, 1, id);
+            if let Some(number) = frame.line {
+                varint_field(&mut line, 2, number as u64);
+            }
+            bytes_field(msg, 4, &line);
+        }
+    }
+}
+
+struct StringTable {
+    strings: Vec<String>,
+    indices: HashMap<String, u64>,
+}
+
+impl StringTable {
+    fn new() -> StringTable {
+        let mut table = StringTable {
+            strings: Vec::new(),
+            indices: HashMap::new(),
+        };
+        table.index("");
+        table
+    }
+
+    fn index(&mut self, s: &str) -> u64 {
+        if let Some(&i) = self.indices.get(s) {
+            return i;
+        }
+        let i = self.strings.len() as u64;
+        self.strings.push(s.to_owned());
+        self.indices.insert(s.to_owned(), i);
+        i
+    }
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn varint_field(out: &mut Vec<u8>, field: u64, value: u64) {
+    varint(out, field << 3);
+    varint(out, value);
+}
+
+fn bytes_field(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    varint(out, (field << 3) | 2);
+    varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn packed_field(out: &mut Vec<u8>, field: u64, values: &[u64]) {
+    let mut packed = Vec::new();
+    for &v in values {
+        varint(&mut packed, v);
+    }
+    bytes_field(out, field, &packed);
+}
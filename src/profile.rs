@@ -0,0 +1,1460 @@
+//! Reading profiles written by the cpuprofiler library
+//!
+//! The cpuprofiler writes its samples in the legacy binary format
+//! described in the [gperftools documentation](https://gperftools.github.io/gperftools/cpuprofile-fileformat.html).
+//! The file is a sequence of machine words (in the native width and byte order of
+//! the profiled process) followed by a copy of `/proc/self/maps`. Profiles
+//! written through this crate end with the samples of each thread, see
+//! `Profile::threads`.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use cpuprofiler::PROFILER;
+//! use cpuprofiler::profile::Profile;
+//!
+//! let path = env::temp_dir().join("parse-example.profile");
+//! PROFILER.lock().unwrap().start(path.to_str().unwrap()).unwrap();
+//! // Code you want to sample goes here!
+//! PROFILER.lock().unwrap().stop().unwrap();
+//!
+//! let profile = Profile::from_file(&path).unwrap();
+//! println!("{} samples at {}Hz", profile.total_samples(), profile.frequency());
+//! ```
+//!
+//! Profiles too large to hold in memory can be read one sample at a time
+//! with a `Reader`. To find out what is wrong with a profile which cannot be
+//! parsed, such as one which was cut short, use `verify`. Stacks are
+//! trimmed as they are parsed when the profile was taken with a `StackTrim`,
+//! see `ProfilerBuilder::max_depth`. A `Filter` picks the stacks of a
+//! symbolized profile to report, hiding noisy frames such as those of the
+//! allocator. A `Writer` writes profiles in the same format, such as
+//! fixtures for tests of code which reads them.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str;
+use std::time::Duration;
+
+use buildid;
+use error::Error;
+use tasks;
+use threads;
+use Profiler;
+
+pub use filter::Filter;
+
+/// A parsed cpuprofiler profile
+///
+/// The default profile has no samples, mappings or sampling period.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    period: u64,
+    samples: Vec<Sample>,
+    mappings: Vec<Mapping>,
+    threads: Vec<Thread>,
+    tasks: Vec<TaskSample>,
+    build_ids: Vec<BuildId>,
+}
+
+/// A single stack trace and the number of times it was sampled
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sample {
+    /// The number of times this stack was observed
+    pub count: u64,
+    /// The program counters of the stack, innermost frame first
+    pub stack: Vec<u64>,
+}
+
+/// A memory mapping of the profiled process
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    /// The first address of the mapping
+    pub start: u64,
+    /// The address one past the end of the mapping
+    pub end: u64,
+    /// The offset of the mapping into the backing file
+    pub offset: u64,
+    /// Whether the mapping is executable
+    pub executable: bool,
+    /// The backing file, if any
+    pub path: Option<String>,
+}
+
+/// The samples taken on one thread of the profiled process
+///
+/// The stacks of the profile do not say which thread they were sampled on,
+/// so only the number of samples of each thread is known.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Thread {
+    /// The id the kernel knows the thread by
+    pub id: u64,
+    /// The name of the thread, see `Profiler::name_thread`
+    pub name: Option<String>,
+    /// The number of samples taken on the thread
+    pub samples: u64,
+}
+
+impl Thread {
+    fn parse(line: &str) -> Option<Thread> {
+        // cpuprofiler-thread: id samples [name]
+        let mut fields = line.strip_prefix(threads::PREFIX)?.trim_start().splitn(3, ' ');
+        let id = fields.next()?.parse().ok()?;
+        let samples = fields.next()?.parse().ok()?;
+        let name = fields.next().unwrap_or("").trim();
+        Some(Thread {
+            id,
+            name: if name.is_empty() { None } else { Some(name.to_owned()) },
+            samples,
+        })
+    }
+}
+
+/// The build id of a binary mapped into the profiled process
+///
+/// Build ids are recorded when the profile stops, so that the binaries can
+/// be told apart from other builds at the same path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BuildId {
+    /// The path of the binary, as in its `Mapping`
+    pub path: String,
+    /// The build id, in hex
+    pub id: String,
+}
+
+impl BuildId {
+    fn parse(line: &str) -> Option<BuildId> {
+        // cpuprofiler-buildid: id path
+        let (id, path) = line.strip_prefix(buildid::PREFIX)?.trim_start().split_once(' ')?;
+        Some(BuildId {
+            path: path.trim().to_owned(),
+            id: id.to_owned(),
+        })
+    }
+}
+
+/// Samples of one stack taken in an async task, see the
+/// [`tasks`](../tasks/index.html) module
+///
+/// These samples are also counted by the `Sample` of the same stack.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TaskSample {
+    /// The name of the task
+    pub task: String,
+    /// The number of times the stack was observed in the task
+    pub count: u64,
+    /// The program counters of the stack, innermost frame first
+    pub stack: Vec<u64>,
+}
+
+impl TaskSample {
+    fn parse(line: &str) -> Option<TaskSample> {
+        // cpuprofiler-task: samples depth pc... name
+        let mut rest = line.strip_prefix(tasks::PREFIX)?.trim_start();
+        let mut field = || {
+            let (field, tail) = rest.split_once(' ').unwrap_or((rest, ""));
+            rest = tail;
+            field
+        };
+        let count = field().parse().ok()?;
+        let depth: usize = field().parse().ok()?;
+        let mut stack = Vec::with_capacity(depth.min(MAX_STREAM_DEPTH as usize));
+        for _ in 0..depth {
+            stack.push(u64::from_str_radix(field().strip_prefix("0x")?, 16).ok()?);
+        }
+        let task = rest.trim();
+        if task.is_empty() {
+            return None;
+        }
+        Some(TaskSample {
+            task: task.to_owned(),
+            count,
+            stack,
+        })
+    }
+}
+
+/// The start of the line recording the `StackTrim` of a profile
+const TRIM_PREFIX: &str = "cpuprofiler-stacks:";
+
+/// How the stacks of a profile are cut down as they are parsed
+///
+/// Skipping drops the innermost frames, such as those of the profiler's own
+/// signal handler. Limiting the depth then drops the outermost frames past
+/// the limit, so that deep recursive or async stacks keep the functions
+/// which were running. Stacks which become the same are merged.
+///
+/// A trim chosen with `ProfilerBuilder::skip_frames` or
+/// `ProfilerBuilder::max_depth` is recorded in the profile after the
+/// memory mappings, and applied when the profile is parsed.
+///
+/// # Examples
+///
+/// ```
+/// use cpuprofiler::profile::StackTrim;
+///
+/// let trim = StackTrim::new().skip_frames(1).max_depth(2);
+/// assert_eq!(trim.apply(&[1, 2, 3, 4]), &[2, 3]);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StackTrim {
+    skip: usize,
+    max_depth: Option<usize>,
+}
+
+impl StackTrim {
+    /// Create a trim which keeps whole stacks
+    pub fn new() -> StackTrim {
+        StackTrim::default()
+    }
+
+    /// Drop the `frames` innermost frames of every stack
+    pub fn skip_frames(mut self, frames: usize) -> StackTrim {
+        self.skip = frames;
+        self
+    }
+
+    /// Keep at most `depth` frames of every stack, after skipping
+    pub fn max_depth(mut self, depth: usize) -> StackTrim {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Returns the number of innermost frames dropped
+    pub fn skipped(&self) -> usize {
+        self.skip
+    }
+
+    /// Returns the most frames kept, if limited
+    pub fn depth_limit(&self) -> Option<usize> {
+        self.max_depth
+    }
+
+    /// Returns whether the trim keeps whole stacks
+    pub fn is_noop(&self) -> bool {
+        self.skip == 0 && self.max_depth.is_none()
+    }
+
+    /// Returns the frames of `stack`, innermost first, which the trim keeps
+    pub fn apply<'a>(&self, stack: &'a [u64]) -> &'a [u64] {
+        let stack = &stack[self.skip.min(stack.len())..];
+        match self.max_depth {
+            Some(depth) if depth < stack.len() => &stack[..depth],
+            _ => stack,
+        }
+    }
+
+    fn parse(line: &str) -> Option<StackTrim> {
+        // cpuprofiler-stacks: skip <n> [max-depth <n>]
+        let mut fields = line.strip_prefix(TRIM_PREFIX)?.split_whitespace();
+        let mut trim = StackTrim::new();
+        while let (Some(key), Some(value)) = (fields.next(), fields.next()) {
+            match key {
+                "skip" => trim.skip = value.parse().ok()?,
+                "max-depth" => trim.max_depth = Some(value.parse().ok()?),
+                _ => {}
+            }
+        }
+        Some(trim)
+    }
+
+    /// Record the trim at the end of the profile at `path`
+    ///
+    /// Nothing is written for a trim which keeps whole stacks.
+    pub(crate) fn append(&self, path: &Path) -> io::Result<()> {
+        if self.is_noop() {
+            return Ok(());
+        }
+        let mut line = format!("{} skip {}", TRIM_PREFIX, self.skip);
+        if let Some(depth) = self.max_depth {
+            line.push_str(&format!(" max-depth {}", depth));
+        }
+        line.push('\n');
+        OpenOptions::new().append(true).open(path)?.write_all(line.as_bytes())
+    }
+}
+
+impl Profiler {
+    /// Trim the stacks of every profile written from now on
+    ///
+    /// The trim is recorded in the profile when it is stopped, and applied
+    /// by `Profile::from_file` and `Reader::aggregate`, so reports and
+    /// exports of the profile only see the trimmed stacks. The samples
+    /// themselves are written whole.
+    pub fn set_stack_trim(&mut self, trim: StackTrim) {
+        self.stack_trim = trim;
+    }
+
+    /// Returns how the stacks of profiles are trimmed
+    pub fn stack_trim(&self) -> StackTrim {
+        self.stack_trim
+    }
+}
+
+impl Mapping {
+    /// Returns true if `addr` falls within this mapping
+    pub fn contains(&self, addr: u64) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    pub(crate) fn parse(line: &str) -> Option<Mapping> {
+        // start-end perms offset dev inode [path]
+        let mut fields = line.split_whitespace();
+        let mut range = fields.next()?.splitn(2, '-');
+        let start = u64::from_str_radix(range.next()?, 16).ok()?;
+        let end = u64::from_str_radix(range.next()?, 16).ok()?;
+        let perms = fields.next()?;
+        let offset = u64::from_str_radix(fields.next()?, 16).ok()?;
+        let _dev = fields.next()?;
+        let _inode = fields.next()?;
+        let path = fields.collect::<Vec<_>>().join(" ");
+
+        Some(Mapping {
+            start,
+            end,
+            offset,
+            executable: perms.contains('x'),
+            path: if path.is_empty() { None } else { Some(path) },
+        })
+    }
+}
+
+impl Profile {
+    /// Read and parse the profile stored at `path`
+    ///
+    /// # Failures
+    ///
+    /// - The file could not be read.
+    /// - The file is not a valid cpuprofiler profile.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Profile, Error> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        Profile::from_bytes(&data)
+    }
+
+    /// Parse a profile from its raw bytes
+    ///
+    /// # Failures
+    ///
+    /// - The bytes are not a valid cpuprofiler profile.
+    pub fn from_bytes(data: &[u8]) -> Result<Profile, Error> {
+        let mut words = Words::detect(data)?;
+
+        // Header: [0, 3, version, period, padding]
+        let header = [words.next()?, words.next()?, words.next()?, words.next()?, words.next()?];
+        if header[2] != 0 {
+            return Err(invalid("unsupported format version"));
+        }
+        let period = header[3];
+
+        let mut samples = Vec::new();
+        loop {
+            let count = words.next()?;
+            let depth = words.next()?;
+            if count == 0 && depth == 1 && words.next()? == 0 {
+                break;
+            }
+            if depth > words.remaining() {
+                return Err(invalid("stack depth exceeds profile length"));
+            }
+            let mut stack = Vec::with_capacity(depth as usize);
+            for _ in 0..depth {
+                stack.push(words.next()?);
+            }
+            samples.push(Sample { count, stack });
+        }
+
+        let footer = Footer::parse(&data[words.pos..]);
+
+        let mut profile = Profile {
+            period,
+            samples,
+            mappings: footer.mappings,
+            threads: footer.threads,
+            tasks: footer.tasks,
+            build_ids: footer.build_ids,
+        };
+        if let Some(trim) = footer.trim {
+            profile.trim_stacks(&trim);
+        }
+        Ok(profile)
+    }
+
+    /// Cut down every stack with `trim`, merging stacks which become the
+    /// same
+    ///
+    /// Profiles are trimmed already as they are parsed, if they were taken
+    /// with a trim. Stacks left with no frames are kept, empty.
+    pub fn trim_stacks(&mut self, trim: &StackTrim) {
+        if trim.is_noop() {
+            return;
+        }
+        let mut merged: Vec<Sample> = Vec::with_capacity(self.samples.len());
+        let mut index: HashMap<Vec<u64>, usize> = HashMap::new();
+        for sample in self.samples.drain(..) {
+            let stack = trim.apply(&sample.stack).to_vec();
+            match index.get(&stack) {
+                Some(&i) => merged[i].count += sample.count,
+                None => {
+                    index.insert(stack.clone(), merged.len());
+                    merged.push(Sample {
+                        count: sample.count,
+                        stack,
+                    });
+                }
+            }
+        }
+        self.samples = merged;
+
+        let mut merged: Vec<TaskSample> = Vec::with_capacity(self.tasks.len());
+        for sample in self.tasks.drain(..) {
+            let stack = trim.apply(&sample.stack).to_vec();
+            match merged.iter_mut().find(|t| t.task == sample.task && t.stack == stack) {
+                Some(existing) => existing.count += sample.count,
+                None => merged.push(TaskSample { stack, ..sample }),
+            }
+        }
+        self.tasks = merged;
+    }
+
+    /// Returns the time between samples
+    pub fn sampling_period(&self) -> Duration {
+        Duration::from_micros(self.period)
+    }
+
+    /// Returns the sampling frequency in Hz
+    pub fn frequency(&self) -> u64 {
+        1_000_000u64.checked_div(self.period).unwrap_or(0)
+    }
+
+    /// Returns the recorded samples
+    pub fn samples(&self) -> &[Sample] {
+        &self.samples
+    }
+
+    /// Returns the memory mappings of the profiled process
+    pub fn mappings(&self) -> &[Mapping] {
+        &self.mappings
+    }
+
+    /// Returns the samples of each thread, most sampled first
+    ///
+    /// This is empty for profiles written without thread counts, such as
+    /// those libprofiler writes by itself from `CPUPROFILE`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use std::thread;
+    /// use cpuprofiler::{Profiler, PROFILER};
+    /// use cpuprofiler::profile::Profile;
+    ///
+    /// let path = env::temp_dir().join("threads-example.profile");
+    /// PROFILER.lock().unwrap().start(path.to_str().unwrap()).unwrap();
+    /// thread::Builder::new()
+    ///     .name("render".to_owned())
+    ///     .spawn(|| {
+    ///         Profiler::register_thread();
+    ///         // Work you want to sample goes here!
+    ///     })
+    ///     .unwrap()
+    ///     .join()
+    ///     .unwrap();
+    /// PROFILER.lock().unwrap().stop().unwrap();
+    ///
+    /// let profile = Profile::from_file(&path).unwrap();
+    /// for thread in profile.threads() {
+    ///     println!("{:?}: {} samples", thread.name, thread.samples);
+    /// }
+    /// ```
+    pub fn threads(&self) -> &[Thread] {
+        &self.threads
+    }
+
+    /// Returns the samples taken in async tasks, see the
+    /// [`tasks`](../tasks/index.html) module
+    pub fn task_samples(&self) -> &[TaskSample] {
+        &self.tasks
+    }
+
+    /// Returns the build ids of the binaries mapped into the process
+    pub fn build_ids(&self) -> &[BuildId] {
+        &self.build_ids
+    }
+
+    /// Returns the build id of the binary of `mapping`, if it was recorded
+    pub fn build_id(&self, mapping: &Mapping) -> Option<&str> {
+        find_build_id(&self.build_ids, mapping)
+    }
+
+    /// Returns the mapping containing `addr`, if any
+    pub fn mapping_for(&self, addr: u64) -> Option<&Mapping> {
+        self.mappings.iter().find(|m| m.contains(addr))
+    }
+
+    /// Returns the total number of samples in the profile
+    pub fn total_samples(&self) -> u64 {
+        self.samples.iter().map(|s| s.count).sum()
+    }
+
+    /// Add the samples of `other`, a profile of the same process
+    ///
+    /// Samples of the same stack are merged, as are the samples of the same
+    /// thread. Mappings which this profile lacks are added, so addresses
+    /// of libraries loaded in between still resolve. Merging into the
+    /// default profile takes on the sampling period of `other`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cpuprofiler::PROFILER;
+    /// use cpuprofiler::profile::Profile;
+    ///
+    /// let mut merged = Profile::default();
+    /// for _ in 0..3 {
+    ///     PROFILER.lock().unwrap().start_in_memory().unwrap();
+    ///     // Code you want to sample goes here!
+    ///     let bytes = PROFILER.lock().unwrap().stop_to_vec().unwrap();
+    ///     merged.merge(Profile::from_bytes(&bytes).unwrap()).unwrap();
+    /// }
+    /// println!("{} samples", merged.total_samples());
+    /// ```
+    ///
+    /// # Failures
+    ///
+    /// - The profiles were sampled with different periods,
+    ///   `Error::InvalidProfile`.
+    pub fn merge(&mut self, other: Profile) -> Result<(), Error> {
+        if self.period == 0 && self.samples.is_empty() {
+            self.period = other.period;
+        } else if other.period != self.period && !other.samples.is_empty() {
+            return Err(Error::InvalidProfile(format!(
+                "merging profiles sampled every {}us and every {}us",
+                self.period, other.period
+            )));
+        }
+
+        let mut stacks: HashMap<Vec<u64>, usize> = self
+            .samples
+            .iter()
+            .enumerate()
+            .map(|(i, sample)| (sample.stack.clone(), i))
+            .collect();
+        for sample in other.samples {
+            match stacks.get(&sample.stack) {
+                Some(&i) => self.samples[i].count += sample.count,
+                None => {
+                    stacks.insert(sample.stack.clone(), self.samples.len());
+                    self.samples.push(sample);
+                }
+            }
+        }
+
+        for mapping in other.mappings {
+            if !self.mappings.contains(&mapping) {
+                self.mappings.push(mapping);
+            }
+        }
+
+        for thread in other.threads {
+            match self.threads.iter_mut().find(|t| t.id == thread.id) {
+                Some(existing) => {
+                    existing.samples += thread.samples;
+                    if existing.name.is_none() {
+                        existing.name = thread.name;
+                    }
+                }
+                None => self.threads.push(thread),
+            }
+        }
+        self.threads.sort_by(|a, b| b.samples.cmp(&a.samples).then(a.id.cmp(&b.id)));
+
+        for build_id in other.build_ids {
+            if !self.build_ids.contains(&build_id) {
+                self.build_ids.push(build_id);
+            }
+        }
+
+        for sample in other.tasks {
+            match self.tasks.iter_mut().find(|t| t.task == sample.task && t.stack == sample.stack) {
+                Some(existing) => existing.count += sample.count,
+                None => self.tasks.push(sample),
+            }
+        }
+        Ok(())
+    }
+
+    /// Weigh the samples as if they had been taken every `period`
+    /// microseconds, so the profile can be merged with one sampled that often
+    ///
+    /// Counts are rounded, and samples rounded down to nothing are dropped.
+    pub(crate) fn resample(&mut self, period: u64) {
+        if self.period == 0 || period == 0 || self.period == period {
+            return;
+        }
+        let from = self.period;
+        let scale = |count: u64| (count as f64 * from as f64 / period as f64).round() as u64;
+        for sample in &mut self.samples {
+            sample.count = scale(sample.count);
+        }
+        self.samples.retain(|sample| sample.count > 0);
+        for sample in &mut self.tasks {
+            sample.count = scale(sample.count);
+        }
+        self.tasks.retain(|sample| sample.count > 0);
+        for thread in &mut self.threads {
+            thread.samples = scale(thread.samples);
+        }
+        self.period = period;
+    }
+
+    /// Record every sample as a sample of the task `name` as well
+    pub(crate) fn attribute_to_task(&mut self, name: &str) {
+        for sample in &self.samples {
+            match self.tasks.iter_mut().find(|t| t.task == name && t.stack == sample.stack) {
+                Some(existing) => existing.count += sample.count,
+                None => self.tasks.push(TaskSample {
+                    task: name.to_owned(),
+                    count: sample.count,
+                    stack: sample.stack.clone(),
+                }),
+            }
+        }
+    }
+
+    /// Write the profile in the cpuprofiler format, with native machine
+    /// words
+    ///
+    /// The stacks are written as they are, already trimmed, so no trim is
+    /// recorded. Line breaks in names are replaced, so that each takes one
+    /// line.
+    pub(crate) fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let samples = self.samples.iter().map(|sample| (sample.count, &sample.stack[..]));
+        write_samples(out, self.period, samples)?;
+
+        let line = |name: &str| -> String { name.chars().map(|c| if c.is_control() { '_' } else { c }).collect() };
+        for mapping in &self.mappings {
+            let perms = if mapping.executable { "r-xp" } else { "r--p" };
+            let path = mapping.path.as_ref().map(|path| line(path)).unwrap_or_default();
+            writeln!(out, "{:x}-{:x} {} {:08x} 00:00 0 {}", mapping.start, mapping.end, perms, mapping.offset, path)?;
+        }
+        for thread in &self.threads {
+            let name = thread.name.as_ref().map(|name| line(name)).unwrap_or_default();
+            writeln!(out, "{} {} {} {}", threads::PREFIX, thread.id, thread.samples, name)?;
+        }
+        for build_id in &self.build_ids {
+            writeln!(out, "{} {} {}", buildid::PREFIX, build_id.id, line(&build_id.path))?;
+        }
+        for sample in &self.tasks {
+            write!(out, "{} {} {}", tasks::PREFIX, sample.count, sample.stack.len())?;
+            for pc in &sample.stack {
+                write!(out, " {:#x}", pc)?;
+            }
+            writeln!(out, " {}", line(&sample.task))?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the header, `samples` and trailer of a profile sampled every
+/// `period` microseconds, in native machine words
+pub(crate) fn write_samples<'a, W, I>(out: &mut W, period: u64, samples: I) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = (u64, &'a [u64])>,
+{
+    let word = |out: &mut W, w: u64| out.write_all(&(w as usize).to_ne_bytes());
+    for &w in &[0, 3, 0, period, 0] {
+        word(out, w)?;
+    }
+    for (count, stack) in samples {
+        word(out, count)?;
+        word(out, stack.len() as u64)?;
+        for &pc in stack {
+            word(out, pc)?;
+        }
+    }
+    for &w in &[0, 1, 0] {
+        word(out, w)?;
+    }
+    Ok(())
+}
+
+/// Writes profiles in the cpuprofiler format
+///
+/// The profile is built up from samples, mappings and the other parts of
+/// a `Profile`, and written in the native machine words of this process,
+/// with the same footer profiles taken through this crate have. Use it to
+/// make fixtures for code which reads profiles, or to write out a `Profile`
+/// which was changed, with `Writer::from`. Profiles taken by the
+/// cpuprofiler library always list mappings, so add at least one for the
+/// profile to pass `verify`.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use cpuprofiler::profile::{Mapping, Profile, Writer};
+///
+/// let bytes = Writer::new(Duration::from_millis(10))
+///     .sample(3, &[0x1010, 0x1200])
+///     .sample(1, &[0x1200])
+///     .mapping(Mapping {
+///         start: 0x1000,
+///         end: 0x2000,
+///         offset: 0,
+///         executable: true,
+///         path: Some("/usr/bin/fixture".to_owned()),
+///     })
+///     .to_bytes();
+///
+/// let profile = Profile::from_bytes(&bytes).unwrap();
+/// assert_eq!(profile.frequency(), 100);
+/// assert_eq!(profile.total_samples(), 4);
+/// assert_eq!(profile.mappings()[0].path.as_deref(), Some("/usr/bin/fixture"));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Writer {
+    profile: Profile,
+}
+
+impl Writer {
+    /// Write a profile sampled every `period`
+    ///
+    /// The period is recorded in whole microseconds, as the cpuprofiler
+    /// library does.
+    pub fn new(period: Duration) -> Writer {
+        Writer {
+            profile: Profile {
+                period: period.as_micros() as u64,
+                ..Profile::default()
+            },
+        }
+    }
+
+    /// Add `count` samples of `stack`, innermost frame first
+    pub fn sample(mut self, count: u64, stack: &[u64]) -> Writer {
+        self.profile.samples.push(Sample {
+            count,
+            stack: stack.to_vec(),
+        });
+        self
+    }
+
+    /// Add a memory mapping of the profiled process
+    pub fn mapping(mut self, mapping: Mapping) -> Writer {
+        self.profile.mappings.push(mapping);
+        self
+    }
+
+    /// Add the samples of a thread, see `Profile::threads`
+    pub fn thread(mut self, thread: Thread) -> Writer {
+        self.profile.threads.push(thread);
+        self
+    }
+
+    /// Add the build id of a mapped binary, see `Profile::build_ids`
+    pub fn build_id(mut self, build_id: BuildId) -> Writer {
+        self.profile.build_ids.push(build_id);
+        self
+    }
+
+    /// Add samples taken in an async task, see `Profile::task_samples`
+    pub fn task_sample(mut self, sample: TaskSample) -> Writer {
+        self.profile.tasks.push(sample);
+        self
+    }
+
+    /// Write the profile to `out`
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        self.profile.write_to(out)
+    }
+
+    /// Returns the bytes of the profile
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        // Writing to a `Vec` cannot fail.
+        let _ = self.write(&mut bytes);
+        bytes
+    }
+
+    /// Write the profile to the file `path`, replacing it
+    ///
+    /// # Failures
+    ///
+    /// - The file could not be written, `Error::Io`.
+    pub fn write_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write(&mut out)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+impl From<Profile> for Writer {
+    fn from(profile: Profile) -> Writer {
+        Writer { profile }
+    }
+}
+
+/// What follows the samples of a profile, read by `Reader::finish`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Footer {
+    /// The memory mappings of the profiled process
+    pub mappings: Vec<Mapping>,
+    /// The samples of each thread, see `Profile::threads`
+    pub threads: Vec<Thread>,
+    /// How the stacks were to be trimmed, see `StackTrim`
+    pub trim: Option<StackTrim>,
+    /// The samples taken in async tasks, see `Profile::task_samples`
+    pub tasks: Vec<TaskSample>,
+    /// The build ids of the mapped binaries, see `Profile::build_ids`
+    pub build_ids: Vec<BuildId>,
+}
+
+impl Footer {
+    /// Parse the lines after the samples
+    ///
+    /// Bytes which are not Utf8, such as those of a path, are replaced
+    /// within their own line, so the other lines are still read.
+    fn parse(maps: &[u8]) -> Footer {
+        let maps = String::from_utf8_lossy(maps);
+        Footer {
+            mappings: maps.lines().filter_map(Mapping::parse).collect(),
+            threads: maps.lines().filter_map(Thread::parse).collect(),
+            trim: maps.lines().filter_map(StackTrim::parse).next(),
+            tasks: maps.lines().filter_map(TaskSample::parse).collect(),
+            build_ids: maps.lines().filter_map(BuildId::parse).collect(),
+        }
+    }
+}
+
+/// Returns the build id of the binary of `mapping` among `build_ids`
+pub(crate) fn find_build_id<'a>(build_ids: &'a [BuildId], mapping: &Mapping) -> Option<&'a str> {
+    let path = mapping.path.as_ref()?;
+    build_ids.iter().find(|b| &b.path == path).map(|b| b.id.as_str())
+}
+
+/// The deepest stack `Reader` accepts, anything deeper is taken as corruption
+const MAX_STREAM_DEPTH: u64 = 1 << 16;
+
+/// Where a `Reader` is in the profile
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Position {
+    Samples,
+    Footer,
+    Failed,
+}
+
+/// Reads the samples of a profile one at a time
+///
+/// `Profile::from_file` holds the whole profile in memory, which is too
+/// much for long captures at high frequencies. A `Reader` yields the
+/// samples as they are read, then `finish` reads the memory mappings which
+/// follow them. libprofiler writes a stack out again every time it is
+/// evicted from its table, so `aggregate` merges repeated stacks into a
+/// `Profile` which only grows with the number of distinct stacks.
+///
+/// The `StackTrim` recorded in a profile is only read after its samples,
+/// so samples are yielded whole unless a trim is given with `trim`.
+/// `aggregate` applies the recorded trim if none was given.
+///
+/// Iteration ends after the last sample, or after the first error.
+///
+/// # Examples
+///
+/// ```
+/// use std::env;
+/// use std::fs::File;
+/// use cpuprofiler::PROFILER;
+/// use cpuprofiler::profile::Reader;
+///
+/// let path = env::temp_dir().join("reader-example.profile");
+/// PROFILER.lock().unwrap().start(path.to_str().unwrap()).unwrap();
+/// // Code you want to sample goes here!
+/// PROFILER.lock().unwrap().stop().unwrap();
+///
+/// let mut reader = Reader::stream(File::open(&path).unwrap()).unwrap();
+/// let mut deepest = 0;
+/// for sample in &mut reader {
+///     deepest = deepest.max(sample.unwrap().stack.len());
+/// }
+/// let footer = reader.finish().unwrap();
+/// println!("deepest stack {} in {} mappings", deepest, footer.mappings.len());
+/// ```
+#[derive(Debug)]
+pub struct Reader<R> {
+    inner: R,
+    width: usize,
+    big_endian: bool,
+    period: u64,
+    offset: u64,
+    position: Position,
+    trim: StackTrim,
+}
+
+impl<R: Read> Reader<BufReader<R>> {
+    /// Read the profile from `inner`, buffering the reads
+    ///
+    /// # Failures
+    ///
+    /// - The header could not be read.
+    /// - `inner` does not start with a cpuprofiler profile header.
+    pub fn stream(inner: R) -> Result<Reader<BufReader<R>>, Error> {
+        Reader::new(BufReader::new(inner))
+    }
+}
+
+impl<R: Read> Reader<R> {
+    /// Read the profile from `inner`, which should already be buffered
+    ///
+    /// # Failures
+    ///
+    /// - See `stream`.
+    pub fn new(mut inner: R) -> Result<Reader<R>, Error> {
+        // Two words of the widest size tell the size and byte order apart.
+        let mut start = [0u8; 16];
+        read_header(&mut inner, &mut start)?;
+        let mut words = Words::detect(&start)?;
+        let mut reader = Reader {
+            inner,
+            width: words.width,
+            big_endian: words.big_endian,
+            period: 0,
+            offset: 0,
+            position: Position::Samples,
+            trim: StackTrim::new(),
+        };
+
+        // Header: [0, 3, version, period, padding]
+        let mut header = [0u64; 5];
+        for word in header.iter_mut().take(16 / reader.width) {
+            *word = words.next()?;
+        }
+        reader.offset = 16;
+        for word in header.iter_mut().skip(16 / reader.width) {
+            *word = reader.word()?;
+        }
+        if header[2] != 0 {
+            return Err(invalid("unsupported format version"));
+        }
+        reader.period = header[3];
+        Ok(reader)
+    }
+
+    /// Cut down the stacks of the samples read with `trim`
+    pub fn trim(mut self, trim: StackTrim) -> Reader<R> {
+        self.trim = trim;
+        self
+    }
+
+    /// Returns the time between samples
+    pub fn sampling_period(&self) -> Duration {
+        Duration::from_micros(self.period)
+    }
+
+    /// Returns the sampling frequency in Hz
+    pub fn frequency(&self) -> u64 {
+        1_000_000u64.checked_div(self.period).unwrap_or(0)
+    }
+
+    /// Returns how many bytes of the profile have been read
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Read the next sample, or `None` after the last one
+    ///
+    /// # Failures
+    ///
+    /// - The profile could not be read, or is truncated or corrupt.
+    pub fn next_sample(&mut self) -> Result<Option<Sample>, Error> {
+        if self.position != Position::Samples {
+            return Ok(None);
+        }
+        let sample = self.read_sample();
+        self.position = match sample {
+            Ok(Some(_)) => Position::Samples,
+            Ok(None) => Position::Footer,
+            Err(_) => Position::Failed,
+        };
+        sample
+    }
+
+    fn read_sample(&mut self) -> Result<Option<Sample>, Error> {
+        let offset = self.offset;
+        let count = self.word()?;
+        let depth = self.word()?;
+        if count == 0 && depth == 1 && self.word()? == 0 {
+            return Ok(None);
+        }
+        if depth > MAX_STREAM_DEPTH {
+            return Err(Error::InvalidProfile(format!(
+                "the record at byte {} has {} frames, the profile is corrupt",
+                offset, depth
+            )));
+        }
+        let mut stack = Vec::with_capacity(depth as usize);
+        for _ in 0..depth {
+            stack.push(self.word()?);
+        }
+        if !self.trim.is_noop() {
+            stack = self.trim.apply(&stack).to_vec();
+        }
+        Ok(Some(Sample { count, stack }))
+    }
+
+    /// Read the rest of the samples and the memory mappings after them
+    ///
+    /// # Failures
+    ///
+    /// - A sample could not be read, see `next_sample`.
+    /// - The mappings could not be read.
+    pub fn finish(mut self) -> Result<Footer, Error> {
+        while self.next_sample()?.is_some() {}
+        if self.position == Position::Failed {
+            return Err(invalid("the profile could not be read"));
+        }
+        let mut maps = Vec::new();
+        self.inner.read_to_end(&mut maps)?;
+        Ok(Footer::parse(&maps))
+    }
+
+    /// Read the whole profile, merging samples of the same stack
+    ///
+    /// Memory use grows with the number of distinct stacks rather than with
+    /// the length of the profile. The samples are ordered by their stacks.
+    /// The trim recorded in the profile is applied unless one was given.
+    ///
+    /// # Failures
+    ///
+    /// - See `next_sample` and `finish`.
+    pub fn aggregate(mut self) -> Result<Profile, Error> {
+        let mut merged: HashMap<Vec<u64>, u64> = HashMap::new();
+        while let Some(sample) = self.next_sample()? {
+            *merged.entry(sample.stack).or_insert(0) += sample.count;
+        }
+        let period = self.period;
+        let trimmed = !self.trim.is_noop();
+        let footer = self.finish()?;
+
+        let mut samples: Vec<Sample> = merged
+            .into_iter()
+            .map(|(stack, count)| Sample { count, stack })
+            .collect();
+        samples.sort_by(|a, b| a.stack.cmp(&b.stack));
+        let mut profile = Profile {
+            period,
+            samples,
+            mappings: footer.mappings,
+            threads: footer.threads,
+            tasks: footer.tasks,
+            build_ids: footer.build_ids,
+        };
+        match footer.trim {
+            Some(trim) if !trimmed => profile.trim_stacks(&trim),
+            _ => {}
+        }
+        Ok(profile)
+    }
+
+    fn word(&mut self) -> Result<u64, Error> {
+        let mut bytes = [0u8; 8];
+        let bytes = &mut bytes[..self.width];
+        match self.inner.read_exact(bytes) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(Error::InvalidProfile(format!("unexpected end of profile at byte {}", self.offset)));
+            }
+            Err(e) => return Err(e.into()),
+        }
+        self.offset += self.width as u64;
+
+        let mut value = 0u64;
+        for i in 0..self.width {
+            let b = if self.big_endian { bytes[i] } else { bytes[self.width - 1 - i] };
+            value = (value << 8) | b as u64;
+        }
+        Ok(value)
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<Sample, Error>;
+
+    fn next(&mut self) -> Option<Result<Sample, Error>> {
+        self.next_sample().transpose()
+    }
+}
+
+fn read_header<R: Read>(inner: &mut R, start: &mut [u8]) -> Result<(), Error> {
+    match inner.read_exact(start) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(invalid("missing profile header")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Something `verify` found wrong with a profile
+///
+/// Offsets are in bytes from the start of the file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// The file does not start with a profile header
+    MissingHeader,
+    /// The file ends at `offset`, inside the header
+    TruncatedHeader {
+        /// The length of the file
+        offset: u64,
+    },
+    /// The header has a format version other than 0
+    UnsupportedVersion {
+        /// Where the version is
+        offset: u64,
+        /// The version found
+        version: u64,
+    },
+    /// The header has a sampling period of 0
+    ZeroPeriod {
+        /// Where the period is
+        offset: u64,
+    },
+    /// The record at `offset` has more frames than the file has room for,
+    /// because the file was cut short or the record is corrupt
+    TruncatedRecord {
+        /// Where the record starts
+        offset: u64,
+        /// The number of frames the record claims
+        depth: u64,
+    },
+    /// The record at `offset` was sampled 0 times
+    ZeroCount {
+        /// Where the record starts
+        offset: u64,
+    },
+    /// The record at `offset` has no frames
+    EmptyStack {
+        /// Where the record starts
+        offset: u64,
+    },
+    /// The file ends at `offset` without the record which ends the samples,
+    /// usually because the profile was never stopped
+    MissingTrailer {
+        /// The length of the file
+        offset: u64,
+    },
+    /// The record at `offset` looks like the end of the samples but is not
+    BadTrailer {
+        /// Where the record starts
+        offset: u64,
+    },
+    /// Nothing follows the samples, the memory mappings are missing
+    MissingMaps {
+        /// Where the mappings should start
+        offset: u64,
+    },
+    /// The memory mappings are not valid Utf8 from `offset`, or hold no
+    /// mapping at all
+    InvalidMaps {
+        /// The first byte which could not be read
+        offset: u64,
+    },
+    /// The profile is intact but holds no samples
+    NoSamples,
+}
+
+impl Problem {
+    /// Returns where in the file the problem is, if it is anywhere
+    pub fn offset(&self) -> Option<u64> {
+        match *self {
+            Problem::MissingHeader => Some(0),
+            Problem::NoSamples => None,
+            Problem::TruncatedHeader { offset }
+            | Problem::UnsupportedVersion { offset, .. }
+            | Problem::ZeroPeriod { offset }
+            | Problem::TruncatedRecord { offset, .. }
+            | Problem::ZeroCount { offset }
+            | Problem::EmptyStack { offset }
+            | Problem::MissingTrailer { offset }
+            | Problem::BadTrailer { offset }
+            | Problem::MissingMaps { offset }
+            | Problem::InvalidMaps { offset } => Some(offset),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::MissingHeader => write!(f, "byte 0: not a cpuprofiler profile"),
+            Problem::TruncatedHeader { offset } => write!(f, "byte {}: the file ends inside the header", offset),
+            Problem::UnsupportedVersion { offset, version } => {
+                write!(f, "byte {}: unsupported format version {}", offset, version)
+            }
+            Problem::ZeroPeriod { offset } => write!(f, "byte {}: the sampling period is 0", offset),
+            Problem::TruncatedRecord { offset, depth } => write!(
+                f,
+                "byte {}: the record has {} frames but the file ends first, it is truncated or corrupt",
+                offset, depth
+            ),
+            Problem::ZeroCount { offset } => write!(f, "byte {}: the record was sampled 0 times", offset),
+            Problem::EmptyStack { offset } => write!(f, "byte {}: the record has no frames", offset),
+            Problem::MissingTrailer { offset } => write!(
+                f,
+                "byte {}: the file ends before the end of the samples, the profile may not have been stopped",
+                offset
+            ),
+            Problem::BadTrailer { offset } => write!(f, "byte {}: malformed end of the samples", offset),
+            Problem::MissingMaps { offset } => write!(f, "byte {}: the memory mappings are missing", offset),
+            Problem::InvalidMaps { offset } => write!(f, "byte {}: the memory mappings are unreadable", offset),
+            Problem::NoSamples => write!(f, "the profile holds no samples"),
+        }
+    }
+}
+
+/// The result of checking a profile with `verify`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Verification {
+    /// The length of the file in bytes
+    pub len: u64,
+    /// The width of the machine words of the profile, 0 without a header
+    pub word_size: usize,
+    /// Whether the words are big endian
+    pub big_endian: bool,
+    /// The number of stack records read
+    pub records: u64,
+    /// The number of samples in those records
+    pub samples: u64,
+    /// The number of memory mappings
+    pub mappings: usize,
+    /// Everything found wrong, in the order of the file
+    pub problems: Vec<Problem>,
+}
+
+impl Verification {
+    /// Returns whether nothing is wrong with the profile
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} bytes, {} samples in {} records, {} mappings",
+            self.len, self.samples, self.records, self.mappings
+        )?;
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check the profile at `path` for truncation and corruption
+///
+/// Unlike `Profile::from_file`, which stops at the first problem, this
+/// reads as much of the file as it can and reports every problem with its
+/// offset. Profiles with no samples are reported too.
+///
+/// # Examples
+///
+/// ```
+/// use std::env;
+/// use cpuprofiler::PROFILER;
+/// use cpuprofiler::profile;
+///
+/// let path = env::temp_dir().join("verify-example.profile");
+/// PROFILER.lock().unwrap().start(path.to_str().unwrap()).unwrap();
+/// PROFILER.lock().unwrap().stop().unwrap();
+///
+/// let verification = profile::verify(&path).unwrap();
+/// for problem in &verification.problems {
+///     println!("{}", problem);
+/// }
+/// ```
+///
+/// # Failures
+///
+/// - The file could not be read.
+pub fn verify<P: AsRef<Path>>(path: P) -> Result<Verification, Error> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(verify_bytes(&data))
+}
+
+/// Check the raw bytes of a profile, see `verify`
+pub fn verify_bytes(data: &[u8]) -> Verification {
+    let mut verification = Verification {
+        len: data.len() as u64,
+        ..Verification::default()
+    };
+    let problems = &mut verification.problems;
+    let end = data.len() as u64;
+
+    let mut words = match Words::detect(data) {
+        Ok(words) => words,
+        // Too short for the two words which tell the word size apart.
+        Err(_) if data.len() < 16 => {
+            problems.push(Problem::TruncatedHeader { offset: end });
+            return verification;
+        }
+        Err(_) => {
+            problems.push(Problem::MissingHeader);
+            return verification;
+        }
+    };
+    verification.word_size = words.width;
+    verification.big_endian = words.big_endian;
+    let width = words.width as u64;
+
+    let header: Result<Vec<u64>, Error> = (0..5).map(|_| words.next()).collect();
+    let header = match header {
+        Ok(header) => header,
+        Err(_) => {
+            problems.push(Problem::TruncatedHeader { offset: end });
+            return verification;
+        }
+    };
+    if header[2] != 0 {
+        problems.push(Problem::UnsupportedVersion {
+            offset: 2 * width,
+            version: header[2],
+        });
+    }
+    if header[3] == 0 {
+        problems.push(Problem::ZeroPeriod { offset: 3 * width });
+    }
+
+    loop {
+        let offset = words.pos as u64;
+        let (count, depth) = match (words.next(), words.next()) {
+            (Ok(count), Ok(depth)) => (count, depth),
+            _ => {
+                problems.push(Problem::MissingTrailer { offset: end });
+                return verification;
+            }
+        };
+        if count == 0 && depth == 1 {
+            match words.next() {
+                Ok(0) => break,
+                Ok(_) => problems.push(Problem::BadTrailer { offset }),
+                Err(_) => {
+                    problems.push(Problem::MissingTrailer { offset: end });
+                    return verification;
+                }
+            }
+            continue;
+        }
+        if depth > words.remaining() {
+            problems.push(Problem::TruncatedRecord { offset, depth });
+            return verification;
+        }
+        if count == 0 {
+            problems.push(Problem::ZeroCount { offset });
+        }
+        if depth == 0 {
+            problems.push(Problem::EmptyStack { offset });
+        }
+        words.pos += depth as usize * words.width;
+        verification.records += 1;
+        verification.samples += count;
+    }
+
+    let offset = words.pos as u64;
+    let maps = &data[words.pos..];
+    if maps.is_empty() {
+        problems.push(Problem::MissingMaps { offset });
+    } else {
+        match str::from_utf8(maps) {
+            Ok(maps) => {
+                verification.mappings = maps.lines().filter_map(Mapping::parse).count();
+                if verification.mappings == 0 {
+                    problems.push(Problem::InvalidMaps { offset });
+                }
+            }
+            Err(e) => problems.push(Problem::InvalidMaps {
+                offset: offset + e.valid_up_to() as u64,
+            }),
+        }
+    }
+    if verification.samples == 0 {
+        problems.push(Problem::NoSamples);
+    }
+    verification
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidProfile(reason.to_owned())
+}
+
+/// Read the sampling frequency from the header of the profile at `path`
+///
+/// Only the header is read, so this is cheap however large the profile is.
+pub(crate) fn header_frequency(path: &Path) -> Option<u64> {
+    let mut header = Vec::with_capacity(40);
+    File::open(path).ok()?.take(40).read_to_end(&mut header).ok()?;
+    let mut words = Words::detect(&header).ok()?;
+    for _ in 0..3 {
+        words.next().ok()?;
+    }
+    1_000_000u64.checked_div(words.next().ok()?)
+}
+
+/// Reads machine words of the width and byte order used by the profile
+struct Words<'a> {
+    data: &'a [u8],
+    pos: usize,
+    width: usize,
+    big_endian: bool,
+}
+
+impl<'a> Words<'a> {
+    fn detect(data: &'a [u8]) -> Result<Words<'a>, Error> {
+        // The second header word is always 3, which tells us both the
+        // word size and the byte order.
+        for &width in &[8, 4] {
+            for &big_endian in &[false, true] {
+                let mut words = Words {
+                    data,
+                    pos: 0,
+                    width,
+                    big_endian,
+                };
+                if let (Ok(0), Ok(3)) = (words.next(), words.next()) {
+                    words.pos = 0;
+                    return Ok(words);
+                }
+            }
+        }
+        Err(invalid("missing profile header"))
+    }
+
+    fn remaining(&self) -> u64 {
+        ((self.data.len() - self.pos) / self.width) as u64
+    }
+
+    fn next(&mut self) -> Result<u64, Error> {
+        if self.data.len() - self.pos < self.width {
+            return Err(invalid("unexpected end of profile"));
+        }
+        let bytes = &self.data[self.pos..self.pos + self.width];
+        self.pos += self.width;
+
+        let mut value = 0u64;
+        for i in 0..self.width {
+            let b = if self.big_endian { bytes[i] } else { bytes[self.width - 1 - i] };
+            value = (value << 8) | b as u64;
+        }
+        Ok(value)
+    }
+}
@@ -0,0 +1,161 @@
+//! Taking turns at profiling
+//!
+//! Only one profile can run at a time, so when two parts of a program both
+//! want a profile the second `Profiler::start` fails with
+//! `Error::InvalidState`. `Profiler::enqueue` instead queues the request,
+//! and a background thread runs the queued profiles one after another, each
+//! into its own file, as soon as the profiler is free. Profiles started
+//! without the queue are waited for too, as is a `ProfilerHandle` being
+//! released.
+//!
+//! Each request gets a `Receiver` on which the summary of its profile, or
+//! the error which stopped it from running, is sent once it is done.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use std::time::Duration;
+//! use cpuprofiler::Profiler;
+//! use cpuprofiler::queue::ProfileRequest;
+//!
+//! let dir = env::temp_dir();
+//! let request = |name: &str| ProfileRequest::new(dir.join(name).to_str().unwrap(), Duration::from_millis(50));
+//! let first = Profiler::enqueue(request("queue-example-1.profile"));
+//! let second = Profiler::enqueue(request("queue-example-2.profile"));
+//!
+//! // Code you want to sample goes here!
+//!
+//! let first = first.recv().unwrap().unwrap();
+//! let second = second.recv().unwrap().unwrap();
+//! assert!(first.path.ends_with("queue-example-1.profile"));
+//! assert!(second.path.ends_with("queue-example-2.profile"));
+//! ```
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use builder::ProfilerBuilder;
+use error::Error;
+use lock;
+use summary::ProfileSummary;
+use Profiler;
+
+/// How often a waiting request checks whether the profiler is free
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A profile to run once the profiler is free
+#[derive(Clone, Debug)]
+pub struct ProfileRequest {
+    fname: Vec<u8>,
+    duration: Duration,
+    builder: ProfilerBuilder,
+}
+
+impl ProfileRequest {
+    /// Profile for `duration` into `fname`, see `Profiler::start` for the name
+    pub fn new<T: Into<Vec<u8>>>(fname: T, duration: Duration) -> ProfileRequest {
+        ProfileRequest {
+            fname: fname.into(),
+            duration,
+            builder: ProfilerBuilder::new(),
+        }
+    }
+
+    /// Start the profile with the settings of `builder`
+    pub fn builder(mut self, builder: ProfilerBuilder) -> ProfileRequest {
+        self.builder = builder;
+        self
+    }
+}
+
+struct Queued {
+    request: ProfileRequest,
+    done: Sender<Result<ProfileSummary, Error>>,
+}
+
+#[derive(Default)]
+struct Queue {
+    pending: VecDeque<Queued>,
+    /// Whether a thread is running the pending requests
+    running: bool,
+}
+
+lazy_static! {
+    static ref QUEUE: Mutex<Queue> = Mutex::new(Queue::default());
+}
+
+impl Profiler {
+    /// Queue a profile to run once the earlier ones have finished
+    ///
+    /// Returns a `Receiver` on which the summary of the profile is sent once
+    /// it has stopped. Requests run in the order they were queued. A
+    /// request which waits does not hold the `PROFILER` lock.
+    ///
+    /// The result is an error if the profile could not be started, see
+    /// `ProfilerBuilder::start_on`, or stopped. A profile which was stopped
+    /// elsewhere before its duration elapsed is summarized without the
+    /// number of samples.
+    pub fn enqueue(request: ProfileRequest) -> Receiver<Result<ProfileSummary, Error>> {
+        let (done, rx) = mpsc::channel();
+        let mut queue = QUEUE.lock().unwrap_or_else(|e| e.into_inner());
+        queue.pending.push_back(Queued { request, done });
+        if !queue.running {
+            queue.running = true;
+            thread::spawn(run_queue);
+        }
+        rx
+    }
+
+    /// Returns the number of queued profiles which have not started yet
+    pub fn queued() -> usize {
+        QUEUE.lock().unwrap_or_else(|e| e.into_inner()).pending.len()
+    }
+}
+
+/// Run the pending requests until there are none left
+fn run_queue() {
+    loop {
+        let next = {
+            let mut queue = QUEUE.lock().unwrap_or_else(|e| e.into_inner());
+            match queue.pending.pop_front() {
+                Some(next) => next,
+                None => {
+                    queue.running = false;
+                    return;
+                }
+            }
+        };
+        // The requester may have stopped waiting for the result.
+        let _ = next.done.send(run(next.request));
+    }
+}
+
+fn run(request: ProfileRequest) -> Result<ProfileSummary, Error> {
+    let (session, path, requested) = loop {
+        let mut profiler = lock::lock();
+        if !profiler.taken && !profiler.state.is_running() {
+            request.builder.start_on(&mut profiler, request.fname)?;
+            break (profiler.session, profiler.path.clone().unwrap_or_default(), profiler.requested.clone());
+        }
+        drop(profiler);
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    thread::sleep(request.duration);
+
+    let mut profiler = lock::lock();
+    if profiler.session == session && profiler.state.is_running() {
+        profiler.stop_with_summary()
+    } else {
+        Ok(ProfileSummary {
+            path,
+            requested,
+            samples: 0,
+            duration: request.duration,
+        })
+    }
+}
@@ -0,0 +1,994 @@
+//! Text and flamegraph reports
+//!
+//! Reports are built from `Stack`s, which name the functions of each sampled
+//! stack. With the `symbolize` feature `Symbolizer::stacks` produces them
+//! from a profile, and `aggregate` totals the samples of each function for
+//! checks in tests. `assert_no_regression` compares a profile against a
+//! baseline, to gate performance in CI, and `write_diff_flamegraph` shows
+//! where two profiles differ. `write_html` puts the flamegraphs, top
+//! functions and differences of profiles into one HTML file to share.
+//! `write_threads` reports how the samples were spread over the threads of
+//! the profiled process, and `annotate_source` lists the source lines of
+//! functions with their samples.
+//!
+//! # Examples
+//!
+//! ```
+//! use cpuprofiler::report::{self, Stack};
+//!
+//! let stacks = vec![
+//!     Stack { count: 3, frames: vec!["main".to_owned(), "parse".to_owned()] },
+//!     Stack { count: 1, frames: vec!["main".to_owned()] },
+//! ];
+//!
+//! let top = report::top(&stacks, 10);
+//! assert_eq!(top[0].name, "parse");
+//! assert_eq!(top[1].cumulative, 4);
+//!
+//! let mut folded = Vec::new();
+//! report::write_folded(&mut folded, &stacks).unwrap();
+//! assert_eq!(String::from_utf8(folded).unwrap(), "main 1\nmain;parse 3\n");
+//! ```
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::cmp::Ordering;
+#[cfg(feature = "symbolize")]
+use std::fs::{self, File};
+#[cfg(feature = "symbolize")]
+use std::io::BufWriter;
+use std::io::{self, Write};
+#[cfg(feature = "symbolize")]
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "symbolize")]
+use error::Error;
+#[cfg(feature = "symbolize")]
+use pattern::Pattern;
+#[cfg(feature = "symbolize")]
+use profile::Profile;
+use profile::Thread;
+#[cfg(feature = "symbolize")]
+use symbolize::Symbolizer;
+
+/// A sampled stack of function names
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Stack {
+    /// The number of samples of this stack
+    pub count: u64,
+    /// The functions on the stack, outermost first
+    pub frames: Vec<String>,
+}
+
+/// The samples attributed to one function
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Entry {
+    /// The function name
+    pub name: String,
+    /// Samples in the function itself
+    pub flat: u64,
+    /// Samples in the function or anything it called
+    pub cumulative: u64,
+}
+
+/// The samples attributed to a function by `aggregate`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Samples {
+    /// Samples in the function itself
+    pub flat: u64,
+    /// Samples in the function or anything it called
+    pub cumulative: u64,
+}
+
+/// Returns the samples of every function on the stacks, by name
+///
+/// # Examples
+///
+/// ```
+/// use cpuprofiler::report::{self, Stack};
+///
+/// let stacks = vec![
+///     Stack { count: 3, frames: vec!["main".to_owned(), "parse".to_owned()] },
+///     Stack { count: 1, frames: vec!["main".to_owned()] },
+/// ];
+///
+/// let samples = report::aggregate_stacks(&stacks);
+/// assert_eq!(samples["main"].flat, 1);
+/// assert_eq!(samples["main"].cumulative, 4);
+/// ```
+pub fn aggregate_stacks(stacks: &[Stack]) -> HashMap<String, Samples> {
+    let mut samples: HashMap<String, Samples> = HashMap::new();
+    for stack in stacks {
+        // Recursive functions only count once towards their cumulative samples.
+        let mut seen = HashSet::new();
+        for (i, frame) in stack.frames.iter().enumerate() {
+            let entry = samples.entry(frame.clone()).or_default();
+            if seen.insert(frame) {
+                entry.cumulative += stack.count;
+            }
+            if i + 1 == stack.frames.len() {
+                entry.flat += stack.count;
+            }
+        }
+    }
+    samples
+}
+
+/// Returns the samples of every function in the profile, by name
+///
+/// Available with the `symbolize` feature. This is meant for tests which
+/// guard against performance regressions, such as checking that a function
+/// stays under a share of the samples.
+///
+/// # Examples
+///
+/// ```no_run
+/// use cpuprofiler::profile::Profile;
+/// use cpuprofiler::report;
+///
+/// let profile = Profile::from_file("./bench.profile").unwrap();
+/// let samples = report::aggregate(&profile);
+/// let parse = samples.get("parse").map_or(0, |s| s.cumulative);
+/// assert!(parse * 20 < profile.total_samples(), "parse takes 5% or more of the samples");
+/// ```
+#[cfg(feature = "symbolize")]
+pub fn aggregate(profile: &Profile) -> HashMap<String, Samples> {
+    aggregate_stacks(&Symbolizer::new().stacks(profile))
+}
+
+/// A function which takes a larger share of the samples than it used to
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Regression {
+    /// The function name
+    pub name: String,
+    /// The share of the baseline's samples in the function or its callees,
+    /// from 0 to 1
+    pub baseline: f64,
+    /// The share of the current samples in the function or its callees
+    pub current: f64,
+}
+
+/// Returns the functions whose share of the samples grew by more than
+/// `threshold`
+///
+/// Shares are cumulative samples divided by the total, so profiles of
+/// different lengths can be compared, and `threshold` is a difference of
+/// shares: 0.05 allows each function to grow by 5 percentage points. The
+/// largest regressions come first.
+///
+/// # Examples
+///
+/// ```
+/// use cpuprofiler::report::{self, Stack};
+///
+/// let stack = |count, name: &str| Stack { count, frames: vec!["main".to_owned(), name.to_owned()] };
+/// let baseline = vec![stack(90, "parse"), stack(10, "render")];
+/// let current = vec![stack(70, "parse"), stack(30, "render")];
+///
+/// let regressions = report::regressions(&baseline, &current, 0.05);
+/// assert_eq!(regressions.len(), 1);
+/// assert_eq!(regressions[0].name, "render");
+/// ```
+pub fn regressions(baseline: &[Stack], current: &[Stack], threshold: f64) -> Vec<Regression> {
+    let shares = |stacks: &[Stack]| {
+        let total = stacks.iter().map(|s| s.count).sum::<u64>().max(1) as f64;
+        aggregate_stacks(stacks)
+            .into_iter()
+            .map(|(name, samples)| (name, samples.cumulative as f64 / total))
+            .collect::<HashMap<String, f64>>()
+    };
+    let before = shares(baseline);
+
+    let mut regressions: Vec<Regression> = shares(current)
+        .into_iter()
+        .filter_map(|(name, current)| {
+            let baseline = before.get(&name).cloned().unwrap_or(0.0);
+            if current - baseline > threshold {
+                Some(Regression { name, baseline, current })
+            } else {
+                None
+            }
+        })
+        .collect();
+    regressions.sort_by(|a, b| {
+        (b.current - b.baseline)
+            .partial_cmp(&(a.current - a.baseline))
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    regressions
+}
+
+/// Compare the profile at `current` against the one at `baseline`, see
+/// `regressions`
+///
+/// Available with the `symbolize` feature. Both profiles are symbolized
+/// here, so the binaries they sampled must be present. An empty list means
+/// that nothing regressed.
+///
+/// # Examples
+///
+/// ```no_run
+/// use cpuprofiler::report;
+///
+/// let regressions = report::assert_no_regression("baseline.profile", "current.profile", 0.02).unwrap();
+/// for r in &regressions {
+///     println!("{}: {:.1}% -> {:.1}%", r.name, r.baseline * 100.0, r.current * 100.0);
+/// }
+/// assert!(regressions.is_empty(), "the profile regressed");
+/// ```
+///
+/// # Failures
+///
+/// - Either profile cannot be read or parsed.
+#[cfg(feature = "symbolize")]
+pub fn assert_no_regression<P, Q>(baseline: P, current: Q, threshold: f64) -> Result<Vec<Regression>, Error>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let baseline = Profile::from_file(baseline)?;
+    let current = Profile::from_file(current)?;
+    let mut symbolizer = Symbolizer::new();
+    let baseline = symbolizer.stacks(&baseline);
+    let current = symbolizer.stacks(&current);
+    Ok(regressions(&baseline, &current, threshold))
+}
+
+/// Returns the `n` functions with the most samples of their own
+///
+/// Ties are broken by cumulative samples, then by name.
+pub fn top(stacks: &[Stack], n: usize) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = aggregate_stacks(stacks)
+        .into_iter()
+        .map(|(name, samples)| Entry {
+            name,
+            flat: samples.flat,
+            cumulative: samples.cumulative,
+        })
+        .collect();
+    entries.sort_by(|a, b| {
+        b.flat
+            .cmp(&a.flat)
+            .then(b.cumulative.cmp(&a.cumulative))
+            .then(a.name.cmp(&b.name))
+    });
+    entries.truncate(n);
+    entries
+}
+
+/// Write the `n` functions with the most samples, like `pprof --text`
+pub fn write_top<W: Write>(out: &mut W, stacks: &[Stack], n: usize) -> io::Result<()> {
+    let total: u64 = stacks.iter().map(|s| s.count).sum();
+    let percent = |count: u64| if total == 0 { 0.0 } else { count as f64 * 100.0 / total as f64 };
+
+    writeln!(out, "Total: {} samples", total)?;
+    let mut sum = 0;
+    for entry in top(stacks, n) {
+        sum += entry.flat;
+        writeln!(
+            out,
+            "{:>8} {:5.1}% {:5.1}% {:>8} {:5.1}% {}",
+            entry.flat,
+            percent(entry.flat),
+            percent(sum),
+            entry.cumulative,
+            percent(entry.cumulative),
+            entry.name
+        )?;
+    }
+    Ok(())
+}
+
+/// A source line of a function and its samples, see `list_source`
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SourceLine {
+    /// The line number, from 1
+    pub line: u32,
+    /// Samples on the line itself
+    pub flat: u64,
+    /// Samples on the line or in anything it called
+    pub cumulative: u64,
+    /// The text of the line, if the source file could be read
+    pub text: Option<String>,
+}
+
+/// The source of a function annotated with its samples
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SourceListing {
+    /// The function name
+    pub function: String,
+    /// The source file of the function, if known
+    pub file: Option<String>,
+    /// Samples in the function itself
+    pub flat: u64,
+    /// Samples in the function or anything it called
+    pub cumulative: u64,
+    /// The samples of the profile, to give the shares of the totals
+    pub total: u64,
+    /// The lines from the first sampled line to the last, or only the
+    /// sampled lines when the source file could not be read
+    pub lines: Vec<SourceLine>,
+}
+
+/// The samples of a function and of each of its lines, see `list_source`
+#[cfg(feature = "symbolize")]
+#[derive(Default)]
+struct Listed {
+    function: Samples,
+    lines: BTreeMap<u32, Samples>,
+}
+
+/// Returns the source lines of every function matching `symbol_regex`
+/// with their samples, like `pprof --list`
+///
+/// Available with the `symbolize` feature. Lines are found in the DWARF
+/// line tables of the binaries the profile sampled, so they must have
+/// debug info. The pattern is a regular expression matched anywhere in a
+/// function name, see `Filter`. The samples of an inlined function are
+/// on its own lines rather than those of the function it was inlined
+/// into. Listings with the most cumulative samples come first.
+///
+/// # Failures
+///
+/// - `symbol_regex` is not a regular expression, `Error::InvalidPattern`.
+#[cfg(feature = "symbolize")]
+pub fn list_source(profile: &Profile, symbol_regex: &str) -> Result<Vec<SourceListing>, Error> {
+    let pattern = Pattern::new(symbol_regex)?;
+    let mut symbolizer = Symbolizer::new();
+
+    let mut functions: HashMap<(String, Option<String>), Listed> = HashMap::new();
+    for sample in profile.samples() {
+        // Recursive functions only count once towards their cumulative samples.
+        let mut seen = HashSet::new();
+        for (i, &pc) in sample.stack.iter().enumerate() {
+            // Callers are looked up at the call instruction, see
+            // `Symbolizer::stacks`.
+            let addr = if i == 0 { pc } else { pc.saturating_sub(1) };
+            for (j, frame) in symbolizer.resolve(profile, addr).into_iter().enumerate() {
+                let name = match frame.function {
+                    Some(ref name) if pattern.is_match(name) => name.clone(),
+                    _ => continue,
+                };
+                let innermost = i == 0 && j == 0;
+                let key = (name, frame.file.clone());
+                let Listed { ref mut function, ref mut lines } = *functions.entry(key.clone()).or_default();
+                if innermost {
+                    function.flat += sample.count;
+                }
+                if seen.insert((key.clone(), None)) {
+                    function.cumulative += sample.count;
+                }
+                if let Some(line) = frame.line {
+                    let samples = lines.entry(line).or_default();
+                    if innermost {
+                        samples.flat += sample.count;
+                    }
+                    if seen.insert((key, Some(line))) {
+                        samples.cumulative += sample.count;
+                    }
+                }
+            }
+        }
+    }
+
+    let total = profile.total_samples();
+    let mut listings: Vec<SourceListing> = functions
+        .into_iter()
+        .map(|((function, file), Listed { function: samples, lines })| {
+            let source = file.as_ref().and_then(|file| fs::read_to_string(file).ok());
+            let sampled = |line: u32| lines.get(&line).cloned().unwrap_or_default();
+            let lines = match (source, lines.keys().next(), lines.keys().next_back()) {
+                (Some(source), Some(&first), Some(&last)) => source
+                    .lines()
+                    .enumerate()
+                    .map(|(i, text)| (i as u32 + 1, text))
+                    .skip_while(|&(line, _)| line < first)
+                    .take_while(|&(line, _)| line <= last)
+                    .map(|(line, text)| SourceLine {
+                        line,
+                        flat: sampled(line).flat,
+                        cumulative: sampled(line).cumulative,
+                        text: Some(text.to_owned()),
+                    })
+                    .collect(),
+                _ => lines
+                    .iter()
+                    .map(|(&line, samples)| SourceLine {
+                        line,
+                        flat: samples.flat,
+                        cumulative: samples.cumulative,
+                        text: None,
+                    })
+                    .collect(),
+            };
+            SourceListing {
+                function,
+                file,
+                flat: samples.flat,
+                cumulative: samples.cumulative,
+                total,
+                lines,
+            }
+        })
+        .collect();
+    listings.sort_by(|a, b| {
+        b.cumulative
+            .cmp(&a.cumulative)
+            .then_with(|| a.function.cmp(&b.function))
+            .then_with(|| a.file.cmp(&b.file))
+    });
+    Ok(listings)
+}
+
+/// Write the source lines of every function matching `symbol_regex` with
+/// their samples, see `list_source`
+///
+/// Available with the `symbolize` feature.
+///
+/// # Examples
+///
+/// ```no_run
+/// use cpuprofiler::profile::Profile;
+/// use cpuprofiler::report;
+///
+/// let profile = Profile::from_file("./bench.profile").unwrap();
+/// report::annotate_source(&mut std::io::stdout(), &profile, "::parse$").unwrap();
+/// ```
+///
+/// # Failures
+///
+/// - `symbol_regex` is not a regular expression, `Error::InvalidPattern`.
+/// - `out` cannot be written, `Error::Io`.
+#[cfg(feature = "symbolize")]
+pub fn annotate_source<W: Write>(out: &mut W, profile: &Profile, symbol_regex: &str) -> Result<(), Error> {
+    write_source(out, &list_source(profile, symbol_regex)?)?;
+    Ok(())
+}
+
+/// Write source listings, see `list_source`
+///
+/// Each listing is headed by the function and its samples, and each line
+/// shows its flat and cumulative samples, `.` for none.
+///
+/// # Examples
+///
+/// ```
+/// use cpuprofiler::report::{self, SourceLine, SourceListing};
+///
+/// let line = |line, flat, cumulative, text: &str| SourceLine { line, flat, cumulative, text: Some(text.to_owned()) };
+/// let listing = SourceListing {
+///     function: "app::parse".to_owned(),
+///     file: Some("src/parse.rs".to_owned()),
+///     flat: 3,
+///     cumulative: 4,
+///     total: 8,
+///     lines: vec![line(10, 3, 3, "    let n = s.len();"), line(11, 0, 1, "    s.parse()")],
+/// };
+///
+/// let mut out = Vec::new();
+/// report::write_source(&mut out, &[listing]).unwrap();
+/// let out = String::from_utf8(out).unwrap();
+/// assert!(out.contains("ROUTINE ======================== app::parse in src/parse.rs"));
+/// assert!(out.contains("       .        1    11:     s.parse()"));
+/// ```
+pub fn write_source<W: Write>(out: &mut W, listings: &[SourceListing]) -> io::Result<()> {
+    let count = |count: u64| if count == 0 { ".".to_owned() } else { count.to_string() };
+    for listing in listings {
+        writeln!(
+            out,
+            "ROUTINE ======================== {} in {}",
+            listing.function,
+            listing.file.as_deref().unwrap_or("??")
+        )?;
+        let percent = if listing.total == 0 { 0.0 } else { listing.cumulative as f64 * 100.0 / listing.total as f64 };
+        writeln!(
+            out,
+            "{:>8} {:>8} (flat, cum) {:.1}% of Total",
+            listing.flat, listing.cumulative, percent
+        )?;
+        for line in &listing.lines {
+            writeln!(
+                out,
+                "{:>8} {:>8} {:>5}: {}",
+                count(line.flat),
+                count(line.cumulative),
+                line.line,
+                line.text.as_deref().unwrap_or("")
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the samples of each thread, see `Profile::threads`
+///
+/// # Examples
+///
+/// ```
+/// use cpuprofiler::profile::Thread;
+/// use cpuprofiler::report;
+///
+/// let threads = vec![
+///     Thread { id: 41, name: Some("render".to_owned()), samples: 3 },
+///     Thread { id: 42, name: None, samples: 1 },
+/// ];
+///
+/// let mut out = Vec::new();
+/// report::write_threads(&mut out, &threads).unwrap();
+/// assert!(String::from_utf8(out).unwrap().contains(" 75.0%       41 render"));
+/// ```
+pub fn write_threads<W: Write>(out: &mut W, threads: &[Thread]) -> io::Result<()> {
+    let total: u64 = threads.iter().map(|t| t.samples).sum();
+    let percent = |count: u64| if total == 0 { 0.0 } else { count as f64 * 100.0 / total as f64 };
+
+    writeln!(out, "Total: {} samples in {} threads", total, threads.len())?;
+    for thread in threads {
+        writeln!(
+            out,
+            "{:>8} {:5.1}% {:>8} {}",
+            thread.samples,
+            percent(thread.samples),
+            thread.id,
+            thread.name.as_deref().unwrap_or("?")
+        )?;
+    }
+    Ok(())
+}
+
+/// Write the stacks in the folded format used by flamegraph tools
+///
+/// Each line holds the frames separated by `;` and the sample count.
+pub fn write_folded<W: Write>(out: &mut W, stacks: &[Stack]) -> io::Result<()> {
+    let mut folded: BTreeMap<String, u64> = BTreeMap::new();
+    for stack in stacks {
+        *folded.entry(stack.frames.join(";")).or_insert(0) += stack.count;
+    }
+    for (frames, count) in folded {
+        writeln!(out, "{} {}", frames, count)?;
+    }
+    Ok(())
+}
+
+const WIDTH: f64 = 1200.0;
+const FRAME_HEIGHT: f64 = 16.0;
+const CHAR_WIDTH: f64 = 7.0;
+
+/// A node of the call tree drawn by `write_flamegraph`
+#[derive(Default)]
+struct Node {
+    count: u64,
+    /// The samples of the baseline, for `write_diff_flamegraph`
+    before: u64,
+    children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    fn depth(&self) -> usize {
+        self.children.values().map(|c| c.depth() + 1).max().unwrap_or(0)
+    }
+
+    /// Add the samples of `stacks`, to the counts `count` picks
+    fn add<F: Fn(&mut Node) -> &mut u64>(&mut self, stacks: &[Stack], count: F) {
+        for stack in stacks {
+            *count(self) += stack.count;
+            let mut node = &mut *self;
+            for frame in &stack.frames {
+                node = node.children.entry(frame.clone()).or_default();
+                *count(node) += stack.count;
+            }
+        }
+    }
+
+    /// Returns the largest change of share of any node under this one
+    fn max_change(&self, totals: (f64, f64)) -> f64 {
+        self.children
+            .values()
+            .map(|child| child.change(totals).abs().max(child.max_change(totals)))
+            .fold(0.0, f64::max)
+    }
+
+    /// Returns the share of the samples gained since the baseline, from -1
+    /// to 1
+    fn change(&self, (before, after): (f64, f64)) -> f64 {
+        self.count as f64 / after - self.before as f64 / before
+    }
+}
+
+/// How the frames of a flamegraph are colored
+#[derive(Clone, Copy)]
+enum Colors {
+    /// Warm colors which tell functions apart
+    Names,
+    /// Red for frames which gained a share of the samples and blue for those
+    /// which lost one, the baseline and current totals and the largest change
+    Diff((f64, f64), f64),
+}
+
+/// Write an SVG flamegraph of the stacks
+///
+/// Callers are drawn below the functions they call, and each function's
+/// width is proportional to its samples. Hovering over a frame shows its
+/// full name and sample count.
+pub fn write_flamegraph<W: Write>(out: &mut W, stacks: &[Stack], title: &str) -> io::Result<()> {
+    let mut root = Node::default();
+    root.add(stacks, |node| &mut node.count);
+    writeln!(out, r#"<?xml version="1.0" standalone="no"?>"#)?;
+    write_svg(out, &root, title, Colors::Names)
+}
+
+/// Write an SVG flamegraph of `current` colored by how it differs from
+/// `baseline`
+///
+/// Frames are laid out as in the flamegraph of `current`. Those which take
+/// a larger share of the samples than in `baseline` are red, those which
+/// take a smaller one blue, the deeper the larger the change. Shares are
+/// compared rather than counts, so profiles of different lengths can be
+/// compared. Functions only sampled in `baseline` are not drawn. Hovering
+/// over a frame shows the change in percentage points.
+///
+/// # Examples
+///
+/// ```
+/// use cpuprofiler::report::{self, Stack};
+///
+/// let stack = |count, name: &str| Stack { count, frames: vec!["main".to_owned(), name.to_owned()] };
+/// let baseline = vec![stack(90, "parse"), stack(10, "render")];
+/// let current = vec![stack(70, "parse"), stack(30, "render")];
+///
+/// let mut svg = Vec::new();
+/// report::write_diff_flamegraph(&mut svg, &baseline, &current, "release vs main").unwrap();
+/// assert!(String::from_utf8(svg).unwrap().contains("render (30 samples, 30.00%, +20.00%)"));
+/// ```
+pub fn write_diff_flamegraph<W: Write>(out: &mut W, baseline: &[Stack], current: &[Stack], title: &str) -> io::Result<()> {
+    let (root, colors) = diff_tree(baseline, current);
+    writeln!(out, r#"<?xml version="1.0" standalone="no"?>"#)?;
+    write_svg(out, &root, title, colors)
+}
+
+/// Returns the call tree of `current` with the samples of `baseline`, and
+/// the colors to draw it with
+fn diff_tree(baseline: &[Stack], current: &[Stack]) -> (Node, Colors) {
+    let mut root = Node::default();
+    root.add(current, |node| &mut node.count);
+    root.add(baseline, |node| &mut node.before);
+    let totals = (root.before.max(1) as f64, root.count.max(1) as f64);
+    let colors = Colors::Diff(totals, root.max_change(totals));
+    (root, colors)
+}
+
+/// Write a differential flamegraph of the profile at `current` against the
+/// one at `baseline` to the file `out`, see `write_diff_flamegraph`
+///
+/// Available with the `symbolize` feature. Both profiles are symbolized
+/// here, so the binaries they sampled must be present.
+///
+/// # Examples
+///
+/// ```no_run
+/// use cpuprofiler::report;
+///
+/// report::diff_flamegraph("before.profile", "after.profile", "diff.svg").unwrap();
+/// ```
+///
+/// # Failures
+///
+/// - Either profile cannot be read or parsed.
+/// - `out` cannot be written, `Error::Io`.
+#[cfg(feature = "symbolize")]
+pub fn diff_flamegraph<P, Q, R>(baseline: P, current: Q, out: R) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    R: AsRef<Path>,
+{
+    let title = format!("{} vs {}", current.as_ref().display(), baseline.as_ref().display());
+    let baseline = Profile::from_file(baseline)?;
+    let current = Profile::from_file(current)?;
+    let mut symbolizer = Symbolizer::new();
+    let baseline = symbolizer.stacks(&baseline);
+    let current = symbolizer.stacks(&current);
+
+    let mut file = BufWriter::new(File::create(out)?);
+    write_diff_flamegraph(&mut file, &baseline, &current, &title)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// The number of functions in each table of an HTML report
+const HTML_ROWS: usize = 50;
+
+/// Write a self-contained HTML report on the named profiles
+///
+/// The report has a tab for each profile, with its flamegraph and the
+/// functions with the most samples as in `write_top`. Given two profiles it
+/// also has a tab comparing the second against the first, the baseline,
+/// with their `write_diff_flamegraph` and the functions whose share of the
+/// samples grew and shrank most. Everything is inline and the tabs need no
+/// scripts, so the file can be attached to a pull request or kept as a CI
+/// artifact and opened anywhere.
+///
+/// # Examples
+///
+/// ```
+/// use cpuprofiler::report::{self, Stack};
+///
+/// let stack = |count, name: &str| Stack { count, frames: vec!["main".to_owned(), name.to_owned()] };
+/// let baseline = vec![stack(90, "parse"), stack(10, "render")];
+/// let current = vec![stack(70, "parse"), stack(30, "render")];
+///
+/// let mut html = Vec::new();
+/// report::write_html(&mut html, &[("main", &baseline), ("branch", &current)]).unwrap();
+/// let html = String::from_utf8(html).unwrap();
+/// assert!(html.contains("branch vs main"));
+/// assert!(html.contains("render (30 samples, 30.00%, +20.00%)"));
+/// ```
+pub fn write_html<W: Write>(out: &mut W, profiles: &[(&str, &[Stack])]) -> io::Result<()> {
+    let mut tabs: Vec<String> = profiles.iter().map(|&(name, _)| name.to_owned()).collect();
+    let diff = match profiles {
+        [(baseline_name, baseline), (current_name, current)] => {
+            tabs.push(format!("{} vs {}", current_name, baseline_name));
+            Some((*baseline, *current, tabs[2].clone()))
+        }
+        _ => None,
+    };
+
+    writeln!(
+        out,
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; margin: 1em; }}
+input[name=tab] {{ display: none; }}
+label {{ display: inline-block; padding: 0.4em 1em; border: 1px solid #ccc; border-bottom: none; cursor: pointer; background: #eee; }}
+.panel {{ display: none; border-top: 1px solid #ccc; padding-top: 1em; overflow-x: auto; }}
+table {{ border-collapse: collapse; font-family: monospace; margin-top: 1em; }}
+th, td {{ padding: 0.1em 0.8em; text-align: right; }}
+th:last-child, td:last-child {{ text-align: left; }}
+tr:nth-child(even) {{ background: #f4f4f4; }}"#,
+        title = escape(&tabs.join(", "))
+    )?;
+    for i in 0..tabs.len() {
+        writeln!(
+            out,
+            "#tab{0}:checked ~ #panel{0} {{ display: block; }} #tab{0}:checked + label {{ background: #fff; font-weight: bold; }}",
+            i
+        )?;
+    }
+    writeln!(out, "</style>\n</head>\n<body>")?;
+    for (i, tab) in tabs.iter().enumerate() {
+        writeln!(
+            out,
+            r#"<input type="radio" name="tab" id="tab{0}"{1}><label for="tab{0}">{2}</label>"#,
+            i,
+            if i == 0 { " checked" } else { "" },
+            escape(tab)
+        )?;
+    }
+
+    for (i, &(name, stacks)) in profiles.iter().enumerate() {
+        writeln!(out, r#"<div class="panel" id="panel{}">"#, i)?;
+        let mut root = Node::default();
+        root.add(stacks, |node| &mut node.count);
+        write_svg(out, &root, name, Colors::Names)?;
+        write_top_table(out, stacks)?;
+        writeln!(out, "</div>")?;
+    }
+    if let Some((baseline, current, title)) = diff {
+        writeln!(out, r#"<div class="panel" id="panel{}">"#, profiles.len())?;
+        let (root, colors) = diff_tree(baseline, current);
+        write_svg(out, &root, &title, colors)?;
+        write_change_table(out, "Grew", &regressions(baseline, current, 0.0), false)?;
+        write_change_table(out, "Shrank", &regressions(current, baseline, 0.0), true)?;
+        writeln!(out, "</div>")?;
+    }
+    writeln!(out, "</body>\n</html>")
+}
+
+/// Write an HTML report on the profiles at `profiles` to
+/// `<out_dir>/index.html`, see `write_html`
+///
+/// Available with the `symbolize` feature. The profiles are symbolized
+/// here, so the binaries they sampled must be present, and named after
+/// their paths in the report. Given two, the second is compared against the
+/// first. `out_dir` is created if it is missing. Returns the path of the
+/// report.
+///
+/// # Examples
+///
+/// ```no_run
+/// use cpuprofiler::report;
+///
+/// let index = report::html(&["main.profile", "branch.profile"], "profile-report").unwrap();
+/// println!("open {}", index.display());
+/// ```
+///
+/// # Failures
+///
+/// - `profiles` is empty, `Error::Unsupported`.
+/// - A profile cannot be read or parsed.
+/// - The report cannot be written, `Error::Io`.
+#[cfg(feature = "symbolize")]
+pub fn html<P, Q>(profiles: &[P], out_dir: Q) -> Result<PathBuf, Error>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    if profiles.is_empty() {
+        return Err(Error::Unsupported("a report on no profiles".to_owned()));
+    }
+    let mut symbolizer = Symbolizer::new();
+    let mut named = Vec::with_capacity(profiles.len());
+    for path in profiles {
+        let profile = Profile::from_file(path)?;
+        named.push((path.as_ref().display().to_string(), symbolizer.stacks(&profile)));
+    }
+    let named: Vec<(&str, &[Stack])> = named.iter().map(|(name, stacks)| (&name[..], &stacks[..])).collect();
+
+    fs::create_dir_all(&out_dir)?;
+    let index = out_dir.as_ref().join("index.html");
+    let mut file = BufWriter::new(File::create(&index)?);
+    write_html(&mut file, &named)?;
+    file.flush()?;
+    Ok(index)
+}
+
+/// Write the functions with the most samples as an HTML table
+fn write_top_table<W: Write>(out: &mut W, stacks: &[Stack]) -> io::Result<()> {
+    let total: u64 = stacks.iter().map(|s| s.count).sum();
+    let percent = |count: u64| if total == 0 { 0.0 } else { count as f64 * 100.0 / total as f64 };
+
+    writeln!(
+        out,
+        "<table>\n<caption>Total: {} samples</caption>\n<tr><th>flat</th><th>flat%</th><th>sum%</th><th>cum</th><th>cum%</th><th>function</th></tr>",
+        total
+    )?;
+    let mut sum = 0;
+    for entry in top(stacks, HTML_ROWS) {
+        sum += entry.flat;
+        writeln!(
+            out,
+            "<tr><td>{}</td><td>{:.1}%</td><td>{:.1}%</td><td>{}</td><td>{:.1}%</td><td>{}</td></tr>",
+            entry.flat,
+            percent(entry.flat),
+            percent(sum),
+            entry.cumulative,
+            percent(entry.cumulative),
+            escape(&entry.name)
+        )?;
+    }
+    writeln!(out, "</table>")
+}
+
+/// Write the changes of share as an HTML table, `swapped` when the baseline
+/// and current shares of `changes` are the other way around
+fn write_change_table<W: Write>(out: &mut W, caption: &str, changes: &[Regression], swapped: bool) -> io::Result<()> {
+    writeln!(
+        out,
+        "<table>\n<caption>{}</caption>\n<tr><th>baseline</th><th>current</th><th>change</th><th>function</th></tr>",
+        caption
+    )?;
+    for change in changes.iter().take(HTML_ROWS) {
+        let (baseline, current) = if swapped {
+            (change.current, change.baseline)
+        } else {
+            (change.baseline, change.current)
+        };
+        writeln!(
+            out,
+            "<tr><td>{:.2}%</td><td>{:.2}%</td><td>{:+.2}%</td><td>{}</td></tr>",
+            baseline * 100.0,
+            current * 100.0,
+            (current - baseline) * 100.0,
+            escape(&change.name)
+        )?;
+    }
+    writeln!(out, "</table>")
+}
+
+fn write_svg<W: Write>(out: &mut W, root: &Node, title: &str, colors: Colors) -> io::Result<()> {
+    let depth = root.depth();
+    let height = (depth + 3) as f64 * FRAME_HEIGHT;
+    writeln!(
+        out,
+        r##"<svg version="1.1" width="{w}" height="{h}" xmlns="http://www.w3.org/2000/svg">
+<style>text {{ font-family: monospace; font-size: 12px; }} rect:hover {{ stroke: black; }}</style>
+<rect x="0" y="0" width="{w}" height="{h}" fill="#f8f8f8"/>
+<text x="{c}" y="16" text-anchor="middle">{t}</text>"##,
+        w = WIDTH,
+        h = height,
+        c = WIDTH / 2.0,
+        t = escape(title)
+    )?;
+
+    let total = root.count.max(1) as f64;
+    let mut x = 0.0;
+    for (name, child) in &root.children {
+        draw(out, name, child, x, height - 2.0 * FRAME_HEIGHT, total, colors)?;
+        x += child.count as f64 / total * WIDTH;
+    }
+    writeln!(out, "</svg>")
+}
+
+fn draw<W: Write>(out: &mut W, name: &str, node: &Node, x: f64, y: f64, total: f64, colors: Colors) -> io::Result<()> {
+    let width = node.count as f64 / total * WIDTH;
+    if width < 0.1 {
+        return Ok(());
+    }
+
+    let chars = ((width - 6.0) / CHAR_WIDTH).max(0.0) as usize;
+    let label: String = if name.chars().count() <= chars {
+        name.to_owned()
+    } else if chars > 2 {
+        name.chars().take(chars - 2).chain("..".chars()).collect()
+    } else {
+        String::new()
+    };
+    let (fill, change) = match colors {
+        Colors::Names => (color(name), String::new()),
+        Colors::Diff(totals, max) => {
+            let change = node.change(totals);
+            (diff_color(change, max), format!(", {:+.2}%", change * 100.0))
+        }
+    };
+    writeln!(
+        out,
+        r#"<g><title>{name} ({count} samples, {pct:.2}%{change})</title><rect x="{x:.1}" y="{y:.1}" width="{w:.1}" height="{h:.1}" fill="{fill}" rx="2"/><text x="{tx:.1}" y="{ty:.1}">{label}</text></g>"#,
+        name = escape(name),
+        count = node.count,
+        pct = node.count as f64 * 100.0 / total,
+        change = change,
+        x = x,
+        y = y,
+        w = width,
+        h = FRAME_HEIGHT - 1.0,
+        fill = fill,
+        tx = x + 3.0,
+        ty = y + FRAME_HEIGHT - 4.0,
+        label = escape(&label)
+    )?;
+
+    let mut child_x = x;
+    for (child_name, child) in &node.children {
+        draw(out, child_name, child, child_x, y - FRAME_HEIGHT, total, colors)?;
+        child_x += child.count as f64 / total * WIDTH;
+    }
+    Ok(())
+}
+
+/// A warm color which is stable for each function name
+fn color(name: &str) -> String {
+    let hash = name
+        .bytes()
+        .fold(5381u32, |h, b| h.wrapping_mul(33) ^ u32::from(b));
+    let r = 205 + hash % 50;
+    let g = (hash >> 8) % 180;
+    let b = (hash >> 16) % 55;
+    format!("rgb({},{},{})", r, g, b)
+}
+
+/// Red for a gain and blue for a loss of share, deeper the closer `change`
+/// is to the largest change `max`
+fn diff_color(change: f64, max: f64) -> String {
+    let depth = if max > 0.0 { change.abs() / max } else { 0.0 };
+    let light = (235.0 - 200.0 * depth) as u8;
+    if change > 0.0 {
+        format!("rgb(255,{0},{0})", light)
+    } else if change < 0.0 {
+        format!("rgb({0},{0},255)", light)
+    } else {
+        "rgb(235,235,235)".to_owned()
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
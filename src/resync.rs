@@ -0,0 +1,132 @@
+//! Keeping the profiler in step with the cpuprofiler library
+//!
+//! The cpuprofiler library can be started and stopped without going
+//! through the `Profiler`: by itself from `CPUPROFILE` when it is linked, by
+//! C code in the process, or through the raw [`ffi`](ffi/index.html)
+//! functions. `PROFILER` asks the library whether it is profiling when it
+//! is first locked, and tracks the library's profile as its own if so, so
+//! that a second profile is not started over it. `Profiler::force_stop`
+//! stops whatever the library is doing and brings the profiler back to
+//! `NotActive`.
+
+use std::path::PathBuf;
+use std::time::Instant;
+
+use error::Error;
+use {set_running, Profiler, ProfilerState};
+
+/// Returns the file of the profile the cpuprofiler library is writing, if
+/// it is profiling
+#[cfg(all(feature = "gperftools", not(feature = "disabled")))]
+pub(crate) fn library_profile() -> Option<PathBuf> {
+    use std::ffi::{CStr, OsStr};
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+
+    use ffi;
+
+    // Asking a library which is not loaded yet would load it, which fixes
+    // its settings, and it cannot be profiling anyway.
+    if !ffi::is_initialized() {
+        return None;
+    }
+    let state = unsafe {
+        let mut state: ffi::ProfilerState = mem::zeroed();
+        ffi::ProfilerGetCurrentState(&mut state);
+        state
+    };
+    if state.enabled == 0 {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(state.profile_name.as_ptr()) };
+    Some(PathBuf::from(OsStr::from_bytes(name.to_bytes())))
+}
+
+#[cfg(not(all(feature = "gperftools", not(feature = "disabled"))))]
+pub(crate) fn library_profile() -> Option<PathBuf> {
+    None
+}
+
+/// Stop the cpuprofiler library's profile, if it is loaded
+#[cfg(all(feature = "gperftools", not(feature = "disabled")))]
+fn stop_library() {
+    use ffi;
+
+    if ffi::is_initialized() {
+        unsafe {
+            ffi::ProfilerStop();
+        }
+    }
+}
+
+#[cfg(not(all(feature = "gperftools", not(feature = "disabled"))))]
+fn stop_library() {}
+
+impl Profiler {
+    /// Track a profile the cpuprofiler library started by itself, returning
+    /// whether there was one
+    ///
+    /// The profile is `Active` from now on, and can be stopped as usual. It
+    /// cannot be paused, since the library was not given the profiler's
+    /// thread filter.
+    pub(crate) fn adopt_library_profile(&mut self) -> bool {
+        let path = match library_profile() {
+            Some(path) => path,
+            None => return false,
+        };
+        self.session += 1;
+        // The library does not say when it started, only that it has.
+        self.started = Some(Instant::now());
+        self.path = Some(path);
+        set_running(self.path.as_deref(), self.backend.signal());
+        self.transition(ProfilerState::Active);
+        true
+    }
+
+    /// Stop profiling, whatever state the profiler believes it is in
+    ///
+    /// A profile the profiler tracks is stopped as with `stop`. Then the
+    /// cpuprofiler library is stopped too if it is still profiling, which
+    /// is the case when something else started it behind the profiler's
+    /// back, and the profiler is left `NotActive`. Use this to recover
+    /// when `start` fails because the library refuses to start a second
+    /// profile.
+    ///
+    /// Returns whether anything was profiling.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use cpuprofiler::PROFILER;
+    ///
+    /// let path = env::temp_dir().join("force-stop-example.profile");
+    /// let mut profiler = PROFILER.lock().unwrap();
+    /// profiler.start(path.to_str().unwrap()).unwrap();
+    /// assert!(profiler.force_stop().unwrap());
+    /// assert!(!profiler.force_stop().unwrap());
+    /// ```
+    ///
+    /// # Failures
+    ///
+    /// - The backend failed to stop the tracked profile, which is left
+    ///   running, see `stop`.
+    /// - The cpuprofiler library is still profiling after being stopped,
+    ///   `Error::Internal`.
+    pub fn force_stop(&mut self) -> Result<bool, Error> {
+        let tracked = self.state.is_running();
+        if tracked {
+            self.stop()?;
+        }
+
+        let untracked = library_profile().is_some();
+        if untracked {
+            stop_library();
+            if library_profile().is_some() {
+                return Err(Error::Internal);
+            }
+        }
+
+        Ok(tracked || untracked)
+    }
+}
@@ -0,0 +1,207 @@
+//! Automatic profile rotation
+//!
+//! A single profile of a long-running service grows without bound and is
+//! hard to work with. The `RotatingProfiler` instead stops and restarts the
+//! profiler on a timer, writing a sequence of time-stamped files such as
+//! `svc-2024-05-01T10:00:00.profile` and deleting the oldest ones once a
+//! retention limit is reached. With a [sink](../sink/index.html) each
+//! finished profile is also stored elsewhere, such as uploaded to S3.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use std::time::Duration;
+//! use cpuprofiler::rotate::RotatingProfiler;
+//!
+//! let rotation = RotatingProfiler::new(env::temp_dir(), "rotate-example")
+//!     .interval(Duration::from_millis(50))
+//!     .retain(2)
+//!     .start()
+//!     .unwrap();
+//!
+//! // Code you want to sample goes here!
+//!
+//! let files = rotation.stop().unwrap();
+//! assert!(files.len() <= 2);
+//! ```
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use error::Error;
+use lock;
+use sink::{ProfileSink, SharedSink};
+use timestamp;
+
+/// Configuration for a rotating profile
+#[derive(Clone, Debug)]
+pub struct RotatingProfiler {
+    dir: PathBuf,
+    prefix: String,
+    interval: Duration,
+    retain: Option<usize>,
+    sink: Option<SharedSink>,
+}
+
+impl RotatingProfiler {
+    /// Write profiles named `<prefix>-<timestamp>.profile` into `dir`
+    pub fn new<D: Into<PathBuf>, P: Into<String>>(dir: D, prefix: P) -> RotatingProfiler {
+        RotatingProfiler {
+            dir: dir.into(),
+            prefix: prefix.into(),
+            interval: Duration::from_secs(60),
+            retain: None,
+            sink: None,
+        }
+    }
+
+    /// Set how long each profile covers, defaults to one minute
+    pub fn interval(mut self, interval: Duration) -> RotatingProfiler {
+        self.interval = interval;
+        self
+    }
+
+    /// Keep at most `count` profiles, deleting the oldest first
+    ///
+    /// Only profiles written by this rotation are deleted. By default
+    /// every profile is kept.
+    pub fn retain(mut self, count: usize) -> RotatingProfiler {
+        self.retain = Some(count);
+        self
+    }
+
+    /// Put every finished profile to `sink`
+    ///
+    /// Profiles are put before old ones are deleted, so `retain(0)` keeps
+    /// no local copies at all. A profile which could not be put is not
+    /// retried, and does not stop the rotation, see
+    /// `RotatingHandle::failed_puts`.
+    pub fn sink<S: ProfileSink + 'static>(mut self, sink: S) -> RotatingProfiler {
+        self.sink = Some(SharedSink::new(sink));
+        self
+    }
+
+    /// Start the first profile and rotate on a background thread
+    ///
+    /// The rotation ends when its profile is stopped elsewhere, such as from
+    /// a control socket. That profile is kept but not put to the sink.
+    ///
+    /// # Failures
+    ///
+    /// - The first profile could not be started, see `Profiler::start`.
+    pub fn start(self) -> Result<RotatingHandle, Error> {
+        let mut files = VecDeque::new();
+        let mut session = self.start_next(&mut files)?;
+
+        let (tx, rx) = mpsc::channel();
+        let failed = Arc::new(AtomicUsize::new(0));
+        let failed_puts = failed.clone();
+
+        let thread = thread::spawn(move || loop {
+            let stopping = !matches!(rx.recv_timeout(self.interval), Err(RecvTimeoutError::Timeout));
+
+            // A profile stopped elsewhere ends the rotation, and one started
+            // elsewhere since is left running.
+            let ours = {
+                let mut profiler = lock::lock();
+                let ours = profiler.session == session && profiler.state.is_running();
+                if ours {
+                    profiler.stop()?;
+                }
+                ours
+            };
+            if let (Some(sink), Some(path)) = (self.sink.as_ref(), files.back()) {
+                if ours && sink.put(path).is_err() {
+                    failed_puts.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            self.enforce_retention(&mut files)?;
+
+            if stopping || !ours {
+                return Ok(files.into_iter().collect());
+            }
+            session = self.start_next(&mut files)?;
+        });
+
+        Ok(RotatingHandle {
+            stop: tx,
+            thread,
+            failed,
+        })
+    }
+
+    /// Start the next profile, returning its session
+    fn start_next(&self, files: &mut VecDeque<PathBuf>) -> Result<u64, Error> {
+        let stamp = timestamp::format_utc(SystemTime::now());
+        let mut path = self.dir.join(format!("{}-{}.profile", self.prefix, stamp));
+
+        // Intervals shorter than a second would otherwise overwrite each other.
+        let mut n = 1;
+        while files.contains(&path) {
+            path = self.dir.join(format!("{}-{}.{}.profile", self.prefix, stamp, n));
+            n += 1;
+        }
+
+        let mut profiler = lock::lock();
+        profiler.start_path(&path)?;
+        files.push_back(path);
+        Ok(profiler.session)
+    }
+
+    fn enforce_retention(&self, files: &mut VecDeque<PathBuf>) -> Result<(), Error> {
+        if let Some(retain) = self.retain {
+            while files.len() > retain {
+                if let Some(old) = files.pop_front() {
+                    match fs::remove_file(old) {
+                        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                        res => res?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A handle to a running rotation
+#[derive(Debug)]
+pub struct RotatingHandle {
+    stop: Sender<()>,
+    thread: JoinHandle<Result<Vec<PathBuf>, Error>>,
+    failed: Arc<AtomicUsize>,
+}
+
+impl RotatingHandle {
+    /// Returns the number of profiles which could not be put to the sink
+    /// so far
+    pub fn failed_puts(&self) -> usize {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    /// Stop rotating, finishing the current profile
+    ///
+    /// Returns the retained profiles, oldest first.
+    ///
+    /// # Failures
+    ///
+    /// - The rotation stopped early because the profiler could not be
+    ///   started or stopped, for example because another profile was started
+    ///   between two of the rotation's.
+    /// - An old profile could not be deleted.
+    pub fn stop(self) -> Result<Vec<PathBuf>, Error> {
+        // The thread may have already exited with an error.
+        let _ = self.stop.send(());
+        match self.thread.join() {
+            Ok(res) => res,
+            Err(_) => Err(Error::Internal),
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Profiling for the length of a scope
+//!
+//! `Profiler::with_profile` starts a profile, runs a closure and stops the
+//! profile when the closure returns or panics, like `std::thread::scope`
+//! does for threads. The closure is given a `ProfileScope`, a token which
+//! can pause, resume and flush the profile but cannot start another one.
+//! The token cannot leave the closure, and is neither `Send` nor `Sync`, so
+//! the profile cannot outlive the scope or be stopped from another thread.
+//!
+//! While the scope runs the profiler is taken, as with `Profiler::take`, so
+//! a nested `Profiler::start`, from the closure or from a library it calls,
+//! fails straight away with `Error::Busy`.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use cpuprofiler::Profiler;
+//!
+//! let path = env::temp_dir().join("scope-example.profile");
+//! let sum = Profiler::with_profile(path.to_str().unwrap(), |scope| {
+//!     // Code you want to sample goes here!
+//!     let sum: u64 = (0..1_000).sum();
+//!     scope.flush().unwrap();
+//!     sum
+//! })
+//! .unwrap();
+//! assert_eq!(sum, 499_500);
+//! ```
+//!
+//! The token cannot be moved to another thread:
+//!
+//! ```compile_fail
+//! use std::thread;
+//! use cpuprofiler::Profiler;
+//!
+//! Profiler::with_profile("./scope-example.profile", |scope| {
+//!     thread::scope(|s| {
+//!         s.spawn(|| scope.pause());
+//!     });
+//! })
+//! .unwrap();
+//! ```
+
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+
+use error::Error;
+use lock;
+use {Profiler, ProfilerState};
+
+/// The profile of a `Profiler::with_profile` scope
+#[derive(Debug)]
+pub struct ProfileScope<'scope> {
+    session: u64,
+    /// Ties the token to the scope, invariantly so it cannot be shortened
+    scope: PhantomData<&'scope mut &'scope ()>,
+    /// Keeps the token on the thread which started the profile
+    not_send: PhantomData<*mut ()>,
+}
+
+impl Profiler {
+    /// Profile `f`, writing the profile to `fname`
+    ///
+    /// The profile is stopped when `f` returns, or when it panics, in which
+    /// case the panic is passed on once it is stopped. Returns what `f`
+    /// returned.
+    ///
+    /// # Failures
+    ///
+    /// - The profiler has been taken, see `Profiler::take`, which includes
+    ///   being inside another scope, `Error::Busy`.
+    /// - The profile could not be started, see `Profiler::start`.
+    /// - The profile could not be stopped, see `Profiler::stop`.
+    pub fn with_profile<T, F, R>(fname: T, f: F) -> Result<R, Error>
+    where
+        T: Into<Vec<u8>>,
+        F: for<'scope> FnOnce(&'scope ProfileScope<'scope>) -> R,
+    {
+        let scope = {
+            let mut profiler = lock::lock();
+            profiler.start(fname)?;
+            profiler.taken = true;
+            ProfileScope {
+                session: profiler.session,
+                scope: PhantomData,
+                not_send: PhantomData,
+            }
+        };
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+
+        let mut profiler = lock::lock();
+        profiler.taken = false;
+        let stopped = if profiler.state.is_running() && profiler.session == scope.session {
+            profiler.stop()
+        } else {
+            Err(Error::InvalidState(ProfilerState::NotActive))
+        };
+        drop(profiler);
+        match res {
+            Ok(res) => stopped.map(|_| res),
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+}
+
+impl<'scope> ProfileScope<'scope> {
+    /// Returns the session of the profile, see `Transition::session`
+    pub fn session(&self) -> u64 {
+        self.session
+    }
+
+    /// Returns the number of samples gathered so far
+    pub fn samples_gathered(&self) -> u64 {
+        lock::lock().backend.samples_gathered()
+    }
+
+    /// Pause the profile, see `Profiler::pause`
+    ///
+    /// # Failures
+    ///
+    /// - See `Profiler::pause`.
+    pub fn pause(&self) -> Result<(), Error> {
+        lock::lock().pause()
+    }
+
+    /// Resume the profile, see `Profiler::resume`
+    ///
+    /// # Failures
+    ///
+    /// - See `Profiler::resume`.
+    pub fn resume(&self) -> Result<(), Error> {
+        lock::lock().resume()
+    }
+
+    /// Write the samples taken so far to the profile, see `Profiler::flush`
+    ///
+    /// # Failures
+    ///
+    /// - See `Profiler::flush`.
+    pub fn flush(&self) -> Result<(), Error> {
+        lock::lock().flush()
+    }
+}
@@ -0,0 +1,183 @@
+//! Accumulating many short sections into one profile
+//!
+//! Profiling each phase of a request separately leaves dozens of tiny
+//! profiles to keep track of. A `SectionProfiler` profiles every section it
+//! is given into memory, and merges the samples into one profile, which
+//! `finish` writes out. The samples of each section are also recorded as
+//! samples of a task named after the section, see the
+//! [`tasks`](../tasks/index.html) module, so the profile can still be split
+//! by section: `pprof::Encoder` exports them with the `task` label.
+//!
+//! Sections are profiled through the shared `PROFILER`, which is only
+//! locked while a section starts and stops. Samples are only taken inside
+//! sections.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use cpuprofiler::sections::SectionProfiler;
+//!
+//! let mut sections = SectionProfiler::new(env::temp_dir().join("sections-example.profile"));
+//! for request in 0..3 {
+//!     let parsed = sections.section("parse", || request * 2).unwrap();
+//!     sections.section("render", || parsed.to_string()).unwrap();
+//! }
+//! assert_eq!(sections.sections().len(), 6);
+//!
+//! let profile = sections.finish().unwrap();
+//! println!("{} samples", profile.total_samples());
+//! ```
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use error::Error;
+use lock;
+use profile::Profile;
+
+/// A section which was profiled
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Section {
+    /// The name the section was given
+    pub name: String,
+    /// The samples taken in the section
+    pub samples: u64,
+    /// How long the section ran for
+    pub elapsed: Duration,
+}
+
+/// Profiles sections of code into one profile
+#[derive(Debug)]
+pub struct SectionProfiler {
+    path: PathBuf,
+    profile: Profile,
+    sections: Vec<Section>,
+}
+
+impl SectionProfiler {
+    /// Accumulate sections into a profile to be written to `path`
+    ///
+    /// Nothing is written until `save` or `finish`.
+    pub fn new<P: Into<PathBuf>>(path: P) -> SectionProfiler {
+        SectionProfiler {
+            path: path.into(),
+            profile: Profile::default(),
+            sections: Vec::new(),
+        }
+    }
+
+    /// Returns where the profile is written
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the sections profiled so far, in order
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    /// Returns the samples of every section so far, merged
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    /// Profile `f` as the section `name`, returning what it returns
+    ///
+    /// If `f` panics the section's profile is stopped and discarded.
+    ///
+    /// # Failures
+    ///
+    /// - `name` is empty, `Error::Unsupported`.
+    /// - The profiler could not be started, see `Profiler::start_in_memory`.
+    /// - The profile was stopped by something else while `f` ran,
+    ///   `Error::InvalidState`.
+    /// - The section's profile could not be read back or merged, see
+    ///   `Profile::merge`.
+    pub fn section<T, F: FnOnce() -> T>(&mut self, name: &str, f: F) -> Result<T, Error> {
+        if name.trim().is_empty() {
+            return Err(Error::Unsupported("a section with an empty name".to_owned()));
+        }
+
+        let session = {
+            let mut profiler = lock::lock();
+            profiler.start_in_memory()?;
+            profiler.session
+        };
+        let started = Instant::now();
+        let guard = StopOnUnwind(session);
+        let value = f();
+        mem::forget(guard);
+        let elapsed = started.elapsed();
+
+        let bytes = {
+            let mut profiler = lock::lock();
+            if profiler.session != session || !profiler.state.is_running() {
+                return Err(Error::InvalidState(profiler.state));
+            }
+            profiler.stop_to_vec()?
+        };
+        let mut profile = Profile::from_bytes(&bytes)?;
+        let samples = profile.total_samples();
+        profile.attribute_to_task(name.trim());
+        self.profile.merge(profile)?;
+        self.sections.push(Section {
+            name: name.to_owned(),
+            samples,
+            elapsed,
+        });
+        Ok(value)
+    }
+
+    /// Write the sections profiled so far to the profile's path
+    ///
+    /// The profile is written next to the path and renamed over it, so a
+    /// reader never sees it half written. It can be saved again after more
+    /// sections.
+    ///
+    /// # Failures
+    ///
+    /// - The profile could not be written, `Error::OutputPath`.
+    pub fn save(&self) -> Result<(), Error> {
+        let mut partial = self.path.clone().into_os_string();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        let written = File::create(&partial).and_then(|file| {
+            let mut out = BufWriter::new(file);
+            self.profile.write_to(&mut out)?;
+            out.flush()
+        });
+        written.and_then(|_| fs::rename(&partial, &self.path)).map_err(|source| {
+            let _ = fs::remove_file(&partial);
+            Error::OutputPath {
+                path: self.path.clone(),
+                source,
+            }
+        })
+    }
+
+    /// Write the profile and return it
+    ///
+    /// # Failures
+    ///
+    /// - The profile could not be written, see `save`.
+    pub fn finish(self) -> Result<Profile, Error> {
+        self.save()?;
+        Ok(self.profile)
+    }
+}
+
+/// Stops the section's profile when its code panics
+struct StopOnUnwind(u64);
+
+impl Drop for StopOnUnwind {
+    fn drop(&mut self) {
+        let mut profiler = lock::lock();
+        if profiler.session == self.0 && profiler.state.is_running() {
+            let _ = profiler.stop_to_vec();
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! Pipes written by signal handlers and read by a background thread
+
+use std::io;
+
+use libc::{self, c_int, c_void};
+
+/// Create a pipe whose write end may be written from a signal handler
+///
+/// Neither end is inherited by programs the process executes, and writes
+/// never block, so a handler which finds the pipe full drops its byte
+/// rather than hang. The read end blocks, for the thread reading it.
+/// Returns the read and write ends.
+pub(crate) fn create() -> io::Result<(c_int, c_int)> {
+    let mut fds = [0 as c_int; 2];
+    unsafe {
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let configured = set_flag(fds[0], libc::F_GETFD, libc::F_SETFD, libc::FD_CLOEXEC)
+            .and_then(|_| set_flag(fds[1], libc::F_GETFD, libc::F_SETFD, libc::FD_CLOEXEC))
+            .and_then(|_| set_flag(fds[1], libc::F_GETFL, libc::F_SETFL, libc::O_NONBLOCK));
+        if let Err(e) = configured {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+            return Err(e);
+        }
+    }
+    Ok((fds[0], fds[1]))
+}
+
+unsafe fn set_flag(fd: c_int, get: c_int, set: c_int, flag: c_int) -> io::Result<()> {
+    let flags = libc::fcntl(fd, get);
+    if flags < 0 || libc::fcntl(fd, set, flags | flag) < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Write `len` bytes at `buf` to `fd` from a signal handler
+///
+/// `errno` is kept as it was, as the interrupted code may be about to read
+/// it.
+pub(crate) unsafe fn write(fd: c_int, buf: *const c_void, len: usize) {
+    let errno = errno_location();
+    let saved = *errno;
+    libc::write(fd, buf, len);
+    *errno = saved;
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn errno_location() -> *mut c_int {
+    libc::__errno_location()
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "dragonfly"))]
+unsafe fn errno_location() -> *mut c_int {
+    libc::__error()
+}
+
+#[cfg(any(target_os = "netbsd", target_os = "openbsd"))]
+unsafe fn errno_location() -> *mut c_int {
+    libc::__errno()
+}
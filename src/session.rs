@@ -0,0 +1,163 @@
+//! Combined cpu and heap profiling
+//!
+//! Available with the `heap` feature.
+//!
+//! A `Session` drives both `PROFILER` and `HEAP_PROFILER`, so that a run
+//! produces a cpu profile and heap profiles which share a name and cover the
+//! same period.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use cpuprofiler::session::Session;
+//!
+//! let session = Session::start(env::temp_dir().join("session-example")).unwrap();
+//! // Code you want to profile goes here!
+//! let files = session.stop().unwrap();
+//!
+//! println!("cpu profile written to {}", files.cpu.display());
+//! for heap in &files.heap {
+//!     println!("heap profile written to {}", heap.display());
+//! }
+//! ```
+
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use error::Error;
+use heap::HEAP_PROFILER;
+use lock;
+use template;
+
+/// A running cpu and heap profile
+///
+/// Both profilers are locked together whenever the session starts, dumps or
+/// stops, so no other thread observes one profiler running without the other.
+#[derive(Debug)]
+#[must_use = "the profilers keep running until the session is stopped"]
+pub struct Session {
+    prefix: PathBuf,
+}
+
+/// The files written by a `Session`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionFiles {
+    /// The cpu profile, `<prefix>.profile`
+    pub cpu: PathBuf,
+    /// The heap profiles, `<prefix>.<NNNN>.heap`, in the order they were written
+    pub heap: Vec<PathBuf>,
+}
+
+impl Session {
+    /// Start the cpu and heap profilers
+    ///
+    /// The cpu profile is written to `<prefix>.profile` and heap profiles to
+    /// `<prefix>.<NNNN>.heap`. Unlike `Profiler::start`, the prefix is used
+    /// as is and `%` tokens are not expanded.
+    ///
+    /// # Failures
+    ///
+    /// - Either profiler is currently `Active`, in which case neither is started.
+    /// - The cpu profiler could not be started, see `Profiler::start`.
+    /// - `prefix` is not a valid `CString`.
+    pub fn start<P: AsRef<Path>>(prefix: P) -> Result<Session, Error> {
+        let prefix = prefix.as_ref().to_path_buf();
+        let mut profiler = lock::lock();
+        let mut heap = HEAP_PROFILER.lock().unwrap();
+
+        // Check the heap profiler first so a failure leaves nothing running.
+        heap.check_inactive()?;
+        profiler.start(template::escape(cpu_path(&prefix).as_os_str().as_bytes()))?;
+        if let Err(e) = heap.start(prefix.as_os_str().as_bytes()) {
+            let _ = profiler.stop();
+            return Err(e);
+        }
+
+        Ok(Session { prefix })
+    }
+
+    /// The prefix shared by the profiles
+    pub fn prefix(&self) -> &Path {
+        &self.prefix
+    }
+
+    /// Write a heap profile now, recording `reason`
+    ///
+    /// # Failures
+    ///
+    /// - The heap profiler was stopped outside of the session.
+    /// - `reason` is not a valid `CString`.
+    pub fn dump<T: Into<Vec<u8>>>(&self, reason: T) -> Result<(), Error> {
+        let _profiler = lock::lock();
+        HEAP_PROFILER.lock().unwrap().dump(reason)
+    }
+
+    /// Write a final heap profile and stop both profilers
+    ///
+    /// # Failures
+    ///
+    /// - Either profiler was stopped outside of the session. The other
+    ///   profiler is still stopped.
+    pub fn stop(self) -> Result<SessionFiles, Error> {
+        let mut profiler = lock::lock();
+        let mut heap = HEAP_PROFILER.lock().unwrap();
+
+        let heap_res = heap.dump("session stop").and_then(|_| heap.stop());
+        let cpu_res = profiler.stop();
+        heap_res?;
+        cpu_res?;
+
+        Ok(SessionFiles {
+            cpu: cpu_path(&self.prefix),
+            heap: heap_files(&self.prefix),
+        })
+    }
+}
+
+fn cpu_path(prefix: &Path) -> PathBuf {
+    let mut path = OsString::from(prefix);
+    path.push(".profile");
+    PathBuf::from(path)
+}
+
+/// Find the files named `<prefix>.<digits>.heap`
+fn heap_files(prefix: &Path) -> Vec<PathBuf> {
+    let name = match prefix.file_name() {
+        Some(name) => name.as_bytes().to_vec(),
+        None => return Vec::new(),
+    };
+    let dir = match prefix.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    let file = path.file_name().map(|f| f.as_bytes()).unwrap_or_default();
+                    file.len() > name.len()
+                        && file.starts_with(&name)
+                        && is_heap_suffix(&file[name.len()..])
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    // The sequence numbers are zero padded so sort in order.
+    files.sort();
+    files
+}
+
+fn is_heap_suffix(suffix: &[u8]) -> bool {
+    suffix.len() > ".heap".len() + 1
+        && suffix[0] == b'.'
+        && suffix.ends_with(b".heap")
+        && suffix[1..suffix.len() - ".heap".len()]
+            .iter()
+            .all(|b| b.is_ascii_digit())
+}
@@ -0,0 +1,117 @@
+//! Toggling the profiler with a signal
+//!
+//! This mirrors the `CPUPROFILESIGNAL` behaviour of the cpuprofiler library:
+//! once installed, each delivery of the signal alternately starts and stops a
+//! profile. This lets operators capture a profile of a live process with
+//! `kill -USR1 <pid>`.
+//!
+//! The signal handler only writes to a pipe, the profiler itself is started
+//! and stopped by a background thread which locks `PROFILER` as usual.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! extern crate cpuprofiler;
+//! extern crate libc;
+//!
+//! use cpuprofiler::PROFILER;
+//!
+//! PROFILER.lock().unwrap().install_signal_toggle(libc::SIGUSR1, "./toggled.profile").unwrap();
+//! ```
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::thread;
+
+use libc::{self, c_int, c_void};
+
+use error::Error;
+use lock;
+use policy::Trigger;
+use selfpipe;
+use {Profiler, ProfilerState};
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(_: c_int) {
+    let byte = 1u8;
+    unsafe {
+        selfpipe::write(WRITE_FD.load(Ordering::SeqCst), &byte as *const u8 as *const c_void, 1);
+    }
+}
+
+impl Profiler {
+    /// Start and stop profiling whenever `signal` is received
+    ///
+    /// The n-th profile toggled on is written to `<fname>.<n>`, counting from zero.
+    /// If the profiler has been started elsewhere when the signal arrives the
+    /// signal is ignored, a toggled profile is only ever stopped by the signal
+    /// or by a call to `stop`. So is a signal the profiler's
+    /// [policy](policy/index.html) refuses.
+    ///
+    /// Only one signal toggle may be installed per process.
+    ///
+    /// # Failures
+    ///
+    /// - A signal toggle is already installed.
+    /// - The signal handler could not be installed.
+    pub fn install_signal_toggle<T: Into<String>>(&mut self, signal: c_int, fname: T) -> Result<(), Error> {
+        if INSTALLED.swap(true, Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "signal toggle already installed").into());
+        }
+        let fname = fname.into();
+
+        let (read_fd, write_fd) = match selfpipe::create() {
+            Ok(fds) => fds,
+            Err(e) => {
+                INSTALLED.store(false, Ordering::SeqCst);
+                return Err(e.into());
+            }
+        };
+        unsafe {
+            WRITE_FD.store(write_fd, Ordering::SeqCst);
+
+            let mut action: libc::sigaction = ::std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signal, &action, ::std::ptr::null_mut()) != 0 {
+                let err = io::Error::last_os_error();
+                libc::close(read_fd);
+                libc::close(write_fd);
+                INSTALLED.store(false, Ordering::SeqCst);
+                return Err(err.into());
+            }
+        }
+
+        thread::spawn(move || {
+            let mut count = 0;
+            let mut toggled = None;
+            let mut byte = 0u8;
+            loop {
+                let n = unsafe { libc::read(read_fd, &mut byte as *mut u8 as *mut c_void, 1) };
+                if n < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                } else if n <= 0 {
+                    return;
+                }
+
+                let mut profiler = lock::lock();
+                if profiler.state.is_running() && toggled == Some(profiler.session) {
+                    let _ = profiler.stop();
+                    toggled = None;
+                } else if profiler.state == ProfilerState::NotActive
+                    && profiler
+                        .start_triggered(Trigger::Signal, |p| p.start(format!("{}.{}", fname, count)))
+                        .is_ok()
+                {
+                    toggled = Some(profiler.session);
+                    count += 1;
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,455 @@
+//! Storing finished profiles somewhere durable
+//!
+//! A `ProfileSink` is handed every profile once it has been written, by
+//! `RotatingProfiler::sink` and `Profiler::start_for_into`. Services running
+//! in containers often have nowhere durable to keep local files, so sinks
+//! which upload profiles are available with these features:
+//!
+//! - `upload`: `HttpPut`, uploading to any server accepting `PUT` requests.
+//! - `s3`: `S3`, uploading to Amazon S3 or a compatible object store.
+//! - `gcs`: `Gcs`, uploading to Google Cloud Storage.
+//!
+//! `Directory` copies profiles to another local directory, such as a mounted
+//! volume, and any `Fn(&Path) -> Result<(), Error>` closure is a sink too.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use std::time::Duration;
+//! use cpuprofiler::rotate::RotatingProfiler;
+//! use cpuprofiler::sink::Directory;
+//!
+//! let rotation = RotatingProfiler::new(env::temp_dir(), "sink-example")
+//!     .interval(Duration::from_millis(50))
+//!     .retain(1)
+//!     .sink(Directory::new(env::temp_dir().join("sink-example-archive")))
+//!     .start()
+//!     .unwrap();
+//!
+//! // Code you want to sample goes here!
+//!
+//! rotation.stop().unwrap();
+//! ```
+
+#[cfg(feature = "s3")]
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+#[cfg(feature = "s3")]
+use std::time::SystemTime;
+
+#[cfg(feature = "s3")]
+use ring::{digest, hmac};
+#[cfg(feature = "upload")]
+use ureq;
+
+use error::Error;
+#[cfg(feature = "gcs")]
+use gce;
+#[cfg(feature = "s3")]
+use timestamp;
+
+/// Somewhere finished profiles are stored
+pub trait ProfileSink: Send + Sync {
+    /// Store the finished profile written to `path`
+    ///
+    /// The profile is left at `path`, it is up to the caller whether it is
+    /// deleted afterwards.
+    fn put(&self, path: &Path) -> Result<(), Error>;
+}
+
+impl<F> ProfileSink for F
+where
+    F: Fn(&Path) -> Result<(), Error> + Send + Sync,
+{
+    fn put(&self, path: &Path) -> Result<(), Error> {
+        self(path)
+    }
+}
+
+/// A sink shared with a background thread
+#[derive(Clone)]
+pub(crate) struct SharedSink(Arc<dyn ProfileSink>);
+
+impl SharedSink {
+    pub(crate) fn new<S: ProfileSink + 'static>(sink: S) -> SharedSink {
+        SharedSink(Arc::new(sink))
+    }
+
+    pub(crate) fn put(&self, path: &Path) -> Result<(), Error> {
+        self.0.put(path)
+    }
+}
+
+impl fmt::Debug for SharedSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedSink")
+    }
+}
+
+/// Returns the file name of `path`, which names the stored profile
+fn file_name(path: &Path) -> Result<String, Error> {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| Error::Upload(format!("{} does not name a file", path.display())))
+}
+
+/// Copies profiles into a directory
+#[derive(Clone, Debug)]
+pub struct Directory {
+    dir: PathBuf,
+}
+
+impl Directory {
+    /// Copy profiles into `dir`, which is created if it does not exist
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Directory {
+        Directory { dir: dir.into() }
+    }
+}
+
+impl ProfileSink for Directory {
+    fn put(&self, path: &Path) -> Result<(), Error> {
+        let dest = self.dir.join(file_name(path)?);
+        fs::create_dir_all(&self.dir)
+            .and_then(|_| fs::copy(path, &dest))
+            .map(|_| ())
+            .map_err(|source| Error::OutputPath { path: dest, source })
+    }
+}
+
+/// Uploads profiles with HTTP `PUT` requests
+///
+/// Available with the `upload` feature. Each profile is uploaded to the base
+/// URL followed by its file name.
+///
+/// # Examples
+///
+/// ```
+/// use cpuprofiler::sink::HttpPut;
+///
+/// let sink = HttpPut::new("https://profiles.example.com/my-service/")
+///     .header("Authorization", "Bearer my-token");
+/// ```
+#[cfg(feature = "upload")]
+#[derive(Clone)]
+pub struct HttpPut {
+    url: String,
+    headers: Vec<(String, String)>,
+}
+
+#[cfg(feature = "upload")]
+impl HttpPut {
+    /// Upload profiles below `url`
+    pub fn new<U: Into<String>>(url: U) -> HttpPut {
+        HttpPut {
+            url: url.into(),
+            headers: Vec::new(),
+        }
+    }
+
+    /// Send the header `name` with every upload, such as credentials
+    pub fn header<K: Into<String>, V: Into<String>>(mut self, name: K, value: V) -> HttpPut {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+}
+
+#[cfg(feature = "upload")]
+impl fmt::Debug for HttpPut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Header values are left out as they often hold credentials.
+        let names: Vec<&str> = self.headers.iter().map(|(k, _)| &**k).collect();
+        f.debug_struct("HttpPut").field("url", &self.url).field("headers", &names).finish()
+    }
+}
+
+#[cfg(feature = "upload")]
+impl ProfileSink for HttpPut {
+    fn put(&self, path: &Path) -> Result<(), Error> {
+        let url = format!("{}/{}", self.url.trim_end_matches('/'), uri_encode(&file_name(path)?));
+        let body = fs::read(path)?;
+        let mut request = ureq::put(&url).set("Content-Type", "application/octet-stream");
+        for (k, v) in &self.headers {
+            request = request.set(k, v);
+        }
+        request.send_bytes(&body).map(|_| ()).map_err(|e| Error::Upload(e.to_string()))
+    }
+}
+
+/// Percent-encode everything but the unreserved characters of RFC 3986
+#[cfg(feature = "upload")]
+fn uri_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Percent-encode an object key, keeping its `/` separators
+#[cfg(any(feature = "s3", feature = "gcs"))]
+fn key_encode(key: &str) -> String {
+    key.split('/').map(uri_encode).collect::<Vec<_>>().join("/")
+}
+
+/// Uploads profiles to Amazon S3
+///
+/// Available with the `s3` feature. Requests are signed with AWS Signature
+/// Version 4. Objects are named by the key prefix followed by the file name
+/// of the profile.
+///
+/// # Examples
+///
+/// ```no_run
+/// use cpuprofiler::sink::S3;
+///
+/// // Credentials and the region are read from the usual AWS variables.
+/// let sink = S3::from_env("my-profiles").unwrap().prefix("my-service/");
+/// ```
+#[cfg(feature = "s3")]
+#[derive(Clone)]
+pub struct S3 {
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+    endpoint: Option<String>,
+    prefix: String,
+}
+
+#[cfg(feature = "s3")]
+impl S3 {
+    /// Upload profiles to `bucket` in `region` with the given credentials
+    pub fn new<B, R, A, S>(bucket: B, region: R, access_key: A, secret_key: S) -> S3
+    where
+        B: Into<String>,
+        R: Into<String>,
+        A: Into<String>,
+        S: Into<String>,
+    {
+        S3 {
+            bucket: bucket.into(),
+            region: region.into(),
+            access_key: access_key.into(),
+            secret_key: secret_key.into(),
+            session_token: None,
+            endpoint: None,
+            prefix: String::new(),
+        }
+    }
+
+    /// Upload profiles to `bucket` with credentials from the environment
+    ///
+    /// The credentials are read from `AWS_ACCESS_KEY_ID`,
+    /// `AWS_SECRET_ACCESS_KEY` and, if set, `AWS_SESSION_TOKEN`. The region
+    /// is read from `AWS_REGION` or `AWS_DEFAULT_REGION`.
+    ///
+    /// # Failures
+    ///
+    /// - The credentials or the region are not set, `Error::Unsupported`.
+    pub fn from_env<B: Into<String>>(bucket: B) -> Result<S3, Error> {
+        let var = |name: &str| env::var(name).ok().filter(|v| !v.is_empty());
+        let missing = |name: &str| Error::Unsupported(format!("uploading to S3 needs {} to be set", name));
+
+        let region = var("AWS_REGION")
+            .or_else(|| var("AWS_DEFAULT_REGION"))
+            .ok_or_else(|| missing("AWS_REGION"))?;
+        let access_key = var("AWS_ACCESS_KEY_ID").ok_or_else(|| missing("AWS_ACCESS_KEY_ID"))?;
+        let secret_key = var("AWS_SECRET_ACCESS_KEY").ok_or_else(|| missing("AWS_SECRET_ACCESS_KEY"))?;
+
+        let mut s3 = S3::new(bucket, region, access_key, secret_key);
+        s3.session_token = var("AWS_SESSION_TOKEN");
+        Ok(s3)
+    }
+
+    /// Sign requests with the session token of temporary credentials
+    pub fn session_token<T: Into<String>>(mut self, token: T) -> S3 {
+        self.session_token = Some(token.into());
+        self
+    }
+
+    /// Upload to a compatible object store at `endpoint`, such as MinIO
+    ///
+    /// Buckets are addressed by path, as `<endpoint>/<bucket>/<key>`.
+    pub fn endpoint<E: Into<String>>(mut self, endpoint: E) -> S3 {
+        self.endpoint = Some(endpoint.into());
+        self
+    }
+
+    /// Start every object key with `prefix`
+    pub fn prefix<P: Into<String>>(mut self, prefix: P) -> S3 {
+        self.prefix = prefix.into();
+        self
+    }
+}
+
+#[cfg(feature = "s3")]
+impl fmt::Debug for S3 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("S3")
+            .field("bucket", &self.bucket)
+            .field("region", &self.region)
+            .field("access_key", &self.access_key)
+            .field("endpoint", &self.endpoint)
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+#[cfg(feature = "s3")]
+impl ProfileSink for S3 {
+    fn put(&self, path: &Path) -> Result<(), Error> {
+        let key = key_encode(&format!("{}{}", self.prefix, file_name(path)?));
+        let (url, host, uri) = match self.endpoint {
+            Some(ref endpoint) => {
+                let endpoint = endpoint.trim_end_matches('/');
+                let host = endpoint.split("://").last().unwrap_or(endpoint).to_owned();
+                let uri = format!("/{}/{}", uri_encode(&self.bucket), key);
+                (format!("{}{}", endpoint, uri), host, uri)
+            }
+            None => {
+                let host = format!("{}.s3.{}.amazonaws.com", self.bucket, self.region);
+                let uri = format!("/{}", key);
+                (format!("https://{}{}", host, uri), host, uri)
+            }
+        };
+
+        let body = fs::read(path)?;
+        let payload_hash = hex(digest::digest(&digest::SHA256, &body).as_ref());
+        let stamp = timestamp::format_utc(SystemTime::now()).replace(['-', ':'], "");
+        let amz_date = format!("{}Z", stamp);
+        let date = &stamp[..8];
+
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(ref token) = self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers: Vec<&str> = headers.iter().map(|&(k, _)| k).collect();
+        let signed_headers = signed_headers.join(";");
+        let canonical_headers: String = headers.iter().map(|(k, v)| format!("{}:{}\n", k, v.trim())).collect();
+
+        let canonical_request = format!("PUT\n{}\n\n{}\n{}\n{}", uri, canonical_headers, signed_headers, payload_hash);
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+        );
+
+        let key = hmac_sha256(format!("AWS4{}", self.secret_key).as_bytes(), date);
+        let key = hmac_sha256(&key, &self.region);
+        let key = hmac_sha256(&key, "s3");
+        let key = hmac_sha256(&key, "aws4_request");
+        let signature = hex(&hmac_sha256(&key, &string_to_sign));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        );
+
+        let mut request = ureq::put(&url).set("Authorization", &authorization);
+        // ureq sends the host itself.
+        for (k, v) in headers.iter().skip(1) {
+            request = request.set(k, v);
+        }
+        request.send_bytes(&body).map(|_| ()).map_err(|e| Error::Upload(e.to_string()))
+    }
+}
+
+#[cfg(feature = "s3")]
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::sign(&key, data.as_bytes()).as_ref().to_vec()
+}
+
+#[cfg(feature = "s3")]
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Uploads profiles to Google Cloud Storage
+///
+/// Available with the `gcs` feature. Objects are named by the prefix
+/// followed by the file name of the profile. Unless a token is given, each
+/// upload fetches an access token for the service account of the host from
+/// the metadata server, which is available on Compute Engine, GKE and Cloud
+/// Run.
+///
+/// # Examples
+///
+/// ```
+/// use cpuprofiler::sink::Gcs;
+///
+/// let sink = Gcs::new("my-profiles").prefix("my-service/");
+/// ```
+#[cfg(feature = "gcs")]
+#[derive(Clone)]
+pub struct Gcs {
+    bucket: String,
+    token: Option<String>,
+    prefix: String,
+}
+
+#[cfg(feature = "gcs")]
+impl Gcs {
+    /// Upload profiles to `bucket`
+    pub fn new<B: Into<String>>(bucket: B) -> Gcs {
+        Gcs {
+            bucket: bucket.into(),
+            token: None,
+            prefix: String::new(),
+        }
+    }
+
+    /// Authenticate with the OAuth 2 access token `token`
+    pub fn token<T: Into<String>>(mut self, token: T) -> Gcs {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Start every object name with `prefix`
+    pub fn prefix<P: Into<String>>(mut self, prefix: P) -> Gcs {
+        self.prefix = prefix.into();
+        self
+    }
+}
+
+#[cfg(feature = "gcs")]
+impl fmt::Debug for Gcs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Gcs")
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+#[cfg(feature = "gcs")]
+impl ProfileSink for Gcs {
+    fn put(&self, path: &Path) -> Result<(), Error> {
+        let name = format!("{}{}", self.prefix, file_name(path)?);
+        let url = format!("https://storage.googleapis.com/{}/{}", uri_encode(&self.bucket), key_encode(&name));
+        let body = fs::read(path)?;
+        let token = match self.token {
+            Some(ref token) => token.clone(),
+            None => gce::access_token()?,
+        };
+        ureq::put(&url)
+            .set("Authorization", &format!("Bearer {}", token))
+            .set("Content-Type", "application/octet-stream")
+            .send_bytes(&body)
+            .map(|_| ())
+            .map_err(|e| Error::Upload(e.to_string()))
+    }
+}
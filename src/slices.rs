@@ -0,0 +1,235 @@
+//! Splitting a long capture into time slices
+//!
+//! A profile only counts how often each stack was sampled, not when, so one
+//! long profile cannot show how the CPU was used over time. A
+//! `SlicedProfiler` instead writes a profile per window of time, such as
+//! every 10s of a 10 minute capture, and records the start and end of each,
+//! so a spike in requests can be matched against the profile of the window
+//! it fell in.
+//!
+//! Windows start on multiples of the window length since the Unix epoch,
+//! so the slices of a 10s window line up with dashboards which also bucket
+//! by 10s. The first and last slices cover what remains of their window.
+//! `write_index` lists the slices as JSON, for tools which load them.
+//!
+//! The profiler is stopped and restarted between slices, which only takes
+//! as long as writing the profile. Samples are not taken meanwhile.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use std::time::Duration;
+//! use cpuprofiler::slices::{self, SlicedProfiler};
+//!
+//! let capture = SlicedProfiler::new(env::temp_dir(), "slices-example")
+//!     .window(Duration::from_millis(50))
+//!     .start()
+//!     .unwrap();
+//!
+//! // Code you want to sample goes here!
+//!
+//! let slices = capture.stop().unwrap();
+//! let mut index = Vec::new();
+//! slices::write_index(&mut index, &slices).unwrap();
+//! # for slice in &slices {
+//! #     let _ = std::fs::remove_file(&slice.path);
+//! # }
+//! ```
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use error::Error;
+use json;
+use lock;
+use timestamp;
+
+/// One window of a sliced capture
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Slice {
+    /// The profile of the window
+    pub path: PathBuf,
+    /// When the profile started
+    pub start: SystemTime,
+    /// When the profile stopped
+    pub end: SystemTime,
+    /// The number of samples gathered
+    pub samples: u64,
+}
+
+/// Configuration for a sliced capture
+#[derive(Clone, Debug)]
+pub struct SlicedProfiler {
+    dir: PathBuf,
+    prefix: String,
+    window: Duration,
+    align: bool,
+}
+
+impl SlicedProfiler {
+    /// Write slices named `<prefix>-<timestamp>.profile` into `dir`
+    pub fn new<D: Into<PathBuf>, P: Into<String>>(dir: D, prefix: P) -> SlicedProfiler {
+        SlicedProfiler {
+            dir: dir.into(),
+            prefix: prefix.into(),
+            window: Duration::from_secs(10),
+            align: true,
+        }
+    }
+
+    /// Set how long each slice covers, defaults to 10s
+    pub fn window(mut self, window: Duration) -> SlicedProfiler {
+        self.window = window;
+        self
+    }
+
+    /// Set whether windows start on multiples of their length since the
+    /// Unix epoch, defaults to `true`
+    ///
+    /// Otherwise each window starts when the last one ended.
+    pub fn align(mut self, align: bool) -> SlicedProfiler {
+        self.align = align;
+        self
+    }
+
+    /// Start the first slice and start the next ones on a background thread
+    ///
+    /// # Failures
+    ///
+    /// - The window is zero, `Error::Unsupported`.
+    /// - The first profile could not be started, see `Profiler::start`.
+    pub fn start(self) -> Result<SlicedHandle, Error> {
+        if self.window == Duration::from_secs(0) {
+            return Err(Error::Unsupported("slices of no time".to_owned()));
+        }
+        let slices = Arc::new(Mutex::new(Vec::new()));
+        let mut current = self.start_slice(&slices.lock().unwrap_or_else(|e| e.into_inner()))?;
+
+        let (tx, rx) = mpsc::channel();
+        let finished = slices.clone();
+        let thread = thread::spawn(move || loop {
+            let wait = self.remaining(current.1);
+            let stopping = !matches!(rx.recv_timeout(wait), Err(RecvTimeoutError::Timeout));
+
+            let slice = self.stop_slice(current)?;
+            let mut slices = finished.lock().unwrap_or_else(|e| e.into_inner());
+            slices.push(slice);
+            if stopping {
+                return Ok(());
+            }
+            current = self.start_slice(&slices)?;
+        });
+
+        Ok(SlicedHandle {
+            stop: tx,
+            thread,
+            slices,
+        })
+    }
+
+    /// Returns how long is left of the window which `start` is in
+    fn remaining(&self, start: SystemTime) -> Duration {
+        let elapsed = if self.align {
+            let since_epoch = start.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let window = self.window.as_nanos();
+            Duration::from_nanos((since_epoch.as_nanos() % window) as u64)
+        } else {
+            Duration::from_secs(0)
+        };
+        let end = start + (self.window - elapsed);
+        end.duration_since(SystemTime::now()).unwrap_or_default()
+    }
+
+    fn start_slice(&self, slices: &[Slice]) -> Result<(PathBuf, SystemTime), Error> {
+        let start = SystemTime::now();
+        let stamp = timestamp::format_utc(start);
+        let mut path = self.dir.join(format!("{}-{}.profile", self.prefix, stamp));
+
+        // Windows shorter than a second would otherwise overwrite each other.
+        let mut n = 1;
+        while slices.iter().any(|slice| slice.path == path) {
+            path = self.dir.join(format!("{}-{}.{}.profile", self.prefix, stamp, n));
+            n += 1;
+        }
+
+        lock::lock().start_path(&path)?;
+        Ok((path, start))
+    }
+
+    fn stop_slice(&self, (path, start): (PathBuf, SystemTime)) -> Result<Slice, Error> {
+        let summary = lock::lock().stop_with_summary()?;
+        Ok(Slice {
+            path,
+            start,
+            end: SystemTime::now(),
+            samples: summary.samples,
+        })
+    }
+}
+
+/// A handle to a running sliced capture
+#[derive(Debug)]
+pub struct SlicedHandle {
+    stop: Sender<()>,
+    thread: JoinHandle<Result<(), Error>>,
+    slices: Arc<Mutex<Vec<Slice>>>,
+}
+
+impl SlicedHandle {
+    /// Returns the slices finished so far, oldest first
+    pub fn slices(&self) -> Vec<Slice> {
+        self.slices.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Stop the capture, finishing the current slice
+    ///
+    /// Returns every slice, oldest first.
+    ///
+    /// # Failures
+    ///
+    /// - The capture stopped early because the profiler could not be
+    ///   started or stopped, for example because it was used elsewhere.
+    pub fn stop(self) -> Result<Vec<Slice>, Error> {
+        // The thread may have already exited with an error.
+        let _ = self.stop.send(());
+        match self.thread.join() {
+            Ok(res) => res?,
+            Err(_) => return Err(Error::Internal),
+        }
+        let slices = self.slices.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(slices.clone())
+    }
+}
+
+/// Write `slices` as a JSON array
+///
+/// Each slice is an object with its `path`, `start` and `end`, in seconds
+/// since the Unix epoch, and `samples`.
+///
+/// ```text
+/// [
+///   {"path": "svc-2024-05-01T10:00:00.profile", "start": 1714557600.000, "end": 1714557610.000, "samples": 986}
+/// ]
+/// ```
+pub fn write_index<W: Write>(out: &mut W, slices: &[Slice]) -> io::Result<()> {
+    let secs = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+    writeln!(out, "[")?;
+    for (i, slice) in slices.iter().enumerate() {
+        writeln!(
+            out,
+            "  {{\"path\": {}, \"start\": {:.3}, \"end\": {:.3}, \"samples\": {}}}{}",
+            json::string(&slice.path.to_string_lossy()),
+            secs(slice.start),
+            secs(slice.end),
+            slice.samples,
+            if i + 1 < slices.len() { "," } else { "" }
+        )?;
+    }
+    writeln!(out, "]")
+}
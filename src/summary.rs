@@ -0,0 +1,66 @@
+//! Summaries of finished profiles
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use cpuprofiler::PROFILER;
+//!
+//! let path = env::temp_dir().join("summary-example.profile");
+//! PROFILER.lock().unwrap().start(path.to_str().unwrap()).unwrap();
+//! // Code you want to sample goes here!
+//! let summary = PROFILER.lock().unwrap().stop_with_summary().unwrap();
+//!
+//! println!("{} samples written to {}", summary.samples, summary.path.display());
+//! ```
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use error::Error;
+use Profiler;
+
+/// Information about a profile, gathered as it was stopped
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ProfileSummary {
+    /// The file the profile was written to
+    pub path: PathBuf,
+    /// The path the profile was started with, when it could not be written
+    /// there and fell back to another directory, see
+    /// `Profiler::set_fallback_dirs`
+    pub requested: Option<PathBuf>,
+    /// The number of samples gathered
+    pub samples: u64,
+    /// How long the profiler was active for
+    pub duration: Duration,
+}
+
+impl Profiler {
+    /// Stop the profiler and summarize the profile
+    ///
+    /// This behaves like `stop` but also reports what the backend
+    /// recorded, so callers can confirm that samples were written.
+    ///
+    /// # Failures
+    ///
+    /// - The profiler is `NotActive`.
+    pub fn stop_with_summary(&mut self) -> Result<ProfileSummary, Error> {
+        if !self.state.is_running() {
+            return Err(Error::InvalidState(self.state));
+        }
+
+        let samples = self.backend.samples_gathered();
+        let duration = self.started.map(|s| s.elapsed()).unwrap_or_default();
+        let path = self.path.clone().unwrap_or_default();
+        let requested = self.requested.clone();
+        self.stop()?;
+
+        Ok(ProfileSummary {
+            path,
+            requested,
+            samples,
+            duration,
+        })
+    }
+}
@@ -0,0 +1,372 @@
+//! Resolving profile addresses to functions
+//!
+//! Available with the `symbolize` feature.
+//!
+//! The `Symbolizer` reads the symbols and debug info of the binaries listed
+//! in a profile's memory mappings, so it must run where those binaries are
+//! available, usually the machine the profile was taken on. Inlined functions
+//! are reported as frames of their own, between the function they were
+//! inlined into and the functions they call, so the samples of a function
+//! inlined into its caller count towards it rather than the caller. The
+//! folded stacks, flamegraphs and reports built from `Symbolizer::stacks`
+//! show them, as do pprof profiles with `Encoder::symbols`. Turn this off
+//! with `Symbolizer::inline_frames` to see only the functions which were
+//! compiled.
+//!
+//! Stripped binaries are symbolized with their separate debug info, found
+//! by build id or debug link under `/usr/lib/debug` and other
+//! `Symbolizer::debug_dir`s, or next to the binary as a `.dwp` package.
+//! With the `debuginfod` feature the debug info is downloaded from the
+//! servers in `DEBUGINFOD_URLS`, or those given to
+//! `Symbolizer::debuginfod_urls`, when it is not found locally.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use cpuprofiler::PROFILER;
+//! use cpuprofiler::profile::Profile;
+//! use cpuprofiler::report;
+//! use cpuprofiler::symbolize::Symbolizer;
+//!
+//! let path = env::temp_dir().join("symbolize-example.profile");
+//! PROFILER.lock().unwrap().start(path.to_str().unwrap()).unwrap();
+//! // Code you want to sample goes here!
+//! PROFILER.lock().unwrap().stop().unwrap();
+//!
+//! let profile = Profile::from_file(&path).unwrap();
+//! let stacks = Symbolizer::new().stacks(&profile);
+//! report::write_top(&mut std::io::stdout(), &stacks, 10).unwrap();
+//! ```
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use addr2line::{self, Loader};
+use object::{Object, ObjectSegment};
+
+use debuginfo::DebugInfo;
+use error::Error;
+use profile::{Mapping, Profile, Reader};
+use report::Stack;
+
+/// A function at an address, possibly inlined into its caller
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// The address which was resolved
+    pub address: u64,
+    /// The demangled function name, if known
+    pub function: Option<String>,
+    /// The source file, if known
+    pub file: Option<String>,
+    /// The source line, if known
+    pub line: Option<u32>,
+    /// Whether the function was inlined into the frame after it
+    pub inlined: bool,
+}
+
+impl Frame {
+    fn unknown(address: u64) -> Frame {
+        Frame {
+            address,
+            function: None,
+            file: None,
+            line: None,
+            inlined: false,
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.function {
+            Some(ref function) => write!(f, "{}", function),
+            None => write!(f, "{:#x}", self.address),
+        }
+    }
+}
+
+/// Resolves addresses using the binaries of the profiled process
+///
+/// Binaries are loaded when first needed and kept for later lookups.
+pub struct Symbolizer {
+    binaries: HashMap<String, Option<Binary>>,
+    cache: HashMap<u64, Vec<Frame>>,
+    debug: DebugInfo,
+    inline_frames: bool,
+}
+
+/// A loaded binary and the file offsets of its loadable segments
+struct Binary {
+    loader: Loader,
+    segments: Vec<Segment>,
+}
+
+struct Segment {
+    file_offset: u64,
+    file_size: u64,
+    address: u64,
+}
+
+impl Symbolizer {
+    /// Create a symbolizer with no binaries loaded
+    pub fn new() -> Symbolizer {
+        Symbolizer {
+            binaries: HashMap::new(),
+            cache: HashMap::new(),
+            debug: DebugInfo::from_env(),
+            inline_frames: true,
+        }
+    }
+
+    /// Report inlined functions as frames of their own, defaults to `true`
+    ///
+    /// Otherwise each address resolves to the function it was compiled
+    /// into, at the line of the innermost inlined call, like
+    /// `pprof -noinlines`.
+    pub fn inline_frames(mut self, enabled: bool) -> Symbolizer {
+        self.inline_frames = enabled;
+        self
+    }
+
+    /// Also look for separate debug info under `dir`, after the others
+    ///
+    /// `/usr/lib/debug` is looked in by default. Debug info is found by
+    /// build id, at `<dir>/.build-id/<xx>/<rest of the id>.debug`, or at
+    /// the path of the binary under `dir`.
+    pub fn debug_dir<P: Into<PathBuf>>(mut self, dir: P) -> Symbolizer {
+        self.debug.dirs.push(dir.into());
+        self
+    }
+
+    /// Download missing debug info from the debuginfod servers `urls`,
+    /// separated by spaces
+    ///
+    /// Available with the `debuginfod` feature. Defaults to the
+    /// `DEBUGINFOD_URLS` environment variable, downloads are cached in
+    /// `DEBUGINFOD_CACHE_PATH` or `~/.cache/debuginfod_client`.
+    #[cfg(feature = "debuginfod")]
+    pub fn debuginfod_urls(mut self, urls: &str) -> Symbolizer {
+        self.debug.urls = ::debuginfo::parse_urls(urls);
+        self
+    }
+
+    /// Cache the debug info downloaded from debuginfod servers in `dir`
+    ///
+    /// Available with the `debuginfod` feature.
+    #[cfg(feature = "debuginfod")]
+    pub fn debuginfod_cache<P: Into<PathBuf>>(mut self, dir: P) -> Symbolizer {
+        self.debug.cache = Some(dir.into());
+        self
+    }
+
+    /// Returns the frames at `addr` in `profile`, innermost first
+    ///
+    /// More than one frame is returned when functions were inlined at the
+    /// address. An address which cannot be resolved gives a single frame
+    /// without a function name.
+    pub fn resolve(&mut self, profile: &Profile, addr: u64) -> Vec<Frame> {
+        self.resolve_mapped(profile.mappings(), addr)
+    }
+
+    /// Returns the frames at `addr` in the process with `mappings`
+    pub(crate) fn resolve_mapped(&mut self, mappings: &[Mapping], addr: u64) -> Vec<Frame> {
+        if let Some(frames) = self.cache.get(&addr) {
+            return frames.clone();
+        }
+
+        let frames = match mappings.iter().find(|m| m.contains(addr)) {
+            Some(mapping) => self.resolve_in(mapping, addr),
+            None => vec![Frame::unknown(addr)],
+        };
+        self.cache.insert(addr, frames.clone());
+        frames
+    }
+
+    /// Symbolize every sample of `profile`
+    ///
+    /// Samples which resolve to the same functions are merged.
+    pub fn stacks(&mut self, profile: &Profile) -> Vec<Stack> {
+        let mut merged: HashMap<Vec<String>, u64> = HashMap::new();
+        for sample in profile.samples() {
+            let mut frames = Vec::new();
+            for (i, &pc) in sample.stack.iter().enumerate() {
+                // Callers are looked up at the call instruction rather than
+                // the return address.
+                let addr = if i == 0 { pc } else { pc.saturating_sub(1) };
+                frames.extend(self.resolve(profile, addr).into_iter().map(|f| f.to_string()));
+            }
+            frames.reverse();
+            *merged.entry(frames).or_insert(0) += sample.count;
+        }
+
+        let mut stacks: Vec<Stack> = merged
+            .into_iter()
+            .map(|(frames, count)| Stack { count, frames })
+            .collect();
+        stacks.sort_by(|a, b| a.frames.cmp(&b.frames));
+        stacks
+    }
+
+    /// Symbolize every sample read by `reader`, see `stacks`
+    ///
+    /// The profile is aggregated as it is read, see `Reader::aggregate`, so
+    /// profiles too large to parse into memory can still be reported on
+    /// and written as folded stacks or flamegraphs.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use std::fs::File;
+    /// use cpuprofiler::PROFILER;
+    /// use cpuprofiler::profile::Reader;
+    /// use cpuprofiler::report;
+    /// use cpuprofiler::symbolize::Symbolizer;
+    ///
+    /// let path = env::temp_dir().join("stream-folded-example.profile");
+    /// PROFILER.lock().unwrap().start(path.to_str().unwrap()).unwrap();
+    /// // Code you want to sample goes here!
+    /// PROFILER.lock().unwrap().stop().unwrap();
+    ///
+    /// let reader = Reader::stream(File::open(&path).unwrap()).unwrap();
+    /// let stacks = Symbolizer::new().stacks_from(reader).unwrap();
+    /// report::write_folded(&mut std::io::stdout(), &stacks).unwrap();
+    /// ```
+    ///
+    /// # Failures
+    ///
+    /// - The profile could not be read, see `Reader::aggregate`.
+    pub fn stacks_from<R: Read>(&mut self, reader: Reader<R>) -> Result<Vec<Stack>, Error> {
+        Ok(self.stacks(&reader.aggregate()?))
+    }
+
+    fn resolve_in(&mut self, mapping: &Mapping, addr: u64) -> Vec<Frame> {
+        let path = match mapping.path {
+            Some(ref path) if !path.starts_with('[') => path,
+            _ => return vec![Frame::unknown(addr)],
+        };
+        let debug = &self.debug;
+        let binary = self
+            .binaries
+            .entry(path.clone())
+            .or_insert_with(|| Binary::load(path, debug));
+        let binary = match *binary {
+            Some(ref binary) => binary,
+            None => return vec![Frame::unknown(addr)],
+        };
+
+        let file_offset = addr - mapping.start + mapping.offset;
+        let mut frames = match binary.address_of(file_offset) {
+            Some(probe) => binary.frames(addr, probe),
+            None => vec![Frame::unknown(addr)],
+        };
+        if !self.inline_frames && frames.len() > 1 {
+            let innermost = frames.remove(0);
+            let mut outermost = frames.pop().unwrap_or_else(|| innermost.clone());
+            outermost.file = innermost.file;
+            outermost.line = innermost.line;
+            frames = vec![outermost];
+        }
+        frames
+    }
+}
+
+impl Default for Symbolizer {
+    fn default() -> Symbolizer {
+        Symbolizer::new()
+    }
+}
+
+impl fmt::Debug for Symbolizer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Symbolizer")
+            .field("binaries", &self.binaries.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Binary {
+    fn load(path: &str, debug: &DebugInfo) -> Option<Binary> {
+        let data = fs::read(path).ok()?;
+        let (segments, debug_file) = {
+            let file = object::File::parse(&*data).ok()?;
+            let segments = file
+                .segments()
+                .map(|segment| {
+                    let (file_offset, file_size) = segment.file_range();
+                    Segment {
+                        file_offset,
+                        file_size,
+                        address: segment.address(),
+                    }
+                })
+                .collect();
+            (segments, debug.locate(Path::new(path), &file))
+        };
+        // Separate debug info has the addresses of the binary it belongs to.
+        let loader = match debug_file.and_then(|debug_file| Loader::new(debug_file).ok()) {
+            Some(loader) => loader,
+            None => Loader::new(path).ok()?,
+        };
+        Some(Binary { loader, segments })
+    }
+
+    /// Convert an offset into the file to the address the binary expects
+    fn address_of(&self, file_offset: u64) -> Option<u64> {
+        self.segments
+            .iter()
+            .find(|s| s.file_offset <= file_offset && file_offset < s.file_offset + s.file_size)
+            .map(|s| file_offset - s.file_offset + s.address)
+    }
+
+    fn frames(&self, addr: u64, probe: u64) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        if let Ok(mut iter) = self.loader.find_frames(probe) {
+            while let Ok(Some(frame)) = iter.next() {
+                let function = frame
+                    .function
+                    .as_ref()
+                    .and_then(|name| name.demangle().ok())
+                    .map(|name| name.into_owned());
+                let (file, line) = match frame.location {
+                    Some(location) => (location.file.map(|f| f.to_owned()), location.line),
+                    None => (None, None),
+                };
+                frames.push(Frame {
+                    address: addr,
+                    function,
+                    file,
+                    line,
+                    inlined: true,
+                });
+            }
+        }
+        // The last frame is the function the others were inlined into.
+        if let Some(last) = frames.last_mut() {
+            last.inlined = false;
+        }
+
+        // Without debug info fall back to the symbol table.
+        if frames.iter().all(|f| f.function.is_none()) {
+            let function = self
+                .loader
+                .find_symbol(probe)
+                .map(|name| addr2line::demangle_auto(Cow::from(name), None).into_owned());
+            let line = frames.first().and_then(|f| f.line);
+            let file = frames.first().and_then(|f| f.file.clone());
+            frames = vec![Frame {
+                address: addr,
+                function,
+                file,
+                line,
+                inlined: false,
+            }];
+        }
+        frames
+    }
+}
@@ -0,0 +1,193 @@
+//! Attributing samples to async tasks
+//!
+//! Executors such as tokio move tasks between their worker threads, so the
+//! stacks of a CPU profile mix the work of every task and the thread counts
+//! say nothing about which task was busy. Marking the code of a task with
+//! `enter`, or wrapping its future with `instrument` so that every poll is
+//! marked, records the name of the task with each sample taken meanwhile.
+//! With the `tracing` feature, `trace::TaskLayer` marks the spans entered
+//! instead, which covers tokio tasks when tokio emits its task spans.
+//!
+//! `pprof::Encoder` exports the task of each sample as its `task` label, so
+//! `pprof -tagfocus task=ingest` and continuous profiling backends can split
+//! a profile by task. The tasks are written after the memory mappings, one
+//! line per task and stack:
+//!
+//! ```text
+//! cpuprofiler-task: <samples> <depth> <pc>... <name>
+//! ```
+//!
+//! The cpuprofiler library does not hand the stacks it takes to Rust, so
+//! only the `Sampler` backend records tasks, and only on Unix, where it
+//! samples from the thread which was interrupted.
+//!
+//! # Examples
+//!
+//! ```
+//! use cpuprofiler::tasks;
+//!
+//! let _task = tasks::enter("ingest");
+//! assert_eq!(tasks::current().as_deref(), Some("ingest"));
+//! // Code you want to sample goes here!
+//! ```
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+
+/// The start of every line of the task section
+pub(crate) const PREFIX: &str = "cpuprofiler-task:";
+
+/// The pprof label the task of a sample is exported as
+pub(crate) const LABEL: &str = "task";
+
+thread_local! {
+    /// The task running on this thread, 0 for none
+    ///
+    /// This is read from the signal handler, so it has no destructor and
+    /// needs no lazy initialization.
+    static CURRENT: Cell<u32> = const { Cell::new(0) };
+}
+
+#[derive(Default)]
+struct Names {
+    ids: HashMap<String, u32>,
+    names: Vec<String>,
+}
+
+lazy_static! {
+    static ref NAMES: Mutex<Names> = Mutex::new(Names::default());
+}
+
+/// Returns the id of the task `name`, the same for every call
+pub(crate) fn intern(name: &str) -> u32 {
+    let mut names = NAMES.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(&id) = names.ids.get(name) {
+        return id;
+    }
+    names.names.push(name.to_owned());
+    let id = names.names.len() as u32;
+    names.ids.insert(name.to_owned(), id);
+    id
+}
+
+/// Returns the name of the task `id`
+pub(crate) fn name(id: u32) -> Option<String> {
+    let names = NAMES.lock().unwrap_or_else(|e| e.into_inner());
+    names.names.get((id as usize).checked_sub(1)?).cloned()
+}
+
+/// Returns the id of the calling thread's task, 0 for none
+///
+/// This runs in the signal handler, so it only reads the thread local.
+#[cfg(all(unix, feature = "sampler"))]
+pub(crate) fn current_id() -> u32 {
+    CURRENT.try_with(|current| current.get()).unwrap_or(0)
+}
+
+/// Make `id` the calling thread's task, returning the previous one
+pub(crate) fn swap(id: u32) -> u32 {
+    CURRENT.try_with(|current| current.replace(id)).unwrap_or(0)
+}
+
+/// Marks the calling thread as running a task, until it is dropped
+///
+/// The guard is not `Send`, since the task is only marked on the thread
+/// which entered it.
+#[derive(Debug)]
+#[must_use = "the task is left as soon as the guard is dropped"]
+pub struct TaskGuard {
+    previous: u32,
+    not_send: PhantomData<*mut ()>,
+}
+
+impl TaskGuard {
+    pub(crate) fn enter_id(id: u32) -> TaskGuard {
+        TaskGuard {
+            previous: swap(id),
+            not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        swap(self.previous);
+    }
+}
+
+/// Record samples of the calling thread as samples of the task `name`
+///
+/// Tasks nest, the task entered last is recorded until its guard is
+/// dropped.
+pub fn enter(name: &str) -> TaskGuard {
+    TaskGuard::enter_id(intern(name))
+}
+
+/// Returns the name of the calling thread's task, if it is in one
+pub fn current() -> Option<String> {
+    name(CURRENT.try_with(|current| current.get()).unwrap_or(0))
+}
+
+/// A future whose polls are samples of a task, see `instrument`
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Instrumented<F> {
+    task: u32,
+    inner: F,
+}
+
+/// Record the samples taken while `future` is polled as samples of the
+/// task `name`
+///
+/// # Examples
+///
+/// ```
+/// use std::future;
+/// use cpuprofiler::tasks;
+///
+/// // Any future, such as the one of an async block, can be instrumented.
+/// let request = tasks::instrument("handle-request", future::ready(()));
+/// # drop(request);
+/// ```
+pub fn instrument<F: Future>(name: &str, future: F) -> Instrumented<F> {
+    Instrumented {
+        task: intern(name),
+        inner: future,
+    }
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+        // The inner future is never moved out of its pinned location.
+        let this = unsafe { self.get_unchecked_mut() };
+        let _task = TaskGuard::enter_id(this.task);
+        unsafe { Pin::new_unchecked(&mut this.inner) }.poll(cx)
+    }
+}
+
+/// Returns the task section for the samples of each task and stack
+///
+/// Line breaks in names are replaced, so that each takes one line.
+#[cfg(all(unix, feature = "sampler"))]
+pub(crate) fn section(samples: &HashMap<(u32, Vec<u64>), u64>) -> Vec<u8> {
+    let mut section = String::new();
+    for (&(id, ref stack), &count) in samples {
+        let name: String = match name(id) {
+            Some(name) => name.chars().map(|c| if c.is_control() { '_' } else { c }).collect(),
+            None => continue,
+        };
+        section.push_str(&format!("{} {} {}", PREFIX, count, stack.len()));
+        for pc in stack {
+            section.push_str(&format!(" {:#x}", pc));
+        }
+        section.push_str(&format!(" {}\n", name));
+    }
+    section.into_bytes()
+}
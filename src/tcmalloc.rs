@@ -0,0 +1,114 @@
+//! Runtime statistics from tcmalloc
+//!
+//! Available with the `heap` feature, which links libtcmalloc.
+//!
+//! # Examples
+//!
+//! ```
+//! use cpuprofiler::tcmalloc;
+//!
+//! let stats = tcmalloc::stats().unwrap();
+//! println!("{} bytes allocated of a {} byte heap", stats.allocated_bytes, stats.heap_size);
+//! ```
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+
+use error::Error;
+
+/// A snapshot of the allocator's memory use, in bytes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MallocStats {
+    /// Bytes currently allocated by the program
+    pub allocated_bytes: u64,
+    /// Bytes reserved from the operating system
+    pub heap_size: u64,
+    /// Free bytes held in the central cache
+    pub central_cache_free_bytes: u64,
+    /// Free bytes held in the per-thread caches
+    pub thread_cache_free_bytes: u64,
+    /// Free bytes held in the transfer cache
+    pub transfer_cache_free_bytes: u64,
+    /// Free bytes in the page heap which are still mapped
+    pub pageheap_free_bytes: u64,
+    /// Free bytes in the page heap which have been returned to the system
+    pub pageheap_unmapped_bytes: u64,
+}
+
+/// Returns the current allocator statistics
+///
+/// # Failures
+///
+/// - tcmalloc does not report one of the statistics, which is always the
+///   case when profiling is `disabled`.
+pub fn stats() -> Result<MallocStats, Error> {
+    let property = |name: &str| {
+        numeric_property(name).ok_or(Error::LibraryUnavailable)
+    };
+
+    Ok(MallocStats {
+        allocated_bytes: property("generic.current_allocated_bytes")?,
+        heap_size: property("generic.heap_size")?,
+        central_cache_free_bytes: property("tcmalloc.central_cache_free_bytes")?,
+        thread_cache_free_bytes: property("tcmalloc.thread_cache_free_bytes")?,
+        transfer_cache_free_bytes: property("tcmalloc.transfer_cache_free_bytes")?,
+        pageheap_free_bytes: property("tcmalloc.pageheap_free_bytes")?,
+        pageheap_unmapped_bytes: property("tcmalloc.pageheap_unmapped_bytes")?,
+    })
+}
+
+/// Returns a numeric property of the allocator, such as `generic.heap_size`
+///
+/// Returns `None` if tcmalloc does not know the property. The available
+/// properties are listed in gperftools' `malloc_extension.h`.
+pub fn numeric_property(name: &str) -> Option<u64> {
+    let c_name = CString::new(name).ok()?;
+    let mut value = 0;
+    let found = unsafe { ffi::MallocExtension_GetNumericProperty(c_name.as_ptr(), &mut value) };
+    if found != 0 {
+        Some(value as u64)
+    } else {
+        None
+    }
+}
+
+/// Returns tcmalloc's human readable description of its state
+pub fn stats_text() -> String {
+    let mut buf = vec![0 as c_char; 16 * 1024];
+    unsafe {
+        ffi::MallocExtension_GetStats(buf.as_mut_ptr(), buf.len() as c_int);
+        // The library always terminates the buffer, make sure of it anyway.
+        *buf.last_mut().unwrap() = 0;
+        CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned()
+    }
+}
+
+/// Declarations of the MallocExtension C functions
+#[allow(non_snake_case)]
+#[cfg(not(feature = "disabled"))]
+mod ffi {
+    use std::os::raw::{c_char, c_int};
+
+    extern "C" {
+        pub fn MallocExtension_GetNumericProperty(property: *const c_char, value: *mut usize) -> c_int;
+
+        pub fn MallocExtension_GetStats(buffer: *mut c_char, length: c_int);
+    }
+}
+
+/// No-op stand-ins used when profiling is compiled out
+#[allow(non_snake_case)]
+#[cfg(feature = "disabled")]
+mod ffi {
+    use std::os::raw::{c_char, c_int};
+
+    pub unsafe fn MallocExtension_GetNumericProperty(_property: *const c_char, _value: *mut usize) -> c_int {
+        0
+    }
+
+    pub unsafe fn MallocExtension_GetStats(buffer: *mut c_char, length: c_int) {
+        if length > 0 {
+            *buffer = 0;
+        }
+    }
+}
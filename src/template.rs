@@ -0,0 +1,64 @@
+//! Expansion of file name templates
+
+#[cfg(not(unix))]
+use std::env;
+use std::process;
+use std::time::SystemTime;
+
+#[cfg(unix)]
+use libc::{self, c_char};
+
+use timestamp;
+
+/// Expand the `%` tokens in a file name template
+///
+/// Unknown tokens are left untouched.
+pub fn expand(template: &[u8], sequence: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(template.len());
+    let mut bytes = template.iter();
+    while let Some(&b) = bytes.next() {
+        if b != b'%' {
+            out.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'p') => out.extend_from_slice(process::id().to_string().as_bytes()),
+            Some(b't') => out.extend_from_slice(timestamp::format_utc(SystemTime::now()).as_bytes()),
+            Some(b'h') => out.extend_from_slice(hostname().as_bytes()),
+            Some(b'n') => out.extend_from_slice(format!("{:04}", sequence).as_bytes()),
+            Some(b'%') => out.push(b'%'),
+            Some(&other) => out.extend_from_slice(&[b'%', other]),
+            None => out.push(b'%'),
+        }
+    }
+    out
+}
+
+/// Escape `name` so that it expands to itself
+pub fn escape(name: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(name.len());
+    for &b in name {
+        out.push(b);
+        if b == b'%' {
+            out.push(b'%');
+        }
+    }
+    out
+}
+
+/// The host name, or `localhost` if it cannot be read
+#[cfg(unix)]
+pub(crate) fn hostname() -> String {
+    let mut buf = [0 as c_char; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len() - 1) };
+    if res != 0 {
+        return "localhost".to_owned();
+    }
+    let bytes: Vec<u8> = buf.iter().take_while(|&&c| c != 0).map(|&c| c as u8).collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn hostname() -> String {
+    env::var("COMPUTERNAME").unwrap_or_else(|_| "localhost".to_owned())
+}
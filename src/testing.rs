@@ -0,0 +1,100 @@
+//! Profiling individual tests
+//!
+//! The test runner runs tests on many threads but only one profile can run
+//! at a time. `profile_test` runs a test while holding a lock shared by all
+//! profiled tests, writing its profile to `target/profiles/<test name>.profile`,
+//! and the `profiled_test!` macro declares such a test.
+//!
+//! Tests which are not profiled still run in parallel with profiled ones, so
+//! their work shows up in the profiles too. Use `--test-threads=1` for
+//! profiles of a single test only.
+//!
+//! # Examples
+//!
+//! ```
+//! #[macro_use]
+//! extern crate cpuprofiler;
+//!
+//! profiled_test! {
+//!     fn sorts_quickly() {
+//!         let mut v: Vec<u32> = (0..100_000).rev().collect();
+//!         v.sort();
+//!     }
+//! }
+//! # fn main() {}
+//! ```
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use paths;
+use template;
+use Profiler;
+
+lazy_static! {
+    static ref TEST_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Run `test` while profiling it as `name`
+///
+/// `::` in the name is replaced by `.`, so names such as
+/// `module_path!()` produces can be used directly. If the profiler cannot
+/// be started, for example because it is already running, the test runs
+/// without being profiled.
+pub fn profile_test<F, R>(name: &str, test: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    // A failed test panics while holding the lock, which is fine to reuse.
+    let _serial = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let dir = profiles_dir();
+    let path = dir.join(format!("{}.profile", name.replace("::", ".")));
+    let guard = fs::create_dir_all(&dir)
+        .map_err(Into::into)
+        .and_then(|_| Profiler::start_async(template::escape(&paths::to_bytes(&path))));
+    if let Err(ref e) = guard {
+        eprintln!("not profiling {}: {}", name, e);
+    }
+
+    // The guard stops the profile even if the test panics.
+    let res = test();
+    if let Ok(guard) = guard {
+        let _ = guard.stop();
+    }
+    res
+}
+
+/// Find `target/profiles` from the location of the test binary
+fn profiles_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("CARGO_TARGET_DIR") {
+        return PathBuf::from(dir).join("profiles");
+    }
+    // Test binaries are built into `target/<profile>/deps`.
+    let exe = env::current_exe().ok();
+    let deps = exe.as_ref().and_then(|exe| exe.parent());
+    match deps {
+        Some(deps) if deps.file_name().is_some_and(|n| n == "deps") => match deps.parent().and_then(|p| p.parent()) {
+            Some(target) => target.join("profiles"),
+            None => PathBuf::from("target/profiles"),
+        },
+        _ => PathBuf::from("target/profiles"),
+    }
+}
+
+/// Declare a test which is profiled with `profile_test`
+///
+/// The profile is named after the module path and name of the test.
+/// Attributes such as `#[should_panic]` are passed on to the test.
+#[macro_export]
+macro_rules! profiled_test {
+    ($(#[$attr:meta])* fn $name:ident() $body:block) => {
+        #[test]
+        $(#[$attr])*
+        fn $name() {
+            $crate::testing::profile_test(concat!(module_path!(), "::", stringify!($name)), || $body)
+        }
+    };
+}
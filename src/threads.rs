@@ -0,0 +1,146 @@
+//! Counting the samples of each thread
+//!
+//! Stacks in the cpuprofiler format do not say which thread they were
+//! sampled on, so all threads are merged. Alongside the stacks the signal
+//! handler counts the samples of every thread, and when the profile stops
+//! the counts are appended to the file after the memory mappings, one line
+//! per thread:
+//!
+//! ```text
+//! cpuprofiler-thread: <id> <samples> <name>
+//! ```
+//!
+//! Other readers of the format, such as pprof, skip lines they do not
+//! recognise among the mappings. Threads are named by
+//! `Profiler::register_thread` or `Profiler::name_thread`, or else by the
+//! name the kernel has for them while they are still running.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+#[cfg(unix)]
+use libc;
+
+/// The most threads counted in one profile, samples of any further
+/// threads are only in the stacks
+const SLOTS: usize = 1024;
+
+/// The start of every line of the thread section
+pub(crate) const PREFIX: &str = "cpuprofiler-thread:";
+
+struct Slot {
+    /// The thread id, 0 while the slot is free
+    id: AtomicU64,
+    samples: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE: Slot = Slot {
+    id: AtomicU64::new(0),
+    samples: AtomicU64::new(0),
+};
+
+static COUNTS: [Slot; SLOTS] = [FREE; SLOTS];
+
+lazy_static! {
+    static ref NAMES: Mutex<HashMap<u64, String>> = Mutex::new(HashMap::new());
+}
+
+/// Returns the id of the calling thread, as the kernel reports it
+#[cfg(target_os = "linux")]
+pub(crate) fn current() -> u64 {
+    unsafe { libc::syscall(libc::SYS_gettid) as u64 }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+pub(crate) fn current() -> u64 {
+    unsafe { libc::pthread_self() as u64 }
+}
+
+#[cfg(windows)]
+pub(crate) fn current() -> u64 {
+    unsafe { ::windows_sys::Win32::System::Threading::GetCurrentThreadId() as u64 }
+}
+
+/// Count a sample of the calling thread
+///
+/// This runs in the signal handler, so it only uses atomics.
+#[cfg(all(unix, any(feature = "gperftools", feature = "sampler")))]
+pub(crate) fn record() {
+    record_samples(current(), 1);
+}
+
+/// Count `samples` samples of the thread `id`
+#[cfg(any(feature = "gperftools", feature = "sampler"))]
+pub(crate) fn record_samples(id: u64, samples: u64) {
+    let start = (id as usize).wrapping_mul(0x9E37_79B9) % SLOTS;
+    for i in 0..SLOTS {
+        let slot = &COUNTS[(start + i) % SLOTS];
+        let owner = match slot.id.compare_exchange(0, id, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => id,
+            Err(owner) => owner,
+        };
+        if owner == id {
+            slot.samples.fetch_add(samples, Ordering::Relaxed);
+            return;
+        }
+    }
+}
+
+/// Forget the counts of the last profile, before the next starts
+pub(crate) fn reset() {
+    for slot in COUNTS.iter() {
+        slot.samples.store(0, Ordering::Relaxed);
+        slot.id.store(0, Ordering::Release);
+    }
+}
+
+/// Name the thread `id` in later profiles
+pub(crate) fn set_name(id: u64, name: String) {
+    NAMES.lock().unwrap_or_else(|e| e.into_inner()).insert(id, name);
+}
+
+fn name(id: u64, names: &HashMap<u64, String>) -> Option<String> {
+    if let Some(name) = names.get(&id) {
+        return Some(name.clone());
+    }
+    let comm = fs::read_to_string(format!("/proc/self/task/{}/comm", id)).ok()?;
+    let comm = comm.trim_end();
+    if comm.is_empty() {
+        None
+    } else {
+        Some(comm.to_owned())
+    }
+}
+
+/// Append the thread section to the profile at `path`
+///
+/// Nothing is written when no samples were counted. Line breaks and `=`,
+/// which pprof reads as a variable assignment, are replaced in names.
+pub(crate) fn append(path: &Path) -> std::io::Result<()> {
+    let mut counts: Vec<(u64, u64)> = COUNTS
+        .iter()
+        .map(|slot| (slot.id.load(Ordering::Acquire), slot.samples.load(Ordering::Relaxed)))
+        .filter(|&(id, samples)| id != 0 && samples > 0)
+        .collect();
+    if counts.is_empty() {
+        return Ok(());
+    }
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let names = NAMES.lock().unwrap_or_else(|e| e.into_inner());
+    let mut section = String::new();
+    for (id, samples) in counts {
+        let name: String = name(id, &names)
+            .unwrap_or_default()
+            .chars()
+            .map(|c| if c == '=' || c.is_control() { '_' } else { c })
+            .collect();
+        section.push_str(&format!("{} {} {} {}\n", PREFIX, id, samples, name));
+    }
+    OpenOptions::new().append(true).open(path)?.write_all(section.as_bytes())
+}
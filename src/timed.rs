@@ -0,0 +1,157 @@
+//! Profiling for a fixed duration
+//!
+//! `Profiler::start_for` starts the profiler and stops it again from a
+//! background thread once the duration has elapsed. This is the
+//! "take a 30 second profile of the running service" use case.
+//! `Profiler::start_for_into` also puts the finished profile to a
+//! [sink](../sink/index.html).
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use std::time::Duration;
+//! use cpuprofiler::PROFILER;
+//!
+//! let path = env::temp_dir().join("timed-example.profile");
+//! let timed = PROFILER.lock()
+//!     .unwrap()
+//!     .start_for(path.to_str().unwrap(), Duration::from_millis(100))
+//!     .unwrap();
+//!
+//! // Code you want to sample goes here!
+//!
+//! timed.wait().unwrap();
+//! ```
+
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use error::Error;
+use lock;
+use sink::{ProfileSink, SharedSink};
+use Profiler;
+
+/// A handle to a profile which stops automatically
+///
+/// Dropping the handle does not stop the profile early, the background thread
+/// will still stop the profiler once the duration has elapsed.
+#[derive(Debug)]
+pub struct TimedProfile {
+    cancel: Sender<()>,
+    thread: JoinHandle<Result<(), Error>>,
+}
+
+impl TimedProfile {
+    /// Block until the profile has been stopped
+    ///
+    /// # Failures
+    ///
+    /// - The profiler could not be stopped.
+    /// - The profile could not be put to the sink, see
+    ///   `Profiler::start_for_into`.
+    pub fn wait(self) -> Result<(), Error> {
+        match self.thread.join() {
+            Ok(res) => res,
+            Err(_) => Err(Error::Internal),
+        }
+    }
+
+    /// Stop the profile now rather than waiting for the duration to elapse
+    ///
+    /// The samples collected so far are still written to the profile.
+    ///
+    /// # Failures
+    ///
+    /// - The profiler could not be stopped.
+    pub fn cancel(self) -> Result<(), Error> {
+        // The thread may have already stopped the profiler.
+        let _ = self.cancel.send(());
+        self.wait()
+    }
+}
+
+impl Profiler {
+    /// Start the profiler and stop it after `duration`
+    ///
+    /// The profiler is stopped from a background thread, which locks
+    /// `PROFILER` once the duration has elapsed. If the profile was already
+    /// stopped by hand in the meantime the background thread does nothing.
+    ///
+    /// # Failures
+    ///
+    /// - The profiler could not be started, see `start`.
+    pub fn start_for<T: Into<Vec<u8>>>(&mut self, fname: T, duration: Duration) -> Result<TimedProfile, Error> {
+        self.start_for_sink(fname, duration, None)
+    }
+
+    /// Start the profiler, stop it after `duration` and put the profile to
+    /// `sink`
+    ///
+    /// The profile is put from the background thread, also when it was
+    /// stopped by hand, unless another profile has been started since.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::env;
+    /// use std::time::Duration;
+    /// use cpuprofiler::PROFILER;
+    /// use cpuprofiler::sink::Directory;
+    ///
+    /// let path = env::temp_dir().join("timed-sink-example.profile");
+    /// let archive = Directory::new(env::temp_dir().join("timed-sink-example"));
+    /// let timed = PROFILER.lock()
+    ///     .unwrap()
+    ///     .start_for_into(path.to_str().unwrap(), Duration::from_millis(50), archive)
+    ///     .unwrap();
+    /// timed.wait().unwrap();
+    /// ```
+    ///
+    /// # Failures
+    ///
+    /// - The profiler could not be started, see `start`.
+    pub fn start_for_into<T, S>(&mut self, fname: T, duration: Duration, sink: S) -> Result<TimedProfile, Error>
+    where
+        T: Into<Vec<u8>>,
+        S: ProfileSink + 'static,
+    {
+        self.start_for_sink(fname, duration, Some(SharedSink::new(sink)))
+    }
+
+    fn start_for_sink<T>(&mut self, fname: T, duration: Duration, sink: Option<SharedSink>) -> Result<TimedProfile, Error>
+    where
+        T: Into<Vec<u8>>,
+    {
+        self.start(fname)?;
+        let session = self.session;
+        let path = self.path.clone();
+
+        let (tx, rx) = mpsc::channel();
+        let started = Instant::now();
+        let thread = thread::spawn(move || {
+            // Either the duration elapsed or we were cancelled. A dropped
+            // handle cancels nothing, so the profile runs its full duration.
+            if let Err(RecvTimeoutError::Disconnected) = rx.recv_timeout(duration) {
+                thread::sleep(duration.saturating_sub(started.elapsed()));
+            }
+
+            let mut profiler = lock::lock();
+            if profiler.session != session {
+                return Ok(());
+            }
+            if profiler.state.is_running() {
+                profiler.stop()?;
+            }
+            drop(profiler);
+
+            match (sink, path) {
+                (Some(sink), Some(path)) => sink.put(&path),
+                _ => Ok(()),
+            }
+        });
+
+        Ok(TimedProfile { cancel: tx, thread })
+    }
+}
@@ -0,0 +1,152 @@
+//! Reading configuration files written in a subset of TOML
+//!
+//! Tables, strings, integers, booleans and arrays of them written on one
+//! line are understood, which is all the agent's configuration needs.
+//! Floats, dates, inline tables and values spanning lines are not.
+
+/// A value of a key
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+/// Parse `text` into its keys, each named by its table and key such as
+/// `agent.dir`, and their values, in order
+///
+/// Errors name the line which could not be parsed.
+pub fn parse(text: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut entries: Vec<(String, Value)> = Vec::new();
+    let mut table = String::new();
+    for (n, line) in text.lines().enumerate() {
+        let at_line = |reason: String| format!("line {}: {}", n + 1, reason);
+        let mut rest = line.trim();
+        if rest.is_empty() || rest.starts_with('#') {
+            continue;
+        }
+
+        if let Some(header) = rest.strip_prefix('[') {
+            let end = header.find(']').ok_or_else(|| at_line("a table header without `]`".to_owned()))?;
+            if !is_comment(&header[end + 1..]) {
+                return Err(at_line("text after a table header".to_owned()));
+            }
+            table = header[..end].trim().to_owned();
+            if table.is_empty() || table.starts_with('[') {
+                return Err(at_line(format!("the table `{}` is not supported", table)));
+            }
+            continue;
+        }
+
+        let key = parse_key(&mut rest).map_err(at_line)?;
+        rest = rest.trim_start();
+        rest = rest.strip_prefix('=').ok_or_else(|| at_line(format!("`=` expected after `{}`", key)))?;
+        let value = parse_value(&mut rest).map_err(at_line)?;
+        if !is_comment(rest) {
+            return Err(at_line(format!("text after the value of `{}`", key)));
+        }
+
+        let name = if table.is_empty() { key } else { format!("{}.{}", table, key) };
+        if entries.iter().any(|(seen, _)| *seen == name) {
+            return Err(at_line(format!("`{}` is set twice", name)));
+        }
+        entries.push((name, value));
+    }
+    Ok(entries)
+}
+
+fn is_comment(rest: &str) -> bool {
+    let rest = rest.trim();
+    rest.is_empty() || rest.starts_with('#')
+}
+
+fn parse_key(rest: &mut &str) -> Result<String, String> {
+    if rest.starts_with('"') {
+        return parse_string(rest);
+    }
+    let end = rest
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+        .unwrap_or(rest.len());
+    if end == 0 {
+        return Err(format!("a key expected at `{}`", rest));
+    }
+    let key = rest[..end].to_owned();
+    *rest = &rest[end..];
+    Ok(key)
+}
+
+/// Parse the value at the start of `rest`, leaving what follows it
+fn parse_value(rest: &mut &str) -> Result<Value, String> {
+    *rest = rest.trim_start();
+    if rest.starts_with('"') || rest.starts_with('\'') {
+        return parse_string(rest).map(Value::String);
+    }
+    if let Some(items) = rest.strip_prefix('[') {
+        *rest = items;
+        let mut values = Vec::new();
+        loop {
+            *rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                *rest = after;
+                return Ok(Value::Array(values));
+            }
+            values.push(parse_value(rest)?);
+            *rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                *rest = after;
+            } else if !rest.starts_with(']') {
+                return Err("`,` or `]` expected in an array, which must be on one line".to_owned());
+            }
+        }
+    }
+
+    let end = rest.find(|c: char| c == ',' || c == ']' || c == '#' || c.is_whitespace()).unwrap_or(rest.len());
+    let word = &rest[..end];
+    *rest = &rest[end..];
+    match word {
+        "true" => Ok(Value::Boolean(true)),
+        "false" => Ok(Value::Boolean(false)),
+        _ => word
+            .replace('_', "")
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| format!("`{}` is not a string, integer, boolean or array", word)),
+    }
+}
+
+/// Parse the basic or literal string at the start of `rest`
+fn parse_string(rest: &mut &str) -> Result<String, String> {
+    let quote = rest.chars().next().unwrap_or('"');
+    let mut out = String::new();
+    let mut chars = rest[1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c == quote => {
+                *rest = &rest[i + 2..];
+                return Ok(out);
+            }
+            '\\' if quote == '"' => {
+                let escaped = match chars.next().map(|(_, c)| c) {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                        u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("`\\u{}` is not a character", hex))?
+                    }
+                    Some(c) => return Err(format!("the escape `\\{}` is not supported", c)),
+                    None => break,
+                };
+                out.push(escaped);
+            }
+            c => out.push(c),
+        }
+    }
+    Err("a string without its closing quote".to_owned())
+}
@@ -0,0 +1,256 @@
+//! Profiling `tracing` spans
+//!
+//! Available with the `tracing` feature.
+//!
+//! The `ProfilingLayer` is a `tracing_subscriber` layer which starts the
+//! profiler when a matching span is entered and stops it when the span is
+//! exited. Spans are matched by target and name, and each span instance
+//! writes its own profile named `<span name>-<span id>.profile`. A span
+//! which is entered again, like the span of a future polled more than once,
+//! writes `<span name>-<span id>.<n>.profile` for its n-th entry.
+//!
+//! Only one profile runs at a time, so a matching span entered while the
+//! profiler is already `Active` is not profiled.
+//!
+//! The `TaskLayer` instead records the samples taken in each span as
+//! samples of a task named after it, see the [`tasks`](../tasks/index.html)
+//! module, so one profile can be split by span.
+//!
+//! # Examples
+//!
+//! ```
+//! extern crate cpuprofiler;
+//! extern crate tracing;
+//! extern crate tracing_subscriber;
+//!
+//! use cpuprofiler::trace::ProfilingLayer;
+//! use tracing_subscriber::layer::SubscriberExt;
+//!
+//! # fn main() {
+//! let layer = ProfilingLayer::new(".").name("expensive");
+//! let subscriber = tracing_subscriber::registry().with(layer);
+//!
+//! tracing::subscriber::with_default(subscriber, || {
+//!     let span = tracing::info_span!("expensive");
+//!     let _entered = span.enter();
+//!     // Code you want to sample goes here!
+//! });
+//! # }
+//! ```
+
+use std::cell::RefCell;
+use std::fmt;
+use std::path::PathBuf;
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use lock;
+use tasks;
+use ProfilerState;
+
+/// A layer which profiles matching spans
+#[derive(Clone, Debug)]
+pub struct ProfilingLayer {
+    dir: PathBuf,
+    target: Option<String>,
+    name: Option<String>,
+}
+
+/// Stored in the extensions of a profiled span
+struct Profiled {
+    entries: u64,
+    session: Option<u64>,
+}
+
+impl ProfilingLayer {
+    /// Write profiles into `dir`
+    ///
+    /// Without a target or name every span is profiled.
+    pub fn new<D: Into<PathBuf>>(dir: D) -> ProfilingLayer {
+        ProfilingLayer {
+            dir: dir.into(),
+            target: None,
+            name: None,
+        }
+    }
+
+    /// Only profile spans in `target` or its child modules
+    pub fn target<T: Into<String>>(mut self, target: T) -> ProfilingLayer {
+        self.target = Some(target.into());
+        self
+    }
+
+    /// Only profile spans named `name`
+    pub fn name<T: Into<String>>(mut self, name: T) -> ProfilingLayer {
+        self.name = Some(name.into());
+        self
+    }
+
+    fn matches(&self, metadata: &Metadata) -> bool {
+        let target_matches = self.target.as_ref().is_none_or(|target| {
+            let span_target = metadata.target();
+            span_target == target
+                || (span_target.starts_with(target.as_str())
+                    && span_target[target.len()..].starts_with("::"))
+        });
+        let name_matches = self.name.as_ref().is_none_or(|name| metadata.name() == name);
+        target_matches && name_matches
+    }
+}
+
+impl<S> Layer<S> for ProfilingLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_enter(&self, id: &Id, ctx: Context<S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        if !self.matches(span.metadata()) {
+            return;
+        }
+
+        let mut extensions = span.extensions_mut();
+        if extensions.get_mut::<Profiled>().is_none() {
+            extensions.insert(Profiled {
+                entries: 0,
+                session: None,
+            });
+        }
+        let profiled = extensions.get_mut::<Profiled>().unwrap();
+
+        let fname = if profiled.entries == 0 {
+            format!("{}-{}.profile", span.name(), id.into_u64())
+        } else {
+            format!("{}-{}.{}.profile", span.name(), id.into_u64(), profiled.entries)
+        };
+        profiled.entries += 1;
+
+        let mut profiler = lock::lock();
+        if profiler.state() == ProfilerState::NotActive && profiler.start_path(self.dir.join(fname)).is_ok() {
+            profiled.session = Some(profiler.session);
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+
+        let mut extensions = span.extensions_mut();
+        let session = match extensions.get_mut::<Profiled>() {
+            Some(profiled) => profiled.session.take(),
+            None => return,
+        };
+
+        let mut profiler = lock::lock();
+        if profiler.state().is_running() && session == Some(profiler.session) {
+            let _ = profiler.stop();
+        }
+    }
+}
+
+/// A layer which records the samples taken in a span as samples of a task
+///
+/// The task is named after the span, or after its `task.name` field if it
+/// has one, which is what tokio names the spans of spawned tasks when it is
+/// built with `tokio_unstable` and its `tracing` feature. The innermost
+/// span entered is the task recorded.
+///
+/// # Examples
+///
+/// ```
+/// extern crate cpuprofiler;
+/// extern crate tracing;
+/// extern crate tracing_subscriber;
+///
+/// use cpuprofiler::tasks;
+/// use cpuprofiler::trace::TaskLayer;
+/// use tracing_subscriber::layer::SubscriberExt;
+///
+/// # fn main() {
+/// let subscriber = tracing_subscriber::registry().with(TaskLayer::new());
+///
+/// tracing::subscriber::with_default(subscriber, || {
+///     let span = tracing::info_span!("ingest");
+///     let _entered = span.enter();
+///     assert_eq!(tasks::current().as_deref(), Some("ingest"));
+/// });
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct TaskLayer {
+    _private: (),
+}
+
+impl TaskLayer {
+    /// Record every span as a task
+    pub fn new() -> TaskLayer {
+        TaskLayer::default()
+    }
+}
+
+/// Stored in the extensions of every span, the id of its task
+struct Task(u32);
+
+/// Finds the `task.name` field of a span
+#[derive(Default)]
+struct TaskName(Option<String>);
+
+impl Visit for TaskName {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "task.name" {
+            self.0 = Some(value.to_owned());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "task.name" {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+thread_local! {
+    /// The tasks of the spans this thread entered before the current one
+    static ENTERED: RefCell<Vec<u32>> = const { RefCell::new(Vec::new()) };
+}
+
+impl<S> Layer<S> for TaskLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut name = TaskName::default();
+        attrs.record(&mut name);
+        let task = tasks::intern(name.0.as_deref().unwrap_or_else(|| span.name()));
+        span.extensions_mut().insert(Task(task));
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<S>) {
+        let task = match ctx.span(id).and_then(|span| span.extensions().get::<Task>().map(|task| task.0)) {
+            Some(task) => task,
+            None => return,
+        };
+        let previous = tasks::swap(task);
+        let _ = ENTERED.try_with(|entered| entered.borrow_mut().push(previous));
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<S>) {
+        if ctx.span(id).is_none_or(|span| span.extensions().get::<Task>().is_none()) {
+            return;
+        }
+        let previous = ENTERED.try_with(|entered| entered.borrow_mut().pop()).ok().flatten();
+        tasks::swap(previous.unwrap_or(0));
+    }
+}
@@ -0,0 +1,74 @@
+//! Observing the profiler's state changes
+//!
+//! `Profiler::on_transition` registers a callback which is told about every
+//! change of `ProfilerState`, for logging or exporting metrics about
+//! profiling sessions.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use cpuprofiler::PROFILER;
+//!
+//! PROFILER.lock().unwrap().on_transition(|t| {
+//!     println!("profile {}: {} -> {}", t.session, t.from, t.to);
+//! });
+//!
+//! let path = env::temp_dir().join("transition-example.profile");
+//! PROFILER.lock().unwrap().start(path.to_str().unwrap()).unwrap();
+//! PROFILER.lock().unwrap().stop().unwrap();
+//! ```
+
+use std::fmt;
+
+use {Profiler, ProfilerState};
+
+/// A change of the profiler's state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Transition {
+    /// The state before the change
+    pub from: ProfilerState,
+    /// The state after the change
+    pub to: ProfilerState,
+    /// The number of the profile, counting from 1 for the first profile started
+    pub session: u64,
+}
+
+type Observer = Box<dyn Fn(&Transition) + Send>;
+
+/// The callbacks registered with `on_transition`
+#[derive(Default)]
+pub(crate) struct Observers(Vec<Observer>);
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Observers({})", self.0.len())
+    }
+}
+
+impl Profiler {
+    /// Call `observer` on every change of the profiler's state
+    ///
+    /// Observers are called in the order they were registered, while the
+    /// profiler is locked, so they must not use `PROFILER` themselves.
+    pub fn on_transition<F>(&mut self, observer: F)
+    where
+        F: Fn(&Transition) + Send + 'static,
+    {
+        self.observers.0.push(Box::new(observer));
+    }
+
+    /// Change the state to `to`, telling the observers
+    pub(crate) fn transition(&mut self, to: ProfilerState) {
+        let transition = Transition {
+            from: self.state,
+            to,
+            session: self.session,
+        };
+        self.state = to;
+        for observer in &self.observers.0 {
+            observer(&transition);
+        }
+    }
+}
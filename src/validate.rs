@@ -0,0 +1,182 @@
+//! Checking that a host is ready to profile
+//!
+//! `Profiler::validate` makes the checks `start` would make, of the
+//! profiler's state, the output path, the backend and the sampling
+//! settings, without starting a profile or changing anything. Every check
+//! is made, even after one fails, and the result describes each of them.
+//! `ProfilerBuilder::validate_on` checks the builder's settings as well, and
+//! `ProfilerBuilder::dry_run` turns `start_on` into the same checks.
+//!
+//! Passing the checks does not guarantee the profile starts, the backend
+//! only gets to refuse once it is asked to.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use cpuprofiler::PROFILER;
+//!
+//! let path = env::temp_dir().join("validate-example.profile");
+//! let diagnosis = PROFILER.lock().unwrap().validate(path.to_str().unwrap());
+//! print!("{}", diagnosis);
+//! if diagnosis.is_ready() {
+//!     println!("profiles will be written to {}", diagnosis.path().unwrap().display());
+//! }
+//! ```
+
+use std::ffi::CString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use builder::{self, TimerKind};
+use error::Error;
+use fallback::check_fallback;
+use paths;
+use template;
+use {Profiler, ProfilerState};
+
+/// The outcome of one check made by `Profiler::validate`
+#[derive(Debug)]
+pub struct Check {
+    /// What was checked, such as `"path"` or `"frequency"`
+    pub name: &'static str,
+    /// What was found, or why the check failed
+    pub result: Result<String, Error>,
+}
+
+/// The outcome of every check made by `Profiler::validate`
+///
+/// `Display` writes one line per check.
+#[derive(Debug)]
+pub struct Diagnosis {
+    path: Option<PathBuf>,
+    checks: Vec<Check>,
+}
+
+impl Diagnosis {
+    /// Returns the checks, in the order they were made
+    pub fn checks(&self) -> &[Check] {
+        &self.checks
+    }
+
+    /// Returns the absolute path the profile would be written to, if the
+    /// path passed its check
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Returns whether every check passed
+    pub fn is_ready(&self) -> bool {
+        self.checks.iter().all(|check| check.result.is_ok())
+    }
+
+    /// Returns the error of the first check which failed, if any
+    ///
+    /// # Failures
+    ///
+    /// - The first error found, see `Profiler::start` for what they mean.
+    pub fn into_result(self) -> Result<(), Error> {
+        match self.checks.into_iter().find_map(|check| check.result.err()) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn check(&mut self, name: &'static str, result: Result<String, Error>) {
+        self.checks.push(Check { name, result });
+    }
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for check in &self.checks {
+            match check.result {
+                Ok(ref found) => writeln!(f, "ok   {}: {}", check.name, found)?,
+                Err(ref e) => writeln!(f, "FAIL {}: {}", check.name, e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The settings to check, those of a `ProfilerBuilder`
+#[derive(Default)]
+pub(crate) struct Settings {
+    pub timer: Option<TimerKind>,
+    pub frequency: Option<u32>,
+    pub per_thread_timers: Option<bool>,
+    pub create_dirs: Option<bool>,
+    pub fallback_dirs: Option<Vec<PathBuf>>,
+}
+
+/// Make every check of starting `profiler` with `settings`
+pub(crate) fn diagnose(profiler: &Profiler, fname: Vec<u8>, settings: &Settings) -> Diagnosis {
+    let mut diagnosis = Diagnosis {
+        path: None,
+        checks: Vec::new(),
+    };
+
+    let state = if profiler.taken {
+        Err(Error::Busy)
+    } else if profiler.state != ProfilerState::NotActive {
+        Err(Error::InvalidState(profiler.state))
+    } else {
+        Ok("no profile is running".to_owned())
+    };
+    diagnosis.check("state", state);
+
+    if cfg!(feature = "disabled") {
+        diagnosis.check("backend", Ok("profiling is compiled out, nothing will be written".to_owned()));
+        return diagnosis;
+    }
+
+    let create_dirs = settings.create_dirs.unwrap_or(profiler.create_dirs);
+    let fallback_dirs = settings.fallback_dirs.as_deref().unwrap_or(&profiler.fallback_dirs);
+    let path = CString::new(template::expand(&fname, profiler.session))
+        .map_err(Error::from)
+        .and_then(|c_fname| check_fallback(&paths::from_bytes(c_fname.as_bytes()), create_dirs, fallback_dirs));
+    let path = path.map(|(path, fell_back)| {
+        let mut found = match path.parent() {
+            Some(parent) if !parent.exists() => format!("{}, creating {}", path.display(), parent.display()),
+            _ => path.display().to_string(),
+        };
+        if fell_back {
+            found.push_str(", falling back from a read only path");
+        }
+        diagnosis.path = Some(path);
+        found
+    });
+    diagnosis.check("path", path);
+
+    let backend = profiler.backend.check_available().map(|()| format!("{:?}", profiler.backend));
+    diagnosis.check("backend", backend);
+
+    if let Some(timer) = settings.timer {
+        diagnosis.check("timer", profiler.backend.check_timer(timer).map(|()| timer.to_string()));
+    }
+    if let Some(hz) = settings.frequency {
+        let frequency = builder::check_frequency(hz)
+            .and_then(|()| profiler.backend.check_frequency(hz))
+            .map(|()| format!("{} Hz", hz));
+        diagnosis.check("frequency", frequency);
+    }
+    if let Some(enabled) = settings.per_thread_timers {
+        let per_thread_timers = profiler
+            .backend
+            .check_per_thread_timers(enabled)
+            .map(|()| if enabled { "enabled" } else { "disabled" }.to_owned());
+        diagnosis.check("per thread timers", per_thread_timers);
+    }
+    diagnosis
+}
+
+impl Profiler {
+    /// Check that a profile could be started into `fname`, without starting it
+    ///
+    /// The current settings of the backend are checked, use
+    /// `ProfilerBuilder::validate_on` to check other settings. See the
+    /// [`validate`](validate/index.html) module.
+    pub fn validate<T: Into<Vec<u8>>>(&self, fname: T) -> Diagnosis {
+        diagnose(self, fname.into(), &Settings::default())
+    }
+}
@@ -0,0 +1,284 @@
+//! Limiting the overhead of profiling
+//!
+//! A `Watchdog` checks the running profile from a background thread and
+//! steps in when profiling costs more than a set share of the process's CPU
+//! time. The overhead over each interval is estimated as the samples taken
+//! in it, times what a sample costs, divided by the CPU time the process
+//! used. Sampling costs more the deeper the stacks are, so set
+//! `Watchdog::sample_cost` from a measurement of the program if the
+//! default is far off.
+//!
+//! When the overhead is too high the watchdog stops the profile, keeping
+//! the samples taken so far. With `OverheadAction::LowerFrequency` it also
+//! lowers the frequency of later profiles, such as the next ones of a
+//! [`RotatingProfiler`](../rotate/struct.RotatingProfiler.html), so they
+//! stay within the limit. A profile's frequency is fixed when it starts, and
+//! the cpuprofiler library only reads it once, so with the `Gperftools`
+//! backend the frequency is only lowered before the library is first used.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::env;
+//! use std::time::Duration;
+//! use cpuprofiler::PROFILER;
+//! use cpuprofiler::watchdog::{OverheadAction, Watchdog};
+//!
+//! let watchdog = Watchdog::new(5.0)
+//!     .interval(Duration::from_millis(100))
+//!     .action(OverheadAction::LowerFrequency)
+//!     .start()
+//!     .unwrap();
+//!
+//! let path = env::temp_dir().join("watchdog-example.profile");
+//! PROFILER.lock().unwrap().start(path.to_str().unwrap()).unwrap();
+//! // Code you want to sample goes here!
+//! let _ = PROFILER.lock().unwrap().stop();
+//!
+//! for trip in watchdog.stop() {
+//!     println!("{}", trip);
+//! }
+//! ```
+
+use std::fmt;
+use std::mem;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+#[cfg(unix)]
+use libc;
+
+use error::Error;
+use lifecycle;
+use lock;
+use Profiler;
+
+/// What the watchdog does when profiling costs too much
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverheadAction {
+    /// Stop the profile
+    Stop,
+    /// Stop the profile and sample later profiles less often
+    LowerFrequency,
+}
+
+/// A time the watchdog stepped in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Trip {
+    /// The estimated overhead, as a percentage of the process's CPU time
+    pub overhead: f64,
+    /// The profile session which was stopped, see `Transition::session`
+    pub session: u64,
+    /// The frequency later profiles sample at, if it was lowered
+    pub frequency: Option<u32>,
+}
+
+impl fmt::Display for Trip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "profile {} stopped at {:.1}% overhead", self.session, self.overhead)?;
+        if let Some(hz) = self.frequency {
+            write!(f, ", frequency lowered to {} Hz", hz)?;
+        }
+        Ok(())
+    }
+}
+
+/// Configuration for an overhead watchdog
+#[derive(Clone, Debug)]
+pub struct Watchdog {
+    max_overhead: f64,
+    interval: Duration,
+    sample_cost: Duration,
+    action: OverheadAction,
+}
+
+impl Watchdog {
+    /// Step in once profiling costs more than `max_overhead` percent of the
+    /// process's CPU time
+    pub fn new(max_overhead: f64) -> Watchdog {
+        Watchdog {
+            max_overhead,
+            interval: Duration::from_secs(10),
+            sample_cost: Duration::from_micros(20),
+            action: OverheadAction::Stop,
+        }
+    }
+
+    /// Set how often the overhead is estimated
+    ///
+    /// Defaults to every 10s. Each estimate covers the interval before it.
+    pub fn interval(mut self, interval: Duration) -> Watchdog {
+        self.interval = interval;
+        self
+    }
+
+    /// Set what taking one sample is assumed to cost
+    ///
+    /// Defaults to 20µs.
+    pub fn sample_cost(mut self, cost: Duration) -> Watchdog {
+        self.sample_cost = cost;
+        self
+    }
+
+    /// Set what is done when the overhead is too high
+    ///
+    /// Defaults to `OverheadAction::Stop`.
+    pub fn action(mut self, action: OverheadAction) -> Watchdog {
+        self.action = action;
+        self
+    }
+
+    /// Estimate the overhead, in percent, of taking `samples` while the
+    /// process used `cpu` of CPU time
+    ///
+    /// Returns `None` when no CPU time was used.
+    pub fn estimate(&self, samples: u64, cpu: Duration) -> Option<f64> {
+        if cpu == Duration::from_secs(0) {
+            return None;
+        }
+        Some(samples as f64 * self.sample_cost.as_secs_f64() / cpu.as_secs_f64() * 100.0)
+    }
+
+    /// Start watching profiles on a background thread
+    ///
+    /// Every profile is watched, whoever started it, until the handle is
+    /// stopped. Intervals in which another thread holds `PROFILER` are
+    /// skipped rather than waited for.
+    ///
+    /// # Failures
+    ///
+    /// - The maximum overhead is not a positive percentage,
+    ///   `Error::Unsupported`.
+    pub fn start(self) -> Result<WatchdogHandle, Error> {
+        if self.max_overhead.is_nan() || self.max_overhead <= 0.0 {
+            return Err(Error::Unsupported(format!(
+                "an overhead limit of {}% is not a positive percentage",
+                self.max_overhead
+            )));
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let trips = Arc::new(Mutex::new(Vec::new()));
+        let tripped = trips.clone();
+
+        let thread = thread::spawn(move || {
+            let mut last: Option<Reading> = None;
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(self.interval) {
+                let mut profiler = match lock::try_lock() {
+                    Ok(profiler) => profiler,
+                    Err(_) => {
+                        last = None;
+                        continue;
+                    }
+                };
+                if profiler.taken || !profiler.state.is_running() {
+                    last = None;
+                    continue;
+                }
+
+                let reading = Reading {
+                    session: profiler.session,
+                    samples: profiler.backend.samples_gathered(),
+                    cpu: process_cpu_time(),
+                };
+                let overhead = match last {
+                    Some(last) if last.session == reading.session && last.samples <= reading.samples => {
+                        self.estimate(reading.samples - last.samples, reading.cpu.saturating_sub(last.cpu))
+                    }
+                    _ => None,
+                };
+                last = Some(reading);
+
+                if let Some(overhead) = overhead.filter(|&overhead| overhead > self.max_overhead) {
+                    let trip = self.trip(&mut profiler, overhead);
+                    lifecycle::overhead(&trip);
+                    tripped.lock().unwrap_or_else(|e| e.into_inner()).push(trip);
+                    last = None;
+                }
+            }
+        });
+
+        Ok(WatchdogHandle { stop: tx, thread, trips })
+    }
+
+    /// Stop the running profile and lower the frequency if asked to
+    fn trip(&self, profiler: &mut Profiler, overhead: f64) -> Trip {
+        let session = profiler.session;
+        let frequency = match self.action {
+            OverheadAction::Stop => None,
+            OverheadAction::LowerFrequency => profiler.backend.frequency().map(|hz| {
+                let lowered = hz as f64 * self.max_overhead / overhead;
+                (lowered as u32).clamp(1, hz)
+            }),
+        };
+        // The profile is over whether or not the backend finished it cleanly.
+        let _ = profiler.stop();
+        let frequency = frequency.filter(|&hz| profiler.backend.set_frequency(hz).is_ok());
+        Trip {
+            overhead,
+            session,
+            frequency,
+        }
+    }
+}
+
+/// What the watchdog saw at the end of an interval
+#[derive(Clone, Copy)]
+struct Reading {
+    session: u64,
+    samples: u64,
+    cpu: Duration,
+}
+
+/// Returns the CPU time used by every thread of the process
+#[cfg(unix)]
+pub(crate) fn process_cpu_time() -> Duration {
+    unsafe {
+        let mut time: libc::timespec = mem::zeroed();
+        libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut time);
+        Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+    }
+}
+
+#[cfg(windows)]
+pub(crate) fn process_cpu_time() -> Duration {
+    use windows_sys::Win32::Foundation::FILETIME;
+    use windows_sys::Win32::System::Threading::{GetCurrentProcess, GetProcessTimes};
+
+    unsafe {
+        let mut times: [FILETIME; 4] = mem::zeroed();
+        let [ref mut created, ref mut exited, ref mut kernel, ref mut user] = times;
+        if GetProcessTimes(GetCurrentProcess(), created, exited, kernel, user) == 0 {
+            return Duration::from_secs(0);
+        }
+        let units = |time: &FILETIME| (time.dwHighDateTime as u64) << 32 | time.dwLowDateTime as u64;
+        Duration::from_nanos((units(kernel) + units(user)) * 100)
+    }
+}
+
+/// A handle to a running watchdog
+#[derive(Debug)]
+pub struct WatchdogHandle {
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+    trips: Arc<Mutex<Vec<Trip>>>,
+}
+
+impl WatchdogHandle {
+    /// Returns the times the watchdog stepped in so far, oldest first
+    pub fn trips(&self) -> Vec<Trip> {
+        self.trips.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Stop watching profiles
+    ///
+    /// Returns the times the watchdog stepped in, oldest first.
+    pub fn stop(self) -> Vec<Trip> {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+        let trips = self.trips.lock().unwrap_or_else(|e| e.into_inner());
+        trips.clone()
+    }
+}